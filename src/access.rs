@@ -0,0 +1,98 @@
+//! Client IP filtering for the server.
+//!
+//! An [`AccessList`] pairs an allowlist with a denylist of [`Cidr`] ranges. A denied address is
+//! always refused; otherwise an empty allowlist lets everyone in and a non-empty one only lets in
+//! the addresses it covers.
+
+use std::fmt::Display;
+use std::net::IpAddr;
+
+use anyhow::{anyhow, Result};
+
+/// An address range such as `192.168.1.0/24` or `fd00::/8`. A bare address covers only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Self> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let network: IpAddr = address
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!(format!("Invalid address: {}", address)))?;
+        let max = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or(anyhow!(format!("Invalid prefix length: {}", prefix)))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+
+    /// Parses a comma-separated list of ranges, ignoring blank entries.
+    pub fn parse_list(value: &str) -> Result<Vec<Self>> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners report IPv4 peers as IPv4-mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self { allow, deny }
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
@@ -0,0 +1,79 @@
+//! Password hashing for server user accounts.
+//!
+//! Passwords are stored as salted PBKDF2-HMAC-SHA256 hashes (see: [`crate::config::UserAccount`])
+//! and are only ever compared in constant time. Accounts made before keep their single salted
+//! SHA-256 digest, recorded as taking [`LEGACY_ROUNDS`], until their password is reset.
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+/// PBKDF2 rounds new passwords are hashed with.
+pub const PASSWORD_ROUNDS: u32 = 200_000;
+/// The rounds recorded for a password hashed as one SHA-256 digest of the salt and password.
+pub const LEGACY_ROUNDS: u32 = 0;
+
+/// A fresh random salt, hex-encoded.
+pub fn new_salt() -> Result<String> {
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut salt).map_err(|e| anyhow!(format!("Could not generate a salt: {}", e)))?;
+    Ok(hex(&salt))
+}
+
+/// The hex-encoded PBKDF2-HMAC-SHA256 hash of `password` with `salt` over `rounds`, or with
+/// [`LEGACY_ROUNDS`], the SHA-256 digest of `salt` followed by `password`.
+pub fn hash_password(salt: &str, password: &str, rounds: u32) -> String {
+    if rounds == LEGACY_ROUNDS {
+        let mut hasher = Sha256::new();
+        hasher.update(salt.as_bytes());
+        hasher.update(password.as_bytes());
+        return hex(&hasher.finalize());
+    }
+    let mut hash = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), rounds, &mut hash);
+    hex(&hash)
+}
+
+/// Whether `password` hashes to `hash` with `salt` over `rounds`.
+pub fn verify_password(salt: &str, password: &str, rounds: u32, hash: &str) -> bool {
+    let candidate = hash_password(salt, password, rounds);
+    // Compare every byte so the time taken doesn't reveal how much of the hash matched
+    candidate.len() == hash.len()
+        && candidate
+            .bytes()
+            .zip(hash.bytes())
+            .fold(0u8, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_verify_only_against_their_own_hash() {
+        let salt = new_salt().unwrap();
+        let hash = hash_password(&salt, "correct horse", 1000);
+
+        assert!(verify_password(&salt, "correct horse", 1000, &hash));
+        assert!(!verify_password(&salt, "correct horse ", 1000, &hash));
+        assert!(!verify_password(&salt, "", 1000, &hash));
+        assert!(!verify_password(&new_salt().unwrap(), "correct horse", 1000, &hash));
+        assert!(!verify_password(&salt, "correct horse", 999, &hash));
+        assert!(!verify_password(&salt, "correct horse", 1000, &hash[..hash.len() - 1]));
+        assert!(!verify_password(&salt, "correct horse", 1000, ""));
+    }
+
+    #[test]
+    fn legacy_hashes_are_salted_sha256() {
+        let hash = hash_password("salt", "password", LEGACY_ROUNDS);
+
+        assert_eq!(hash, hex(&Sha256::digest(b"saltpassword")));
+        assert!(verify_password("salt", "password", LEGACY_ROUNDS, &hash));
+        assert!(!verify_password("salt", "password", 1, &hash));
+        assert_ne!(new_salt().unwrap(), new_salt().unwrap());
+    }
+}
@@ -0,0 +1,86 @@
+//! Append-only audit log of file accesses on a server.
+//!
+//! Every download, upload and share link redemption is appended as one line to
+//! `oxideux/audit/<profile>.log` under the config directory. Lines hold the time, who (`user@ip`, or
+//! just the IP for anonymous clients), the action, the file, its size in bytes and the outcome
+//! (`ok` or `failed: <reason>`), separated by tabs.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use log::warn;
+
+use crate::config;
+
+const AUDIT_DIR: &str = "oxideux/audit";
+
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Opens the log of `profile_name` for appending, creating it if needed.
+    pub fn open(profile_name: &str) -> Result<Self> {
+        let path = log_path(profile_name)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    /// Appends an entry. `who` identifies the client, `action` what it did (e.g. `download`) and
+    /// `outcome` is `Ok` or the reason it failed.
+    pub fn record(&self, who: &str, action: &str, file: &str, bytes: u64, outcome: &Result<()>) {
+        let outcome = match outcome {
+            Ok(_) => "ok".to_string(),
+            Err(e) => format!("failed: {}", e),
+        };
+        let line = [
+            humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            sanitize(who),
+            sanitize(action),
+            sanitize(file),
+            bytes.to_string(),
+            sanitize(&outcome),
+        ]
+        .join("\t");
+
+        // An entry that can't be written is reported, but mustn't take the transfer down with it
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            warn!("Could not write to the audit log: {}", e);
+        }
+    }
+}
+
+/// The last `count` entries of the log of `profile_name`, oldest first.
+pub fn recent(profile_name: &str, count: usize) -> Result<Vec<String>> {
+    let path = log_path(profile_name)?;
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let contents = fs::read_to_string(path)?;
+    let lines: Vec<String> = contents.lines().map(str::to_string).collect();
+    let skip = lines.len().saturating_sub(count);
+    Ok(lines.into_iter().skip(skip).collect())
+}
+
+fn log_path(profile_name: &str) -> Result<PathBuf> {
+    config::config_dir_ext(format!("{}/{}.log", AUDIT_DIR, sanitize_file_name(profile_name)))
+}
+
+/// Keeps a field from breaking the one-entry-per-line, tab-separated layout.
+fn sanitize(field: &str) -> String {
+    field.replace(['\t', '\n', '\r'], " ")
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == ' ' { c } else { '_' })
+        .collect()
+}
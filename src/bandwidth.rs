@@ -0,0 +1,149 @@
+//! Bandwidth limits for transfers, which can change with the time of day so that long-running
+//! syncs don't get in the way of daytime use.
+//!
+//! A limit is written as comma-separated rules, each a rate per second optionally followed by the
+//! local time window it applies to, such as `1MiB/s 08:00-22:00`. Windows may wrap past midnight
+//! (`22:00-06:00`). A rule without a window applies whenever none of the others does; without one,
+//! transfers are unlimited outside of the windows. `1M 08:00-22:00, 10M` holds transfers to 1 MiB/s
+//! during the day and 10 MiB/s at night.
+
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, Result};
+
+use crate::parity;
+use crate::schedule;
+
+/// How long bytes are counted for before the count starts over, so a slow stretch doesn't allow a
+/// burst afterwards.
+const PACE_WINDOW: Duration = Duration::from_secs(1);
+
+/// A rate applying between two times of day, in minutes since local midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Window {
+    start: u32,
+    end: u32,
+    rate: Option<u64>,
+}
+
+impl Window {
+    fn contains(&self, minute: u32) -> bool {
+        match self.start <= self.end {
+            true => (self.start..self.end).contains(&minute),
+            false => minute >= self.start || minute < self.end,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BandwidthSchedule {
+    windows: Vec<Window>,
+    /// The rate outside of every window, `None` for unlimited.
+    default: Option<u64>,
+}
+
+impl BandwidthSchedule {
+    /// Parses a limit as described in the [module documentation](self). An empty one is unlimited.
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut schedule = BandwidthSchedule::default();
+        let mut has_default = false;
+        for rule in input.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let (rate, window) = match rule.split_once(char::is_whitespace) {
+                Some((rate, window)) => (rate, Some(window.trim())),
+                None => (rule, None),
+            };
+            let rate = parse_rate(rate)?;
+            match window {
+                Some(window) => {
+                    let (start, end) = window
+                        .split_once('-')
+                        .ok_or(anyhow!(format!("Invalid time window '{}', expected HH:MM-HH:MM", window)))?;
+                    let (start, end) = (parse_minute(start)?, parse_minute(end)?);
+                    if start == end {
+                        return Err(anyhow!(format!("The time window '{}' is empty", window)));
+                    }
+                    schedule.windows.push(Window { start, end, rate });
+                }
+                None if has_default => return Err(anyhow!("Only one rate may apply outside of time windows")),
+                None => {
+                    schedule.default = rate;
+                    has_default = true;
+                }
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// Whether transfers are unlimited at all times.
+    pub fn is_unlimited(&self) -> bool {
+        self.default.is_none() && self.windows.iter().all(|window| window.rate.is_none())
+    }
+
+    /// The rate in bytes per second at `time`, `None` for unlimited. The first window holding
+    /// `time` wins.
+    pub fn rate_at(&self, time: SystemTime) -> Option<u64> {
+        let minute = schedule::local_minute_of_day(time);
+        match self.windows.iter().find(|window| window.contains(minute)) {
+            Some(window) => window.rate,
+            None => self.default,
+        }
+    }
+}
+
+/// A rate such as `512K`, `1MiB/s` or `unlimited`.
+fn parse_rate(input: &str) -> Result<Option<u64>> {
+    let rate = input.strip_suffix("/s").unwrap_or(input);
+    if rate.eq_ignore_ascii_case("unlimited") {
+        return Ok(None);
+    }
+    match parity::parse_size(rate)? {
+        0 => Err(anyhow!(format!("A rate of '{}' would stop transfers, use 'unlimited' for no limit", input))),
+        rate => Ok(Some(rate)),
+    }
+}
+
+fn parse_minute(input: &str) -> Result<u32> {
+    let (hour, minute) = schedule::parse_time_of_day(input.trim())?;
+    Ok(hour as u32 * 60 + minute as u32)
+}
+
+/// Holds transfers of one connection to a [`BandwidthSchedule`], by sleeping once they get ahead.
+#[derive(Debug)]
+pub struct Throttle {
+    schedule: BandwidthSchedule,
+    rate: Option<u64>,
+    since: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub fn new(schedule: BandwidthSchedule) -> Self {
+        Self {
+            schedule,
+            rate: None,
+            since: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Counts `bytes` that were just transferred, sleeping for as long as it takes to get back
+    /// under the current rate.
+    pub fn pace(&mut self, bytes: usize) {
+        let rate = self.schedule.rate_at(SystemTime::now());
+        if rate != self.rate || self.since.elapsed() >= PACE_WINDOW {
+            self.rate = rate;
+            self.since = Instant::now();
+            self.bytes = 0;
+        }
+        let rate = match rate {
+            Some(rate) => rate,
+            None => return,
+        };
+        self.bytes += bytes as u64;
+        let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+        if let Some(wait) = due.checked_sub(self.since.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Batch scripts: transfers written down once and run by `client batch <profile> <script>`, for
+//! jobs that are repeated but too simple to warrant a shell script.
+//!
+//! A script holds one operation per line; blank lines and lines starting with `#` are skipped:
+//!
+//! ```text
+//! # Nightly reports
+//! get reports/2024-*
+//! put exports/*.csv
+//! sync
+//! ```
+//!
+//! `get` downloads the remote files matching a glob pattern into the parity root, `put` uploads the
+//! files of the parity root matching one, and `sync` runs a sync pass as the profile's sync mode
+//! says. Operations run one after another, and a failing one doesn't stop those after it.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    Get(String),
+    Put(String),
+    Sync,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Get(pattern) => write!(f, "get {}", pattern),
+            Operation::Put(pattern) => write!(f, "put {}", pattern),
+            Operation::Sync => write!(f, "sync"),
+        }
+    }
+}
+
+/// An operation of a script, with the line it was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub line: usize,
+    pub operation: Operation,
+}
+
+/// Parses a whole script, so a typo on its last line is caught before anything is transferred.
+pub fn parse(script: &str) -> Result<Vec<Step>> {
+    let mut steps = vec![];
+    for (index, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (verb, argument) = match line.split_once(char::is_whitespace) {
+            Some((verb, argument)) => (verb, argument.trim()),
+            None => (line, ""),
+        };
+        let operation = match (verb, argument) {
+            ("get" | "put", "") => return Err(anyhow!(format!("Line {}: '{}' needs a pattern", index + 1, verb))),
+            ("get", pattern) => Operation::Get(pattern.to_string()),
+            ("put", pattern) => Operation::Put(pattern.to_string()),
+            ("sync", "") => Operation::Sync,
+            ("sync", _) => return Err(anyhow!(format!("Line {}: 'sync' takes no arguments", index + 1))),
+            _ => return Err(anyhow!(format!("Line {}: unknown operation '{}'", index + 1, verb))),
+        };
+        steps.push(Step { line: index + 1, operation });
+    }
+    Ok(steps)
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Step>> {
+    let script = fs::read_to_string(path.as_ref())
+        .map_err(|e| anyhow!(format!("Could not read {}: {}", path.as_ref().display(), e)))?;
+    parse(&script)
+}
+
+/// What running a script came to, one outcome per step.
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    /// Each step, with how many files it transferred or why it failed.
+    pub outcomes: Vec<(Step, Result<usize, String>)>,
+}
+
+impl BatchReport {
+    pub fn failed(&self) -> usize {
+        self.outcomes.iter().filter(|(_, outcome)| outcome.is_err()).count()
+    }
+
+    pub fn transferred(&self) -> usize {
+        self.outcomes.iter().filter_map(|(_, outcome)| outcome.as_ref().ok()).sum()
+    }
+
+    /// One line per step, then the totals.
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        for (step, outcome) in &self.outcomes {
+            let result = match outcome {
+                Ok(count) => format!("{} file(s)", count),
+                Err(e) => format!("failed: {}", e),
+            };
+            summary.push_str(&format!("Line {}: {} - {}\n", step.line, step.operation, result));
+        }
+        summary.push_str(&format!(
+            "{} operation(s), {} failed, {} file(s) transferred",
+            self.outcomes.len(),
+            self.failed(),
+            self.transferred()
+        ));
+        summary
+    }
+}
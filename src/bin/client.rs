@@ -1,21 +1,91 @@
-use std::net::TcpStream;
-use std::path::PathBuf;
-use std::process::Command;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::net::Shutdown;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use oxideux_rs::app;
-use oxideux_rs::cli;
-use oxideux_rs::config::{self, ClientProfile};
-use oxideux_rs::connection::Connection;
-use oxideux_rs::request::{Request, RequestResult};
-use oxideux_rs::validated_values::ValidatedValue;
+use oxideux_rs::batch::{self, BatchReport, Operation, Step};
+use oxideux_rs::cli::{self, Align, Table};
+use oxideux_rs::client::{self, Client, InsufficientSpace, SHARE_LINK_PROFILE};
+use oxideux_rs::config::{self, ClientProfile, CollisionPolicy, ConflictStrategy, DownloadOrder, SyncMode};
+use oxideux_rs::connection::{
+    Capability, Connection, KeepAlive, TransferControl, TransferEvent, KEEP_ALIVE_INTERVAL, KEEP_ALIVE_TIMEOUT,
+};
+use oxideux_rs::discovery;
+use oxideux_rs::dry_run::{Change, SyncPlan};
+use oxideux_rs::history::{self, Side};
+use oxideux_rs::hooks::{self, Event, Hook};
+use oxideux_rs::logging;
+use oxideux_rs::meter::{format_size, TransferStats};
+use oxideux_rs::parity::{self, EntryQuery};
+use oxideux_rs::request::Request;
+use oxideux_rs::schedule::Schedule;
+use oxideux_rs::secrets;
+use oxideux_rs::server::DEFAULT_PORT;
+#[cfg(unix)]
+use oxideux_rs::tui;
+use oxideux_rs::two_way::{Conflict, FileState, Resolution};
+use oxideux_rs::validated_values::{ValidatedDirectory, ValidatedIPv4, ValidatedPort, ValidatedValue};
+use oxideux_rs::watch::Notification;
 
 use anyhow::{self, Result};
+use log::{error, info, log_enabled, warn, Level};
+
+/// How many transfers the history view shows.
+const HISTORY_PAGE: usize = 30;
+
+/// How much of a file name the remote file listing shows.
+const LIST_NAME_WIDTH: usize = 80;
+
+/// How many matches a search of the remote files shows.
+const SEARCH_LIMIT: u32 = 100;
+
+/// How often the progress line of a transfer is redrawn.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How many times a scheduled sync is tried before it is left for its next occurrence.
+const SCHEDULED_ATTEMPTS: u32 = 3;
+/// How long a failed scheduled sync waits before it is tried again, doubled with every attempt.
+const SCHEDULED_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// A persistent connection to a server, kept alive in the background while the user picks actions.
+struct Session {
+    client: Arc<Mutex<Client>>,
+    /// Steers the client's transfers while it is locked for them.
+    control: TransferControl,
+    keep_alive: Option<KeepAlive>,
+    addr: String,
+}
+
+impl Session {
+    fn is_lost(&self) -> bool {
+        self.keep_alive.as_ref().is_some_and(|k| k.is_lost())
+    }
+}
 
 #[derive(Default)]
 struct AppData {
     profile_names: Vec<String>,
     current_profile: Option<ClientProfile>,
+    session: Option<Session>,
     notices: Vec<String>,
+    /// Profiles erased this session, the last one first to be put back by undoing.
+    erased: Vec<String>,
+    /// The tag the profile list is narrowed to, if any.
+    tag_filter: Option<String>,
+    /// The profile the transfer history is narrowed to, if any.
+    history_filter: Option<String>,
+    /// Whether syncs and downloads from the session only show what they would do.
+    dry_run: bool,
+    /// What the last dry run found, until it has been shown.
+    plan: Option<SyncPlan>,
+    /// The state the help screen returns to.
+    help_return: &'static str,
 }
 
 impl AppData {
@@ -23,6 +93,14 @@ impl AppData {
         self.notices.push(message.to_string());
     }
 
+    fn push_error<S: ToString>(&mut self, message: S) {
+        self.notices.push(cli::styled(message.to_string(), cli::Style::Error));
+    }
+
+    fn push_success<S: ToString>(&mut self, message: S) {
+        self.notices.push(cli::styled(message.to_string(), cli::Style::Success));
+    }
+
     fn clear_notices(&mut self) {
         self.notices.clear();
     }
@@ -41,17 +119,84 @@ impl AppData {
 fn main() -> Result<()> {
     config::client::init_config_file()?;
 
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    logging::init_from_args(&mut args)?;
+    if let Some(index) = args.iter().position(|arg| arg == "--output") {
+        match args.get(index + 1).map(String::as_str) {
+            Some("json") => cli::set_json_output(true),
+            Some("text") => (),
+            _ => return Err(anyhow::anyhow!("Usage: client --output <text|json> <command> ...")),
+        }
+        args.drain(index..index + 2);
+    }
+    unlock_secrets()?;
+    if let Some(index) = args.iter().position(|arg| arg == "--tui") {
+        args.remove(index);
+        if !args.is_empty() || cli::json_output() {
+            return Err(anyhow::anyhow!("Usage: client --tui"));
+        }
+        return run_tui();
+    }
+
+    if !args.is_empty() {
+        let result = run_command(&args);
+        if let Err(e) = &result {
+            if cli::json_output() {
+                cli::json_line(json::object! { "type": "error", "message": e.to_string() });
+                std::process::exit(1);
+            }
+        }
+        return result;
+    }
+    if cli::json_output() {
+        return Err(anyhow::anyhow!("JSON output is only for commands, such as 'client --output json sync <profile>'"));
+    }
+
     let app_data = AppData::default();
 
     let mut app = app::App::new(app_data);
     app.register_state("pick_profile", state_pick_profile);
     app.register_state("manage_profile", state_manage_profile);
+    app.register_state("discover_servers", state_discover_servers);
+    app.register_state("create_profile", state_create_profile);
+    app.register_state("check_config", state_check_config);
+    app.register_state("encrypt_secrets", state_encrypt_secrets);
     app.register_state("change_name", state_change_name);
+    app.register_state("change_notes", state_change_notes);
+    app.register_state("change_tags", state_change_tags);
     app.register_state("change_parity_root", state_change_parity_root);
     app.register_state("change_port", state_change_port);
     app.register_state("change_ipv4", state_change_ipv4);
+    app.register_state("change_sync_interval", state_change_sync_interval);
+    app.register_state("change_schedule", state_change_schedule);
+    app.register_state("change_bandwidth", state_change_bandwidth);
+    app.register_state("change_trash_dir", state_change_trash_dir);
+    app.register_state("change_trash_max_age", state_change_trash_max_age);
+    app.register_state("change_exclusions", state_change_exclusions);
+    app.register_state("change_priority", state_change_priority);
+    app.register_state("change_filter", state_change_filter);
+    app.register_state("change_login", state_change_login);
+    app.register_state("change_shared_secret", state_change_shared_secret);
+    app.register_state("change_server_public_key", state_change_server_public_key);
+    app.register_state("change_ssh_jump", state_change_ssh_jump);
+    app.register_state("change_socks_proxy", state_change_socks_proxy);
+    app.register_state("manage_hooks", state_manage_hooks);
+    app.register_state("add_hook", state_add_hook);
     app.register_state("save_updated_profile", state_save_updated_profile);
     app.register_state("start_client", state_start_client);
+    app.register_state("remote_stats", state_remote_stats);
+    app.register_state("test_connection", state_test_connection);
+    app.register_state("session", state_session);
+    app.register_state("inspect_remote_file", state_inspect_remote_file);
+    app.register_state("restore_version", state_restore_version);
+    app.register_state("download_matching", state_download_matching);
+    app.register_state("find_remote", state_find_remote);
+    app.register_state("watch_remote", state_watch_remote);
+    app.register_state("upload_file", state_upload_file);
+    app.register_state("auto_sync", state_auto_sync);
+    app.register_state("show_plan", state_show_plan);
+    app.register_state("transfer_history", state_transfer_history);
+    app.register_state("help", state_help);
 
     app.queue_state("pick_profile");
 
@@ -63,6 +208,115 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// Asks for the passphrase saved passwords are encrypted with, unless they aren't or it was given
+/// in [`secrets::PASSPHRASE_VAR`].
+fn unlock_secrets() -> Result<()> {
+    if !config::client::secrets_locked()? {
+        return Ok(());
+    }
+    if let Ok(passphrase) = env::var(secrets::PASSPHRASE_VAR) {
+        return config::client::unlock_secrets(&passphrase);
+    }
+    if cli::json_output() {
+        return Err(anyhow::anyhow!(format!("Saved passwords are encrypted: set {} to the passphrase", secrets::PASSPHRASE_VAR)));
+    }
+    loop {
+        cli::out("Passphrase of the saved passwords:");
+        let passphrase = cli::input_hidden();
+        if passphrase.is_empty() {
+            return Err(anyhow::anyhow!("Saved passwords are encrypted, and no passphrase was given"));
+        }
+        match config::client::unlock_secrets(&passphrase) {
+            Ok(_) => return Ok(()),
+            Err(e) => cli::error(e),
+        }
+    }
+}
+
+/// The directory given with `--dest` among `args`, with placeholders filled in, failing with
+/// `usage` when there's no directory after it.
+fn dest_arg<F: Fn() -> anyhow::Error>(args: &[String], usage: F) -> Result<Option<PathBuf>> {
+    match args.iter().position(|arg| arg == "--dest") {
+        Some(index) => {
+            let dest = args.get(index + 1).ok_or_else(usage)?;
+            Ok(Some(PathBuf::from(config::fill_path_placeholders(dest.clone())?)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Runs the command line command `args` (such as `sync <profile>`) instead of the menus.
+fn run_command(args: &[String]) -> Result<()> {
+    match args[0].as_str() {
+        "daemon" => {
+            let profile_name = args.get(1).ok_or(anyhow::anyhow!("Usage: client daemon <profile>"))?;
+            let profile = config::client::get_profile(profile_name)?;
+            run_sync_daemon(&profile, &AtomicBool::new(false));
+            Ok(())
+        }
+        "fetch" => {
+            let link = args.get(1).ok_or(anyhow::anyhow!("Usage: client fetch <oxideux://host:port/token> [directory]"))?;
+            let output = args.get(2).map(String::as_str).unwrap_or(".");
+            cli::out(format!("Destination: {}", output));
+            Client::fetch_share_link_with(link, output, transfer_printer())
+        }
+        command @ ("sync" | "download-all") => {
+            let usage = || {
+                anyhow::anyhow!(format!(
+                    "Usage: client {} <profile> [--dry-run] [--report <file.json|file.csv>]{}",
+                    command,
+                    if command == "download-all" { " [--dest <directory>]" } else { "" }
+                ))
+            };
+            let profile = config::client::get_profile(args.get(1).ok_or_else(usage)?)?;
+            let dry_run = args.iter().skip(2).any(|arg| arg == "--dry-run");
+            let report = match args.iter().position(|arg| arg == "--report") {
+                Some(index) => Some(PathBuf::from(args.get(index + 1).ok_or_else(usage)?)),
+                None => None,
+            };
+            let dest = dest_arg(args, usage)?;
+            if command == "sync" && dest.is_some() {
+                return Err(usage());
+            }
+            run_once(&profile, command, dry_run, report.as_deref(), dest.as_deref())
+        }
+        "get" => {
+            let usage = || anyhow::anyhow!("Usage: client get <profile> <pattern> [--dest <directory>]");
+            let profile = config::client::get_profile(args.get(1).ok_or_else(usage)?)?;
+            let pattern = args.get(2).ok_or_else(usage)?;
+            let dest = dest_arg(args, usage)?;
+            get_matching(&profile, pattern, dest.as_deref())
+        }
+        "list" => {
+            let profile = config::client::get_profile(args.get(1).ok_or(anyhow::anyhow!("Usage: client list <profile>"))?)?;
+            list_remote(&profile)
+        }
+        "cat" => {
+            let usage = || anyhow::anyhow!("Usage: client cat <profile> <remote-name>");
+            let profile = config::client::get_profile(args.get(1).ok_or_else(usage)?)?;
+            let name = args.get(2).ok_or_else(usage)?;
+            cat_remote(&profile, name)
+        }
+        "put" => {
+            let usage = || anyhow::anyhow!("Usage: client put <profile> <name> -");
+            let profile = config::client::get_profile(args.get(1).ok_or_else(usage)?)?;
+            let name = args.get(2).ok_or_else(usage)?;
+            if args.get(3).map(String::as_str) != Some("-") {
+                return Err(usage());
+            }
+            put_stdin(&profile, name)
+        }
+        "batch" => {
+            let usage = || anyhow::anyhow!("Usage: client batch <profile> <script>");
+            let profile = config::client::get_profile(args.get(1).ok_or_else(usage)?)?;
+            let steps = batch::load(args.get(2).ok_or_else(usage)?)?;
+            run_batch(&profile, steps)
+        }
+        "check-config" => check_config(),
+        other => Err(anyhow::anyhow!(format!("Unknown command: {}", other))),
+    }
+}
+
 fn state_pick_profile(app_data: &mut AppData, command: &mut app::Command) {
     app_data.refresh_profile_names();
     app_data.refresh_cli();
@@ -70,84 +324,501 @@ fn state_pick_profile(app_data: &mut AppData, command: &mut app::Command) {
     let mut options = cli::InputOptions::new();
     
     // Headers
+    let header = match &app_data.tag_filter {
+        Some(tag) => format!("PICK A PROFILE (tagged '{}'):", tag),
+        None => "PICK A PROFILE:".to_string(),
+    };
     options
-        .set_header_dynamic("PICK A PROFILE:")
+        .set_header_dynamic(header)
         .set_header_static("__________");
 
-    // Add profile names
+    // Add profile names with their tags, the one last used standing out
+    let current = app_data.current_profile.as_ref().map(|profile| profile.name.as_str());
+    let mut shown = vec![];
     for profile_name in &app_data.profile_names {
-        options.add_dynamic(profile_name);
+        let tags = config::client::get_profile(profile_name).map(|profile| profile.tags).unwrap_or_default();
+        if app_data.tag_filter.as_ref().is_some_and(|tag| !config::has_tag(&tags, tag)) {
+            continue;
+        }
+        let label = match tags.is_empty() {
+            true => profile_name.clone(),
+            false => format!("{} [{}]", profile_name, tags.join(", ")),
+        };
+        match Some(profile_name.as_str()) == current {
+            true => options.add_dynamic(cli::styled(label, cli::Style::Highlight)),
+            false => options.add_dynamic(label),
+        };
+        shown.push(profile_name.clone());
     }
 
     // Add controls
     options 
         .add_static("a", "Create new profile")
+        .add_static("d", "Discover servers")
+        .add_static("hist", "Transfer history")
+        .add_static("tg", "Filter by tag")
+        .add_static("chk", "Check every profile");
+    match config::client::secrets_encrypted().unwrap_or(false) {
+        true => options.add_static("enc", "Change the passphrase of saved passwords"),
+        false => options.add_static("enc", "Encrypt saved passwords"),
+    };
+    if let Some(name) = app_data.erased.last() {
+        options.add_static("u", format!("Undo erasing '{}'", name));
+    }
+    options
         .add_static("r", "Refresh profiles")
         .add_static("c", "Open config directory")
+        .add_static("h", "Help")
         .add_static("q", "Terminate program");
 
     match options.get() {
         cli::OptionType::Dynamic(index) => {
-            let profile_name = &app_data.profile_names[index];
+            let profile_name = &shown[index];
             let profile = config::client::get_profile(profile_name).unwrap();
             app_data.current_profile = Some(profile);
             command.queue_state("manage_profile");
         },
         cli::OptionType::Static(key) => match key.as_str() {
-            "a" => {
-                let count = app_data.profile_names.len();
-                let _ = config::client::create_profile(format!("profile #{}", count), "{download}", 49160, "localhost");
-            },
+            "a" => command.queue_state("create_profile"),
+            "d" => command.queue_state("discover_servers"),
+            "tg" => {
+                cli::out("Show only the profiles tagged (leave blank to show all):");
+                let tag = cli::input();
+                app_data.tag_filter = Some(tag.trim().to_string()).filter(|tag| !tag.is_empty());
+            }
+            "hist" => command.queue_state("transfer_history"),
+            "chk" => command.queue_state("check_config"),
+            "enc" => command.queue_state("encrypt_secrets"),
+            "u" => {
+                let name = app_data.erased.pop().unwrap();
+                match config::client::restore_profile(&name) {
+                    Ok(_) => app_data.push_success(format!("Restored profile '{}'", name)),
+                    Err(e) => app_data.push_error(format!("Could not restore profile '{}': {}", name, e)),
+                }
+            }
             "r" => app_data.refresh_profile_names(),
-            "c" => {
-                let path = match config::config_dir_ext("oxideux") {
-                    Ok(v) => v,
-                    Err(e) => {
-                        app_data.push_notice(e);
-                        return;
-                    }
-                };
-
-                #[cfg(target_os = "linux")]
-                let command = "xdg-open";
-                #[cfg(target_os = "windows")]
-                let command = "explorer";
-
-                match Command::new(command)
-                    .arg(path)
-                    .output() {
-                        Ok(_) => (),
-                        Err(e) => {
-                            app_data.push_notice(e);
-                        },
+            "h" => {
+                app_data.help_return = "pick_profile";
+                command.queue_state("help");
+            }
+            "c" => match config::config_dir_ext("oxideux") {
+                Ok(path) => {
+                    if let Err(e) = cli::open_path(path) {
+                        app_data.push_error(format!("Could not open the config directory: {}", e));
                     }
+                }
+                Err(e) => app_data.push_error(e),
             },
             "q" => command.exit(),
             _ => unreachable!()
         },
-        cli::OptionType::Error(e) => app_data.push_notice(e)
+        cli::OptionType::Error(e) => app_data.push_error(e)
     }
 }
 
-fn state_manage_profile(app_data: &mut AppData, command: &mut app::Command) {
+fn state_create_profile(app_data: &mut AppData, command: &mut app::Command) {
     app_data.refresh_cli();
+    command.queue_state("pick_profile");
 
-    let profile = app_data.current_profile.as_ref().unwrap();
-    
-    // Error checking
-    let mut errors = vec![];
-    
-    if let Err(e) = profile.parity_root.is_valid() {
-        errors.push(format!("Parity root: {}.", e.to_string()));
+    cli::notice("Clear a line and press enter to cancel.");
+    cli::blank();
+
+    let taken = app_data.profile_names.clone();
+    let name = cli::prompt_checked("Name", &format!("profile #{}", taken.len()), |input| {
+        match taken.iter().any(|name| name == input) {
+            true => Err(anyhow::anyhow!("A profile with that name already exists")),
+            false => Ok(input.to_string()),
+        }
+    });
+    let Some(name) = name else { return };
+
+    cli::blank();
+    cli::out("The local directory the server's files are synced into. A path may start with a placeholder:");
+    for (placeholder, meaning) in config::PATH_PLACEHOLDERS {
+        cli::out(format!("  {:<12}{}", placeholder, meaning));
+    }
+    let Some(parity_root) = cli::prompt_checked("Parity root", "{download}", check_new_root) else { return };
+
+    cli::blank();
+    let port = cli::prompt_checked("Port", &DEFAULT_PORT.to_string(), |input| {
+        let port = input.parse::<u16>()?;
+        ValidatedPort::is_value_valid(&port)?;
+        Ok(port)
+    });
+    let Some(port) = port else { return };
+
+    cli::blank();
+    cli::out("The server's IPv4 address, or localhost.");
+    let host = cli::prompt_checked("Server address", "localhost", |input| {
+        ValidatedIPv4::is_value_valid(&input.to_string())?;
+        Ok(input.to_string())
+    });
+    let Some(host) = host else { return };
+
+    if let Err(e) = config::client::create_profile(&name, parity_root, port, host) {
+        app_data.push_error(format!("Could not create the profile: {}", e));
+        return;
+    }
+    match config::client::get_profile(&name) {
+        Ok(profile) => {
+            app_data.push_success(format!("Created profile '{}'", name));
+            app_data.current_profile = Some(profile);
+            command.queue_state("manage_profile");
+        }
+        Err(e) => app_data.push_error(e),
     }
+}
 
-    if let Err(e) = profile.port.is_valid() {
-        errors.push(format!("Port: {}.", e.to_string()));
+/// Checks a parity root typed into the profile wizard, offering to create it when it's missing.
+/// Keeps what was typed, placeholders and all, for the config file.
+fn check_new_root(input: &str) -> Result<String> {
+    let path = config::fill_path_placeholders(input.to_string())?;
+    if !Path::new(&path).exists() && cli::confirm(format!("{} doesn't exist. Create it?", path)) {
+        fs::create_dir_all(&path)?;
     }
-    
-    if let Err(e) = profile.ipv4.is_valid() {
-        errors.push(format!("IPv4: {}.", e.to_string()));
+    ValidatedDirectory::is_value_valid(&path)?;
+    Ok(input.to_string())
+}
+
+/// Checks every profile, printing what is wrong with each, and fails when any has problems.
+fn check_config() -> Result<()> {
+    let checks = config::client::check_profiles()?;
+    match print_check_report(&checks) {
+        0 => {
+            cli::out(format!("All {} profiles are valid.", checks.len()));
+            Ok(())
+        }
+        invalid => Err(anyhow::anyhow!(format!("{} of {} profiles have problems", invalid, checks.len()))),
+    }
+}
+
+/// Prints what [`config::ProfileCheck`]s found, returning how many profiles have problems.
+fn print_check_report(checks: &[config::ProfileCheck]) -> usize {
+    let mut table = Table::new();
+    table.add_column("Profile", Align::Left).add_column("Problem", Align::Left);
+    for check in checks {
+        if cli::json_output() {
+            cli::json_line(json::object! {
+                "type": "profile",
+                "name": check.name.as_str(),
+                "valid": check.is_valid(),
+                "problems": check.problems.clone(),
+            });
+            continue;
+        }
+        if check.is_valid() {
+            table.add_row([check.name.as_str(), "none"]);
+        }
+        for problem in &check.problems {
+            table.add_row([check.name.as_str(), problem.as_str()]);
+        }
+    }
+    if !table.is_empty() {
+        cli::table(&table);
+    }
+    checks.iter().filter(|check| !check.is_valid()).count()
+}
+
+fn state_encrypt_secrets(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("pick_profile");
+
+    let encrypted = config::client::secrets_encrypted().unwrap_or(false);
+    cli::out(match encrypted {
+        true => "New passphrase (leave blank to store passwords in plain text again):",
+        false => "Passphrase to encrypt saved passwords with (leave blank to cancel):",
+    });
+    let passphrase = cli::input_hidden();
+    if passphrase.is_empty() {
+        if !encrypted || !cli::confirm("Store saved passwords in plain text?") {
+            return;
+        }
+        match config::client::set_secrets_passphrase(None) {
+            Ok(_) => app_data.push_success("Saved passwords are stored in plain text"),
+            Err(e) => app_data.push_error(format!("Could not decrypt saved passwords: {}", e)),
+        }
+        return;
+    }
+
+    cli::out("Type it again:");
+    if cli::input_hidden() != passphrase {
+        app_data.push_error("The passphrases didn't match, so nothing was changed");
+        return;
+    }
+    match config::client::set_secrets_passphrase(Some(&passphrase)) {
+        Ok(_) => app_data.push_success("Saved passwords are encrypted, the passphrase is asked for when the client starts"),
+        Err(e) => app_data.push_error(format!("Could not encrypt saved passwords: {}", e)),
+    }
+}
+
+fn state_check_config(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("pick_profile");
+
+    match config::client::check_profiles() {
+        Ok(checks) => match print_check_report(&checks) {
+            0 => cli::success(format!("All {} profiles are valid.", checks.len())),
+            invalid => cli::error(format!("{} of {} profiles have problems.", invalid, checks.len())),
+        },
+        Err(e) => cli::error(format!("Could not read the config file: {}", e)),
+    }
+
+    cli::blank();
+    cli::out("Press enter to return.");
+    cli::input();
+}
+
+fn state_transfer_history(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let filter = app_data.history_filter.clone();
+    match history::recent(Side::Client, filter.as_deref(), HISTORY_PAGE) {
+        Ok(records) if records.is_empty() => cli::out("No transfers recorded yet."),
+        Ok(records) => {
+            cli::out(format!(
+                "Last {} transfers of {}, newest first:",
+                records.len(),
+                filter.as_deref().unwrap_or("all profiles")
+            ));
+            cli::sep_thin();
+            cli::table(&history::table(&records));
+        }
+        Err(e) => cli::error(format!("Could not read the transfer history: {}", e)),
+    }
+    cli::blank();
+
+    let profile_names = history::profiles(Side::Client).unwrap_or_default();
+
+    let mut options = cli::InputOptions::new();
+    options
+        .set_header_dynamic("SHOW ONLY:")
+        .set_header_static("__________");
+    for profile_name in &profile_names {
+        options.add_dynamic(profile_name);
+    }
+    options.add_static("a", "Show all profiles");
+    if filter.as_deref().is_some_and(|filter| filter != SHARE_LINK_PROFILE) {
+        options.add_static("r", "Re-download failed files");
+    }
+    options.add_static("q", "Return");
+
+    match options.get() {
+        cli::OptionType::Dynamic(index) => app_data.history_filter = Some(profile_names[index].clone()),
+        cli::OptionType::Static(key) => match key.as_ref() {
+            "a" => app_data.history_filter = None,
+            "r" => {
+                let result = retry_failed_downloads(filter.as_deref().unwrap());
+                match result {
+                    Ok(0) => app_data.push_notice("No failed downloads to retry"),
+                    Ok(count) => app_data.push_success(format!("Re-downloaded {} files", count)),
+                    Err(e) => app_data.push_error(format!("Retrying failed downloads failed: {}", e)),
+                }
+            }
+            "q" => {
+                app_data.history_filter = None;
+                command.queue_state("pick_profile");
+            }
+            _ => unreachable!(),
+        },
+        cli::OptionType::Error(e) => app_data.push_error(e),
+    }
+}
+
+fn state_help(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state(app_data.help_return);
+
+    cli::out("Type a key from a menu and press enter. Numbered entries pick from a list: '>' and '<' page through");
+    cli::out("long lists, '/text' shows only the entries matching text and '/' shows them all again. Up and down");
+    cli::out("recall earlier input, and ctrl-d on an empty line cancels a prompt.");
+
+    print_help_section("PROFILE LIST:", "Key", &[
+        ("a", "Create a new profile step by step: name, parity root, port and server address"),
+        ("d", "Look for servers advertising themselves on the local network and save one as a profile"),
+        ("hist", "Show recent transfers, optionally narrowed to a profile, and retry failed downloads"),
+        ("tg", "Show only the profiles with a tag, or all of them again"),
+        ("chk", "Check every profile for invalid values and unreachable directories"),
+        ("u", "Put back the last profile erased since the program started"),
+        ("enc", "Encrypt saved passwords with a passphrase asked for at start, or in $OXIDEUX_PASSPHRASE"),
+        ("r", "Read the profile names from the config file again"),
+        ("c", "Open the config directory in the file manager"),
+        ("q", "Quit"),
+    ]);
+
+    print_help_section("PROFILE MENU:", "Key", &[
+        ("s", "Connect to the server and open a session (only while the profile has no errors)"),
+        ("t", "Connect, ping the server and show its capabilities, remembering when this last worked"),
+        ("as", "Sync at the sync interval, or on the sync schedule, until enter is pressed"),
+        ("rs", "Show how many files the server has, how large they are, and the largest and newest"),
+        ("hk", "Add or remove commands run before or after transfers"),
+        ("o", "Open the parity root in the file manager, to see where downloads land"),
+        ("erase", "Delete the profile once its name is typed, until undone from the profile list"),
+        ("q", "Return to the profile list"),
+    ]);
+
+    print_help_section("SESSION MENU:", "Key", &[
+        ("d", "Show how many remote files there are, then download them all into the parity root"),
+        ("sy", "Sync once, the way the sync mode says"),
+        ("dr", "Toggle dry runs: syncs and downloads only list what they would change"),
+        ("n", "Count the remote files"),
+        ("f", "Search remote files by name"),
+        ("v", "Hash local files and compare them with the server's"),
+        ("i", "Show a remote file's size, modification time and hash"),
+        ("m", "Download the remote files matching a pattern, into the parity root or anywhere else"),
+        ("w", "Show changes on the server as they happen"),
+        ("u", "Upload a local file"),
+        ("pv", "Download a version of a file that an upload replaced, on servers with versioning"),
+        ("x", "Disconnect and return to the profile menu"),
+    ]);
+
+    print_help_section("WHILE TRANSFERRING:", "Key", &[
+        ("p", "Pause after the current chunk"),
+        ("r", "Resume"),
+        ("c", "Skip the file being transferred and go on with the rest"),
+        ("a", "Abort every transfer left"),
+    ]);
+
+    print_help_section("PROFILE FIELDS:", "Key", &[
+        ("cn", "Name: what the profile is listed as"),
+        ("nt", "Notes: free-form text about the profile, for your own reference"),
+        ("tg", "Tags: comma-separated labels the profile list can be narrowed to"),
+        ("cr", "Parity root: the local directory the server's files are synced into"),
+        ("ps", "Per-server directory: whether files go into a directory named after the server in the parity root"),
+        ("cp", "Port: the server's port"),
+        ("ci", "IPv4: the server's address"),
+        ("cs", "Sync interval: seconds between syncs when auto-syncing without a schedule"),
+        ("sc", "Sync schedule: when auto-sync runs, e.g. 'every 6h' or 'daily 03:00'"),
+        ("bw", "Bandwidth limit: a rate, optionally per time of day, e.g. '1M 08:00-22:00, 10M'"),
+        ("sm", "Sync mode: download only, or two-way with uploads"),
+        ("cc", "Conflicts: how two-way sync settles files changed on both sides"),
+        ("md", "Mirror deletions: whether files removed on the server are removed locally"),
+        ("kp", "Trash: whether syncs keep the files they replace or remove, with the time in their name"),
+        ("ct", "Trash directory: where the trash is, .oxideux-trash in the parity root unless set"),
+        ("ta", "Trash age: how long files stay in the trash before syncs delete them, e.g. 30days"),
+        ("ce", "Exclusions: comma-separated patterns of files never synced"),
+        ("cf", "Sync filter: only sync matching files, e.g. 'ext:iso size:1M..2G since:7d name:report'"),
+        ("qo", "Download order: as listed, smallest first or newest first, after the priority patterns"),
+        ("qp", "Priority: comma-separated patterns of files syncs download first, in the order given"),
+        ("hf", "Hidden files: whether dotfiles are synced"),
+        ("sl", "Symlinks: skip them, follow them, or stop with an error"),
+        ("kb", "Existing files: overwritten by downloads, or kept with the download saved as 'name (1).ext'"),
+        ("cl", "Login: the user and password to sign in with, or anonymous"),
+        ("sk", "Shared secret: encrypts the connection, and must be the same as the server's"),
+        ("pk", "Server public key: downloads must match the manifest the server signed with its key"),
+        ("cj", "SSH jump: tunnel through user@host[:port] to reach the server"),
+        ("cx", "SOCKS5 proxy: connect through [user:password@]host:port"),
+    ]);
+
+    print_help_section("PATH PLACEHOLDERS:", "Placeholder", &config::PATH_PLACEHOLDERS);
+    cli::out("Placeholders are only replaced at the start of a path, e.g. {download}/oxideux.");
+    cli::out("Values in the 'defaults' section of client_config.json apply to every profile that leaves them out.");
+
+    cli::blank();
+    cli::out("Press enter to return.");
+    cli::input();
+}
+
+/// Prints a titled two-column table for the help screen, `heading` over the first column.
+fn print_help_section(title: &str, heading: &str, rows: &[(&str, &str)]) {
+    let mut table = Table::new();
+    table.add_column(heading, Align::Left).add_column("Description", Align::Left);
+    for (key, description) in rows {
+        table.add_row([*key, *description]);
+    }
+
+    cli::blank();
+    cli::out(title);
+    cli::table(&table);
+}
+
+/// Downloads again every file whose last download with `profile_name` failed, returning how many
+/// succeeded this time.
+fn retry_failed_downloads(profile_name: &str) -> Result<usize> {
+    let failed = history::failed_downloads(profile_name)?;
+    if failed.is_empty() {
+        return Ok(0);
+    }
+
+    let profile = config::client::get_profile(profile_name)?;
+    let mut client = open_client(&profile)?;
+    let mut count = 0;
+    for name in failed {
+        let output = parity::safe_join(profile.local_root(), &name)?;
+        if client.download(&name, &output).is_ok() {
+            count += 1;
+        }
+    }
+    client.disconnect()?;
+    Ok(count)
+}
+
+fn state_discover_servers(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("pick_profile");
+
+    cli::out("Searching the local network for servers...");
+    let servers = match discovery::discover(Duration::from_secs(3)) {
+        Ok(servers) => servers,
+        Err(e) => {
+            app_data.push_error(format!("Discovery failed: {}", e));
+            return;
+        }
+    };
+    if servers.is_empty() {
+        app_data.push_notice("No servers were found on the local network.");
+        return;
+    }
+
+    let mut options = cli::InputOptions::new();
+    options
+        .set_header_dynamic("PICK A SERVER TO CREATE A PROFILE FOR:")
+        .set_header_static("__________");
+    for server in &servers {
+        let addresses: Vec<String> = server.addresses.iter().map(|a| a.to_string()).collect();
+        options.add_dynamic(format!("{} ({}:{})", server.instance, addresses.join(", "), server.port));
+    }
+    options.add_static("q", "Return");
+
+    match options.get() {
+        cli::OptionType::Dynamic(index) => {
+            let server = &servers[index];
+            let address = match server.addresses.first() {
+                Some(address) => address.to_string(),
+                None => {
+                    app_data.push_notice(format!("'{}' did not report an IPv4 address", server.instance));
+                    return;
+                }
+            };
+
+            // Don't clobber an existing profile of the same name
+            app_data.refresh_profile_names();
+            let mut name = server.instance.clone();
+            let mut suffix = 1;
+            while app_data.profile_names.contains(&name) {
+                suffix += 1;
+                name = format!("{} ({})", server.instance, suffix);
+            }
+
+            match config::client::create_profile(&name, "{download}", server.port, address) {
+                Ok(_) => app_data.push_success(format!("Created profile '{}'", name)),
+                Err(e) => app_data.push_error(e),
+            }
+        }
+        cli::OptionType::Static(key) => match key.as_str() {
+            "q" => (),
+            _ => unreachable!(),
+        },
+        cli::OptionType::Error(e) => app_data.push_error(e),
     }
+}
+
+fn state_manage_profile(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_ref().unwrap();
+    
+    // Error checking
+    let mut errors = profile.problems();
 
     if errors.len() != 0 {
         errors.push(format!("Due to {} previous error(s), the client may not be started.", errors.len()));
@@ -155,96 +826,460 @@ fn state_manage_profile(app_data: &mut AppData, command: &mut app::Command) {
 
     // Print our errors
     for error in &errors {
-        cli::notice(error);
+        cli::error(error);
     }
-    println!();
+    cli::blank();
 
     // Display profile info
-    cli::out(format!("Profile: {}", profile.name));
+    cli::out(format!("Profile: {}", cli::styled(&profile.name, cli::Style::Highlight)));
+    cli::out(format!("Tags: {}", if profile.tags.is_empty() { "none".to_string() } else { profile.tags.join(", ") }));
+    cli::out(format!("Notes: {}", if profile.notes.is_empty() { "none" } else { profile.notes.as_str() }));
     cli::out(format!("Parity root: {}", profile.parity_root.get()));
+    cli::out(format!(
+        "Per-server directory: {}",
+        if profile.per_server_dir { format!("on, files go into {}", profile.local_root().display()) } else { "off".to_string() }
+    ));
     cli::out(format!("Port: {}", profile.port.get()));
     cli::out(format!("IPv4: {}", profile.ipv4.get()));
-    println!();
+    cli::out(format!("Sync interval: {}s", profile.sync_interval.get()));
+    cli::out(format!(
+        "Sync schedule: {}",
+        if profile.schedule.is_set() { profile.schedule.get().as_str() } else { "none, syncing at the interval" }
+    ));
+    cli::out(format!(
+        "Bandwidth limit: {}",
+        if profile.bandwidth.is_set() { profile.bandwidth.get().as_str() } else { "unlimited" }
+    ));
+    cli::out(match profile.sync_mode {
+        SyncMode::Download if !profile.mirror_deletions => "Sync mode: download".to_string(),
+        SyncMode::Download => "Sync mode: download, mirroring deletions".to_string(),
+        SyncMode::TwoWay => format!("Sync mode: two-way, conflicts: {}", describe_strategy(profile.conflicts)),
+    });
+    cli::out(match profile.trash() {
+        Some(trash) => format!(
+            "Trash: replaced and removed files are kept in {}, {}",
+            trash.dir().display(),
+            match *profile.trash_max_age.get() {
+                0 => "until removed by hand".to_string(),
+                secs => format!("for {}", humantime::format_duration(Duration::from_secs(secs))),
+            }
+        ),
+        None => "Trash: off, replaced and removed files are gone for good".to_string(),
+    });
+    cli::out(format!(
+        "Exclusions: {}",
+        if profile.exclude.get().is_empty() { "none" } else { profile.exclude.get().as_str() }
+    ));
+    cli::out(format!(
+        "Sync filter: {}",
+        if profile.filter.get().is_empty() { "none" } else { profile.filter.get().as_str() }
+    ));
+    cli::out(format!(
+        "Download order: {}{}",
+        match profile.download_order {
+            DownloadOrder::Listed => "as listed",
+            DownloadOrder::SmallestFirst => "smallest first",
+            DownloadOrder::NewestFirst => "newest first",
+        },
+        if profile.priority.get().is_empty() { String::new() } else { format!(", after {}", profile.priority.get()) }
+    ));
+    cli::out(format!("Hidden files: {}", if profile.hidden_files { "included" } else { "excluded" }));
+    cli::out(format!("Symlinks: {}", profile.symlinks.key()));
+    cli::out(format!(
+        "Existing files: {}",
+        match profile.collisions {
+            CollisionPolicy::Overwrite => "overwritten by downloads",
+            CollisionPolicy::KeepBoth => "kept, downloads go next to them as 'name (1).ext'",
+        }
+    ));
+    cli::out(format!(
+        "SSH jump: {}",
+        if profile.ssh_jump.is_set() { profile.ssh_jump.get().as_str() } else { "none" }
+    ));
+    cli::out(format!("User: {}", if profile.user.is_empty() { "anonymous" } else { profile.user.as_str() }));
+    cli::out(format!("Shared secret: {}", if profile.shared_secret.is_empty() { "none" } else { "set" }));
+    cli::out(format!(
+        "Server public key: {}",
+        if profile.server_public_key.is_set() { profile.server_public_key.get().as_str() } else { "none (downloads aren't checked)" }
+    ));
+    cli::out(format!(
+        "SOCKS5 proxy: {}",
+        if profile.socks_proxy.is_set() { profile.socks_proxy.get().as_str() } else { "none" }
+    ));
+    cli::out(format!(
+        "Last successful connection: {}",
+        match profile.last_successful_connection {
+            Some(secs) => humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string(),
+            None => "never".to_string(),
+        }
+    ));
+    cli::blank();
 
     let mut options = cli::InputOptions::new();
 
     if errors.len() == 0 {
         options.add_static("s", "Start client");
+        options.add_static("as", "Start auto-sync");
+        options.add_static("rs", "Show remote root statistics");
     }
 
     options
+        .add_static("t", "Test connection")
         .add_static("cn", "Change name")
+        .add_static("nt", "Change notes")
+        .add_static("tg", "Change tags")
         .add_static("cr", "Change parity root")
+        .add_static("ps", "Toggle per-server directory")
         .add_static("cp", "Change port")
         .add_static("ci", "Change IPv4")
-        .add_static("erase", "Erase the profile (permanently)")
+        .add_static("cs", "Change sync interval")
+        .add_static("sc", "Change sync schedule")
+        .add_static("bw", "Change bandwidth limit")
+        .add_static("sm", "Change sync mode")
+        .add_static("cc", "Change how conflicts are settled")
+        .add_static("md", "Toggle mirroring deletions")
+        .add_static("kp", "Toggle keeping replaced and removed files in the trash")
+        .add_static("ct", "Change trash directory")
+        .add_static("ta", "Change how long the trash keeps files")
+        .add_static("ce", "Change exclusions")
+        .add_static("cf", "Change sync filter")
+        .add_static("qo", "Cycle download order (listed, smallest first, newest first)")
+        .add_static("qp", "Change priority patterns")
+        .add_static("hf", "Toggle hidden files")
+        .add_static("sl", "Cycle symlink policy (skip, follow, error)")
+        .add_static("kb", "Toggle keeping existing files when downloading")
+        .add_static("cl", "Change login")
+        .add_static("sk", "Change shared secret")
+        .add_static("pk", "Change pinned server public key")
+        .add_static("cj", "Change SSH jump host")
+        .add_static("cx", "Change SOCKS5 proxy")
+        .add_static("hk", "Manage hooks")
+        .add_static("o", "Open parity root in file manager")
+        .add_static("erase", "Erase the profile")
+        .add_static("h", "Help")
         .add_static("q", "Return");
 
     match options.get() {
         cli::OptionType::Dynamic(_) => unreachable!(),
         cli::OptionType::Static(key) => match key.as_ref() {
             "s" => command.queue_state("start_client"),
+            "t" => command.queue_state("test_connection"),
+            "h" => {
+                app_data.help_return = "manage_profile";
+                command.queue_state("help");
+            }
             "cn" => command.queue_state("change_name"),
+            "nt" => command.queue_state("change_notes"),
+            "tg" => command.queue_state("change_tags"),
             "cr" => command.queue_state("change_parity_root"),
+            "ps" => {
+                app_data.current_profile.as_mut().unwrap().per_server_dir ^= true;
+                command.queue_state("save_updated_profile");
+            }
             "cp" => command.queue_state("change_port"),
             "ci" => command.queue_state("change_ipv4"),
-            "erase" => match config::client::erase_profile(&profile.name) {
-                Ok(_) => {
-                    match config::client::erase_profile(&profile.name) {
-                        Ok(_) => command.queue_state("pick_profile"),
-                        Err(e) => app_data.push_notice(e),
+            "cs" => command.queue_state("change_sync_interval"),
+            "sc" => command.queue_state("change_schedule"),
+            "bw" => command.queue_state("change_bandwidth"),
+            "sm" => {
+                let profile = app_data.current_profile.as_mut().unwrap();
+                profile.sync_mode = profile.sync_mode.next();
+                command.queue_state("save_updated_profile");
+            }
+            "md" => {
+                app_data.current_profile.as_mut().unwrap().mirror_deletions ^= true;
+                command.queue_state("save_updated_profile");
+            }
+            "kp" => {
+                app_data.current_profile.as_mut().unwrap().keep_previous ^= true;
+                command.queue_state("save_updated_profile");
+            }
+            "ct" => command.queue_state("change_trash_dir"),
+            "ta" => command.queue_state("change_trash_max_age"),
+            "ce" => command.queue_state("change_exclusions"),
+            "cf" => command.queue_state("change_filter"),
+            "qo" => {
+                let profile = app_data.current_profile.as_mut().unwrap();
+                profile.download_order = profile.download_order.next();
+                command.queue_state("save_updated_profile");
+            }
+            "qp" => command.queue_state("change_priority"),
+            "hf" => {
+                app_data.current_profile.as_mut().unwrap().hidden_files ^= true;
+                command.queue_state("save_updated_profile");
+            }
+            "sl" => {
+                let profile = app_data.current_profile.as_mut().unwrap();
+                profile.symlinks = profile.symlinks.next();
+                command.queue_state("save_updated_profile");
+            }
+            "cc" => {
+                let profile = app_data.current_profile.as_mut().unwrap();
+                profile.conflicts = profile.conflicts.next();
+                command.queue_state("save_updated_profile");
+            }
+            "kb" => {
+                let profile = app_data.current_profile.as_mut().unwrap();
+                profile.collisions = profile.collisions.next();
+                command.queue_state("save_updated_profile");
+            }
+            "cl" => command.queue_state("change_login"),
+            "sk" => command.queue_state("change_shared_secret"),
+            "pk" => command.queue_state("change_server_public_key"),
+            "cj" => command.queue_state("change_ssh_jump"),
+            "cx" => command.queue_state("change_socks_proxy"),
+            "hk" => command.queue_state("manage_hooks"),
+            "as" => command.queue_state("auto_sync"),
+            "rs" => command.queue_state("remote_stats"),
+            "o" => {
+                // The server's directory when there is one, as that's where the files are
+                let root = Some(profile.local_root()).filter(|root| root.is_dir());
+                if let Err(e) = cli::open_path(root.unwrap_or_else(|| PathBuf::from(profile.parity_root.get()))) {
+                    app_data.push_error(format!("Could not open the parity root: {}", e));
+                }
+            }
+            "erase" => {
+                cli::out(format!("Type the profile's name, '{}', to erase it:", profile.name));
+                if cli::input() != profile.name {
+                    app_data.push_notice("The name didn't match, so the profile was kept.");
+                    return;
+                }
+                let name = profile.name.clone();
+                match config::client::erase_profile(&name) {
+                    Ok(_) => {
+                        app_data.push_success(format!("Erased profile '{}', which can be undone from the profile list", name));
+                        app_data.erased.push(name);
+                        app_data.current_profile = None;
+                        command.queue_state("pick_profile");
                     }
-                },
-                Err(e) => app_data.push_notice(format!("Error erasing file: {}", e)),
+                    Err(e) => app_data.push_error(format!("Error erasing file: {}", e)),
+                }
             }
             "q" => command.queue_state("pick_profile"),
             _ => unreachable!()
         },
-        cli::OptionType::Error(e) => app_data.push_notice(e),
+        cli::OptionType::Error(e) => app_data.push_error(e),
     }
 }
 
-fn state_change_name(app_data: &mut AppData, command: &mut app::Command) {
+fn state_manage_hooks(app_data: &mut AppData, command: &mut app::Command) {
     app_data.refresh_cli();
 
-    let profile = app_data.current_profile.as_mut().unwrap();
-
-    cli::notice("Leave blank to cancel.");
-    println!();
+    let profile = app_data.current_profile.as_ref().unwrap();
 
-    cli::out(format!("Changing: name"));
-    cli::out(format!("Current: {}", profile.name));
+    cli::out("Commands get the event as JSON on stdin and in $OXIDEUX_PAYLOAD; http:// URLs are POSTed it.");
+    cli::blank();
 
-    let input = cli::input();
-    if input.len() == 0 {
-        command.queue_state("manage_profile");
-        return;
+    let mut options = cli::InputOptions::new();
+    options
+        .set_header_dynamic("HOOKS (pick one to remove it):")
+        .set_header_static("__________");
+    for hook in &profile.hooks {
+        options.add_dynamic(format!("{}: {}", hook.event.key(), hook.action));
     }
+    options
+        .add_static("a", "Add hook")
+        .add_static("q", "Return");
 
-    match config::client::rename_profile(&profile.name, input.clone()) {
-        Ok(_) => {
-            profile.name = input;
-            command.queue_state("manage_profile");
+    match options.get() {
+        cli::OptionType::Dynamic(index) => {
+            let hook = &profile.hooks[index];
+            if !cli::confirm(format!("Remove the hook '{}: {}'?", hook.event.key(), hook.action)) {
+                return;
+            }
+            app_data.current_profile.as_mut().unwrap().hooks.remove(index);
+            command.queue_state("save_updated_profile");
+        }
+        cli::OptionType::Static(key) => match key.as_ref() {
+            "a" => command.queue_state("add_hook"),
+            "q" => command.queue_state("manage_profile"),
+            _ => unreachable!(),
         },
-        Err(e) => app_data.push_notice(e),
+        cli::OptionType::Error(e) => app_data.push_error(e),
     }
 }
 
-macro_rules! state_change_property {
-    ($fn_name:ident, $name:expr, $prop:ident, $intercept:expr) => {
-        fn $fn_name(app_data: &mut AppData, command: &mut app::Command) {
-            app_data.refresh_cli();
-
-            let profile = app_data.current_profile.as_mut().unwrap();
+fn state_add_hook(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("manage_hooks");
 
-            cli::notice("Leave blank to cancel.");
-            println!();
+    let mut options = cli::InputOptions::new();
+    options
+        .set_header_dynamic("RUN ON:")
+        .set_header_static("__________");
+    for event in Event::CLIENT {
+        options.add_dynamic(event.key());
+    }
+    options.add_static("q", "Cancel");
+
+    let event = match options.get() {
+        cli::OptionType::Dynamic(index) => Event::CLIENT[index],
+        cli::OptionType::Static(_) => return,
+        cli::OptionType::Error(e) => {
+            app_data.push_error(e);
+            return;
+        }
+    };
+
+    cli::notice("Leave blank to cancel.");
+    cli::blank();
+    cli::out("Shell command or http:// URL:");
+    let action = cli::input();
+    if action.is_empty() {
+        return;
+    }
+    if let Err(e) = Hook::validate_action(&action) {
+        app_data.push_error(e);
+        return;
+    }
+
+    app_data.current_profile.as_mut().unwrap().hooks.push(Hook { event, action });
+    command.queue_state("save_updated_profile");
+}
+
+fn state_change_name(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_mut().unwrap();
+
+    cli::notice("Press enter to keep the current name.");
+    cli::blank();
+
+    cli::out(format!("Changing: name"));
+    cli::out(format!("Current: {}", profile.name));
+
+    let input = cli::input_with_default(&profile.name);
+    if input.is_empty() || input == profile.name {
+        command.queue_state("manage_profile");
+        return;
+    }
+
+    match config::client::rename_profile(&profile.name, input.clone()) {
+        Ok(_) => {
+            profile.name = input;
+            command.queue_state("manage_profile");
+        },
+        Err(e) => app_data.push_error(e),
+    }
+}
+
+fn state_change_notes(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_mut().unwrap();
+
+    cli::notice("Press enter to keep the current notes.");
+    cli::blank();
+
+    cli::out("Changing: notes ('none' to clear them)");
+    let current = if profile.notes.is_empty() { "none" } else { profile.notes.as_str() };
+    cli::out(format!("Current: {}", current));
+
+    let input = cli::input_with_default(current);
+    if input.is_empty() || input == current {
+        command.queue_state("manage_profile");
+        return;
+    }
+
+    profile.notes = if input == "none" { String::new() } else { input };
+    command.queue_state("save_updated_profile");
+}
+
+fn state_change_tags(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_mut().unwrap();
+
+    cli::notice("Press enter to keep the current tags.");
+    cli::blank();
+
+    cli::out("Changing: tags (comma-separated, or 'none' to clear them)");
+    let current = if profile.tags.is_empty() { "none".to_string() } else { profile.tags.join(", ") };
+    cli::out(format!("Current: {}", current));
+
+    let input = cli::input_with_default(&current);
+    if input.is_empty() || input == current {
+        command.queue_state("manage_profile");
+        return;
+    }
+
+    profile.tags = if input == "none" { vec![] } else { config::parse_tags(&input) };
+    command.queue_state("save_updated_profile");
+}
+
+fn state_change_login(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_mut().unwrap();
+
+    cli::notice("Leave blank to cancel.");
+    cli::blank();
+
+    cli::out("Changing: login (user name, or 'none' to connect anonymously)");
+    let current = if profile.user.is_empty() { "none" } else { profile.user.as_str() };
+    cli::out(format!("Current: {}", current));
+
+    let user = cli::input_with_default(current);
+    if user.is_empty() {
+        command.queue_state("manage_profile");
+        return;
+    }
+    if user == "none" {
+        profile.user.clear();
+        profile.password.clear();
+        command.queue_state("save_updated_profile");
+        return;
+    }
+
+    cli::out("Password (stored in the client config):");
+    let password = cli::input_hidden();
+    if password.is_empty() {
+        command.queue_state("manage_profile");
+        return;
+    }
+
+    profile.user = user;
+    profile.password = password;
+    command.queue_state("save_updated_profile");
+}
+
+fn state_change_shared_secret(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_mut().unwrap();
+
+    cli::notice("Leave blank to cancel.");
+    cli::blank();
+
+    cli::out("Changing: shared secret (the same as the server's, stored in the client config, or 'none' to not encrypt connections)");
+    cli::out(format!("Current: {}", if profile.shared_secret.is_empty() { "none" } else { "set" }));
+
+    let secret = cli::input_hidden();
+    if secret.is_empty() {
+        command.queue_state("manage_profile");
+        return;
+    }
+
+    profile.shared_secret = if secret == "none" { String::new() } else { secret };
+    command.queue_state("save_updated_profile");
+}
+
+macro_rules! state_change_property {
+    ($fn_name:ident, $name:expr, $prop:ident, $intercept:expr) => {
+        fn $fn_name(app_data: &mut AppData, command: &mut app::Command) {
+            app_data.refresh_cli();
+
+            let profile = app_data.current_profile.as_mut().unwrap();
+            let current = profile.$prop.get().to_string();
+
+            cli::notice("Press enter to keep the current value.");
+            cli::blank();
 
             cli::out(format!("Changing: {}", $name));
-            cli::out(format!("Current: {}", profile.$prop.get()));
+            cli::out(format!("Current: {}", current));
 
-            let input = cli::input();
-            if input.len() == 0 {
+            let input = cli::input_with_default(&current);
+            if input.is_empty() || input == current {
                 command.queue_state("manage_profile");
                 return;
             }
@@ -252,14 +1287,14 @@ macro_rules! state_change_property {
             let parsed = match $intercept(input) {
                 Ok(v) => v,
                 Err(e) => {
-                    app_data.push_notice(e);
+                    app_data.push_error(e);
                     return;
                 }
             };
 
             match profile.$prop.safe_set(parsed) {
                 Ok(_) => command.queue_state("save_updated_profile"),
-                Err(e) => app_data.push_notice(e),
+                Err(e) => app_data.push_error(e),
             }
         }
     };
@@ -268,6 +1303,40 @@ macro_rules! state_change_property {
 state_change_property!(state_change_parity_root, "parity root", parity_root, |input| config::fill_path_placeholders(input) );
 state_change_property!(state_change_port, "port", port, |input: String| input.parse::<u16>());
 state_change_property!(state_change_ipv4, "ipv4", ipv4, |input| -> Result<String> { Result::Ok(input) });
+state_change_property!(state_change_sync_interval, "sync interval (seconds)", sync_interval, |input: String| input.parse::<u64>());
+state_change_property!(state_change_schedule, "sync schedule (e.g. 'every 6h', 'daily 03:00', or 'none')", schedule, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_bandwidth, "bandwidth limit (e.g. '1M 08:00-22:00, 10M', or 'none')", bandwidth, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_trash_dir, "trash directory (or 'none' for .oxideux-trash in the parity root)", trash_dir, |input: String| {
+    config::fill_path_placeholders(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_trash_max_age, "trash age (e.g. 7days, 0 to keep files until removed by hand)", trash_max_age, |input: String| -> Result<u64> {
+    match input.as_str() {
+        "0" => Result::Ok(0),
+        input => Result::Ok(humantime::parse_duration(input)?.as_secs()),
+    }
+});
+state_change_property!(state_change_exclusions, "exclusions (comma-separated patterns, or 'none')", exclude, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_priority, "priority (comma-separated patterns, or 'none')", priority, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_filter, "sync filter (e.g. ext:iso size:1M..2G since:7d name:report, or 'none')", filter, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_ssh_jump, "SSH jump host (user@host[:port], or 'none')", ssh_jump, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_socks_proxy, "SOCKS5 proxy ([user:password@]host:port, or 'none')", socks_proxy, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_server_public_key, "server public key (as shown by the server, or 'none')", server_public_key, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input.to_ascii_lowercase() })
+});
 
 fn state_save_updated_profile(app_data: &mut AppData, command: &mut app::Command) {
     app_data.refresh_cli();
@@ -276,7 +1345,7 @@ fn state_save_updated_profile(app_data: &mut AppData, command: &mut app::Command
 
     cli::out(format!("Changes have been made to the following profile: {}", profile.name));
     cli::out("Would you like to save these changes?");
-    println!();
+    cli::blank();
 
     let mut options = cli::InputOptions::new();
     options
@@ -288,82 +1357,1263 @@ fn state_save_updated_profile(app_data: &mut AppData, command: &mut app::Command
         cli::OptionType::Static(key) => match key.as_ref() {
             "y" => {
                 if let Err(e) = config::client::save_profile(profile) {
-                    app_data.push_notice(format!("Error saving profile: {}", e));
+                    app_data.push_error(format!("Error saving profile: {}", e));
                 } else {
-                    app_data.push_notice("Profile successfully saved.");
+                    app_data.push_success("Profile successfully saved.");
                 }
                 command.queue_state("manage_profile");
             }
             "n" => command.queue_state("manage_profile"),
             _ => unreachable!()
         },
-        cli::OptionType::Error(e) => app_data.push_notice(e),
+        cli::OptionType::Error(e) => app_data.push_error(e),
     }
 }
 
 fn state_start_client(app_data: &mut AppData, command: &mut app::Command) {
     let profile = app_data.current_profile.as_ref().unwrap();
-    let result = client(profile);
-    app_data.push_notice(match result {
-        Ok(_) => "Client terminated (OK)".to_string(),
-        Err(e) => format!("Client terminated (ERROR): {}", e),
+    match connect(profile) {
+        Ok(session) => {
+            app_data.session = Some(session);
+            command.queue_state("session");
+        }
+        Err(e) => {
+            app_data.push_error(format!("Could not connect: {}", e));
+            command.queue_state("manage_profile");
+        }
+    }
+}
+
+fn state_test_connection(app_data: &mut AppData, command: &mut app::Command) {
+    command.queue_state("manage_profile");
+
+    let profile = app_data.current_profile.as_mut().unwrap();
+    let spinner = cli::spinner(format!("Connecting to {}…", client::server_addr(profile)));
+    let start = Instant::now();
+    let result = Client::connect(profile).and_then(|mut client| {
+        let connected = start.elapsed();
+        let round_trip = client.ping(KEEP_ALIVE_TIMEOUT)?;
+        let capabilities = client.capabilities().to_string();
+        client.disconnect()?;
+        Ok((connected, round_trip, capabilities))
+    });
+    drop(spinner);
+
+    let (connected, round_trip, capabilities) = match result {
+        Ok(v) => v,
+        Err(e) => {
+            app_data.push_error(format!("Connection test failed: {}", e));
+            return;
+        }
+    };
+
+    // Only the timestamp is saved, leaving any changes that weren't saved alone
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    profile.last_successful_connection = Some(now);
+    let saved = config::client::get_profile(&profile.name).and_then(|mut stored| {
+        stored.last_successful_connection = Some(now);
+        config::client::save_profile(&stored)
     });
+
+    app_data.push_success(format!(
+        "Connected in {} ms, the server answers a ping in {} ms.",
+        connected.as_millis(),
+        round_trip.as_millis()
+    ));
+    app_data.push_notice(format!("Capabilities: {}", capabilities));
+    if let Err(e) = saved {
+        app_data.push_error(format!("Could not save when the connection succeeded: {}", e));
+    }
+}
+
+fn state_remote_stats(app_data: &mut AppData, command: &mut app::Command) {
     command.queue_state("manage_profile");
+
+    let profile = app_data.current_profile.as_ref().unwrap();
+    let result = open_client(profile).and_then(|mut client| {
+        let stats = client.root_stats();
+        let _ = client.connection().send_request(&Request::Disconnect);
+        stats
+    });
+
+    let stats = match result {
+        Ok(stats) => stats,
+        Err(e) => {
+            app_data.push_error(format!("Could not get remote statistics: {}", e));
+            return;
+        }
+    };
+    app_data.push_notice(format!(
+        "Remote root: {} files, {}",
+        format_count(stats.files),
+        format_size(stats.bytes)
+    ));
+    if let Some(largest) = stats.largest {
+        app_data.push_notice(format!("Largest: {} ({})", largest.name, format_size(largest.length)));
+    }
+    if let Some(newest) = stats.newest {
+        let modified = newest
+            .modified
+            .map(|secs| humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string())
+            .unwrap_or_default();
+        app_data.push_notice(format!("Newest: {} ({})", newest.name, modified));
+    }
 }
 
-fn client(profile: &ClientProfile) -> Result<()> {
-    let addr = format!(
-        "{}:{}",
-        profile.ipv4.get(),
-        profile.port.get()
-    );
-    let stream = TcpStream::connect(&addr)?;
+fn state_auto_sync(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("manage_profile");
+
+    let profile = app_data.current_profile.clone().unwrap();
+    let when = match profile.schedule.schedule() {
+        Some(schedule) => schedule.to_string(),
+        None => format!("every {}s", profile.sync_interval.get()),
+    };
+    cli::out(format!("Auto-syncing {} into {}. Press enter to stop.", when, profile.local_root().display()));
+    cli::blank();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let daemon = {
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || run_sync_daemon(&profile, &stop))
+    };
+
+    cli::input();
+    stop.store(true, Ordering::Relaxed);
+    cli::out("Stopping after the current pass...");
+    let _ = daemon.join();
+    app_data.push_notice("Auto-sync stopped");
+}
+
+fn state_session(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let session = app_data.session.as_ref().unwrap();
+    if session.is_lost() {
+        app_data.session = None;
+        app_data.push_error("Client terminated (ERROR): connection lost");
+        command.queue_state("manage_profile");
+        return;
+    }
+
+    cli::out(format!("Connected to {}", session.addr));
+    cli::out(format!("Capabilities: {}", session.client.lock().unwrap().capabilities()));
+    if app_data.dry_run {
+        cli::out("Dry run: syncs and downloads only show what they would do");
+    }
+    cli::blank();
+
+    let (supports_file_info, supports_matching, supports_watch, supports_upload, supports_versions) = {
+        let client = session.client.lock().unwrap();
+        (
+            client.supports(Capability::FileInfo),
+            client.supports(Capability::DownloadMatching),
+            client.supports(Capability::Watch),
+            client.supports(Capability::Upload),
+            client.supports(Capability::Versions),
+        )
+    };
+
+    let mut options = cli::InputOptions::new();
+    options
+        .add_static("d", "Download all files")
+        .add_static("sy", "Sync now")
+        .add_static("dr", if app_data.dry_run { "Turn dry run off" } else { "Turn dry run on" })
+        .add_static("n", "Count remote files")
+        .add_static("f", "Find remote files")
+        .add_static("v", "Verify local files against the server");
+    if supports_file_info {
+        options.add_static("i", "Inspect remote file");
+    }
+    if supports_matching {
+        options.add_static("m", "Download files matching a pattern");
+    }
+    if supports_watch {
+        options.add_static("w", "Watch remote changes");
+    }
+    if supports_upload {
+        options.add_static("u", "Upload a file");
+    }
+    if supports_versions {
+        options.add_static("pv", "Restore a previous version of a file");
+    }
+    options.add_static("x", "Disconnect");
+
+    let choice = options.get();
 
-    println!(
-        "Established connection to {}\nParity root: {}",
+    // The keep-alive may have noticed a lost connection while we were waiting for input
+    if session.is_lost() {
+        return;
+    }
+
+    let profile = app_data.current_profile.as_ref().unwrap();
+    let mut client = session.client.lock().unwrap();
+
+    match choice {
+        cli::OptionType::Dynamic(_) => unreachable!(),
+        cli::OptionType::Static(key) => match key.as_ref() {
+            "d" | "sy" if app_data.dry_run => {
+                let spinner = cli::spinner("Working out what would change…");
+                let result = match key.as_ref() {
+                    "d" => client.plan_download_all(profile.local_root()),
+                    _ => plan_once(&mut client, profile),
+                };
+                drop(spinner);
+                drop(client);
+                match result {
+                    Ok(plan) => {
+                        app_data.plan = Some(plan);
+                        command.queue_state("show_plan");
+                    }
+                    Err(e) => app_data.push_error(format!("Dry run failed: {}", e)),
+                }
+            }
+            "dr" => {
+                drop(client);
+                app_data.dry_run ^= true;
+            }
+            "d" => {
+                // A peek first, so a profile pointed at the wrong server doesn't start pulling everything
+                let peek = match client.supports(Capability::Stats) {
+                    true => client
+                        .root_stats()
+                        .map(|stats| format!("{} files, {}", format_count(stats.files), format_size(stats.bytes))),
+                    false => client.file_count().map(|count| format!("{} files", format_count(count as u64))),
+                };
+                drop(client);
+                let peek = match peek {
+                    Ok(v) => v,
+                    Err(e) => {
+                        app_data.push_error(format!("Could not count the remote files: {}", e));
+                        return;
+                    }
+                };
+
+                // The lock is let go while asking, so the keep-alive can keep pinging
+                cli::out(format!("The server has {}.", peek));
+                cli::out(format!("Destination: {}", profile.local_root().display()));
+                if !cli::confirm("Download all of it?") || session.is_lost() {
+                    return;
+                }
+
+                let mut client = session.client.lock().unwrap();
+                client.take_summary();
+                let result = with_transfer_keys(&session.control, || {
+                    with_space_prompt(&mut client, |client| client.download_all(profile.local_root()))
+                });
+                let summary = client.take_summary();
+                drop(client);
+                if let Err(e) = result {
+                    app_data.push_error(format!("Download failed: {}", e));
+                }
+                app_data.push_notice(format!("Download: {}", summary.describe()));
+            }
+            "sy" => {
+                client.take_summary();
+                let result = with_transfer_keys(&session.control, || {
+                    with_space_prompt(&mut client, |client| sync_once(client, profile, ask_conflict))
+                });
+                let summary = client.take_summary();
+                drop(client);
+                if let Err(e) = result {
+                    app_data.push_error(format!("Sync failed: {}", e));
+                }
+                app_data.push_notice(format!("Sync: {}", summary.describe()));
+            }
+            "i" => {
+                drop(client);
+                command.queue_state("inspect_remote_file");
+            }
+            "m" => {
+                drop(client);
+                command.queue_state("download_matching");
+            }
+            "f" => {
+                drop(client);
+                command.queue_state("find_remote");
+            }
+            "v" => {
+                let spinner = cli::spinner("Hashing files to compare them with the server's…");
+                let result = client.verify(profile.local_root());
+                drop(spinner);
+                drop(client);
+                match result {
+                    Ok(report) => {
+                        for name in &report.missing {
+                            app_data.push_notice(format!("Missing: {}", name));
+                        }
+                        for name in &report.mismatched {
+                            app_data.push_notice(format!("Mismatched: {}", name));
+                        }
+                        for name in &report.extra {
+                            app_data.push_notice(format!("Extra: {}", name));
+                        }
+                        app_data.push_notice(format!(
+                            "Verified {} file(s): {} missing, {} mismatched, {} extra",
+                            report.verified,
+                            report.missing.len(),
+                            report.mismatched.len(),
+                            report.extra.len()
+                        ));
+                    }
+                    Err(e) => app_data.push_error(format!("Verification failed: {}", e)),
+                }
+            }
+            "w" => {
+                drop(client);
+                command.queue_state("watch_remote");
+            }
+            "u" => {
+                drop(client);
+                command.queue_state("upload_file");
+            }
+            "pv" => {
+                drop(client);
+                command.queue_state("restore_version");
+            }
+            "n" => {
+                let result = client.file_count();
+                drop(client);
+                match result {
+                    Ok(count) => app_data.push_notice(format!("There are {} files", count)),
+                    Err(e) => app_data.push_error(e),
+                }
+            }
+            "x" => {
+                let result = client.connection().send_request(&Request::Disconnect);
+                let summary = client.take_summary();
+                drop(client);
+                app_data.session = None;
+                if summary.transferred + summary.failed > 0 {
+                    app_data.push_notice(format!("Session: {}", summary.describe()));
+                }
+                match result {
+                    Ok(_) => app_data.push_notice("Client terminated (OK)"),
+                    Err(e) => app_data.push_error(format!("Client terminated (ERROR): {}", e)),
+                }
+                command.queue_state("manage_profile");
+            }
+            _ => unreachable!(),
+        },
+        cli::OptionType::Error(e) => {
+            drop(client);
+            app_data.push_error(e);
+        }
+    }
+}
+
+fn state_show_plan(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("session");
+
+    cli::out("Dry run, nothing was changed:");
+    cli::sep_thin();
+    print_plan(&app_data.plan.take().unwrap_or_default());
+    cli::blank();
+
+    let mut options = cli::InputOptions::new();
+    options.add_static("q", "Return");
+    if let cli::OptionType::Error(e) = options.get() {
+        app_data.push_error(e);
+    }
+}
+
+fn state_download_matching(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    cli::notice("Leave blank to cancel.");
+    cli::blank();
+    cli::out("Pattern relative to the remote parity root (e.g. *.iso, reports/2024-*):");
+
+    let pattern = cli::input();
+    if pattern.is_empty() {
+        command.queue_state("session");
+        return;
+    }
+
+    let profile = app_data.current_profile.as_ref().unwrap();
+    cli::out("Destination, for this download only ('.' is the current directory):");
+    let root = profile.local_root().to_string_lossy().to_string();
+    let dest = match cli::input_with_default(&root) {
+        dest if dest.is_empty() => root,
+        dest => match config::fill_path_placeholders(dest) {
+            Ok(dest) => dest,
+            Err(e) => {
+                app_data.push_error(format!("Invalid destination: {}", e));
+                command.queue_state("session");
+                return;
+            }
+        },
+    };
+
+    let session = app_data.session.as_ref().unwrap();
+    let result = with_transfer_keys(&session.control, || {
+        session.client.lock().unwrap().download_matching(&pattern, &dest)
+    });
+
+    if let Err(e) = result {
+        app_data.push_error(format!("Download failed: {}", e));
+    }
+    command.queue_state("session");
+}
+
+fn state_find_remote(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("session");
+
+    cli::notice("Leave blank to cancel.");
+    cli::blank();
+    cli::out("Query (e.g. ext:iso size:1M..2G since:7d name:report):");
+
+    let spec = cli::input();
+    if spec.is_empty() {
+        return;
+    }
+    let query = match EntryQuery::parse(&spec) {
+        Ok(query) => query,
+        Err(e) => {
+            app_data.push_error(format!("Invalid query: {}", e));
+            return;
+        }
+    };
+
+    let session = app_data.session.as_ref().unwrap();
+    let spinner = cli::spinner("Searching the server…");
+    let result = session.client.lock().unwrap().search(&query, SEARCH_LIMIT);
+    drop(spinner);
+    match result {
+        Ok(entries) => {
+            for entry in &entries {
+                app_data.push_notice(format!("{} ({} bytes)", entry.name, entry.length));
+            }
+            match entries.len() as u32 {
+                SEARCH_LIMIT => app_data.push_notice(format!("Showing the first {} matches, narrow the query for the rest", SEARCH_LIMIT)),
+                matched => app_data.push_notice(format!("{} file(s) matched", matched)),
+            }
+        }
+        Err(e) => app_data.push_error(format!("Query failed: {}", e)),
+    }
+}
+
+fn state_upload_file(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("session");
+
+    cli::notice("Leave blank to cancel.");
+    cli::blank();
+    cli::out("Local file to upload:");
+
+    let local = cli::input();
+    if local.is_empty() {
+        return;
+    }
+    let local = match config::fill_path_placeholders(local) {
+        Ok(local) => PathBuf::from(local),
+        Err(e) => {
+            app_data.push_error(e);
+            return;
+        }
+    };
+    let default_name = local
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    cli::out(format!("Remote name (blank for '{}'):", default_name));
+    let name = match cli::input() {
+        name if name.is_empty() => default_name,
+        name => name,
+    };
+
+    let session = app_data.session.as_ref().unwrap();
+    let result = with_transfer_keys(&session.control, || session.client.lock().unwrap().upload(local, &name));
+    match result {
+        Ok(_) => app_data.push_success(format!("Uploaded '{}'", name)),
+        Err(e) => app_data.push_error(format!("Upload failed: {}", e)),
+    }
+}
+
+fn state_watch_remote(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("session");
+
+    // Notifications get a connection of their own so the session stays usable
+    let profile = app_data.current_profile.as_ref().unwrap();
+    let mut conn = match subscribe(profile) {
+        Ok(conn) => conn,
+        Err(e) => {
+            app_data.push_error(format!("Could not watch: {}", e));
+            return;
+        }
+    };
+
+    let stream = match conn.stream().try_clone() {
+        Ok(stream) => stream,
+        Err(e) => {
+            app_data.push_error(e);
+            return;
+        }
+    };
+
+    cli::out("Watching remote changes. Press enter to stop.");
+    cli::blank();
+
+    let watcher = thread::spawn(move || loop {
+        match conn.read_object::<Notification>() {
+            Ok(Notification::Heartbeat) => (),
+            Ok(Notification::Added(name)) => cli::out(format!("+ {}", name)),
+            Ok(Notification::Changed(name)) => cli::out(format!("~ {}", name)),
+            Ok(Notification::Removed(name)) => cli::out(format!("- {}", name)),
+            Err(e) => return e,
+        }
+    });
+
+    cli::input();
+    let _ = stream.shutdown(Shutdown::Both);
+    if let Ok(e) = watcher.join() {
+        if !e.is::<std::io::Error>() {
+            app_data.push_error(format!("Watching stopped: {}", e));
+        }
+    }
+}
+
+fn state_inspect_remote_file(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    cli::notice("Leave blank to cancel.");
+    cli::blank();
+    cli::out("Name of the remote file:");
+
+    let name = cli::input();
+    if name.is_empty() {
+        command.queue_state("session");
+        return;
+    }
+
+    let session = app_data.session.as_ref().unwrap();
+    let profile = app_data.current_profile.as_ref().unwrap();
+    let result = session.client.lock().unwrap().file_info(&name);
+
+    match result {
+        Ok(info) => {
+            let modified = match info.modified_time() {
+                Some(time) => humantime::format_rfc3339_seconds(time).to_string(),
+                None => "unknown".to_string(),
+            };
+
+            let mut local = profile.local_root();
+            local.push(&info.name);
+            let local_state = match local.is_file() {
+                false => "missing".to_string(),
+                true => match parity::hash_file(&local) {
+                    Ok(hash) if hash == info.hash => "up to date".to_string(),
+                    Ok(_) => "differs from remote".to_string(),
+                    Err(e) => format!("could not be hashed ({})", e),
+                },
+            };
+
+            app_data.push_notice(format!("Name: {}", info.name));
+            app_data.push_notice(format!("Size: {} bytes", info.length));
+            app_data.push_notice(format!("Modified: {}", modified));
+            app_data.push_notice(format!("SHA-256: {}", info.hash));
+            app_data.push_notice(format!("Local copy: {}", local_state));
+        }
+        Err(e) => app_data.push_error(e),
+    }
+
+    command.queue_state("session");
+}
+
+fn state_restore_version(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("session");
+
+    cli::notice("Leave blank to cancel.");
+    cli::blank();
+    cli::out("Name of the remote file:");
+
+    let name = cli::input();
+    if name.is_empty() {
+        return;
+    }
+
+    let session = app_data.session.as_ref().unwrap();
+    let result = session.client.lock().unwrap().list_versions(&name);
+    let versions = match result {
+        Ok(versions) if versions.is_empty() => {
+            app_data.push_notice(format!("There are no previous versions of '{}'", name));
+            return;
+        }
+        Ok(versions) => versions,
+        Err(e) => {
+            app_data.push_error(e);
+            return;
+        }
+    };
+
+    let mut options = cli::InputOptions::new();
+    options
+        .set_header_dynamic("REPLACED AT (UTC):")
+        .set_header_static("__________");
+    for version in &versions {
+        options.add_dynamic(format!("{} ({})", version.id, format_size(version.length)));
+    }
+    options.add_static("q", "Cancel");
+
+    let version = match options.get() {
+        cli::OptionType::Dynamic(index) => &versions[index],
+        cli::OptionType::Static(_) => return,
+        cli::OptionType::Error(e) => {
+            app_data.push_error(e);
+            return;
+        }
+    };
+    if session.is_lost() {
+        return;
+    }
+
+    // Where the file itself would be synced, or next to it as the collision policy says
+    let profile = app_data.current_profile.as_ref().unwrap();
+    let dest = match parity::safe_join(profile.local_root(), &name) {
+        Ok(dest) => dest,
+        Err(e) => {
+            app_data.push_error(e);
+            return;
+        }
+    };
+    let result = with_transfer_keys(&session.control, || {
+        session.client.lock().unwrap().download_version(&name, &version.id, dest)
+    });
+    match result {
+        Ok(_) => app_data.push_success(format!("Restored the version of '{}' replaced at {}", name, version.id)),
+        Err(e) => app_data.push_error(format!("Download failed: {}", e)),
+    }
+}
+
+/// Runs `transfer` while the keys steering transfers through `control` are listened to (see:
+/// [`transfer_keys`]).
+fn with_transfer_keys<T, F: FnOnce() -> T>(control: &TransferControl, transfer: F) -> T {
+    let keys = transfer_keys(control);
+    let result = transfer();
+    drop(keys);
+    result
+}
+
+/// Listens to the keys that pause (p), resume (r), skip the file being transferred (c) and abort
+/// every transfer (a) through `control`, which starts afresh, and says which they are.
+fn transfer_keys(control: &TransferControl) -> cli::KeyListener {
+    control.reset();
+    let keys = cli::listen_keys({
+        let control = control.clone();
+        move |key| match key {
+            'p' if !control.is_paused() => {
+                control.pause();
+                cli::blank();
+                cli::notice("Paused, r resumes");
+            }
+            'r' if control.is_paused() => {
+                control.resume();
+                cli::notice("Resumed");
+            }
+            'c' => {
+                control.skip_current();
+                cli::blank();
+                cli::notice("Skipping the current file");
+            }
+            'a' if !control.is_aborted() => {
+                control.abort();
+                cli::blank();
+                cli::notice("Aborting every transfer");
+            }
+            _ => (),
+        }
+    });
+    if keys.is_listening() {
+        cli::notice("p pauses, r resumes, c skips the current file, a aborts every transfer");
+    }
+    keys
+}
+
+/// Prints transfer events as they come, redrawing a single progress line per file with how fast
+/// it goes and how long it has left.
+fn transfer_printer() -> impl FnMut(&TransferEvent) + Send + 'static {
+    let mut drawn: Option<Instant> = None;
+    move |event| {
+        if cli::json_output() {
+            if let TransferEvent::Progressed { .. } = event {
+                if drawn.is_some_and(|drawn| drawn.elapsed() < PROGRESS_INTERVAL) {
+                    return;
+                }
+                drawn = Some(Instant::now());
+            }
+            return cli::json_line(transfer_json(event));
+        }
+        print_transfer(event, &mut drawn);
+    }
+}
+
+/// A transfer event as a JSON line, such as
+/// `{"type":"transfer","event":"completed","name":"a.iso","length":1024,"elapsed_seconds":0.5,...}`.
+fn transfer_json(event: &TransferEvent) -> json::JsonValue {
+    match event {
+        TransferEvent::Started { name, length } => json::object! {
+            "type": "transfer", "event": "started", "name": *name, "length": *length,
+        },
+        TransferEvent::Progressed { name, stats, .. } => json::object! {
+            "type": "transfer",
+            "event": "progressed",
+            "name": *name,
+            "length": stats.length,
+            "transferred": stats.transferred,
+            "speed": stats.current_speed(),
+            "eta_seconds": stats.eta().map(|eta| eta.as_secs_f64()),
+        },
+        TransferEvent::Completed { name, length, stats } => json::object! {
+            "type": "transfer",
+            "event": "completed",
+            "name": *name,
+            "length": *length,
+            "elapsed_seconds": stats.elapsed.as_secs_f64(),
+            "average_speed": stats.average_speed,
+        },
+        TransferEvent::Failed { name, error } => json::object! {
+            "type": "transfer", "event": "failed", "name": *name, "error": error.to_string(),
+        },
+    }
+}
+
+fn print_transfer(event: &TransferEvent, drawn: &mut Option<Instant>) {
+    match event {
+        TransferEvent::Started { length, .. } => {
+            *drawn = None;
+            info!("Downloading file ({} MiB)", length / 1048576);
+        }
+        TransferEvent::Progressed { stats, .. } => {
+            // The line is redrawn in place, which only a terminal that shows the rest can take
+            if !log_enabled!(Level::Info) || drawn.is_some_and(|drawn| drawn.elapsed() < PROGRESS_INTERVAL) {
+                return;
+            }
+            *drawn = Some(Instant::now());
+            print!("\r{:<78}", describe_progress(stats));
+            let _ = io::stdout().flush();
+        }
+        TransferEvent::Completed { name, stats, .. } => {
+            if drawn.take().is_some() {
+                cli::blank();
+            }
+            info!(
+                "Downloaded '{}' in {} ({}/s on average)",
+                name,
+                humantime::format_duration(Duration::from_secs(stats.elapsed.as_secs())),
+                format_size(stats.average_speed as u64)
+            );
+        }
+        TransferEvent::Failed { name, error } => {
+            if drawn.take().is_some() {
+                cli::blank();
+            }
+            warn!("Failed to download '{}': {}", name, error);
+        }
+    }
+}
+
+/// Such as `42.0% of 38.2 GiB at 11.8 MiB/s, 22.1 GiB left, about 32m 5s to go`.
+fn describe_progress(stats: &TransferStats) -> String {
+    let percent = match stats.length {
+        0 => 100.0,
+        length => stats.transferred as f64 * 100.0 / length as f64,
+    };
+    let eta = match stats.eta() {
+        Some(eta) => format!("about {} to go", humantime::format_duration(Duration::from_secs(eta.as_secs()))),
+        None => "estimating".to_string(),
+    };
+    format!(
+        "{:.1}% of {} at {}/s, {} left, {}",
+        percent,
+        format_size(stats.length),
+        format_size(stats.current_speed() as u64),
+        format_size(stats.remaining()),
+        eta
+    )
+}
+
+/// Prints every remote file, with its size and when it was last modified.
+fn list_remote(profile: &ClientProfile) -> Result<()> {
+    let mut client = Client::connect(profile)?;
+    let spinner = cli::spinner("Listing remote files…");
+    let entries = client.list();
+    drop(spinner);
+    let _ = client.disconnect();
+    let mut table = Table::new();
+    table
+        .add_column("Size", Align::Right)
+        .add_column("Modified", Align::Left)
+        .add_column("Name", Align::Left)
+        .set_max_width(LIST_NAME_WIDTH);
+    for entry in entries? {
+        match cli::json_output() {
+            true => cli::json_line(json::object! {
+                "type": "file",
+                "name": entry.name.as_str(),
+                "length": entry.length,
+                "modified": entry.modified,
+            }),
+            false => {
+                let modified = entry
+                    .modified
+                    .map(|secs| humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string())
+                    .unwrap_or_else(|| "unknown".to_string());
+                table.add_row([format_size(entry.length), modified, entry.name]);
+            }
+        }
+    }
+    if !table.is_empty() {
+        cli::table(&table);
+    }
+    Ok(())
+}
+
+/// Writes the remote file `name` to stdout, so it can be piped into other programs. Nothing else
+/// is printed there.
+fn cat_remote(profile: &ClientProfile, name: &str) -> Result<()> {
+    let mut client = Client::connect(profile)?;
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+    let result = client.download_to(name, &mut stdout);
+    let _ = client.disconnect();
+    result
+}
+
+/// Downloads the remote files matching `pattern` into `dest`, or the parity root when there's none.
+fn get_matching(profile: &ClientProfile, pattern: &str, dest: Option<&Path>) -> Result<()> {
+    let mut client = open_client(profile)?;
+    let dest = dest.map(Path::to_path_buf).unwrap_or_else(|| profile.local_root());
+    cli::out(format!("Destination: {}", dest.display()));
+    let result = with_transfer_keys(&client.transfer_control(), || client.download_matching(pattern, dest));
+    let _ = client.disconnect();
+    let count = result?;
+    cli::json_line(json::object! { "type": "summary", "files": count });
+    info!("Downloaded {} file(s) matching '{}'", count, pattern);
+    Ok(())
+}
+
+/// Uploads what is piped into stdin as the remote file `name`.
+fn put_stdin(profile: &ClientProfile, name: &str) -> Result<()> {
+    let mut client = Client::connect(profile)?;
+    let result = client.upload_from(&mut io::stdin().lock(), name);
+    let _ = client.disconnect();
+    result
+}
+
+#[cfg(unix)]
+fn run_tui() -> Result<()> {
+    tui::run()
+}
+
+#[cfg(not(unix))]
+fn run_tui() -> Result<()> {
+    Err(anyhow::anyhow!("The TUI is only available on Unix"))
+}
+
+fn open_client(profile: &ClientProfile) -> Result<Client> {
+    let mut client = Client::connect(profile)?;
+    client.set_transfer_handler(transfer_printer());
+    Ok(client)
+}
+
+fn connect(profile: &ClientProfile) -> Result<Session> {
+    let addr = client::server_addr(profile);
+    let client = open_client(profile)?;
+    let capabilities = client.capabilities();
+    let control = client.transfer_control();
+
+    let client = Arc::new(Mutex::new(client));
+    let keep_alive = match capabilities.contains(Capability::KeepAlive) {
+        true => Some(KeepAlive::spawn(Arc::clone(&client), |e| {
+            cli::blank();
+            cli::error(format!("Connection lost: {}", e));
+        })),
+        false => None,
+    };
+
+    Ok(Session {
+        client,
+        control,
+        keep_alive,
         addr,
-        profile.parity_root.get()
-    );
+    })
+}
 
-    let mut conn = Connection(stream);
+fn subscribe(profile: &ClientProfile) -> Result<Connection> {
+    let mut conn = open_client(profile)?.into_connection();
+    conn.stream().set_read_timeout(Some(KEEP_ALIVE_TIMEOUT))?;
+    conn.send_request(&Request::Subscribe)?;
+    conn.read_request_result()?.naturalize()?;
+    Ok(conn)
+}
 
-    let request = Request::DownloadAllFiles;
-    conn.send_request(&request)?;
+/// Keeps the parity root in sync with the server until `stop` is set: every sync interval the
+/// remote listing is compared with the local files and anything new or changed is downloaded, or
+/// copied both ways in two-way mode. Lost connections are re-established on the next pass.
+fn run_sync_daemon(profile: &ClientProfile, stop: &AtomicBool) {
+    if let Some(schedule) = profile.schedule.schedule() {
+        return run_scheduled_sync(profile, schedule, stop);
+    }
+    let interval = Duration::from_secs(*profile.sync_interval.get());
 
-    match request {
-        Request::Disconnect => {}
-        Request::GetFileCount => {
-            conn.read_request_result()?;
-            let count = conn.read_u32()?;
-            println!("There are {} files", count);
+    while !stop.load(Ordering::Relaxed) {
+        match open_client(profile) {
+            Ok(mut client) => loop {
+                // Nobody is there to ask, so such conflicts wait for a sync from the session
+                match sync_once(&mut client, profile, |_| Resolution::Skip) {
+                    Ok(0) => (),
+                    Ok(count) => {
+                        info!("Synced {} file(s)", count);
+                        cli::json_line(json::object! { "type": "sync", "files": count });
+                        fire_sync_completed(profile, count);
+                    }
+                    Err(e) => {
+                        warn!("Sync failed, reconnecting: {}", e);
+                        break;
+                    }
+                }
+                if let Err(e) = idle(client.connection(), interval, stop) {
+                    warn!("Connection lost, reconnecting: {}", e);
+                    break;
+                }
+                if stop.load(Ordering::Relaxed) {
+                    let _ = client.disconnect();
+                    return;
+                }
+            },
+            Err(e) => warn!("Could not connect, retrying in {}s: {}", interval.as_secs(), e),
         }
-        Request::DownloadFileByIndex(_) => {
-            conn.read_request_result()?;
-            let name = conn.read_string()?;
-            let mut output = PathBuf::from(profile.parity_root.get());
-            output.push(name);
-            conn.read_file(&output)?;
+
+        let deadline = Instant::now() + interval;
+        while Instant::now() < deadline && !stop.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(250));
         }
-        Request::DownloadFileByName(name) => {
-            conn.read_request_result()?;
-            let mut output = PathBuf::from(profile.parity_root.get());
-            output.push(name);
-            conn.read_file(&output)?;
+    }
+}
+
+/// Syncs whenever `schedule` says, connecting for each pass. Every line logged is stamped with the
+/// time, as nobody may be watching; a failing pass is retried a few times before it is given up
+/// until the next occurrence.
+fn run_scheduled_sync(profile: &ClientProfile, schedule: Schedule, stop: &AtomicBool) {
+    let stamp = |message: String| format!("[{}] {}", humantime::format_rfc3339_seconds(SystemTime::now()), message);
+    let mut next = match schedule {
+        Schedule::Every(_) => SystemTime::now(),
+        Schedule::Daily { .. } => schedule.next_after(SystemTime::now()),
+    };
+
+    loop {
+        info!("{}", stamp(format!("Next sync at {}", humantime::format_rfc3339_seconds(next))));
+        if !wait_until(next, stop) {
+            return;
         }
-        Request::DownloadAllFiles => {
-            conn.read_request_result()?;
-            let count = conn.read_u32()?;
-            for i in 0..count {
-                println!();
-                let name = conn.read_string()?;
-                let mut output = PathBuf::from(profile.parity_root.get());
-                println!("({}/{}) Destination file: {:?}/{}", i, count - 1, &output, name);
-                output.push(name);
-                conn.read_file(&output)?;
-                conn.send_request_result(RequestResult::Ok)?;
+
+        let started = SystemTime::now();
+        for attempt in 1..=SCHEDULED_ATTEMPTS {
+            let result = open_client(profile).and_then(|mut client| {
+                let synced = sync_once(&mut client, profile, |_| Resolution::Skip);
+                let _ = client.disconnect();
+                synced
+            });
+            match result {
+                Ok(count) => {
+                    info!("{}", stamp(format!("Scheduled sync done, {} file(s) synced", count)));
+                    cli::json_line(json::object! { "type": "sync", "files": count });
+                    if count > 0 {
+                        fire_sync_completed(profile, count);
+                    }
+                    break;
+                }
+                Err(e) if attempt < SCHEDULED_ATTEMPTS => {
+                    let delay = SCHEDULED_RETRY_DELAY * 2u32.pow(attempt - 1);
+                    warn!(
+                        "{}",
+                        stamp(format!(
+                            "Scheduled sync failed (attempt {} of {}), retrying in {}s: {}",
+                            attempt,
+                            SCHEDULED_ATTEMPTS,
+                            delay.as_secs(),
+                            e
+                        ))
+                    );
+                    if !wait_until(SystemTime::now() + delay, stop) {
+                        return;
+                    }
+                }
+                Err(e) => error!(
+                    "{}",
+                    stamp(format!("Scheduled sync failed {} times, giving up until the next one: {}", attempt, e))
+                ),
             }
         }
+
+        // Occurrences missed while syncing or retrying are skipped rather than caught up on
+        next = schedule.next_after(started);
+        while next <= SystemTime::now() {
+            next = schedule.next_after(next);
+        }
+    }
+}
+
+/// Sleeps until `time`, returning false instead if `stop` is set first.
+fn wait_until(time: SystemTime, stop: &AtomicBool) -> bool {
+    while SystemTime::now() < time {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(250));
     }
+    !stop.load(Ordering::Relaxed)
+}
 
+fn fire_sync_completed(profile: &ClientProfile, count: usize) {
+    hooks::fire(&profile.hooks, Event::SyncCompleted, &profile.name, json::object! {
+        "files": count,
+        "parity_root": profile.local_root().to_string_lossy().to_string(),
+    });
+}
+
+/// Runs a single `sync` or `download-all` for the command line, or only prints what it would do.
+/// What was transferred is summed up afterwards, and written to `report` as well when given.
+fn run_once(profile: &ClientProfile, command: &str, dry_run: bool, report: Option<&Path>, dest: Option<&Path>) -> Result<()> {
+    let mut client = open_client(profile)?;
+    let root = dest.map(Path::to_path_buf).unwrap_or_else(|| profile.local_root());
+    let result = match (command, dry_run) {
+        ("sync", true) => {
+            let spinner = cli::spinner("Working out what would change…");
+            let plan = plan_once(&mut client, profile);
+            drop(spinner);
+            plan.map(|plan| print_plan(&plan))
+        }
+        ("sync", false) => {
+            let control = client.transfer_control();
+            with_transfer_keys(&control, || sync_once(&mut client, profile, ask_conflict).map(|_| ()))
+        }
+        (_, true) => {
+            let spinner = cli::spinner("Working out what would change…");
+            let plan = client.plan_download_all(root);
+            drop(spinner);
+            plan.map(|plan| print_plan(&plan))
+        }
+        (_, false) => with_transfer_keys(&client.transfer_control(), || client.download_all(root)),
+    };
+    let summary = client.take_summary();
+    let _ = client.disconnect();
+    if !dry_run {
+        let mut line = json::object! { "type": "summary" };
+        for (key, value) in summary.to_json_value().entries() {
+            line[key] = value.clone();
+        }
+        cli::json_line(line);
+        info!("Summary: {}", summary.describe());
+        if let Some(report) = report {
+            summary.write(report)?;
+            info!("Report written to {}", report.display());
+        }
+    }
+    result
+}
+
+/// Runs the steps of a batch script in order, then prints what each came to. Fails when any of
+/// them did, so jobs running it can tell.
+fn run_batch(profile: &ClientProfile, steps: Vec<Step>) -> Result<()> {
+    let mut client = open_client(profile)?;
+    let root = profile.local_root();
+    let control = client.transfer_control();
+    let keys = transfer_keys(&control);
+    let mut report = BatchReport::default();
+    for step in steps {
+        info!("Line {}: {}", step.line, step.operation);
+        let outcome = match &step.operation {
+            Operation::Get(pattern) => client.download_matching(pattern, &root),
+            Operation::Put(pattern) => client.upload_matching(pattern, &root),
+            Operation::Sync => sync_once(&mut client, profile, ask_conflict),
+        };
+        let outcome = outcome.map_err(|e| e.to_string());
+        cli::json_line(match &outcome {
+            Ok(count) => json::object! {
+                "type": "step", "line": step.line, "operation": step.operation.to_string(), "files": *count,
+            },
+            Err(e) => json::object! {
+                "type": "step", "line": step.line, "operation": step.operation.to_string(), "error": e.as_str(),
+            },
+        });
+        report.outcomes.push((step, outcome));
+    }
+    drop(keys);
+    let _ = client.disconnect();
+
+    cli::sep_thin();
+    for line in report.summary().lines() {
+        cli::out(line);
+    }
+    match report.failed() {
+        0 => Ok(()),
+        failed => Err(anyhow::anyhow!(format!("{} batch operation(s) failed", failed))),
+    }
+}
+
+/// What [`sync_once`] would do, without doing it.
+fn plan_once(client: &mut Client, profile: &ClientProfile) -> Result<SyncPlan> {
+    match profile.sync_mode {
+        SyncMode::Download => client.plan_sync(profile.local_root(), profile.mirror_deletions),
+        SyncMode::TwoWay => client.plan_two_way(profile.local_root(), profile.conflicts),
+    }
+}
+
+fn print_plan(plan: &SyncPlan) {
+    if cli::json_output() {
+        for planned in &plan.changes {
+            cli::json_line(json::object! {
+                "type": "plan",
+                "change": planned.change.key(),
+                "name": planned.name.as_str(),
+                "bytes": planned.bytes,
+            });
+        }
+        return;
+    }
+    if plan.is_empty() {
+        cli::out("Nothing to do, everything is up to date.");
+        return;
+    }
+    for planned in &plan.changes {
+        match planned.change {
+            Change::Conflict => cli::out(format!("{:<9} {}", planned.change.key(), planned.name)),
+            change => cli::out(format!("{:<9} {} ({} bytes)", change.key(), planned.name, planned.bytes)),
+        }
+    }
+    cli::sep_thin();
+    for line in plan.totals().lines() {
+        cli::out(line);
+    }
+}
+
+/// Runs one sync pass in the profile's sync mode, returning how many files were transferred.
+/// Conflicts the profile leaves to the user are settled by `ask`.
+fn sync_once<F: FnMut(&Conflict) -> Resolution>(client: &mut Client, profile: &ClientProfile, ask: F) -> Result<usize> {
+    match profile.sync_mode {
+        SyncMode::Download if profile.mirror_deletions => {
+            let report = client.sync_mirrored(profile.local_root())?;
+            if report.removed > 0 {
+                info!("Removed {} file(s) no longer on the server", report.removed);
+            }
+            Ok(report.downloaded)
+        }
+        SyncMode::Download => client.sync(profile.local_root()),
+        SyncMode::TwoWay => {
+            let report = client.sync_two_way(profile.local_root(), profile.conflicts, ask)?;
+            if report.conflicts > 0 {
+                info!("Settled {} conflict(s)", report.conflicts);
+            }
+            if !report.unresolved.is_empty() {
+                warn!("Changed on both sides, left to settle: {}", report.unresolved.join(", "));
+            }
+            Ok(report.transferred())
+        }
+    }
+}
+
+/// Runs `transfer`, and when it is refused for not fitting on the disk, asks whether to go ahead
+/// anyway.
+fn with_space_prompt<T, F: FnMut(&mut Client) -> Result<T>>(client: &mut Client, mut transfer: F) -> Result<T> {
+    let error = match transfer(client) {
+        Err(e) if e.downcast_ref::<InsufficientSpace>().is_some() => e,
+        result => return result,
+    };
+
+    cli::blank();
+    cli::error(&error);
+    let mut options = cli::InputOptions::new();
+    options.add_static("y", "Download anyway").add_static("n", "Cancel");
+    match options.get() {
+        cli::OptionType::Static(key) if key == "y" => {
+            client.set_space_check(false);
+            let result = transfer(client);
+            client.set_space_check(true);
+            result
+        }
+        _ => Err(error),
+    }
+}
+
+/// Asks the user how to settle `conflict`. With JSON output nobody is there to answer, so it is
+/// skipped, as the daemon does.
+fn ask_conflict(conflict: &Conflict) -> Resolution {
+    if cli::json_output() {
+        cli::json_line(json::object! { "type": "conflict", "name": conflict.name.as_str(), "resolution": "skip" });
+        return Resolution::Skip;
+    }
+    let describe = |state: &FileState| {
+        let modified = state
+            .modified
+            .map(|secs| humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        format!("{} bytes, modified {}", state.length, modified)
+    };
+
+    cli::blank();
+    cli::out(format!("'{}' changed on both sides", conflict.name));
+    cli::out(format!("Local: {}", describe(&conflict.local)));
+    cli::out(format!("Remote: {}", describe(&conflict.remote)));
+
+    let mut options = cli::InputOptions::new();
+    options
+        .add_static("l", "Keep the local version")
+        .add_static("r", "Keep the remote version")
+        .add_static("b", "Keep both")
+        .add_static("s", "Skip for now");
+    loop {
+        match options.get() {
+            cli::OptionType::Dynamic(_) => unreachable!(),
+            cli::OptionType::Static(key) => {
+                return match key.as_ref() {
+                    "l" => Resolution::KeepLocal,
+                    "r" => Resolution::KeepRemote,
+                    "b" => Resolution::KeepBoth,
+                    "s" => Resolution::Skip,
+                    _ => unreachable!(),
+                }
+            }
+            cli::OptionType::Error(e) => cli::error(e),
+        }
+    }
+}
+
+/// `1204` as `1,204`.
+fn format_count(count: u64) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    formatted
+}
+
+fn describe_strategy(strategy: ConflictStrategy) -> &'static str {
+    match strategy {
+        ConflictStrategy::NewestWins => "newest wins",
+        ConflictStrategy::KeepBoth => "keep both",
+        ConflictStrategy::Ask => "ask",
+    }
+}
+
+/// Waits for `duration` (or until `stop` is set), pinging the server so the session isn't dropped.
+fn idle(conn: &mut Connection, duration: Duration, stop: &AtomicBool) -> Result<()> {
+    let deadline = Instant::now() + duration;
+    let mut last_ping = Instant::now();
+    while Instant::now() < deadline && !stop.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(250));
+        if conn.supports(Capability::KeepAlive) && last_ping.elapsed() >= KEEP_ALIVE_INTERVAL {
+            conn.ping(KEEP_ALIVE_TIMEOUT)?;
+            last_ping = Instant::now();
+        }
+    }
     Ok(())
 }
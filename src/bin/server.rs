@@ -1,22 +1,46 @@
-use std::net::{Shutdown, TcpListener};
-use std::path::PathBuf;
-use std::process::Command;
+use std::env;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use oxideux_rs::app;
-use oxideux_rs::cli;
-use oxideux_rs::config::{self, ServerProfile};
-use oxideux_rs::connection::Connection;
+use oxideux_rs::audit;
+use oxideux_rs::cli::{self, Align, Table};
+use oxideux_rs::config::{self, ServerMode, ServerProfile, UserAccount};
+use oxideux_rs::history::{self, Side};
+use oxideux_rs::hooks::{Event, Hook};
+use oxideux_rs::logging::{self, Rotation};
 use oxideux_rs::parity;
-use oxideux_rs::request::{Request, RequestResult};
-use oxideux_rs::validated_values::ValidatedValue;
+use oxideux_rs::port_mapping;
+use oxideux_rs::s3::Bucket;
+use oxideux_rs::server::{Server, DEFAULT_PORT};
+use oxideux_rs::share;
+use oxideux_rs::signing;
+use oxideux_rs::validated_values::{ValidatedDirectory, ValidatedIPv4, ValidatedPort, ValidatedValue};
 
 use anyhow::{self, Result};
 
+/// How many entries the audit log view shows.
+const AUDIT_LOG_PAGE: usize = 30;
+/// How many transfers the history view shows.
+const HISTORY_PAGE: usize = 30;
+
 #[derive(Default)]
 struct AppData {
     profile_names: Vec<String>,
     current_profile: Option<ServerProfile>,
+    /// Index into the current profile's users, while one is being managed.
+    current_user: Option<usize>,
     notices: Vec<String>,
+    /// Profiles erased this session, the last one first to be put back by undoing.
+    erased: Vec<String>,
+    /// The tag the profile list is narrowed to, if any.
+    tag_filter: Option<String>,
+    /// The state the help screen returns to.
+    help_return: &'static str,
 }
 
 impl AppData {
@@ -24,6 +48,14 @@ impl AppData {
         self.notices.push(message.to_string());
     }
 
+    fn push_error<S: ToString>(&mut self, message: S) {
+        self.notices.push(cli::styled(message.to_string(), cli::Style::Error));
+    }
+
+    fn push_success<S: ToString>(&mut self, message: S) {
+        self.notices.push(cli::styled(message.to_string(), cli::Style::Success));
+    }
+
     fn clear_notices(&mut self) {
         self.notices.clear();
     }
@@ -40,19 +72,66 @@ impl AppData {
 }
 
 fn main() -> Result<()> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    logging::init_from_args(&mut args)?;
     config::server::init_config_file()?;
+    match args.first().map(String::as_str) {
+        Some("check-config") if args.len() == 1 => return check_config(),
+        Some(arg) => {
+            return Err(anyhow::anyhow!(format!(
+                "Unknown argument: {} (expected check-config, -q, -v, -vv or --log-file <path>)",
+                arg
+            )))
+        }
+        None => (),
+    }
 
     let app_data = AppData::default();
 
     let mut app = app::App::new(app_data);
     app.register_state("pick_profile", state_pick_profile);
     app.register_state("manage_profile", state_manage_profile);
+    app.register_state("create_profile", state_create_profile);
+    app.register_state("check_config", state_check_config);
     app.register_state("change_name", state_change_name);
+    app.register_state("change_notes", state_change_notes);
+    app.register_state("change_tags", state_change_tags);
     app.register_state("change_parity_root", state_change_parity_root);
     app.register_state("change_port", state_change_port);
     app.register_state("change_mask", state_change_mask);
+    app.register_state("change_upload_root", state_change_upload_root);
+    app.register_state("change_allowlist", state_change_allowlist);
+    app.register_state("change_denylist", state_change_denylist);
+    app.register_state("change_exclusions", state_change_exclusions);
+    app.register_state("change_connection_limit", state_change_connection_limit);
+    app.register_state("change_request_limit", state_change_request_limit);
+    app.register_state("change_first_request_timeout", state_change_first_request_timeout);
+    app.register_state("change_min_transfer_rate", state_change_min_transfer_rate);
+    app.register_state("change_max_upload_size", state_change_max_upload_size);
+    app.register_state("change_upload_quota", state_change_upload_quota);
+    app.register_state("change_metrics_port", state_change_metrics_port);
+    app.register_state("change_http_port", state_change_http_port);
+    app.register_state("change_ws_port", state_change_ws_port);
+    app.register_state("change_log_file", state_change_log_file);
+    app.register_state("change_log_max_size", state_change_log_max_size);
+    app.register_state("change_log_max_age", state_change_log_max_age);
+    app.register_state("change_log_retention", state_change_log_retention);
+    app.register_state("change_storage", state_change_storage);
+    app.register_state("manage_users", state_manage_users);
+    app.register_state("change_shared_secret", state_change_shared_secret);
+    app.register_state("signing_key", state_signing_key);
+    app.register_state("change_run_as", state_change_run_as);
+    app.register_state("add_user", state_add_user);
+    app.register_state("manage_user", state_manage_user);
+    app.register_state("manage_shares", state_manage_shares);
+    app.register_state("mint_share", state_mint_share);
+    app.register_state("view_audit_log", state_view_audit_log);
+    app.register_state("view_history", state_view_history);
+    app.register_state("manage_hooks", state_manage_hooks);
+    app.register_state("add_hook", state_add_hook);
     app.register_state("save_updated_profile", state_save_updated_profile);
     app.register_state("start_server", state_start_server);
+    app.register_state("help", state_help);
 
     app.queue_state("pick_profile");
 
@@ -71,84 +150,160 @@ fn state_pick_profile(app_data: &mut AppData, command: &mut app::Command) {
     let mut options = cli::InputOptions::new();
     
     // Headers
+    let header = match &app_data.tag_filter {
+        Some(tag) => format!("PICK A PROFILE (tagged '{}'):", tag),
+        None => "PICK A PROFILE:".to_string(),
+    };
     options
-        .set_header_dynamic("PICK A PROFILE:")
+        .set_header_dynamic(header)
         .set_header_static("__________");
 
-    // Add profile names
+    // Add profile names with their tags, the one last used standing out
+    let current = app_data.current_profile.as_ref().map(|profile| profile.name.as_str());
+    let mut shown = vec![];
     for profile_name in &app_data.profile_names {
-        options.add_dynamic(profile_name);
+        let tags = config::server::get_profile(profile_name).map(|profile| profile.tags).unwrap_or_default();
+        if app_data.tag_filter.as_ref().is_some_and(|tag| !config::has_tag(&tags, tag)) {
+            continue;
+        }
+        let label = match tags.is_empty() {
+            true => profile_name.clone(),
+            false => format!("{} [{}]", profile_name, tags.join(", ")),
+        };
+        match Some(profile_name.as_str()) == current {
+            true => options.add_dynamic(cli::styled(label, cli::Style::Highlight)),
+            false => options.add_dynamic(label),
+        };
+        shown.push(profile_name.clone());
     }
 
     // Add controls
     options 
         .add_static("a", "Create new profile")
+        .add_static("tg", "Filter by tag")
+        .add_static("chk", "Check every profile");
+    if let Some(name) = app_data.erased.last() {
+        options.add_static("u", format!("Undo erasing '{}'", name));
+    }
+    options
         .add_static("r", "Refresh profiles")
         .add_static("c", "Open config directory")
+        .add_static("h", "Help")
         .add_static("q", "Terminate program");
 
     match options.get() {
         cli::OptionType::Dynamic(index) => {
-            let profile_name = &app_data.profile_names[index];
+            let profile_name = &shown[index];
             let profile = config::server::get_profile(profile_name).unwrap();
             app_data.current_profile = Some(profile);
             command.queue_state("manage_profile");
         },
         cli::OptionType::Static(key) => match key.as_str() {
-            "a" => {
-                let count = app_data.profile_names.len();
-                let _ = config::server::create_profile(format!("profile #{}", count), "{home}/oxideux/source", 49160, "0.0.0.0");
-            },
+            "a" => command.queue_state("create_profile"),
+            "tg" => {
+                cli::out("Show only the profiles tagged (leave blank to show all):");
+                let tag = cli::input();
+                app_data.tag_filter = Some(tag.trim().to_string()).filter(|tag| !tag.is_empty());
+            }
+            "chk" => command.queue_state("check_config"),
+            "u" => {
+                let name = app_data.erased.pop().unwrap();
+                match config::server::restore_profile(&name) {
+                    Ok(_) => app_data.push_success(format!("Restored profile '{}'", name)),
+                    Err(e) => app_data.push_error(format!("Could not restore profile '{}': {}", name, e)),
+                }
+            }
             "r" => app_data.refresh_profile_names(),
-            "c" => {
-                let path = match config::config_dir_ext("oxideux") {
-                    Ok(v) => v,
-                    Err(e) => {
-                        app_data.push_notice(e);
-                        return;
-                    }
-                };
-
-                #[cfg(target_os = "linux")]
-                let command = "xdg-open";
-                #[cfg(target_os = "windows")]
-                let command = "explorer";
-
-                match Command::new(command)
-                    .arg(path)
-                    .output() {
-                        Ok(_) => (),
-                        Err(e) => {
-                            app_data.push_notice(e);
-                        },
+            "h" => {
+                app_data.help_return = "pick_profile";
+                command.queue_state("help");
+            }
+            "c" => match config::config_dir_ext("oxideux") {
+                Ok(path) => {
+                    if let Err(e) = cli::open_path(path) {
+                        app_data.push_error(format!("Could not open the config directory: {}", e));
                     }
+                }
+                Err(e) => app_data.push_error(e),
             },
             "q" => command.exit(),
             _ => unreachable!()
         },
-        cli::OptionType::Error(e) => app_data.push_notice(e)
+        cli::OptionType::Error(e) => app_data.push_error(e)
     }
 }
 
-fn state_manage_profile(app_data: &mut AppData, command: &mut app::Command) {
+fn state_create_profile(app_data: &mut AppData, command: &mut app::Command) {
     app_data.refresh_cli();
+    command.queue_state("pick_profile");
 
-    let profile = app_data.current_profile.as_ref().unwrap();
-    
-    // Error checking
-    let mut errors = vec![];
-    
-    if let Err(e) = profile.parity_root.is_valid() {
-        errors.push(format!("Parity root: {}.", e.to_string()));
+    cli::notice("Clear a line and press enter to cancel.");
+    cli::blank();
+
+    let taken = app_data.profile_names.clone();
+    let name = cli::prompt_checked("Name", &format!("profile #{}", taken.len()), |input| {
+        match taken.iter().any(|name| name == input) {
+            true => Err(anyhow::anyhow!("A profile with that name already exists")),
+            false => Ok(input.to_string()),
+        }
+    });
+    let Some(name) = name else { return };
+
+    cli::blank();
+    cli::out("The directory served to clients. A path may start with a placeholder:");
+    for (placeholder, meaning) in config::PATH_PLACEHOLDERS {
+        cli::out(format!("  {:<12}{}", placeholder, meaning));
     }
+    let Some(parity_root) = cli::prompt_checked("Parity root", "{home}/oxideux/source", check_new_root) else { return };
 
-    if let Err(e) = profile.port.is_valid() {
-        errors.push(format!("Port: {}.", e.to_string()));
+    cli::blank();
+    let port = cli::prompt_checked("Port", &DEFAULT_PORT.to_string(), |input| {
+        let port = input.parse::<u16>()?;
+        ValidatedPort::is_value_valid(&port)?;
+        Ok(port)
+    });
+    let Some(port) = port else { return };
+
+    cli::blank();
+    cli::out("The address to listen on, 0.0.0.0 for every interface.");
+    let host = cli::prompt_checked("Mask", "0.0.0.0", |input| {
+        ValidatedIPv4::is_value_valid(&input.to_string())?;
+        Ok(input.to_string())
+    });
+    let Some(host) = host else { return };
+
+    if let Err(e) = config::server::create_profile(&name, parity_root, port, host) {
+        app_data.push_error(format!("Could not create the profile: {}", e));
+        return;
     }
-    
-    if let Err(e) = profile.mask.is_valid() {
-        errors.push(format!("Mask: {}.", e.to_string()));
+    match config::server::get_profile(&name) {
+        Ok(profile) => {
+            app_data.push_success(format!("Created profile '{}'", name));
+            app_data.current_profile = Some(profile);
+            command.queue_state("manage_profile");
+        }
+        Err(e) => app_data.push_error(e),
+    }
+}
+
+/// Checks a parity root typed into the profile wizard, offering to create it when it's missing.
+/// Keeps what was typed, placeholders and all, for the config file.
+fn check_new_root(input: &str) -> Result<String> {
+    let path = config::fill_path_placeholders(input.to_string())?;
+    if !Path::new(&path).exists() && cli::confirm(format!("{} doesn't exist. Create it?", path)) {
+        fs::create_dir_all(&path)?;
     }
+    ValidatedDirectory::is_value_valid(&path)?;
+    Ok(input.to_string())
+}
+
+fn state_manage_profile(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_ref().unwrap();
+    
+    // Error checking
+    let mut errors = profile.problems();
 
     if errors.len() != 0 {
         errors.push(format!("Due to {} previous error(s), the server may not be started.", errors.len()));
@@ -156,16 +311,111 @@ fn state_manage_profile(app_data: &mut AppData, command: &mut app::Command) {
 
     // Print our errors
     for error in &errors {
-        cli::notice(error);
+        cli::error(error);
     }
-    println!();
+    cli::blank();
 
     // Display profile info
-    cli::out(format!("Profile: {}", profile.name));
+    cli::out(format!("Profile: {}", cli::styled(&profile.name, cli::Style::Highlight)));
+    cli::out(format!("Tags: {}", if profile.tags.is_empty() { "none".to_string() } else { profile.tags.join(", ") }));
+    cli::out(format!("Notes: {}", if profile.notes.is_empty() { "none" } else { profile.notes.as_str() }));
     cli::out(format!("Parity root: {}", profile.parity_root.get()));
+    cli::out(format!(
+        "Storage: {}",
+        match Bucket::parse(profile.storage.get()) {
+            Ok(bucket) => bucket.to_string(),
+            Err(_) => "parity root".to_string(),
+        }
+    ));
     cli::out(format!("Port: {}", profile.port.get()));
     cli::out(format!("Mask: {}", profile.mask.get()));
-    println!();
+    cli::out(format!("Mode: {}", profile.mode.key()));
+    cli::out(format!(
+        "Upload root: {}",
+        if profile.upload_root.is_set() { profile.upload_root.get().as_str() } else { "parity root" }
+    ));
+    cli::out(format!(
+        "Upload limits: {} per file, {} in total",
+        describe_size(*profile.max_upload_size.get()),
+        describe_size(*profile.upload_quota.get())
+    ));
+    cli::out(format!("Versioning: {}", if profile.versioning { "on" } else { "off" }));
+    cli::out(format!(
+        "Users: {}",
+        if profile.users.is_empty() { "none (anyone may connect)".to_string() } else { profile.users.len().to_string() }
+    ));
+    cli::out(format!("Shared secret: {}", if profile.shared_secret.is_empty() { "none" } else { "set" }));
+    cli::out(format!(
+        "Signing key: {}",
+        match signing::public_key(&profile.signing_key) {
+            Ok(public_key) => format!("public key {}", public_key),
+            Err(_) if profile.signing_key.is_empty() => "none".to_string(),
+            Err(_) => "invalid".to_string(),
+        }
+    ));
+    cli::out(format!(
+        "Run as: {}",
+        match (profile.run_as.is_set(), profile.chroot) {
+            (false, _) => "whoever starts the server".to_string(),
+            (true, false) => profile.run_as.get().clone(),
+            (true, true) => format!("{}, confined to the parity root", profile.run_as.get()),
+        }
+    ));
+    cli::out(format!("Watch: {}", if profile.watch { "on" } else { "off" }));
+    cli::out(format!("Advertise on LAN: {}", if profile.advertise { "on" } else { "off" }));
+    cli::out(format!("Router port mapping: {}", if profile.port_mapping { "on" } else { "off" }));
+    cli::out(format!("Allowlist: {}", if profile.allow.get().is_empty() { "anyone" } else { profile.allow.get() }));
+    cli::out(format!("Denylist: {}", if profile.deny.get().is_empty() { "none" } else { profile.deny.get() }));
+    cli::out(format!("Exclusions: {}", if profile.exclude.get().is_empty() { "none" } else { profile.exclude.get() }));
+    cli::out(format!("Hidden files: {}", if profile.hidden_files { "included" } else { "excluded" }));
+    cli::out(format!("Symlinks: {}", profile.symlinks.key()));
+    cli::out(format!(
+        "Listing order: {} ({})",
+        profile.sort_by.key(),
+        if profile.sort_descending { "descending" } else { "ascending" }
+    ));
+    cli::out(format!("Connections per IP: {}", describe_limit(*profile.max_connections_per_ip.get())));
+    cli::out(format!("Requests per minute: {}", describe_limit(*profile.max_requests_per_minute.get())));
+    cli::out(format!(
+        "First request within: {}",
+        match *profile.first_request_timeout.get() {
+            0 => "no limit".to_string(),
+            secs => humantime::format_duration(Duration::from_secs(secs)).to_string(),
+        }
+    ));
+    cli::out(format!(
+        "Minimum transfer rate: {}",
+        match *profile.min_transfer_rate.get() {
+            0 => "none".to_string(),
+            rate => format!("{} bytes/s", rate),
+        }
+    ));
+    cli::out(format!(
+        "Metrics port: {}",
+        if profile.metrics_port.is_set() { profile.metrics_port.get().to_string() } else { "off".to_string() }
+    ));
+    cli::out(format!(
+        "HTTP gateway port: {}",
+        if profile.http_port.is_set() { profile.http_port.get().to_string() } else { "off".to_string() }
+    ));
+    cli::out(format!(
+        "WebSocket port: {}",
+        if profile.ws_port.is_set() { profile.ws_port.get().to_string() } else { "off".to_string() }
+    ));
+    cli::out(format!(
+        "Log file: {}",
+        if profile.log_file.is_set() { profile.log_file.get().as_str() } else { "none" }
+    ));
+    cli::out(format!(
+        "Log rotation: at {}, every {}, keeping {}",
+        describe_size(*profile.log_max_size.get()),
+        match *profile.log_max_age.get() {
+            0 => "never".to_string(),
+            secs => humantime::format_duration(Duration::from_secs(secs)).to_string(),
+        },
+        profile.log_retention.get()
+    ));
+    cli::blank();
 
     let mut options = cli::InputOptions::new();
 
@@ -175,33 +425,161 @@ fn state_manage_profile(app_data: &mut AppData, command: &mut app::Command) {
 
     options
         .add_static("cn", "Change name")
+        .add_static("nt", "Change notes")
+        .add_static("tg", "Change tags")
         .add_static("cr", "Change parity root")
         .add_static("cp", "Change port")
         .add_static("cm", "Change mask")
-        .add_static("erase", "Erase the profile (permanently)")
+        .add_static("co", "Cycle mode (read-only, read-write, drop-box)")
+        .add_static("ci", "Change upload root")
+        .add_static("cb", "Change storage (parity root or S3 bucket)")
+        .add_static("mu", "Change max upload size")
+        .add_static("uq", "Change upload quota")
+        .add_static("ve", "Toggle versioning")
+        .add_static("us", "Manage users")
+        .add_static("sk", "Change shared secret")
+        .add_static("sg", "Manage signing key")
+        .add_static("ru", "Change user to run as")
+        .add_static("rc", "Toggle confining to the parity root")
+        .add_static("sh", "Manage share links")
+        .add_static("log", "View audit log")
+        .add_static("hist", "View transfer history")
+        .add_static("hk", "Manage hooks")
+        .add_static("cw", "Toggle watch mode")
+        .add_static("ca", "Toggle LAN advertising")
+        .add_static("cu", "Toggle router port mapping")
+        .add_static("cl", "Change allowlist")
+        .add_static("cd", "Change denylist")
+        .add_static("cx", "Change exclusions")
+        .add_static("hf", "Toggle hidden files")
+        .add_static("sl", "Cycle symlink policy (skip, follow, error)")
+        .add_static("so", "Cycle listing order (name, size, modified)")
+        .add_static("sd", "Toggle descending listing order")
+        .add_static("cc", "Change connections per IP")
+        .add_static("cq", "Change requests per minute")
+        .add_static("ft", "Change first request timeout")
+        .add_static("mr", "Change minimum transfer rate")
+        .add_static("ce", "Change metrics port")
+        .add_static("cg", "Change HTTP gateway port")
+        .add_static("cv", "Change WebSocket port")
+        .add_static("lf", "Change log file")
+        .add_static("lz", "Change log rotation size")
+        .add_static("la", "Change log rotation age")
+        .add_static("lk", "Change how many rotated logs are kept")
+        .add_static("o", "Open parity root in file manager")
+        .add_static("erase", "Erase the profile")
+        .add_static("h", "Help")
         .add_static("q", "Return");
 
     match options.get() {
         cli::OptionType::Dynamic(_) => unreachable!(),
         cli::OptionType::Static(key) => match key.as_ref() {
             "s" => command.queue_state("start_server"),
+            "h" => {
+                app_data.help_return = "manage_profile";
+                command.queue_state("help");
+            }
             "cn" => command.queue_state("change_name"),
+            "nt" => command.queue_state("change_notes"),
+            "tg" => command.queue_state("change_tags"),
             "cr" => command.queue_state("change_parity_root"),
             "cp" => command.queue_state("change_port"),
             "cm" => command.queue_state("change_mask"),
-            "erase" => match config::server::erase_profile(&profile.name) {
-                Ok(_) => {
-                    match config::server::erase_profile(&profile.name) {
-                        Ok(_) => command.queue_state("pick_profile"),
-                        Err(e) => app_data.push_notice(e),
+            "ci" => command.queue_state("change_upload_root"),
+            "cb" => command.queue_state("change_storage"),
+            "mu" => command.queue_state("change_max_upload_size"),
+            "uq" => command.queue_state("change_upload_quota"),
+            "ve" => {
+                app_data.current_profile.as_mut().unwrap().versioning ^= true;
+                command.queue_state("save_updated_profile");
+            }
+            "us" => command.queue_state("manage_users"),
+            "sk" => command.queue_state("change_shared_secret"),
+            "sg" => command.queue_state("signing_key"),
+            "ru" => command.queue_state("change_run_as"),
+            "rc" => {
+                app_data.current_profile.as_mut().unwrap().chroot ^= true;
+                command.queue_state("save_updated_profile");
+            }
+            "sh" => command.queue_state("manage_shares"),
+            "log" => command.queue_state("view_audit_log"),
+            "hist" => command.queue_state("view_history"),
+            "hk" => command.queue_state("manage_hooks"),
+            "co" => {
+                let profile = app_data.current_profile.as_mut().unwrap();
+                profile.mode = profile.mode.next();
+                command.queue_state("save_updated_profile");
+            }
+            "cw" => {
+                app_data.current_profile.as_mut().unwrap().watch ^= true;
+                command.queue_state("save_updated_profile");
+            }
+            "ca" => {
+                app_data.current_profile.as_mut().unwrap().advertise ^= true;
+                command.queue_state("save_updated_profile");
+            }
+            "cl" => command.queue_state("change_allowlist"),
+            "cd" => command.queue_state("change_denylist"),
+            "cx" => command.queue_state("change_exclusions"),
+            "hf" => {
+                app_data.current_profile.as_mut().unwrap().hidden_files ^= true;
+                command.queue_state("save_updated_profile");
+            }
+            "sl" => {
+                let profile = app_data.current_profile.as_mut().unwrap();
+                profile.symlinks = profile.symlinks.next();
+                command.queue_state("save_updated_profile");
+            }
+            "so" => {
+                let profile = app_data.current_profile.as_mut().unwrap();
+                profile.sort_by = profile.sort_by.next();
+                command.queue_state("save_updated_profile");
+            }
+            "sd" => {
+                app_data.current_profile.as_mut().unwrap().sort_descending ^= true;
+                command.queue_state("save_updated_profile");
+            }
+            "cc" => command.queue_state("change_connection_limit"),
+            "cq" => command.queue_state("change_request_limit"),
+            "ft" => command.queue_state("change_first_request_timeout"),
+            "mr" => command.queue_state("change_min_transfer_rate"),
+            "ce" => command.queue_state("change_metrics_port"),
+            "cg" => command.queue_state("change_http_port"),
+            "cv" => command.queue_state("change_ws_port"),
+            "lf" => command.queue_state("change_log_file"),
+            "lz" => command.queue_state("change_log_max_size"),
+            "la" => command.queue_state("change_log_max_age"),
+            "lk" => command.queue_state("change_log_retention"),
+            "cu" => {
+                app_data.current_profile.as_mut().unwrap().port_mapping ^= true;
+                command.queue_state("save_updated_profile");
+            }
+            "o" => {
+                if let Err(e) = cli::open_path(profile.parity_root.get()) {
+                    app_data.push_error(format!("Could not open the parity root: {}", e));
+                }
+            }
+            "erase" => {
+                cli::out(format!("Type the profile's name, '{}', to erase it:", profile.name));
+                if cli::input() != profile.name {
+                    app_data.push_notice("The name didn't match, so the profile was kept.");
+                    return;
+                }
+                let name = profile.name.clone();
+                match config::server::erase_profile(&name) {
+                    Ok(_) => {
+                        app_data.push_success(format!("Erased profile '{}', which can be undone from the profile list", name));
+                        app_data.erased.push(name);
+                        app_data.current_profile = None;
+                        command.queue_state("pick_profile");
                     }
-                },
-                Err(e) => app_data.push_notice(format!("Error erasing file: {}", e)),
+                    Err(e) => app_data.push_error(format!("Error erasing file: {}", e)),
+                }
             }
             "q" => command.queue_state("pick_profile"),
             _ => unreachable!()
         },
-        cli::OptionType::Error(e) => app_data.push_notice(e),
+        cli::OptionType::Error(e) => app_data.push_error(e),
     }
 }
 
@@ -210,14 +588,14 @@ fn state_change_name(app_data: &mut AppData, command: &mut app::Command) {
 
     let profile = app_data.current_profile.as_mut().unwrap();
 
-    cli::notice("Leave blank to cancel.");
-    println!();
+    cli::notice("Press enter to keep the current name.");
+    cli::blank();
 
     cli::out(format!("Changing: name"));
     cli::out(format!("Current: {}", profile.name));
 
-    let input = cli::input();
-    if input.len() == 0 {
+    let input = cli::input_with_default(&profile.name);
+    if input.is_empty() || input == profile.name {
         command.queue_state("manage_profile");
         return;
     }
@@ -227,25 +605,694 @@ fn state_change_name(app_data: &mut AppData, command: &mut app::Command) {
             profile.name = input;
             command.queue_state("manage_profile");
         },
-        Err(e) => app_data.push_notice(e),
+        Err(e) => app_data.push_error(e),
     }
 }
 
+fn state_manage_hooks(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_ref().unwrap();
+
+    cli::out("Commands get the event as JSON on stdin and in $OXIDEUX_PAYLOAD; http:// URLs are POSTed it.");
+    cli::blank();
+
+    let mut options = cli::InputOptions::new();
+    options
+        .set_header_dynamic("HOOKS (pick one to remove it):")
+        .set_header_static("__________");
+    for hook in &profile.hooks {
+        options.add_dynamic(format!("{}: {}", hook.event.key(), hook.action));
+    }
+    options
+        .add_static("a", "Add hook")
+        .add_static("q", "Return");
+
+    match options.get() {
+        cli::OptionType::Dynamic(index) => {
+            let hook = &profile.hooks[index];
+            if !cli::confirm(format!("Remove the hook '{}: {}'?", hook.event.key(), hook.action)) {
+                return;
+            }
+            app_data.current_profile.as_mut().unwrap().hooks.remove(index);
+            command.queue_state("save_updated_profile");
+        }
+        cli::OptionType::Static(key) => match key.as_ref() {
+            "a" => command.queue_state("add_hook"),
+            "q" => command.queue_state("manage_profile"),
+            _ => unreachable!(),
+        },
+        cli::OptionType::Error(e) => app_data.push_error(e),
+    }
+}
+
+fn state_add_hook(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("manage_hooks");
+
+    let mut options = cli::InputOptions::new();
+    options
+        .set_header_dynamic("RUN ON:")
+        .set_header_static("__________");
+    for event in Event::SERVER {
+        options.add_dynamic(event.key());
+    }
+    options.add_static("q", "Cancel");
+
+    let event = match options.get() {
+        cli::OptionType::Dynamic(index) => Event::SERVER[index],
+        cli::OptionType::Static(_) => return,
+        cli::OptionType::Error(e) => {
+            app_data.push_error(e);
+            return;
+        }
+    };
+
+    cli::notice("Leave blank to cancel.");
+    cli::blank();
+    cli::out("Shell command or http:// URL:");
+    let action = cli::input();
+    if action.is_empty() {
+        return;
+    }
+    if let Err(e) = Hook::validate_action(&action) {
+        app_data.push_error(e);
+        return;
+    }
+
+    app_data.current_profile.as_mut().unwrap().hooks.push(Hook { event, action });
+    command.queue_state("save_updated_profile");
+}
+
+fn state_manage_users(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_ref().unwrap();
+
+    cli::out("Once a user exists, clients must log in and only see that user's parity root.");
+    cli::blank();
+
+    let mut options = cli::InputOptions::new();
+    options
+        .set_header_dynamic("USERS:")
+        .set_header_static("__________");
+    for user in &profile.users {
+        options.add_dynamic(format!("{} ({}, {})", user.name, user.mode.key(), user.parity_root.get()));
+    }
+    options
+        .add_static("a", "Add user")
+        .add_static("q", "Return");
+
+    match options.get() {
+        cli::OptionType::Dynamic(index) => {
+            app_data.current_user = Some(index);
+            command.queue_state("manage_user");
+        }
+        cli::OptionType::Static(key) => match key.as_ref() {
+            "a" => command.queue_state("add_user"),
+            "q" => command.queue_state("manage_profile"),
+            _ => unreachable!(),
+        },
+        cli::OptionType::Error(e) => app_data.push_error(e),
+    }
+}
+
+fn state_signing_key(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_ref().unwrap();
+
+    cli::out("Clients pinning the public key check everything they download against the manifest it signs.");
+    cli::blank();
+    match signing::public_key(&profile.signing_key) {
+        Ok(public_key) => cli::out(format!("Public key: {}", public_key)),
+        Err(_) => cli::out("Public key: none"),
+    }
+    cli::blank();
+
+    let has_key = !profile.signing_key.is_empty();
+    let mut options = cli::InputOptions::new();
+    options.add_static("g", "Generate a new key");
+    if has_key {
+        options.add_static("r", "Remove the key");
+    }
+    options.add_static("q", "Return");
+
+    match options.get() {
+        cli::OptionType::Static(key) => match key.as_ref() {
+            "g" => {
+                if has_key && !cli::confirm("Clients pinning the current public key will refuse the server. Replace the key?") {
+                    return;
+                }
+                match signing::generate_key() {
+                    Ok(signing_key) => {
+                        app_data.current_profile.as_mut().unwrap().signing_key = signing_key;
+                        command.queue_state("save_updated_profile");
+                    }
+                    Err(e) => app_data.push_error(e),
+                }
+            }
+            "r" => {
+                app_data.current_profile.as_mut().unwrap().signing_key.clear();
+                command.queue_state("save_updated_profile");
+            }
+            "q" => command.queue_state("manage_profile"),
+            _ => unreachable!(),
+        },
+        cli::OptionType::Dynamic(_) => unreachable!(),
+        cli::OptionType::Error(e) => app_data.push_error(e),
+    }
+}
+
+fn state_add_user(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("manage_users");
+
+    cli::notice("Leave blank to cancel.");
+    cli::blank();
+
+    cli::out("User name:");
+    let name = cli::input();
+    if name.is_empty() {
+        return;
+    }
+    let profile = app_data.current_profile.as_ref().unwrap();
+    if profile.users.iter().any(|user| user.name == name) {
+        app_data.push_notice(format!("User '{}' already exists", name));
+        return;
+    }
+
+    cli::out("Password:");
+    let password = cli::input_hidden();
+    if password.is_empty() {
+        return;
+    }
+
+    cli::out(format!("Parity root (blank for {}):", profile.parity_root.get()));
+    let parity_root = match cli::input() {
+        input if input.is_empty() => profile.parity_root.get().clone(),
+        input => match config::fill_path_placeholders(input) {
+            Ok(path) => path,
+            Err(e) => {
+                app_data.push_error(e);
+                return;
+            }
+        },
+    };
+    if let Err(e) = ValidatedDirectory::is_value_valid(&parity_root) {
+        app_data.push_error(format!("Parity root: {}", e));
+        return;
+    }
+
+    let user = match UserAccount::new(name, &password, parity_root, ServerMode::ReadOnly) {
+        Ok(user) => user,
+        Err(e) => {
+            app_data.push_error(e);
+            return;
+        }
+    };
+    app_data.current_profile.as_mut().unwrap().users.push(user);
+    command.queue_state("save_updated_profile");
+}
+
+fn state_manage_user(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let index = app_data.current_user.unwrap();
+    let user = &app_data.current_profile.as_ref().unwrap().users[index];
+
+    cli::out(format!("User: {}", user.name));
+    cli::out(format!("Parity root: {}", user.parity_root.get()));
+    cli::out(format!("Mode: {}", user.mode.key()));
+    if user.needs_rehash() {
+        cli::notice("The password is hashed the old, weaker way. Reset it to rehash it.");
+    }
+    cli::out(format!(
+        "Upload limits: {} per file, {} in total",
+        if user.max_upload_size == 0 { "profile's".to_string() } else { describe_size(user.max_upload_size) },
+        if user.upload_quota == 0 { "profile's".to_string() } else { describe_size(user.upload_quota) }
+    ));
+    cli::blank();
+
+    let mut options = cli::InputOptions::new();
+    options
+        .add_static("p", "Reset password")
+        .add_static("o", "Cycle mode (read-only, read-write, drop-box)")
+        .add_static("l", "Change upload limits")
+        .add_static("erase", "Remove the user")
+        .add_static("q", "Return");
+
+    match options.get() {
+        cli::OptionType::Dynamic(_) => unreachable!(),
+        cli::OptionType::Static(key) => match key.as_ref() {
+            "p" => {
+                cli::notice("Leave blank to cancel.");
+                cli::out("New password:");
+                let password = cli::input_hidden();
+                if password.is_empty() {
+                    return;
+                }
+                let user = &mut app_data.current_profile.as_mut().unwrap().users[index];
+                let mut reset = match UserAccount::new(&user.name, &password, user.parity_root.get(), user.mode) {
+                    Ok(reset) => reset,
+                    Err(e) => {
+                        app_data.push_error(e);
+                        return;
+                    }
+                };
+                reset.max_upload_size = user.max_upload_size;
+                reset.upload_quota = user.upload_quota;
+                *user = reset;
+                command.queue_state("save_updated_profile");
+            }
+            "l" => {
+                cli::notice("Leave blank to keep the current value, 0 for the profile's.");
+                cli::out("Max upload size (e.g. 2G):");
+                let max_upload_size = cli::input();
+                cli::out("Upload quota (e.g. 50G):");
+                let upload_quota = cli::input();
+
+                let parse = |input: String| match input.is_empty() {
+                    true => Ok(None),
+                    false => parity::parse_size(&input).map(Some),
+                };
+                let (max_upload_size, upload_quota) = match (parse(max_upload_size), parse(upload_quota)) {
+                    (Ok(max_upload_size), Ok(upload_quota)) => (max_upload_size, upload_quota),
+                    (Err(e), _) | (_, Err(e)) => {
+                        app_data.push_error(e);
+                        return;
+                    }
+                };
+
+                let user = &mut app_data.current_profile.as_mut().unwrap().users[index];
+                user.max_upload_size = max_upload_size.unwrap_or(user.max_upload_size);
+                user.upload_quota = upload_quota.unwrap_or(user.upload_quota);
+                command.queue_state("save_updated_profile");
+            }
+            "o" => {
+                let user = &mut app_data.current_profile.as_mut().unwrap().users[index];
+                user.mode = user.mode.next();
+                command.queue_state("save_updated_profile");
+            }
+            "erase" => {
+                let user = &app_data.current_profile.as_ref().unwrap().users[index];
+                if !cli::confirm(format!("Remove the user '{}'?", user.name)) {
+                    return;
+                }
+                app_data.current_profile.as_mut().unwrap().users.remove(index);
+                app_data.current_user = None;
+                command.queue_state("save_updated_profile");
+            }
+            "q" => {
+                app_data.current_user = None;
+                command.queue_state("manage_users");
+            }
+            _ => unreachable!(),
+        },
+        cli::OptionType::Error(e) => app_data.push_error(e),
+    }
+}
+
+fn state_view_audit_log(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("manage_profile");
+
+    let profile = app_data.current_profile.as_ref().unwrap();
+    match audit::recent(&profile.name, AUDIT_LOG_PAGE) {
+        Ok(entries) if entries.is_empty() => cli::out("The audit log is empty."),
+        Ok(entries) => {
+            cli::out(format!("Last {} audit log entries:", entries.len()));
+            cli::sep_thin();
+            for entry in entries {
+                cli::out(entry);
+            }
+        }
+        Err(e) => cli::error(format!("Could not read the audit log: {}", e)),
+    }
+
+    cli::blank();
+    cli::out("Press enter to return.");
+    cli::input();
+}
+
+/// Checks every profile, printing what is wrong with each, and fails when any has problems.
+fn check_config() -> Result<()> {
+    let checks = config::server::check_profiles()?;
+    match print_check_report(&checks) {
+        0 => {
+            cli::out(format!("All {} profiles are valid.", checks.len()));
+            Ok(())
+        }
+        invalid => Err(anyhow::anyhow!(format!("{} of {} profiles have problems", invalid, checks.len()))),
+    }
+}
+
+/// Prints what [`config::ProfileCheck`]s found, returning how many profiles have problems.
+fn print_check_report(checks: &[config::ProfileCheck]) -> usize {
+    let mut table = Table::new();
+    table.add_column("Profile", Align::Left).add_column("Problem", Align::Left);
+    for check in checks {
+        if check.is_valid() {
+            table.add_row([check.name.as_str(), "none"]);
+        }
+        for problem in &check.problems {
+            table.add_row([check.name.as_str(), problem.as_str()]);
+        }
+    }
+    if !table.is_empty() {
+        cli::table(&table);
+    }
+    checks.iter().filter(|check| !check.is_valid()).count()
+}
+
+fn state_check_config(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("pick_profile");
+
+    match config::server::check_profiles() {
+        Ok(checks) => match print_check_report(&checks) {
+            0 => cli::success(format!("All {} profiles are valid.", checks.len())),
+            invalid => cli::error(format!("{} of {} profiles have problems.", invalid, checks.len())),
+        },
+        Err(e) => cli::error(format!("Could not read the config file: {}", e)),
+    }
+
+    cli::blank();
+    cli::out("Press enter to return.");
+    cli::input();
+}
+
+fn state_view_history(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("manage_profile");
+
+    let profile = app_data.current_profile.as_ref().unwrap();
+    match history::recent(Side::Server, Some(&profile.name), HISTORY_PAGE) {
+        Ok(records) if records.is_empty() => cli::out("No transfers recorded yet."),
+        Ok(records) => {
+            cli::out(format!("Last {} transfers, newest first:", records.len()));
+            cli::sep_thin();
+            cli::table(&history::table(&records));
+        }
+        Err(e) => cli::error(format!("Could not read the transfer history: {}", e)),
+    }
+
+    cli::blank();
+    cli::out("Press enter to return.");
+    cli::input();
+}
+
+fn state_help(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state(app_data.help_return);
+
+    cli::out("Type a key from a menu and press enter. Numbered entries pick from a list: '>' and '<' page through");
+    cli::out("long lists, '/text' shows only the entries matching text and '/' shows them all again. Up and down");
+    cli::out("recall earlier input, and ctrl-d on an empty line cancels a prompt.");
+
+    print_help_section("PROFILE LIST:", "Key", &[
+        ("a", "Create a new profile step by step: name, parity root, port and mask"),
+        ("tg", "Show only the profiles with a tag, or all of them again"),
+        ("chk", "Check every profile for invalid values and unreachable directories"),
+        ("u", "Put back the last profile erased since the program started"),
+        ("r", "Read the profile names from the config file again"),
+        ("c", "Open the config directory in the file manager"),
+        ("q", "Quit"),
+    ]);
+
+    print_help_section("PROFILE MENU:", "Key", &[
+        ("s", "Start serving (only while the profile has no errors), until the server is drained"),
+        ("us", "Add, change or remove the users clients sign in as"),
+        ("sh", "Create or revoke links that let anyone download a single file"),
+        ("log", "Show the latest audit log entries"),
+        ("hist", "Show the latest transfers"),
+        ("hk", "Add or remove commands run before or after transfers"),
+        ("o", "Open the parity root in the file manager, to see what is served"),
+        ("erase", "Delete the profile once its name is typed, until undone from the profile list"),
+        ("q", "Return to the profile list"),
+    ]);
+
+    print_help_section("WHILE SERVING:", "Key", &[
+        ("d", "Drain and stop: take no new connections, let those in the middle of a request finish"),
+        ("r", "Drain and start again with the profile as saved meanwhile, e.g. from another window. Not once the server switched users"),
+    ]);
+
+    print_help_section("PROFILE FIELDS:", "Key", &[
+        ("cn", "Name: what the profile is listed as"),
+        ("nt", "Notes: free-form text about the profile, for your own reference"),
+        ("tg", "Tags: comma-separated labels the profile list can be narrowed to"),
+        ("cr", "Parity root: the directory served to clients"),
+        ("cb", "Storage: the parity root, or an S3 bucket given as s3://key:secret@host:port/bucket"),
+        ("cp", "Port: the port clients connect to"),
+        ("cm", "Mask: the address to listen on, 0.0.0.0 for every interface"),
+        ("co", "Mode: read-only, read-write, or drop-box where files can be uploaded but not listed"),
+        ("ci", "Upload root: where uploads are stored, the parity root unless set"),
+        ("mu", "Max upload size: the largest file a client may upload, e.g. 2G"),
+        ("uq", "Upload quota: how much may be uploaded in total, e.g. 50G"),
+        ("ve", "Versioning: whether files replaced by uploads are kept in .oxideux-versions"),
+        ("cw", "Watch: whether clients are told about changes to the parity root as they happen"),
+        ("ca", "Advertise on LAN: whether clients on the local network can discover the server"),
+        ("cu", "Router port mapping: whether the router is asked to forward the port"),
+        ("sk", "Shared secret: encrypts connections, so only clients with the same secret can connect"),
+        ("sg", "Signing key: signs the manifest, so clients pinning its public key can check downloads"),
+        ("ru", "Run as: user[:group] to switch to once the port is bound, when started as root"),
+        ("rc", "Confine to parity root: chroot into the parity root when switching users"),
+        ("cl", "Allowlist: comma-separated CIDRs that may connect, anyone if empty"),
+        ("cd", "Denylist: comma-separated CIDRs that may never connect"),
+        ("cx", "Exclusions: comma-separated patterns of files never served"),
+        ("hf", "Hidden files: whether dotfiles are served"),
+        ("sl", "Symlinks: skip them, follow them, or stop with an error"),
+        ("so", "Listing order: what listings are sorted by, with sd toggling descending"),
+        ("cc", "Connections per IP: how many connections one address may hold, 0 for unlimited"),
+        ("cq", "Requests per minute: how many requests one address may make, 0 for unlimited"),
+        ("ft", "First request within: how long a client may take to send its first request"),
+        ("mr", "Minimum transfer rate: transfers slower than this for 30s are dropped, e.g. 1K"),
+        ("ce", "Metrics port: where Prometheus metrics are served, off if unset"),
+        ("cg", "HTTP gateway port: where files can be fetched with a browser or curl, off if unset"),
+        ("cv", "WebSocket port: where clients that can only open WebSockets connect, off if unset"),
+        ("lf", "Log file: a file the server log is written to as well as the terminal"),
+        ("lz", "Log rotation size: the size at which the log file is rotated, e.g. 10M"),
+        ("la", "Log rotation age: the age at which the log file is rotated, e.g. 7days"),
+        ("lk", "Rotated logs kept: how many rotated log files are kept"),
+    ]);
+
+    print_help_section("PATH PLACEHOLDERS:", "Placeholder", &config::PATH_PLACEHOLDERS);
+    cli::out("Placeholders are only replaced at the start of a path, e.g. {home}/oxideux/source.");
+    cli::out("Values in the 'defaults' section of server_config.json apply to every profile that leaves them out.");
+
+    cli::blank();
+    cli::out("Press enter to return.");
+    cli::input();
+}
+
+/// Prints a titled two-column table for the help screen, `heading` over the first column.
+fn print_help_section(title: &str, heading: &str, rows: &[(&str, &str)]) {
+    let mut table = Table::new();
+    table.add_column(heading, Align::Left).add_column("Description", Align::Left);
+    for (key, description) in rows {
+        table.add_row([*key, *description]);
+    }
+
+    cli::blank();
+    cli::out(title);
+    cli::table(&table);
+}
+
+/// The host to put in share links: the bound address, or this machine's LAN address when bound to
+/// every interface.
+fn share_host(profile: &ServerProfile) -> String {
+    let mask = profile.mask.get();
+    if mask != "0.0.0.0" {
+        return mask.clone();
+    }
+    port_mapping::local_ip_towards(Ipv4Addr::new(192, 0, 2, 1))
+        .map(|ip| ip.to_string())
+        .unwrap_or("localhost".to_string())
+}
+
+fn state_manage_shares(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_ref().unwrap();
+    let tokens = match share::list(&profile.name) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            app_data.push_error(format!("Could not read share links: {}", e));
+            command.queue_state("manage_profile");
+            return;
+        }
+    };
+
+    let host = share_host(profile);
+    for token in &tokens {
+        cli::out(format!(
+            "{} -> {} (expires: {}{})",
+            share::link(&host, *profile.port.get(), &token.token),
+            token.path,
+            token.expiry(),
+            if token.single_use { ", single use" } else { "" }
+        ));
+    }
+    cli::blank();
+
+    let mut options = cli::InputOptions::new();
+    options
+        .set_header_dynamic("PICK A LINK TO REVOKE:")
+        .set_header_static("__________");
+    for token in &tokens {
+        options.add_dynamic(&token.path);
+    }
+    options
+        .add_static("a", "Create share link")
+        .add_static("q", "Return");
+
+    match options.get() {
+        cli::OptionType::Dynamic(index) => {
+            if !cli::confirm(format!("Revoke the link to {}?", tokens[index].path)) {
+                return;
+            }
+            match share::revoke(&profile.name, &tokens[index].token) {
+                Ok(_) => app_data.push_success(format!("Revoked the link to {}", tokens[index].path)),
+                Err(e) => app_data.push_error(e),
+            }
+        }
+        cli::OptionType::Static(key) => match key.as_ref() {
+            "a" => command.queue_state("mint_share"),
+            "q" => command.queue_state("manage_profile"),
+            _ => unreachable!(),
+        },
+        cli::OptionType::Error(e) => app_data.push_error(e),
+    }
+}
+
+fn state_mint_share(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+    command.queue_state("manage_shares");
+
+    let profile = app_data.current_profile.as_ref().unwrap();
+
+    cli::notice("Leave blank to cancel.");
+    cli::blank();
+
+    cli::out("File or directory to share, relative to the parity root:");
+    let path = cli::input();
+    if path.is_empty() {
+        return;
+    }
+    let exists = match profile.storage.is_set() {
+        true => parity::open_storage(profile).map(|storage| storage.find(&path).is_ok()),
+        false => parity::safe_join(profile.parity_root.get(), &path).map(|full| full.exists()),
+    };
+    if !matches!(exists, Ok(true)) {
+        app_data.push_notice(format!("'{}' is not in the parity root", path));
+        return;
+    }
+
+    cli::out("Lifetime (e.g. 30m, 2h, 7days; blank for no expiry):");
+    let lifetime = match cli::input() {
+        input if input.is_empty() => None,
+        input => match humantime::parse_duration(&input) {
+            Ok(lifetime) => Some(lifetime),
+            Err(e) => {
+                app_data.push_error(format!("Invalid lifetime: {}", e));
+                return;
+            }
+        },
+    };
+
+    cli::out("Single use? (y/n)");
+    let single_use = cli::input() == "y";
+
+    match share::mint(&profile.name, &path, lifetime, single_use) {
+        Ok(token) => app_data.push_notice(format!(
+            "Share link: {}",
+            share::link(&share_host(profile), *profile.port.get(), &token.token)
+        )),
+        Err(e) => app_data.push_error(format!("Could not create the share link: {}", e)),
+    }
+}
+
+fn state_change_notes(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_mut().unwrap();
+
+    cli::notice("Press enter to keep the current notes.");
+    cli::blank();
+
+    cli::out("Changing: notes ('none' to clear them)");
+    let current = if profile.notes.is_empty() { "none" } else { profile.notes.as_str() };
+    cli::out(format!("Current: {}", current));
+
+    let input = cli::input_with_default(current);
+    if input.is_empty() || input == current {
+        command.queue_state("manage_profile");
+        return;
+    }
+
+    profile.notes = if input == "none" { String::new() } else { input };
+    command.queue_state("save_updated_profile");
+}
+
+fn state_change_tags(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_mut().unwrap();
+
+    cli::notice("Press enter to keep the current tags.");
+    cli::blank();
+
+    cli::out("Changing: tags (comma-separated, or 'none' to clear them)");
+    let current = if profile.tags.is_empty() { "none".to_string() } else { profile.tags.join(", ") };
+    cli::out(format!("Current: {}", current));
+
+    let input = cli::input_with_default(&current);
+    if input.is_empty() || input == current {
+        command.queue_state("manage_profile");
+        return;
+    }
+
+    profile.tags = if input == "none" { vec![] } else { config::parse_tags(&input) };
+    command.queue_state("save_updated_profile");
+}
+
+fn state_change_shared_secret(app_data: &mut AppData, command: &mut app::Command) {
+    app_data.refresh_cli();
+
+    let profile = app_data.current_profile.as_mut().unwrap();
+
+    cli::notice("Leave blank to cancel.");
+    cli::blank();
+
+    cli::out("Changing: shared secret (clients must have the same one, or 'none' to not encrypt connections)");
+    cli::out(format!("Current: {}", if profile.shared_secret.is_empty() { "none" } else { "set" }));
+
+    let secret = cli::input_hidden();
+    if secret.is_empty() {
+        command.queue_state("manage_profile");
+        return;
+    }
+
+    profile.shared_secret = if secret == "none" { String::new() } else { secret };
+    command.queue_state("save_updated_profile");
+}
+
 macro_rules! state_change_property {
     ($fn_name:ident, $name:expr, $prop:ident, $intercept:expr) => {
         fn $fn_name(app_data: &mut AppData, command: &mut app::Command) {
             app_data.refresh_cli();
 
             let profile = app_data.current_profile.as_mut().unwrap();
+            let current = profile.$prop.get().to_string();
 
-            cli::notice("Leave blank to cancel.");
-            println!();
+            cli::notice("Press enter to keep the current value.");
+            cli::blank();
 
             cli::out(format!("Changing: {}", $name));
-            cli::out(format!("Current: {}", profile.$prop.get()));
+            cli::out(format!("Current: {}", current));
 
-            let input = cli::input();
-            if input.len() == 0 {
+            let input = cli::input_with_default(&current);
+            if input.is_empty() || input == current {
                 command.queue_state("manage_profile");
                 return;
             }
@@ -253,14 +1300,14 @@ macro_rules! state_change_property {
             let parsed = match $intercept(input) {
                 Ok(v) => v,
                 Err(e) => {
-                    app_data.push_notice(e);
+                    app_data.push_error(e);
                     return;
                 }
             };
 
             match profile.$prop.safe_set(parsed) {
                 Ok(_) => command.queue_state("save_updated_profile"),
-                Err(e) => app_data.push_notice(e),
+                Err(e) => app_data.push_error(e),
             }
         }
     };
@@ -269,6 +1316,49 @@ macro_rules! state_change_property {
 state_change_property!(state_change_parity_root, "parity root", parity_root, |input| config::fill_path_placeholders(input) );
 state_change_property!(state_change_port, "port", port, |input: String| input.parse::<u16>());
 state_change_property!(state_change_mask, "mask", mask, |input| -> Result<String> { Result::Ok(input) });
+state_change_property!(state_change_upload_root, "upload root (or 'none' for the parity root)", upload_root, |input: String| {
+    config::fill_path_placeholders(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_allowlist, "allowlist (comma-separated CIDRs, or 'none')", allow, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_connection_limit, "connections per IP (0 for unlimited)", max_connections_per_ip, |input: String| input.parse::<u64>());
+state_change_property!(state_change_request_limit, "requests per minute (0 for unlimited)", max_requests_per_minute, |input: String| input.parse::<u64>());
+state_change_property!(state_change_first_request_timeout, "first request timeout in seconds (e.g. 30 or 1m, 0 for no limit)", first_request_timeout, |input: String| -> Result<u64> {
+    match input.parse::<u64>() {
+        Result::Ok(secs) => Result::Ok(secs),
+        Err(_) => Result::Ok(humantime::parse_duration(&input)?.as_secs()),
+    }
+});
+state_change_property!(state_change_min_transfer_rate, "minimum transfer rate in bytes/s (e.g. 1K, 0 for none)", min_transfer_rate, |input: String| parity::parse_size(&input));
+state_change_property!(state_change_max_upload_size, "max upload size (e.g. 2G, 0 for unlimited)", max_upload_size, |input: String| parity::parse_size(&input));
+state_change_property!(state_change_upload_quota, "upload quota (e.g. 50G, 0 for unlimited)", upload_quota, |input: String| parity::parse_size(&input));
+state_change_property!(state_change_metrics_port, "metrics port (0 to turn metrics off)", metrics_port, |input: String| input.parse::<u16>());
+state_change_property!(state_change_http_port, "HTTP gateway port (0 to turn the gateway off)", http_port, |input: String| input.parse::<u16>());
+state_change_property!(state_change_ws_port, "WebSocket port (0 to turn WebSockets off)", ws_port, |input: String| input.parse::<u16>());
+state_change_property!(state_change_log_file, "log file (or 'none' to only log to the terminal)", log_file, |input: String| {
+    config::fill_path_placeholders(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_log_max_size, "log rotation size (e.g. 10M, 0 for no limit)", log_max_size, |input: String| parity::parse_size(&input));
+state_change_property!(state_change_log_max_age, "log rotation age (e.g. 1day, 7days, 0 for no limit)", log_max_age, |input: String| -> Result<u64> {
+    match input.as_str() {
+        "0" => Result::Ok(0),
+        input => Result::Ok(humantime::parse_duration(input)?.as_secs()),
+    }
+});
+state_change_property!(state_change_log_retention, "rotated logs kept", log_retention, |input: String| input.parse::<u64>());
+state_change_property!(state_change_run_as, "user to run as (user or user:group, or 'none')", run_as, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_storage, "storage (s3://key:secret@host:port/bucket, or 'none' for the parity root)", storage, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_denylist, "denylist (comma-separated CIDRs, or 'none')", deny, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input })
+});
+state_change_property!(state_change_exclusions, "exclusions (comma-separated patterns, or 'none')", exclude, |input: String| -> Result<String> {
+    Result::Ok(if input == "none" { String::new() } else { input })
+});
 
 fn state_save_updated_profile(app_data: &mut AppData, command: &mut app::Command) {
     app_data.refresh_cli();
@@ -277,7 +1367,7 @@ fn state_save_updated_profile(app_data: &mut AppData, command: &mut app::Command
 
     cli::out(format!("Changes have been made to the following profile: {}", profile.name));
     cli::out("Would you like to save these changes?");
-    println!();
+    cli::blank();
 
     let mut options = cli::InputOptions::new();
     options
@@ -289,112 +1379,100 @@ fn state_save_updated_profile(app_data: &mut AppData, command: &mut app::Command
         cli::OptionType::Static(key) => match key.as_ref() {
             "y" => {
                 if let Err(e) = config::server::save_profile(profile) {
-                    app_data.push_notice(format!("Error saving profile: {}", e));
+                    app_data.push_error(format!("Error saving profile: {}", e));
                 } else {
-                    app_data.push_notice("Profile successfully saved.");
+                    app_data.push_success("Profile successfully saved.");
                 }
                 command.queue_state("manage_profile");
             }
             "n" => command.queue_state("manage_profile"),
             _ => unreachable!()
         },
-        cli::OptionType::Error(e) => app_data.push_notice(e),
+        cli::OptionType::Error(e) => app_data.push_error(e),
     }
 }
 
 fn state_start_server(app_data: &mut AppData, command: &mut app::Command) {
     let profile = app_data.current_profile.as_ref().unwrap();
-    let result = server(profile);
-    app_data.push_notice(match result {
-        Ok(_) => "Server terminated (OK)".to_string(),
-        Err(e) => format!("Server terminated (ERROR): {}", e),
-    });
-    command.queue_state("manage_profile");
-}
-
-fn server(profile: &ServerProfile) -> Result<()> {
-    let addr = format!("{}:{}", profile.mask.get(), profile.port.get());
-    let listener = TcpListener::bind(&addr)?;
-
-    println!(
-        "Listening for connections on {}\nParity root: {}",
-        addr,
-        profile.parity_root.get()
-    );
-
-    for connection in listener.incoming() {
-        match connection {
-            Ok(stream) => {
-                println!("Connection established: {:?}", stream.peer_addr());
-                let result = handle_client(profile.clone(), &mut Connection(stream));
-                println!("Connection terminated: {:?}", result);
-            }
-            Err(error) => {
-                println!("Connection error: {}", error);
-            }
+    if profile.log_file.is_set() {
+        let rotation = Rotation {
+            max_size: *profile.log_max_size.get(),
+            max_age: Some(Duration::from_secs(*profile.log_max_age.get())).filter(|age| !age.is_zero()),
+            keep: *profile.log_retention.get(),
+        };
+        if let Err(e) = logging::log_to_file(Path::new(profile.log_file.get()), rotation) {
+            app_data.push_error(e);
+            return command.queue_state("manage_profile");
         }
     }
-
-    Ok(())
-}
-
-fn handle_client(profile: ServerProfile, conn: &mut Connection) -> Result<()> {
-    let request = conn.read_request()?;
-
-    match request {
-        Request::Disconnect => {
-            conn.shutdown(Shutdown::Both)?;
-        }
-        Request::GetFileCount => {
-            let entries = parity::get_file_entries(PathBuf::from(profile.parity_root.get()))?;
-            conn.send_request_result(RequestResult::Ok)?;
-            conn.send_u32(entries.len() as u32)?;
+    let server = match Server::new(profile.clone()) {
+        Ok(server) => server,
+        Err(e) => {
+            app_data.push_error(format!("Server terminated (ERROR): {}", e));
+            return command.queue_state("manage_profile");
         }
-        Request::DownloadFileByIndex(index) => {
-            let entries = parity::get_file_entries(PathBuf::from(profile.parity_root.get()))?;
+    };
 
-            // // Index out of bounds
-            if index as usize >= entries.len() {
-                conn.send_request_result(RequestResult::ErrIndexOutOfBounds)?
-                    .naturalize()?;
+    // Draining lets the transfers in progress finish, before stopping or starting again. A server
+    // that switched users can no longer bind its port or read its config, so it is only stopped
+    let restartable = !profile.run_as.is_set();
+    let reload = Arc::new(AtomicBool::new(false));
+    let keys = cli::listen_keys({
+        let (drain, reload) = (server.drain(), Arc::clone(&reload));
+        move |key| match key {
+            'd' | 'r' if !drain.is_draining() && (key == 'd' || restartable) => {
+                reload.store(key == 'r', Ordering::Relaxed);
+                drain.start();
+                cli::blank();
+                cli::notice(format!(
+                    "Draining: waiting for {} connection(s) to finish, then {}",
+                    drain.open_connections(),
+                    if key == 'r' { "starting again with the saved profile" } else { "stopping" }
+                ));
             }
-
-            let entry = &entries[index as usize];
-            conn.send_request_result(RequestResult::Ok)?;
-            conn.send_string(&entry.name)?;
-            conn.send_file(entry)?;
+            _ => (),
         }
-        Request::DownloadFileByName(name) => {
-            let parity_root = PathBuf::from(profile.parity_root.get());
-
-            let mut file_path = parity_root.clone();
-            file_path.push(name);
-            file_path.canonicalize()?;
+    });
+    match (keys.is_listening(), restartable) {
+        (true, true) => cli::notice("d drains and stops the server, r drains it and starts it again with the saved profile"),
+        (true, false) => cli::notice(format!(
+            "d drains and stops the server. It can't be started again with r, as it runs as {} once the port is bound",
+            profile.run_as.get()
+        )),
+        (false, _) => (),
+    }
+    let result = server.serve();
+    drop(keys);
 
-            // Unauthorized file access
-            if !file_path.starts_with(parity_root) {
-                conn.send_request_result(RequestResult::ErrUnauthorizedAccess)?
-                    .naturalize()?;
+    match result {
+        Ok(_) if reload.load(Ordering::Relaxed) => match config::server::get_profile(&profile.name) {
+            Ok(profile) => {
+                let problems = profile.problems();
+                app_data.current_profile = Some(profile);
+                if problems.is_empty() {
+                    app_data.push_notice("Server drained, starting again with the saved profile");
+                    return command.queue_state("start_server");
+                }
+                app_data.push_error("Server drained, but the saved profile has errors to fix before starting again");
             }
+            Err(e) => app_data.push_error(format!("Server drained, but the profile could not be read again: {}", e)),
+        },
+        Ok(_) => app_data.push_notice("Server drained and stopped (OK)"),
+        Err(e) => app_data.push_error(format!("Server terminated (ERROR): {}", e)),
+    }
+    command.queue_state("manage_profile");
+}
 
-            let entry = parity::get_file_entry(file_path)?;
-            conn.send_request_result(RequestResult::Ok)?;
-            conn.send_file(&entry)?;
-        }
-        Request::DownloadAllFiles => {
-            let entries = parity::get_file_entries(PathBuf::from(profile.parity_root.get()))?;
-            conn.send_request_result(RequestResult::Ok)?;
-
-            let count = entries.len();
-            conn.send_u32(count as u32)?;
-
-            for entry in entries {
-                conn.send_string(&entry.name)?;
-                conn.send_file(&entry)?;
-                conn.read_request_result()?;
-            }
-        }
+fn describe_size(limit: u64) -> String {
+    match limit {
+        0 => "unlimited".to_string(),
+        limit => format!("{} bytes", limit),
     }
+}
 
-    Ok(())
+fn describe_limit(limit: u64) -> String {
+    match limit {
+        0 => "unlimited".to_string(),
+        limit => limit.to_string(),
+    }
 }
@@ -0,0 +1,204 @@
+//! Content-defined chunking, so a file that is mostly the same as one the client already has only
+//! costs the chunks that differ.
+//!
+//! Files are cut with the 2020 version of FastCDC (Xia et al.), from the `fastcdc` crate:
+//! boundaries depend on the content around them, so inserting or removing bytes only changes the
+//! chunks nearby, and the same data cuts into the same chunks wherever it appears. Each chunk is
+//! identified by its SHA-256.
+//!
+//! The server answers a file's [`Chunk`] list (see: [`crate::request::Request::GetChunks`]) and
+//! the chunks asked for by hash. The client keeps a [`ChunkIndex`] of where each chunk can be
+//! found in the files it already has, and only asks for the rest.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use fastcdc::v2020::StreamCDC;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config;
+use crate::parity::{self, Entry, Storage};
+
+const DATABASE_FILE: &str = "oxideux/chunks.sqlite3";
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+pub const AVERAGE_CHUNK_SIZE: usize = 64 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+/// Files smaller than this are cheaper to send whole than to chunk.
+pub const CHUNKED_THRESHOLD: u64 = 4 * 1024 * 1024;
+/// How many manifests the server keeps before starting over.
+const CACHED_MANIFESTS: usize = 1024;
+
+/// A piece of a file, as listed in answer to [`crate::request::Request::GetChunks`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// Lowercase hex SHA-256 of the contents.
+    pub hash: String,
+    pub offset: u64,
+    pub length: u32,
+}
+
+/// Cuts everything `reader` yields into chunks.
+pub fn chunk<R: Read>(reader: R) -> Result<Vec<Chunk>> {
+    let cutter = StreamCDC::new(reader, MIN_CHUNK_SIZE as u32, AVERAGE_CHUNK_SIZE as u32, MAX_CHUNK_SIZE as u32);
+    let mut chunks = vec![];
+    for data in cutter {
+        let data = data?;
+        chunks.push(Chunk {
+            hash: hash(&data.data),
+            offset: data.offset,
+            length: data.length as u32,
+        });
+    }
+    Ok(chunks)
+}
+
+/// Lowercase hex SHA-256 of `data`.
+pub fn hash(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The chunk lists of the files a server was asked about, so large files aren't read again until
+/// they change.
+#[derive(Default)]
+pub struct ManifestCache {
+    manifests: Mutex<HashMap<PathBuf, Manifest>>,
+}
+
+/// The chunks of a file as it was when it was cut.
+struct Manifest {
+    length: u64,
+    modified: Option<u64>,
+    chunks: Arc<Vec<Chunk>>,
+}
+
+impl ManifestCache {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// The chunks of `entry`, cut on first use or when its length or modification time changed.
+    pub fn manifest(&self, storage: &dyn Storage, entry: &Entry) -> Result<Arc<Vec<Chunk>>> {
+        if let Some(manifest) = self.manifests.lock().unwrap().get(&entry.path) {
+            if manifest.length == entry.length && manifest.modified == entry.modified {
+                return Ok(Arc::clone(&manifest.chunks));
+            }
+        }
+
+        // Cut without holding the lock, as large files take a while
+        let chunks = Arc::new(chunk(storage.open(entry, 0)?)?);
+        let mut manifests = self.manifests.lock().unwrap();
+        if manifests.len() >= CACHED_MANIFESTS {
+            manifests.clear();
+        }
+        manifests.insert(entry.path.clone(), Manifest {
+            length: entry.length,
+            modified: entry.modified,
+            chunks: Arc::clone(&chunks),
+        });
+        Ok(chunks)
+    }
+}
+
+/// Where the chunks of local files are, in an SQLite database at `oxideux/chunks.sqlite3` under the
+/// config directory. Files are only cut again when their length or modification time changed, and
+/// chunks are checked against their hash when read, so a stale index never yields wrong data.
+pub struct ChunkIndex {
+    db: Connection,
+}
+
+impl ChunkIndex {
+    pub fn open() -> Result<Self> {
+        let path = config::config_dir_ext(DATABASE_FILE)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let db = Connection::open(path)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS files (
+                 path TEXT PRIMARY KEY,
+                 length INTEGER NOT NULL,
+                 modified INTEGER
+             );
+             CREATE TABLE IF NOT EXISTS chunks (
+                 hash TEXT NOT NULL,
+                 path TEXT NOT NULL,
+                 offset INTEGER NOT NULL,
+                 length INTEGER NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS chunks_by_hash ON chunks (hash);
+             CREATE INDEX IF NOT EXISTS chunks_by_path ON chunks (path);",
+        )?;
+        Ok(Self { db })
+    }
+
+    /// Records the chunks of the file at `path`, unless they already are.
+    pub fn index_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref().canonicalize()?;
+        let key = path.to_string_lossy().to_string();
+        let metadata = fs::metadata(&path)?;
+        let length = metadata.len() as i64;
+        let modified = parity::modified_secs(&metadata).map(|secs| secs as i64);
+
+        let indexed = self
+            .db
+            .query_row("SELECT length, modified FROM files WHERE path = ?1", params![key], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, Option<i64>>(1)?))
+            })
+            .ok();
+        if indexed == Some((length, modified)) {
+            return Ok(());
+        }
+
+        let chunks = chunk(File::open(&path)?)?;
+        let transaction = self.db.transaction()?;
+        transaction.execute("DELETE FROM chunks WHERE path = ?1", params![key])?;
+        for chunk in &chunks {
+            transaction.execute(
+                "INSERT INTO chunks (hash, path, offset, length) VALUES (?1, ?2, ?3, ?4)",
+                params![chunk.hash, key, chunk.offset as i64, chunk.length],
+            )?;
+        }
+        transaction.execute(
+            "INSERT OR REPLACE INTO files (path, length, modified) VALUES (?1, ?2, ?3)",
+            params![key, length, modified],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Whether a chunk with `hash` is indexed, without checking that it is still there.
+    pub fn contains(&self, hash: &str) -> Result<bool> {
+        let count: i64 = self
+            .db
+            .query_row("SELECT COUNT(*) FROM chunks WHERE hash = ?1", params![hash], |row| row.get(0))?;
+        Ok(count > 0)
+    }
+
+    /// The contents of a chunk with `hash` from any local file that still has it.
+    pub fn read(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        let mut statement = self.db.prepare("SELECT path, offset, length FROM chunks WHERE hash = ?1")?;
+        let locations = statement
+            .query_map(params![hash], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64, row.get::<_, i64>(2)? as usize))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for (path, offset, length) in locations {
+            let mut data = vec![0u8; length];
+            let read = File::open(&path).and_then(|mut file| {
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut data)
+            });
+            if read.is_ok() && self::hash(&data) == hash {
+                return Ok(Some(data));
+            }
+        }
+        Ok(None)
+    }
+}
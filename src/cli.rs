@@ -2,25 +2,147 @@
 //! 
 //!  This module is for standardizing actions related to the command-line interface.
 
-use std::io::{self, Write};
+use std::env;
+use std::io::{self, IsTerminal, Write};
 use std::fmt::Display;
+use std::path::Path;
+use std::process::{self, Command};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use crossterm::cursor::MoveTo;
+use crossterm::execute;
+use crossterm::terminal::{Clear, ClearType};
 use indexmap::IndexMap;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+/// Printed where an answer is expected.
+const PROMPT: &str = ">> ";
+/// Drawn in turn by a [`spinner`].
+const SPINNER_FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+/// How often a [`KeyListener`] checks whether it was dropped while no key is pressed.
+const KEY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How many dynamic options [`InputOptions`] shows at a time.
+const PAGE_SIZE: usize = 20;
+/// Keys turning the pages of dynamic options, which no static option uses.
+const NEXT_PAGE: &str = ">";
+const PREVIOUS_PAGE: &str = "<";
+/// Starts a filter on the dynamic options, such as `/report`.
+const FILTER: &str = "/";
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+/// Edits the lines typed on a terminal, keeping those typed so far for the up and down arrows to
+/// bring back. Made on the first prompt.
+static EDITOR: Mutex<Option<DefaultEditor>> = Mutex::new(None);
+/// Set while a [`KeyListener`] reads keys from stdin.
+static KEYS_LISTENING: AtomicBool = AtomicBool::new(false);
+/// Set while a prompt reads stdin instead, which a [`KeyListener`] then leaves alone.
+static KEYS_HELD: AtomicBool = AtomicBool::new(false);
+
+/// Switches to JSON output, for other programs to read: results are printed to stdout one JSON
+/// object per line with [`json_line`], everything else goes to stderr, and [`input`] no longer
+/// waits for an answer.
+pub fn set_json_output(enabled: bool) {
+    JSON_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Prints `value` on a line of its own, in JSON output mode only.
+pub fn json_line(value: json::JsonValue) {
+    if json_output() {
+        println!("{}", value.dump());
+    }
+}
+
+/// What a piece of output is, which picks its color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Error,
+    Notice,
+    Success,
+    /// Stands out from what is around it, such as the profile in use.
+    Highlight,
+}
+
+impl Style {
+    fn ansi_code(&self) -> &'static str {
+        match self {
+            Style::Error => "31",
+            Style::Notice => "33",
+            Style::Success => "32",
+            Style::Highlight => "1;36",
+        }
+    }
+}
+
+/// Whether lines for people go to a terminal that understands ANSI escape sequences, which a
+/// `dumb` one doesn't.
+fn ansi_terminal() -> bool {
+    static STDOUT: OnceLock<bool> = OnceLock::new();
+    static STDERR: OnceLock<bool> = OnceLock::new();
+    let dumb = || env::var("TERM").is_ok_and(|term| term == "dumb");
+    match json_output() {
+        true => *STDERR.get_or_init(|| io::stderr().is_terminal() && !dumb()),
+        false => *STDOUT.get_or_init(|| io::stdout().is_terminal() && !dumb()),
+    }
+}
+
+/// Whether lines for people are printed in color: only to a terminal, and not when `NO_COLOR` is
+/// set (see: <https://no-color.org>).
+pub fn colors() -> bool {
+    ansi_terminal() && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+/// `what` in the color of `style`, or as is when [`colors`] are off.
+pub fn styled<O: Display>(what: O, style: Style) -> String {
+    match colors() {
+        true => format!("\x1b[{}m{}\x1b[0m", style.ansi_code(), what),
+        false => what.to_string(),
+    }
+}
+
+/// Prints a line for people, kept out of stdout in JSON output mode.
+fn line<O: Display>(what: O) {
+    match json_output() {
+        true => eprintln!("{}", what),
+        false => println!("{}", what),
+    }
+}
 
 pub fn sep_low() {
-    println!("__________");
+    line("__________");
 }
 
 pub fn sep_thin() {
-    println!("----------");
+    line("----------");
 }
 
 pub fn sep_thick() {
-    println!("==========");
+    line("==========");
+}
+
+pub fn blank() {
+    line("");
 }
 
 pub fn notice<O: Display>(what: O) {
-    println!("<(!)> {}", what);
+    line(styled(format!("<(!)> {}", what), Style::Notice));
+}
+
+pub fn error<O: Display>(what: O) {
+    line(styled(format!("<(!)> {}", what), Style::Error));
+}
+
+pub fn success<O: Display>(what: O) {
+    line(styled(format!("<(!)> {}", what), Style::Success));
 }
 
 pub fn notice_if_some<O: Display>(what: &Option<O>) {
@@ -30,15 +152,15 @@ pub fn notice_if_some<O: Display>(what: &Option<O>) {
 }
 
 pub fn notice_all<O: Display>(what: &Vec<O>) {
-    println!();
+    blank();
     for value in what {
         notice(value);
     }
-    println!();
+    blank();
 }
 
 pub fn out<O: Display>(what: O) {
-    println!("{}", what);
+    line(what);
 }
 
 pub fn out_if_some<O: Display>(what: &Option<O>) {
@@ -47,14 +169,213 @@ pub fn out_if_some<O: Display>(what: &Option<O>) {
     }
 }
 
+/// Clears the screen and moves the cursor to its top, so what is printed next (notices first)
+/// starts there. Where that can't be done, such as on a `dumb` terminal or into a file, blank
+/// lines set the new screen apart instead.
 pub fn clear() {
-    for _ in 0..20 {
-        println!();
+    if !ansi_terminal() {
+        for _ in 0..20 {
+            blank();
+        }
+        return;
+    }
+    match json_output() {
+        true => execute!(io::stderr(), MoveTo(0, 0), Clear(ClearType::All)),
+        false => execute!(io::stdout(), MoveTo(0, 0), Clear(ClearType::All)),
     }
+    .expect("Could not clear the screen");
 }
 
+/// Writes `what` where lines for people go, as is: no newline is added.
+fn write_raw(what: &str) {
+    match json_output() {
+        true => eprint!("{}", what),
+        false => {
+            print!("{}", what);
+            io::stdout().flush().expect("Could not flush stdout");
+        }
+    }
+}
+
+/// Shows `what` with a spinner and the seconds gone by next to it until the returned [`Spinner`]
+/// is dropped, so that something taking a while doesn't look stuck. Only on a terminal, where the
+/// line is cleared again afterwards; elsewhere nothing is shown.
+pub fn spinner<O: Display>(what: O) -> Spinner {
+    if !ansi_terminal() {
+        return Spinner { stop: Arc::new(AtomicBool::new(true)), thread: None };
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let what = what.to_string();
+    let thread = thread::spawn({
+        let stop = Arc::clone(&stop);
+        move || {
+            let started = Instant::now();
+            for frame in SPINNER_FRAMES.iter().cycle() {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                write_raw(&format!("\r\x1b[K{} {} {}s", frame, what, started.elapsed().as_secs()));
+                thread::sleep(SPINNER_INTERVAL);
+            }
+            write_raw("\r\x1b[K");
+        }
+    });
+    Spinner { stop, thread: Some(thread) }
+}
+
+/// Keeps a [`spinner`] turning until dropped.
+#[derive(Debug)]
+pub struct Spinner {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Calls `on_key` with every key pressed until the returned [`KeyListener`] is dropped, as it's
+/// pressed rather than once a line is typed, such as to steer what is being done meanwhile. Prompts
+/// shown in the meantime read their answers as usual. Only on a terminal, and not in JSON output
+/// mode; elsewhere nothing is listened to.
+#[cfg(unix)]
+pub fn listen_keys<F: FnMut(char) + Send + 'static>(mut on_key: F) -> KeyListener {
+    let stop = Arc::new(AtomicBool::new(false));
+    if json_output() || !io::stdin().is_terminal() {
+        return KeyListener { stop, thread: None, terminal: None };
+    }
+    let terminal = change_terminal(|termios| {
+        // Signals are left on, so Ctrl-C still stops the program
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+    });
+    if !terminal.is_changed() {
+        return KeyListener { stop, thread: None, terminal: None };
+    }
+
+    KEYS_LISTENING.store(true, Ordering::Relaxed);
+    let thread = thread::spawn({
+        let stop = Arc::clone(&stop);
+        move || {
+            while !stop.load(Ordering::Relaxed) {
+                if KEYS_HELD.load(Ordering::Relaxed) {
+                    thread::sleep(KEY_POLL_INTERVAL);
+                    continue;
+                }
+                if !stdin_ready(KEY_POLL_INTERVAL) || KEYS_HELD.load(Ordering::Relaxed) {
+                    continue;
+                }
+                // Read straight from the descriptor, as the buffer of io::stdin would keep what
+                // comes after the key from prompts
+                let mut byte = [0u8];
+                // SAFETY: at most one byte is read into a local buffer of one byte
+                match unsafe { libc::read(libc::STDIN_FILENO, byte.as_mut_ptr().cast(), 1) } {
+                    1 if byte[0].is_ascii() => on_key(byte[0] as char),
+                    1 => (),
+                    _ => break,
+                }
+            }
+        }
+    });
+    KeyListener { stop, thread: Some(thread), terminal: Some(terminal) }
+}
+
+/// Without a portable way to read keys as they're pressed, none are listened to.
+#[cfg(not(unix))]
+pub fn listen_keys<F: FnMut(char) + Send + 'static>(_on_key: F) -> KeyListener {
+    KeyListener { stop: Arc::new(AtomicBool::new(true)), thread: None }
+}
+
+/// Whether stdin has something to read within `timeout`.
+#[cfg(unix)]
+fn stdin_ready(timeout: Duration) -> bool {
+    let mut poll = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+    // SAFETY: the pointer is to a single local pollfd
+    unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) > 0 }
+}
+
+/// Keeps [`listen_keys`] listening until dropped, when the terminal is put back as it was.
+pub struct KeyListener {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    #[cfg(unix)]
+    terminal: Option<SavedTerminal>,
+}
+
+impl KeyListener {
+    /// Whether keys are listened to at all, which they aren't when stdin isn't a terminal.
+    pub fn is_listening(&self) -> bool {
+        self.thread.is_some()
+    }
+}
+
+impl Drop for KeyListener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+            KEYS_LISTENING.store(false, Ordering::Relaxed);
+        }
+        #[cfg(unix)]
+        drop(self.terminal.take());
+    }
+}
+
+/// Takes stdin back from a [`KeyListener`] for a prompt, with the terminal as it was before, until
+/// dropped. `None` when no keys are listened to, or stdin was taken back already.
+#[cfg(unix)]
+fn hold_keys() -> Option<KeysHeld> {
+    if !KEYS_LISTENING.load(Ordering::Relaxed) || KEYS_HELD.swap(true, Ordering::Relaxed) {
+        return None;
+    }
+    let terminal = change_terminal(|termios| termios.c_lflag |= libc::ICANON | libc::ECHO);
+    Some(KeysHeld { terminal: Some(terminal) })
+}
+
+#[cfg(not(unix))]
+fn hold_keys() {}
+
+#[cfg(unix)]
+struct KeysHeld {
+    terminal: Option<SavedTerminal>,
+}
+
+#[cfg(unix)]
+impl Drop for KeysHeld {
+    fn drop(&mut self) {
+        // The listener's settings are back before it reads again
+        drop(self.terminal.take());
+        KEYS_HELD.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Prints `table` with its columns aligned, see: [`Table`].
+pub fn table(table: &Table) {
+    for row in table.render() {
+        line(row);
+    }
+}
+
+/// Reads a line from stdin, or returns an empty one in JSON output mode where nobody is there to
+/// answer.
+///
+/// On a terminal the line can be edited as it's typed, and the up and down arrows bring back lines
+/// typed earlier on.
 pub fn input() -> String {
-    print!(">> ");
+    if json_output() {
+        return String::new();
+    }
+    let _held = hold_keys();
+    if let Some(line) = edit_line("") {
+        return line;
+    }
+    print!("{}", PROMPT);
     io::stdout().flush().expect("Could not flush stdout");
 
     let mut input = String::new();
@@ -63,6 +384,203 @@ pub fn input() -> String {
     input.trim().to_string()
 }
 
+/// Reads a line from stdin like [`input`], without showing what's typed, so passwords don't end up
+/// on screen or in the terminal's scrollback.
+pub fn input_hidden() -> String {
+    if json_output() {
+        return String::new();
+    }
+    let _held = hold_keys();
+    print!("{}", PROMPT);
+    io::stdout().flush().expect("Could not flush stdout");
+
+    let mut input = String::new();
+    let read = {
+        let _echo = hide_echo();
+        io::stdin().read_line(&mut input)
+    };
+    read.expect("Could not read from stdin");
+
+    input.trim().to_string()
+}
+
+/// Reads a line from stdin that starts out as `default`, to be edited in place rather than typed
+/// out again: pressing enter right away keeps it, and Ctrl-D gives an empty line. Where the line
+/// can't be edited, such as when stdin isn't a terminal, an empty line keeps `default` instead.
+pub fn input_with_default(default: &str) -> String {
+    if json_output() {
+        return default.to_string();
+    }
+    let _held = hold_keys();
+    if let Some(line) = edit_line(default) {
+        return line;
+    }
+    match input() {
+        input if input.is_empty() => default.to_string(),
+        input => input,
+    }
+}
+
+/// The terminal's settings from before they were changed, put back when dropped.
+#[cfg(unix)]
+struct SavedTerminal {
+    /// `None` when nothing was changed, such as when stdin isn't a terminal.
+    saved: Option<libc::termios>,
+}
+
+#[cfg(unix)]
+impl SavedTerminal {
+    fn is_changed(&self) -> bool {
+        self.saved.is_some()
+    }
+}
+
+/// Changes the settings of the terminal stdin is, until what this returns is dropped.
+#[cfg(unix)]
+fn change_terminal<F: FnOnce(&mut libc::termios)>(change: F) -> SavedTerminal {
+    // SAFETY: termios is plain data that tcgetattr fills in, and both pointers are to locals
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+            return SavedTerminal { saved: None };
+        }
+        let saved = termios;
+        change(&mut termios);
+        match libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) {
+            0 => SavedTerminal { saved: Some(saved) },
+            _ => SavedTerminal { saved: None },
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SavedTerminal {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            // SAFETY: the pointer is to settings tcgetattr filled in
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved);
+            }
+        }
+    }
+}
+
+/// Keeps the terminal from echoing what's typed into stdin, until dropped.
+#[cfg(unix)]
+fn hide_echo() -> SavedTerminal {
+    change_terminal(|termios| {
+        // The newline is still echoed, so what comes next starts on a line of its own
+        termios.c_lflag &= !libc::ECHO;
+        termios.c_lflag |= libc::ECHONL;
+    })
+}
+
+#[cfg(not(unix))]
+struct SavedTerminal;
+
+/// Without a portable way to turn echoing off, what's typed is shown.
+#[cfg(not(unix))]
+fn hide_echo() -> SavedTerminal {
+    SavedTerminal
+}
+
+/// Lets `default` be edited in place, or returns `None` where that can't be done. What's typed is
+/// added to the history.
+fn edit_line(default: &str) -> Option<String> {
+    if !ansi_terminal() || !io::stdin().is_terminal() {
+        return None;
+    }
+    let mut editor = EDITOR.lock().unwrap();
+    if editor.is_none() {
+        *editor = DefaultEditor::new().ok();
+    }
+    let editor = editor.as_mut()?;
+
+    let line = match editor.readline_with_initial(PROMPT, (default, "")) {
+        Ok(line) => line.trim().to_string(),
+        Err(ReadlineError::Eof) => String::new(),
+        // Signals are off while editing; Ctrl-C still stops the program as it always has
+        Err(ReadlineError::Interrupted) => process::exit(130),
+        Err(_) => return None,
+    };
+    if !line.is_empty() {
+        let _ = editor.add_history_entry(line.as_str());
+    }
+    Some(line)
+}
+
+/// Asks `question` until it's answered yes or no, an empty answer being no, so nothing is done
+/// by pressing enter once too often.
+pub fn confirm<O: Display>(question: O) -> bool {
+    loop {
+        out(format!("{} [y/N]", question));
+        match input().to_lowercase().as_str() {
+            "y" | "yes" => return true,
+            "" | "n" | "no" => return false,
+            answer => error(format!("'{}' is neither yes nor no.", answer)),
+        }
+    }
+}
+
+/// Asks for `label` until what's typed parses as a `T`. Typing nothing cancels, giving `None`.
+pub fn prompt_parse<T: FromStr>(label: &str) -> Option<T>
+where
+    T::Err: Display,
+{
+    loop {
+        out(format!("{}:", label));
+        let answer = input();
+        if answer.is_empty() {
+            return None;
+        }
+        match answer.parse::<T>() {
+            Ok(value) => return Some(value),
+            Err(e) => error(format!("'{}' is not a valid {}: {}", answer, label.to_lowercase(), e)),
+        }
+    }
+}
+
+/// Asks for `label`, offering `default`, until `check` accepts what's typed, giving what it made of
+/// it. Clearing the line cancels, giving `None`.
+pub fn prompt_checked<T, F>(label: &str, default: &str, mut check: F) -> Option<T>
+where
+    F: FnMut(&str) -> anyhow::Result<T>,
+{
+    loop {
+        out(format!("{}:", label));
+        let answer = input_with_default(default);
+        if answer.is_empty() {
+            return None;
+        }
+        match check(&answer) {
+            Ok(value) => return Some(value),
+            Err(e) => error(format!("'{}' is not a valid {}: {}", answer, label.to_lowercase(), e)),
+        }
+    }
+}
+
+/// Opens `path` with whatever the desktop opens it with: a file manager for directories.
+///
+/// Uses `open` on macOS, `explorer` on Windows and `xdg-open` everywhere else. Explorer's exit
+/// code means nothing, so on Windows only failing to start it is an error.
+pub fn open_path<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    #[cfg(target_os = "macos")]
+    let opener = "open";
+    #[cfg(target_os = "windows")]
+    let opener = "explorer";
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    let opener = "xdg-open";
+
+    let status = Command::new(opener)
+        .arg(path.as_ref())
+        .status()
+        .map_err(|e| io::Error::new(e.kind(), format!("could not run '{}': {}", opener, e)))?;
+    if status.success() || cfg!(target_os = "windows") {
+        return Ok(());
+    }
+    Err(io::Error::other(format!("'{}' could not open {} ({})", opener, path.as_ref().display(), status)))
+}
+
 #[derive(Debug)]
 pub enum OptionType {
     Dynamic(usize),
@@ -108,11 +626,64 @@ impl InputOptions {
     }
 
     /// Queries [`stdin`] for an input, then converts it to an [`OptionType`]
+    ///
+    /// Dynamic options are shown [`PAGE_SIZE`] at a time, and typing `/` followed by part of a
+    /// label shows only those matching it (a bare `/` shows them all again). Turning the pages and
+    /// filtering are handled here, and an option keeps its number however it's shown.
     pub fn get(&self) -> OptionType {
-        if self.dynamic_options.len() > 0 {
+        let mut filter = String::new();
+        let mut shown: Vec<usize> = (0..self.dynamic_options.len()).collect();
+        let mut page = 0;
+        loop {
+            let pages = shown.len().div_ceil(PAGE_SIZE);
+            self.print_page(&shown, page, pages, &filter);
+            let option = input();
+            match option.as_str() {
+                NEXT_PAGE if pages > 1 => page = (page + 1).min(pages - 1),
+                PREVIOUS_PAGE if pages > 1 => page = page.saturating_sub(1),
+                _ if option.starts_with(FILTER) && !self.dynamic_options.is_empty() => {
+                    filter = option[FILTER.len()..].trim().to_string();
+                    shown = (0..self.dynamic_options.len())
+                        .filter(|index| fuzzy_match(&self.dynamic_options[*index], &filter))
+                        .collect();
+                    page = 0;
+                }
+                _ => return self.resolve(option),
+            }
+            blank();
+        }
+    }
+
+    /// Prints the page `page` of the dynamic options `shown` (indices into all of them), and the
+    /// static options.
+    fn print_page(&self, shown: &[usize], page: usize, pages: usize, filter: &str) {
+        if !self.dynamic_options.is_empty() {
             out_if_some(&self.header_dynamic);
-            for (key, label) in self.dynamic_options.iter().enumerate() {
-                out(format!("{} :: {}", key, label));
+            let start = page * PAGE_SIZE;
+            let end = (start + PAGE_SIZE).min(shown.len());
+            for index in &shown[start..end] {
+                out(format!("{} :: {}", index, self.dynamic_options[*index]));
+            }
+            if !filter.is_empty() {
+                out(format!(
+                    "({} of {} match '{}', [{}] shows all)",
+                    shown.len(),
+                    self.dynamic_options.len(),
+                    filter,
+                    FILTER
+                ));
+            }
+            if pages > 1 {
+                out(format!("(showing {}–{} of {})", start + 1, end, shown.len()));
+                if page + 1 < pages {
+                    out(format!("[{}] Next page", NEXT_PAGE));
+                }
+                if page > 0 {
+                    out(format!("[{}] Previous page", PREVIOUS_PAGE));
+                }
+            }
+            if pages > 1 || !filter.is_empty() {
+                out(format!("[{}text] Show only those matching text", FILTER));
             }
         }
 
@@ -122,9 +693,9 @@ impl InputOptions {
                 out(format!("[{}] {}", key, label));
             }
         }
+    }
 
-        let option = input();
-        
+    fn resolve(&self, option: String) -> OptionType {
         // First try to resolve it as a static option
         if self.static_options.contains_key(&option) {
             return OptionType::Static(option);
@@ -139,4 +710,106 @@ impl InputOptions {
 
         OptionType::Error(format!("'{}' is not a valid option.", option))
     }
-}
\ No newline at end of file
+}
+
+/// Whether the characters of `pattern` all appear in `label`, in order but not necessarily next to
+/// each other, ignoring case: `rp24` matches `reports/2024-03.csv`.
+fn fuzzy_match(label: &str, pattern: &str) -> bool {
+    let mut label = label.chars().flat_map(char::to_lowercase);
+    pattern
+        .chars()
+        .flat_map(char::to_lowercase)
+        .all(|wanted| label.any(|found| found == wanted))
+}
+
+/// Which side of its column a cell sticks to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    /// For numbers and sizes, so their digits line up.
+    Right,
+}
+
+#[derive(Debug)]
+struct Column {
+    header: String,
+    align: Align,
+    max_width: Option<usize>,
+}
+
+/// Rows of cells under a header, printed with [`table`]. Each column is as wide as its widest cell,
+/// and cells longer than the column's maximum width, if it has one, are cut short with an
+/// ellipsis.
+#[derive(Debug, Default)]
+pub struct Table {
+    columns: Vec<Column>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_column<S: ToString>(&mut self, header: S, align: Align) -> &mut Self {
+        self.columns.push(Column {
+            header: header.to_string(),
+            align,
+            max_width: None,
+        });
+        self
+    }
+
+    /// Cuts the cells of the column added last down to `width` characters.
+    pub fn set_max_width(&mut self, width: usize) -> &mut Self {
+        if let Some(column) = self.columns.last_mut() {
+            column.max_width = Some(width.max(1));
+        }
+        self
+    }
+
+    /// Adds a row, one cell per column: missing cells are left blank and extra ones dropped.
+    pub fn add_row<I: IntoIterator<Item = S>, S: ToString>(&mut self, cells: I) -> &mut Self {
+        self.rows.push(cells.into_iter().map(|cell| cell.to_string()).collect());
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The header and every row as lines, columns two spaces apart.
+    pub fn render(&self) -> Vec<String> {
+        let cell = |row: &[String], index: usize| -> String {
+            let cell = row.get(index).map(String::as_str).unwrap_or("");
+            match self.columns[index].max_width {
+                Some(max) if cell.chars().count() > max => {
+                    format!("{}…", cell.chars().take(max - 1).collect::<String>())
+                }
+                _ => cell.to_string(),
+            }
+        };
+        let headers: Vec<String> = self.columns.iter().map(|column| column.header.clone()).collect();
+        let rows: Vec<Vec<String>> = std::iter::once(&headers)
+            .chain(&self.rows)
+            .map(|row| (0..self.columns.len()).map(|index| cell(row, index)).collect())
+            .collect();
+        let widths: Vec<usize> = (0..self.columns.len())
+            .map(|index| rows.iter().map(|row| row[index].chars().count()).max().unwrap_or(0))
+            .collect();
+        rows.iter()
+            .map(|row| {
+                let cells: Vec<String> = row
+                    .iter()
+                    .zip(&self.columns)
+                    .zip(&widths)
+                    .map(|((cell, column), width)| match column.align {
+                        Align::Left => format!("{:<width$}", cell, width = width),
+                        Align::Right => format!("{:>width$}", cell, width = width),
+                    })
+                    .collect();
+                cells.join("  ").trim_end().to_string()
+            })
+            .collect()
+    }
+}
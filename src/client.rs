@@ -0,0 +1,1613 @@
+//! The client side of the protocol as a library, so other programs can embed oxideux instead of
+//! driving the `client` binary.
+//!
+//! ```no_run
+//! use oxideux_rs::client::Client;
+//! use oxideux_rs::config;
+//! use oxideux_rs::validated_values::ValidatedValue;
+//!
+//! let profile = config::client::get_profile("default")?;
+//! let mut client = Client::connect(&profile)?;
+//! for file in client.list()? {
+//!     println!("{} ({} bytes)", file.name, file.length);
+//! }
+//! client.sync(profile.parity_root.get())?;
+//! client.disconnect()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Every download and upload is recorded in the transfer history (see: [`crate::history`]).
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+
+use crate::bandwidth::{BandwidthSchedule, Throttle};
+use crate::chunks::{self, Chunk, ChunkIndex, CHUNKED_THRESHOLD};
+use crate::config::{ClientProfile, CollisionPolicy, ConflictStrategy};
+use crate::connection::{Cancelled, Capabilities, Capability, Connection, TransferControl, TransferEvent};
+use crate::delta::{self, DeltaOp, Patcher, DELTA_THRESHOLD};
+use crate::dry_run::{Change, SyncPlan};
+use crate::history::{self, Direction, Side, Transferred};
+use crate::ignore::IgnoreRules;
+use crate::meter::TransferSummary;
+use crate::parity::{self, Entry, EntryQuery, EntrySummary, FileInfo, Manifest, RootStats, StableFile};
+use crate::proxy::Proxy;
+use crate::psk::{PskTransport, Role};
+use crate::queue::{PendingQueue, QueueOrder};
+use crate::request::{Request, RequestResult, MAX_CHUNKS_PER_REQUEST};
+use crate::share;
+use crate::signing;
+use crate::trash::Trash;
+use crate::tunnel::{self, Jump};
+use crate::two_way::{self, Action, Conflict, FileState, Resolution, SyncReport, SyncState};
+use crate::validated_values::ValidatedValue;
+use crate::versions::Version;
+
+/// The profile name share link downloads are recorded under in the transfer history.
+pub const SHARE_LINK_PROFILE: &str = "share link";
+
+/// Uploads at least this long continue where an interrupted attempt stopped, when the server kept
+/// it. Smaller ones aren't worth the extra round trip.
+pub const RESUME_THRESHOLD: u64 = 1 << 20;
+
+/// How a chunked download (see: [`Client::download_chunked`]) came together.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChunkedDownload {
+    /// How many chunks the file cuts into.
+    pub chunks: usize,
+    /// How many of them had to be fetched from the server, the rest being found locally.
+    pub fetched: usize,
+    pub bytes_received: u64,
+}
+
+/// How a delta download (see: [`Client::download_delta`]) went.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeltaDownload {
+    /// The length of the rebuilt file.
+    pub length: u64,
+    /// How much of it had to be sent, the rest being copied from the local file.
+    pub bytes_received: u64,
+}
+
+/// Returned (wrapped in an [`anyhow::Error`]) when a download or sync is refused before it starts
+/// because what it would write doesn't fit on the destination's filesystem. Callers can recover it
+/// with `error.downcast_ref::<InsufficientSpace>()`, and go ahead anyway after
+/// [`Client::set_space_check`].
+#[derive(Debug)]
+pub struct InsufficientSpace {
+    pub dest: PathBuf,
+    /// How many bytes the transfer would write.
+    pub needed: u64,
+    pub available: u64,
+}
+
+impl std::fmt::Display for InsufficientSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Not enough space in '{}': {} bytes needed, {} available",
+            self.dest.display(),
+            self.needed,
+            self.available
+        )
+    }
+}
+
+impl std::error::Error for InsufficientSpace {}
+
+/// How a local parity root compares with the server's files (see: [`Client::verify`]). Names are
+/// sorted.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// How many local files have the same contents as the remote ones.
+    pub verified: usize,
+    /// Remote files without a local copy.
+    pub missing: Vec<String>,
+    /// Local files whose contents differ from the remote ones.
+    pub mismatched: Vec<String>,
+    /// Local files the server doesn't have.
+    pub extra: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Whether the local files are exactly the remote ones.
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// What a mirroring sync (see: [`Client::sync_mirrored`]) did.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MirrorSync {
+    pub downloaded: usize,
+    /// Local files removed for no longer being on the server.
+    pub removed: usize,
+}
+
+/// What a two-way sync pass settled on, before anything is transferred.
+struct TwoWayPass {
+    remote: HashMap<String, EntrySummary>,
+    local: HashMap<String, EntrySummary>,
+    /// What to do about each file, [`Resolution::Skip`] for files that already match and only
+    /// need to be recorded as in sync.
+    resolutions: Vec<(String, Resolution)>,
+    conflicts: usize,
+    unresolved: Vec<String>,
+}
+
+impl TwoWayPass {
+    /// The name the local version of the conflicting `name` is kept under, free on both sides.
+    fn conflict_copy_name(&self, name: &str) -> String {
+        two_way::conflict_copy_name(name, |candidate| {
+            self.remote.contains_key(candidate) || self.local.contains_key(candidate)
+        })
+    }
+}
+
+/// A connection to a server, logged in as the profile's user if it has one.
+pub struct Client {
+    conn: Connection,
+    /// The profile transfers are recorded under in the history.
+    profile_name: String,
+    /// The server's address as configured, which is not necessarily the socket's peer (e.g. when
+    /// tunnelling).
+    peer: String,
+    /// The profile's exclusions and policies, for files syncs never transfer either way.
+    exclude: IgnoreRules,
+    /// The files syncs are limited to, on both sides.
+    filter: EntryQuery,
+    /// Whether downloads and syncs make sure what they fetch fits before starting.
+    check_space: bool,
+    /// What downloads do about files that are there already.
+    collisions: CollisionPolicy,
+    /// What order syncs download files in.
+    queue_order: QueueOrder,
+    /// Where syncs keep the files they replace or remove, if anywhere.
+    trash: Option<Trash>,
+    /// The server's public key, hex-encoded, when downloads are checked against its signed
+    /// manifest (see: [`crate::signing`]).
+    public_key: Option<String>,
+    /// The signed manifest downloads were last checked against, fetched on first use.
+    signed: Option<Manifest>,
+    /// What was transferred since the summary was last taken (see: [`Client::take_summary`]).
+    summary: TransferSummary,
+    summary_started: Instant,
+}
+
+impl Client {
+    /// Connects to the server of `profile`, going through its SSH jump host and SOCKS5 proxy if it
+    /// has them, and logs in if it has a user.
+    pub fn connect(profile: &ClientProfile) -> Result<Self> {
+        let stream = match profile.ssh_jump.is_set() {
+            true => {
+                let jump = Jump::parse(profile.ssh_jump.get())?;
+                let stream = dial(profile, &jump.host, jump.port)?;
+                tunnel::open(&jump, stream, profile.ipv4.get(), *profile.port.get())?
+            }
+            false => dial(profile, profile.ipv4.get(), *profile.port.get())?,
+        };
+        let mut client = Self::handshake(stream, &profile.shared_secret, &profile.name, server_addr(profile))?;
+        debug!("Connected to {}, capabilities: {}", server_addr(profile), client.capabilities());
+        client.exclude = IgnoreRules::parse(&profile.exclude.entries())?
+            .hidden_files(profile.hidden_files)
+            .symlinks(profile.symlinks);
+        client.filter = EntryQuery::parse(profile.filter.get())?;
+        client.collisions = profile.collisions;
+        client.queue_order = QueueOrder::new(profile.download_order, &profile.priority.entries())?;
+        client.trash = profile.trash();
+        if profile.server_public_key.is_set() {
+            if !client.supports(Capability::Signatures) {
+                return Err(anyhow!("The profile pins the server's public key, but the server does not sign its files"));
+            }
+            client.public_key = Some(profile.server_public_key.get().clone());
+        }
+        let bandwidth = BandwidthSchedule::parse(profile.bandwidth.get())?;
+        if !bandwidth.is_unlimited() {
+            debug!("Bandwidth limited to {}", profile.bandwidth.get());
+            client.conn.set_throttle(Some(Throttle::new(bandwidth)));
+        }
+        if !profile.user.is_empty() {
+            client.log_in(&profile.user, &profile.password)?;
+            debug!("Logged in as '{}'", profile.user);
+        }
+        Ok(client)
+    }
+
+    /// Downloads what the share `link` (`oxideux://host:port/token`) points to into `dest`,
+    /// without a profile.
+    pub fn fetch_share_link<P: AsRef<Path>>(link: &str, dest: P) -> Result<()> {
+        Self::fetch_share_link_with(link, dest, |_| ())
+    }
+
+    /// Like [`Client::fetch_share_link`], reporting progress to `handler`.
+    pub fn fetch_share_link_with<P: AsRef<Path>, F: FnMut(&TransferEvent) + Send + 'static>(
+        link: &str,
+        dest: P,
+        handler: F,
+    ) -> Result<()> {
+        let (host, port, token) = share::parse_link(link)?;
+        let stream = TcpStream::connect((host.as_str(), port))?;
+        let mut client = Self::handshake(stream, "", SHARE_LINK_PROFILE, format!("{}:{}", host, port))?;
+        client.set_transfer_handler(handler);
+        if !client.supports(Capability::Share) {
+            return Err(anyhow!("The server does not support share links"));
+        }
+
+        client.conn.send_request(&Request::RedeemToken(token))?;
+        client.receive_files(dest.as_ref())?;
+        client.disconnect()
+    }
+
+    /// Sets up the connection over `stream`, encrypted with `shared_secret` unless it's empty.
+    fn handshake(stream: TcpStream, shared_secret: &str, profile_name: &str, peer: String) -> Result<Self> {
+        let mut conn = match shared_secret.is_empty() {
+            true => Connection::new(stream)?,
+            false => Connection::with_transport(Box::new(PskTransport::new(Box::new(stream), shared_secret, Role::Client)?))?,
+        };
+        conn.handshake_client(Capabilities::local())?;
+        Ok(Self {
+            conn,
+            profile_name: profile_name.to_string(),
+            peer,
+            exclude: IgnoreRules::default(),
+            filter: EntryQuery::default(),
+            check_space: true,
+            collisions: CollisionPolicy::default(),
+            queue_order: QueueOrder::default(),
+            trash: None,
+            public_key: None,
+            signed: None,
+            summary: TransferSummary::default(),
+            summary_started: Instant::now(),
+        })
+    }
+
+    /// What was transferred since the connection was made or the summary last taken, after which
+    /// it starts over.
+    pub fn take_summary(&mut self) -> TransferSummary {
+        let mut summary = std::mem::take(&mut self.summary);
+        summary.elapsed = self.summary_started.elapsed();
+        self.summary_started = Instant::now();
+        summary
+    }
+
+    fn log_in(&mut self, user: &str, password: &str) -> Result<()> {
+        if !self.supports(Capability::Accounts) {
+            return Err(anyhow!("The server does not support logging in"));
+        }
+        self.conn.send_request(&Request::Authenticate {
+            user: user.to_string(),
+            password: password.to_string(),
+        })?;
+        self.conn.read_request_result()?.naturalize()
+    }
+
+    /// Registers the handler that receives every [`TransferEvent`], e.g. to show progress.
+    /// Turns off (or back on) refusing downloads and syncs that don't fit on the destination's
+    /// filesystem (see: [`InsufficientSpace`]). On by default.
+    pub fn set_space_check(&mut self, check: bool) {
+        self.check_space = check;
+    }
+
+    /// Fails with [`InsufficientSpace`] when `needed` bytes don't fit in `dest`. Filesystems whose
+    /// free space can't be told are assumed to have room.
+    fn ensure_space(&self, dest: &Path, needed: u64) -> Result<()> {
+        if !self.check_space || needed == 0 {
+            return Ok(());
+        }
+        match parity::available_space(dest)? {
+            Some(available) if available < needed => Err(InsufficientSpace {
+                dest: dest.to_path_buf(),
+                needed,
+                available,
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Changes what downloads do about files that are there already. Overwriting by default.
+    pub fn set_collision_policy(&mut self, collisions: CollisionPolicy) {
+        self.collisions = collisions;
+    }
+
+    /// Changes what order syncs download files in. As the server lists them by default.
+    pub fn set_queue_order(&mut self, order: QueueOrder) {
+        self.queue_order = order;
+    }
+
+    /// Changes where syncs keep the files they replace or remove, `None` to destroy them. None by
+    /// default.
+    pub fn set_trash(&mut self, trash: Option<Trash>) {
+        self.trash = trash;
+    }
+
+    /// Empties the trash of what has been in it long enough.
+    fn prune_trash(&self) -> Result<()> {
+        if let Some(trash) = &self.trash {
+            let removed = trash.prune()?;
+            if removed > 0 {
+                debug!("Pruned {} file(s) from the trash at {}", removed, trash.dir().display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Where a download meant for `dest` goes, as the collision policy says.
+    fn destination(&self, dest: PathBuf) -> PathBuf {
+        match self.collisions {
+            CollisionPolicy::Overwrite => dest,
+            CollisionPolicy::KeepBoth => parity::keep_both_path(dest),
+        }
+    }
+
+    pub fn set_transfer_handler<F: FnMut(&TransferEvent) + Send + 'static>(&mut self, handler: F) {
+        self.conn.set_transfer_handler(handler);
+    }
+
+    /// A handle that pauses, resumes and stops this client's transfers from another thread, such as
+    /// while this one is busy downloading.
+    pub fn transfer_control(&self) -> TransferControl {
+        self.conn.transfer_control()
+    }
+
+    /// Whether both this client and the server support `capability`.
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.conn.supports(capability)
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.conn.capabilities()
+    }
+
+    /// The underlying connection, for requests this type has no method for.
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+
+    /// Gives up the client for its connection, e.g. to dedicate it to watching the server.
+    pub fn into_connection(self) -> Connection {
+        self.conn
+    }
+
+    /// The profile's exclusions together with those of the ignore file in the local `root`.
+    fn ignore_rules(&self, root: &Path) -> Result<IgnoreRules> {
+        self.exclude.clone().with_ignore_file(root)
+    }
+
+    /// The remote files the profile's filter selects and `ignore` doesn't exclude.
+    fn remote_files(&mut self, ignore: &IgnoreRules) -> Result<Vec<EntrySummary>> {
+        let mut remote = match self.filter.is_empty() {
+            true => self.list()?,
+            false => self.query(&self.filter.clone())?,
+        };
+        remote.retain(|summary| !ignore.is_ignored(&summary.name));
+        Ok(remote)
+    }
+
+    /// The remote files matching `query`, filtered by the server when it can.
+    pub fn query(&mut self, query: &EntryQuery) -> Result<Vec<EntrySummary>> {
+        if !self.supports(Capability::Query) {
+            let mut remote = self.list()?;
+            remote.retain(|summary| query.matches(summary));
+            return Ok(remote);
+        }
+        self.conn.send_request(&Request::QueryFiles(query.clone()))?;
+        self.conn.read_request_result()?.naturalize()?;
+        self.conn.read_object()
+    }
+
+    /// The first `limit` remote files matching `query`, without listing every file when the server
+    /// can search itself. The server returns at most [`crate::request::MAX_SEARCH_RESULTS`].
+    pub fn search(&mut self, query: &EntryQuery, limit: u32) -> Result<Vec<EntrySummary>> {
+        if !self.supports(Capability::Search) {
+            let mut remote = self.query(query)?;
+            remote.truncate(limit as usize);
+            return Ok(remote);
+        }
+        self.conn.send_request(&Request::Search {
+            query: query.clone(),
+            limit,
+        })?;
+        self.conn.read_request_result()?.naturalize()?;
+        self.conn.read_object()
+    }
+
+    /// Every file of the server's parity root.
+    pub fn list(&mut self) -> Result<Vec<EntrySummary>> {
+        if !self.supports(Capability::List) {
+            return Err(anyhow!("The server does not support listing files"));
+        }
+        self.conn.send_request(&Request::ListFiles)?;
+        self.conn.read_request_result()?.naturalize()?;
+        self.conn.read_object()
+    }
+
+    /// Totals over the remote parity root, worked out from a full listing when the server can't
+    /// answer them itself.
+    pub fn root_stats(&mut self) -> Result<RootStats> {
+        if !self.supports(Capability::Stats) {
+            return Ok(RootStats::of(&self.list()?));
+        }
+        self.conn.send_request(&Request::GetRootStats)?;
+        self.conn.read_request_result()?.naturalize()?;
+        self.conn.read_object()
+    }
+
+    pub fn file_count(&mut self) -> Result<u32> {
+        self.conn.send_request(&Request::GetFileCount)?;
+        self.conn.read_request_result()?.naturalize()?;
+        self.conn.read_count()
+    }
+
+    pub fn file_info(&mut self, name: &str) -> Result<FileInfo> {
+        self.conn.send_request(&Request::GetFileInfo(name.to_string()))?;
+        self.conn.read_request_result()?.naturalize()?;
+        self.conn.read_object()
+    }
+
+    /// The names, sizes and hashes of every remote file, without the server hashing them all.
+    /// Checked against the server's signature when the profile pins its public key.
+    pub fn manifest(&mut self) -> Result<Manifest> {
+        if self.public_key.is_some() {
+            let manifest = self.signed_manifest()?;
+            self.signed = Some(manifest.clone());
+            return Ok(manifest);
+        }
+        if !self.supports(Capability::Manifest) {
+            return Err(anyhow!("The server does not support manifests"));
+        }
+        self.conn.send_request(&Request::GetManifest)?;
+        self.conn.read_request_result()?.naturalize()?;
+        self.conn.read_object()
+    }
+
+    /// The manifest the server signed, failing unless the signature was made with the key of the
+    /// pinned public key.
+    fn signed_manifest(&mut self) -> Result<Manifest> {
+        let public_key = self.public_key.clone().ok_or(anyhow!("The profile does not pin the server's public key"))?;
+        self.conn.send_request(&Request::GetSignedManifest)?;
+        self.conn.read_request_result()?.naturalize()?;
+        let manifest: Manifest = self.conn.read_object()?;
+        let signature: Vec<u8> = self.conn.read_object()?;
+        signing::verify_manifest(&public_key, &manifest, &signature)?;
+        Ok(manifest)
+    }
+
+    /// Checks `local`, just downloaded as the remote file `name`, against the signed manifest when
+    /// the profile pins the server's public key. The manifest is fetched again when it doesn't
+    /// match, as the file may have changed on the server since it was signed.
+    fn authenticate(&mut self, name: &str, local: &Path) -> Result<()> {
+        if self.public_key.is_none() {
+            return Ok(());
+        }
+        let hash = parity::hash_file(local)?;
+        let signed = |manifest: &Option<Manifest>| {
+            manifest
+                .as_ref()
+                .and_then(|manifest| manifest.get(name))
+                .is_some_and(|file| file.hash.eq_ignore_ascii_case(&hash))
+        };
+        if signed(&self.signed) {
+            return Ok(());
+        }
+        self.signed = Some(self.signed_manifest()?);
+        match signed(&self.signed) {
+            true => Ok(()),
+            false => Err(anyhow!(format!("'{}' does not match what the server signed", name))),
+        }
+    }
+
+    /// Passes on `result`, the download of the remote file `name` into `local`, once `local` is
+    /// authenticated (see: [`Client::authenticate`]). `local` is removed when it can't be.
+    fn authenticated(&mut self, name: &str, local: &Path, result: Result<()>) -> Result<()> {
+        result?;
+        let authenticated = self.authenticate(name, local);
+        if authenticated.is_err() {
+            let _ = fs::remove_file(local);
+        }
+        authenticated
+    }
+
+    /// Reads up to `length` bytes of the remote file `name` from `offset`, without downloading the
+    /// rest. At most [`crate::request::MAX_RANGE_LENGTH`] bytes come back per call.
+    pub fn read_range(&mut self, name: &str, offset: u64, length: u32) -> Result<Vec<u8>> {
+        if !self.supports(Capability::Range) {
+            return Err(anyhow!("The server does not support ranged reads"));
+        }
+        self.conn.send_request(&Request::ReadRange {
+            name: name.to_string(),
+            offset,
+            length,
+        })?;
+        self.conn.read_request_result()?.naturalize()?;
+        self.conn.read_object()
+    }
+
+    /// Downloads the remote file `name` into the local file `dest`, or next to it when it's there
+    /// already and the collision policy keeps both.
+    pub fn download<P: AsRef<Path>>(&mut self, name: &str, dest: P) -> Result<()> {
+        let dest = self.destination(dest.as_ref().to_path_buf());
+        self.download_over(name, dest)
+    }
+
+    /// Downloads the remote file `name` into the local file `dest`, replacing what's there.
+    fn download_over(&mut self, name: &str, dest: PathBuf) -> Result<()> {
+        let result = self.request_file(Request::DownloadFileByName(name.to_string()), &dest);
+        let result = self.authenticated(name, &dest, result);
+        self.record(Direction::Download, name, &dest, &result);
+        result
+    }
+
+    /// Downloads the remote file `name` into the local file `dest`, replacing what's there, unless
+    /// the copy there is the same by length and either modification time or hash, which the server
+    /// tells without sending the file. Returns whether the file was downloaded.
+    pub fn download_if_changed<P: AsRef<Path>>(&mut self, name: &str, dest: P) -> Result<bool> {
+        let dest = dest.as_ref().to_path_buf();
+        if !self.supports(Capability::Conditional) {
+            self.download_over(name, dest)?;
+            return Ok(true);
+        }
+        let (length, modified, hash) = match fs::metadata(&dest) {
+            Ok(metadata) if metadata.is_file() => {
+                (metadata.len(), parity::modified_secs(&metadata), Some(parity::hash_file(&dest)?))
+            }
+            _ => (0, None, None),
+        };
+
+        self.conn.send_request(&Request::DownloadIfChanged {
+            name: name.to_string(),
+            length,
+            modified,
+            hash,
+        })?;
+        match self.conn.read_request_result()? {
+            RequestResult::NotModified => return Ok(false),
+            result => result.naturalize()?,
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let result = self.conn.read_file(&dest);
+        let result = self.authenticated(name, &dest, result);
+        self.record(Direction::Download, name, &dest, &result);
+        result.map(|_| true)
+    }
+
+    /// Streams the remote file `name` into `output` as it arrives, such as to stdout, without
+    /// writing it anywhere on disk. Not recorded in the transfer history. Refused when the profile
+    /// pins the server's public key, as nothing could be taken back once it doesn't match.
+    pub fn download_to<W: Write>(&mut self, name: &str, output: &mut W) -> Result<()> {
+        if self.public_key.is_some() {
+            return Err(anyhow!("Streamed downloads can't be checked against what the server signed"));
+        }
+        self.conn.send_request(&Request::DownloadFileByName(name.to_string()))?;
+        self.conn.read_request_result()?.naturalize()?;
+        self.conn.read_file_to(name, output)
+    }
+
+    /// The previous versions of the remote file `name` that uploads replaced, newest first.
+    pub fn list_versions(&mut self, name: &str) -> Result<Vec<Version>> {
+        if !self.supports(Capability::Versions) {
+            return Err(anyhow!("The server does not keep previous versions"));
+        }
+        self.conn.send_request(&Request::ListVersions(name.to_string()))?;
+        self.conn.read_request_result()?.naturalize()?;
+        self.conn.read_object()
+    }
+
+    /// Downloads a previous version of the remote file `name`, as listed by
+    /// [`Client::list_versions`], into the local file `dest` or next to it as the collision policy
+    /// says. Refused when the profile pins the server's public key, as only current files are
+    /// signed.
+    pub fn download_version<P: AsRef<Path>>(&mut self, name: &str, version: &str, dest: P) -> Result<()> {
+        if !self.supports(Capability::Versions) {
+            return Err(anyhow!("The server does not keep previous versions"));
+        }
+        if self.public_key.is_some() {
+            return Err(anyhow!("Previous versions are not signed by the server"));
+        }
+        let dest = self.destination(dest.as_ref().to_path_buf());
+        let request = Request::DownloadVersion {
+            name: name.to_string(),
+            version: version.to_string(),
+        };
+        let result = self.request_file(request, &dest);
+        self.record(Direction::Download, name, &dest, &result);
+        result
+    }
+
+    /// Downloads the remote file whose contents hash to `hash` (see: [`Manifest`]) into the local
+    /// file `dest`, whatever it is called on the server, and returns its remote name. Returns
+    /// `None` without downloading anything when `dest` holds those contents already, so missing
+    /// files can be filled in by hash as often as needed. A download that doesn't hash to `hash`
+    /// is removed again.
+    pub fn download_by_hash<P: AsRef<Path>>(&mut self, hash: &str, dest: P) -> Result<Option<String>> {
+        let dest = dest.as_ref();
+        if !self.supports(Capability::ByHash) {
+            return Err(anyhow!("The server does not support downloads by hash"));
+        }
+        if dest.is_file() && parity::hash_file(dest)?.eq_ignore_ascii_case(hash) {
+            return Ok(None);
+        }
+
+        self.conn.send_request(&Request::DownloadByHash(hash.to_string()))?;
+        self.conn.read_request_result()?.naturalize()?;
+        let name = self.conn.read_string()?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let dest = dest.to_path_buf();
+        let result = self.conn.read_file(&dest).and_then(|_| match parity::hash_file(&dest)? {
+            received if received.eq_ignore_ascii_case(hash) => Ok(()),
+            // The file changed on the server since its manifest was made
+            received => {
+                fs::remove_file(&dest)?;
+                Err(anyhow!(format!("'{}' was expected to hash to {}, but hashed to {}", name, hash, received)))
+            }
+        });
+        let result = self.authenticated(&name, &dest, result);
+        self.record(Direction::Download, &name, &dest, &result);
+        result.map(|_| Some(name))
+    }
+
+    /// Sends `request`, for a file, and receives the file into `dest`.
+    fn request_file(&mut self, request: Request, dest: &PathBuf) -> Result<()> {
+        self.conn.send_request(&request)?;
+        self.conn.read_request_result()?.naturalize()?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.conn.read_file(dest)
+    }
+
+    /// Downloads the remote file `name` into the local file `dest`, only fetching the chunks that
+    /// can't be found in local files already (see: [`crate::chunks`]). The previous version of
+    /// `dest` is indexed first, and the new one once downloaded.
+    pub fn download_chunked<P: AsRef<Path>>(&mut self, name: &str, dest: P) -> Result<ChunkedDownload> {
+        let dest = dest.as_ref().to_path_buf();
+        let result = self.fetch_chunked(name, &dest, None);
+        let outcome = result.as_ref().map(|_| ()).map_err(|e| anyhow!(e.to_string()));
+        self.record(Direction::Download, name, &dest, &outcome);
+        result
+    }
+
+    /// Assembles `name` into `dest` chunk by chunk, giving it the `modified` time before it's
+    /// indexed so the index stays current.
+    fn fetch_chunked(&mut self, name: &str, dest: &Path, modified: Option<u64>) -> Result<ChunkedDownload> {
+        if !self.supports(Capability::Chunks) {
+            return Err(anyhow!("The server does not support chunked transfers"));
+        }
+        self.conn.send_request(&Request::GetChunks(name.to_string()))?;
+        self.conn.read_request_result()?.naturalize()?;
+        let manifest: Vec<Chunk> = self.conn.read_object()?;
+
+        let mut index = ChunkIndex::open()?;
+        if dest.is_file() {
+            index.index_file(dest)?;
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial = partial_path(dest);
+
+        let length = manifest.iter().map(|chunk| chunk.length as u64).sum();
+        self.conn.emit_started(name, length);
+        let result = self
+            .assemble(name, &manifest, &index, &partial)
+            .and_then(|stats| self.authenticate(name, &partial).map(|_| stats))
+            .and_then(|stats| finish_partial(&partial, dest, modified).map(|_| stats));
+        self.emit_outcome(name, length, &partial, &result);
+        let stats = result?;
+        index.index_file(dest)?;
+        Ok(stats)
+    }
+
+    /// Writes the chunks of `manifest` into `output` in order, from local files where the index
+    /// has them and from the server otherwise.
+    fn assemble(&mut self, name: &str, manifest: &[Chunk], index: &ChunkIndex, output: &Path) -> Result<ChunkedDownload> {
+        let length = manifest.iter().map(|chunk| chunk.length as u64).sum();
+        let mut file = BufWriter::new(File::create(output)?);
+        let mut stats = ChunkedDownload {
+            chunks: manifest.len(),
+            ..Default::default()
+        };
+        let mut written = 0;
+
+        for batch in manifest.chunks(MAX_CHUNKS_PER_REQUEST) {
+            self.conn.checkpoint()?;
+            let mut found = HashMap::new();
+            let mut missing = vec![];
+            for chunk in batch {
+                if found.contains_key(&chunk.hash) || missing.contains(&chunk.hash) {
+                    continue;
+                }
+                match index.read(&chunk.hash)? {
+                    Some(data) => {
+                        found.insert(chunk.hash.clone(), data);
+                    }
+                    None => missing.push(chunk.hash.clone()),
+                }
+            }
+            stats.fetched += missing.len();
+            for (hash, data) in self.read_chunks(name, missing)? {
+                stats.bytes_received += data.len() as u64;
+                found.insert(hash, data);
+            }
+
+            for chunk in batch {
+                file.write_all(&found[&chunk.hash])?;
+                written += chunk.length as u64;
+                self.conn.emit_progressed(name, written, length);
+            }
+        }
+        file.flush()?;
+        Ok(stats)
+    }
+
+    /// Downloads the remote file `name` over the local file `dest`, only receiving what differs
+    /// between the two (see: [`crate::delta`]).
+    pub fn download_delta<P: AsRef<Path>>(&mut self, name: &str, dest: P) -> Result<DeltaDownload> {
+        let dest = dest.as_ref().to_path_buf();
+        let result = self.fetch_delta(name, &dest, None);
+        let outcome = result.as_ref().map(|_| ()).map_err(|e| anyhow!(e.to_string()));
+        self.record(Direction::Download, name, &dest, &outcome);
+        result
+    }
+
+    fn fetch_delta(&mut self, name: &str, dest: &Path, modified: Option<u64>) -> Result<DeltaDownload> {
+        if !self.supports(Capability::Delta) {
+            return Err(anyhow!("The server does not support delta transfers"));
+        }
+        let basis = File::open(dest).map_err(|e| anyhow!(format!("No local copy to start from: {}", e)))?;
+        let block_size = delta::block_size(basis.metadata()?.len());
+        let signature = delta::signature(BufReader::new(&basis), block_size)?;
+
+        self.conn.send_request(&Request::DownloadDelta {
+            name: name.to_string(),
+            signature,
+        })?;
+        self.conn.read_request_result()?.naturalize()?;
+        let length: u64 = self.conn.read_object()?;
+
+        let partial = partial_path(dest);
+        self.conn.emit_started(name, length);
+        let result = self
+            .patch(name, length, &basis, block_size, &partial)
+            .and_then(|stats| self.authenticate(name, &partial).map(|_| stats))
+            .and_then(|stats| finish_partial(&partial, dest, modified).map(|_| stats));
+        self.emit_outcome(name, length, &partial, &result);
+        result
+    }
+
+    /// Rebuilds `name` into `output` from the delta the server streams against `basis`.
+    fn patch(&mut self, name: &str, length: u64, basis: &File, block_size: u32, output: &Path) -> Result<DeltaDownload> {
+        let mut patcher = Patcher::new(basis, BufWriter::new(File::create(output)?), block_size);
+        let mut stats = DeltaDownload {
+            length,
+            ..Default::default()
+        };
+        loop {
+            let op: DeltaOp = self.conn.read_object()?;
+            if let DeltaOp::Literal(bytes) = &op {
+                stats.bytes_received += bytes.len() as u64;
+            }
+            if patcher.apply(op)? {
+                return Ok(stats);
+            }
+            let transferred = patcher.written();
+            self.conn.emit_progressed(name, transferred, length);
+        }
+    }
+
+    /// Reports how a download assembled into `partial` went, removing it if it failed.
+    fn emit_outcome<T>(&mut self, name: &str, length: u64, partial: &Path, result: &Result<T>) {
+        match result {
+            Ok(_) => self.conn.emit_completed(name, length),
+            Err(error) => {
+                let _ = fs::remove_file(partial);
+                self.conn.emit(TransferEvent::Failed { name, error });
+            }
+        }
+    }
+
+    /// Fetches the chunks of `name` with `hashes`, checking each against its hash.
+    fn read_chunks(&mut self, name: &str, hashes: Vec<String>) -> Result<HashMap<String, Vec<u8>>> {
+        if hashes.is_empty() {
+            return Ok(HashMap::new());
+        }
+        self.conn.send_request(&Request::ReadChunks {
+            name: name.to_string(),
+            hashes: hashes.clone(),
+        })?;
+        self.conn.read_request_result()?.naturalize()?;
+
+        let mut chunks = HashMap::new();
+        for hash in hashes {
+            let data: Vec<u8> = self.conn.read_object()?;
+            if chunks::hash(&data) != hash {
+                return Err(anyhow!(format!("A chunk of '{}' arrived damaged, or the file changed on the server", name)));
+            }
+            chunks.insert(hash, data);
+        }
+        Ok(chunks)
+    }
+
+    /// Downloads every remote file into the directory `dest`, except for those the profile or the
+    /// ignore file in `dest` exclude.
+    pub fn download_all<P: AsRef<Path>>(&mut self, dest: P) -> Result<()> {
+        let dest = dest.as_ref();
+        let ignore = self.ignore_rules(dest)?;
+        if ignore.is_empty() {
+            if self.check_space {
+                let needed = self.root_stats()?.bytes;
+                self.ensure_space(dest, needed)?;
+            }
+            self.conn.send_request(&Request::DownloadAllFiles)?;
+            return self.receive_files(dest).map(|_| ());
+        }
+        // Excluded files must not even be sent, so they are asked for one by one
+        let mut remote = self.remote_files(&ignore)?;
+        remote.sort_by(|a, b| self.queue_order.compare(a, b));
+        self.ensure_space(dest, remote.iter().map(|summary| summary.length).sum())?;
+        for summary in remote {
+            self.conn.hold()?;
+            let dest = parity::safe_join(dest, &summary.name)?;
+            unless_skipped(self.download(&summary.name, dest))?;
+        }
+        Ok(())
+    }
+
+    /// Downloads the remote files matching the glob `pattern` (e.g. `reports/2024-*`) into the
+    /// directory `dest`, returning how many were downloaded.
+    pub fn download_matching<P: AsRef<Path>>(&mut self, pattern: &str, dest: P) -> Result<usize> {
+        self.conn.send_request(&Request::DownloadMatching(pattern.to_string()))?;
+        self.receive_files(dest.as_ref())
+    }
+
+    /// Uploads the files of the directory `root` matching the glob `pattern`, named by their path
+    /// relative to `root`, returning how many were uploaded. Files the profile or the ignore file
+    /// in `root` exclude are skipped.
+    pub fn upload_matching<P: AsRef<Path>>(&mut self, pattern: &str, root: P) -> Result<usize> {
+        let root = root.as_ref();
+        let entries = parity::get_matching_entries(root, pattern, &self.ignore_rules(root)?)?;
+        let mut uploaded = 0;
+        for entry in &entries {
+            self.conn.hold()?;
+            if unless_skipped(self.upload(&entry.path, &entry.name))? {
+                uploaded += 1;
+            }
+        }
+        Ok(uploaded)
+    }
+
+    /// Uploads the local file `local` as `name`. Large files continue from where an interrupted
+    /// upload of the same name stopped, provided `local` hasn't changed since.
+    pub fn upload<P: AsRef<Path>>(&mut self, local: P, name: &str) -> Result<()> {
+        let local = local.as_ref().to_path_buf();
+        let result = self.send_upload(local.clone(), name);
+        self.record(Direction::Upload, name, &local, &result);
+        result
+    }
+
+    /// Uploads everything read from `input`, such as stdin, as the remote file `name`. Uploads
+    /// state their length up front, so `input` is read to its end into a temporary file first.
+    pub fn upload_from<R: Read>(&mut self, input: &mut R, name: &str) -> Result<()> {
+        let spool = env::temp_dir().join(format!("oxideux-upload-{}.part", std::process::id()));
+        let result = File::create(&spool)
+            .and_then(|mut file| io::copy(input, &mut file))
+            .map_err(anyhow::Error::from)
+            .and_then(|_| self.upload(&spool, name));
+        let _ = fs::remove_file(&spool);
+        result
+    }
+
+    fn send_upload(&mut self, local: PathBuf, name: &str) -> Result<()> {
+        let mut entry = parity::get_file_entry(local)?;
+        entry.name = name.to_string();
+
+        let length = fs::metadata(&entry.path)?.len();
+        let offset = match length >= RESUME_THRESHOLD && self.supports(Capability::ResumeUpload) {
+            true => Some(self.upload_offset(name)?).filter(|offset| *offset > 0 && *offset <= length),
+            false => None,
+        };
+
+        let request = match offset {
+            Some(offset) => Request::ResumeUpload {
+                name: name.to_string(),
+                offset,
+                length,
+            },
+            None if self.supports(Capability::SizedUpload) => Request::UploadSizedFile {
+                name: name.to_string(),
+                length,
+            },
+            None => Request::UploadFile(name.to_string()),
+        };
+        self.conn.send_request(&request)?;
+        self.conn.read_request_result()?.naturalize()?;
+        match offset {
+            Some(offset) => {
+                let rest = Entry {
+                    length: length - offset,
+                    ..entry.clone()
+                };
+                self.conn.send_from(&rest, || {
+                    let mut file = StableFile::open(&entry)?;
+                    file.seek(SeekFrom::Start(offset))?;
+                    Ok(file)
+                })?;
+            }
+            None => self.conn.send_file(&entry)?,
+        }
+        self.conn.read_request_result()?.naturalize()?;
+        Ok(())
+    }
+
+    /// How many bytes of an interrupted upload of `name` the server kept, zero when there is
+    /// nothing to resume.
+    pub fn upload_offset(&mut self, name: &str) -> Result<u64> {
+        if !self.supports(Capability::ResumeUpload) {
+            return Ok(0);
+        }
+        self.conn.send_request(&Request::GetUploadOffset(name.to_string()))?;
+        self.conn.read_request_result()?.naturalize()?;
+        self.conn.read_object()
+    }
+
+    /// Downloads every remote file that is missing from the directory `dest` or differs in size or
+    /// modification time, returning how many were downloaded. Large files are downloaded chunk by
+    /// chunk when the server supports it, so only what changed is sent again.
+    ///
+    /// What is left of a previous sync of `dest` that was cut short (see: [`PendingQueue`]) is
+    /// downloaded first, resuming the file it was in the middle of.
+    pub fn sync<P: AsRef<Path>>(&mut self, dest: P) -> Result<usize> {
+        let resumed = self.resume_queue(dest.as_ref())?;
+        let ignore = self.ignore_rules(dest.as_ref())?;
+        let remote = self.remote_files(&ignore)?;
+        Ok(resumed + self.download_changed(dest.as_ref(), &remote)?)
+    }
+
+    /// Like [`Client::sync`], then removes the files directly in `dest` that are no longer on the
+    /// server, so `dest` mirrors it. Removed files are moved into the trash when there is one, and
+    /// deleted otherwise.
+    pub fn sync_mirrored<P: AsRef<Path>>(&mut self, dest: P) -> Result<MirrorSync> {
+        let dest = dest.as_ref();
+        let resumed = self.resume_queue(dest)?;
+        let ignore = self.ignore_rules(dest)?;
+        let remote = self.remote_files(&ignore)?;
+        let downloaded = resumed + self.download_changed(dest, &remote)?;
+
+        let names: HashSet<&str> = remote.iter().map(|summary| summary.name.as_str()).collect();
+        let mut removed = 0;
+        for name in local_summaries(dest, &ignore, &self.filter)?.into_keys() {
+            if names.contains(name.as_str()) {
+                continue;
+            }
+            let path = parity::safe_join(dest, &name)?;
+            match &self.trash {
+                Some(trash) => {
+                    trash.move_in(&path, &name)?;
+                }
+                None => fs::remove_file(&path)?,
+            }
+            removed += 1;
+        }
+        Ok(MirrorSync { downloaded, removed })
+    }
+
+    /// What [`Client::sync`] would do to `dest`, without doing it. With `mirror`, also lists the
+    /// local files [`Client::sync_mirrored`] would remove.
+    pub fn plan_sync<P: AsRef<Path>>(&mut self, dest: P, mirror: bool) -> Result<SyncPlan> {
+        let dest = dest.as_ref();
+        let ignore = self.ignore_rules(dest)?;
+        let mut remote = self.remote_files(&ignore)?;
+        // Listed in the order they would be downloaded in
+        remote.sort_by(|a, b| self.queue_order.compare(a, b));
+        let mut plan = SyncPlan::default();
+        for summary in &remote {
+            let output = parity::safe_join(dest, &summary.name)?;
+            if !is_up_to_date(&output, summary) {
+                plan.push(&summary.name, download_change(&output), summary.length);
+            }
+        }
+        if mirror {
+            let names: HashSet<&str> = remote.iter().map(|summary| summary.name.as_str()).collect();
+            for (name, local) in local_summaries(dest, &ignore, &self.filter)? {
+                if !names.contains(name.as_str()) {
+                    plan.push(&name, Change::Delete, local.length);
+                }
+            }
+        }
+        Ok(plan)
+    }
+
+    /// What [`Client::download_all`] would do to `dest`, without doing it.
+    pub fn plan_download_all<P: AsRef<Path>>(&mut self, dest: P) -> Result<SyncPlan> {
+        let ignore = self.ignore_rules(dest.as_ref())?;
+        let mut plan = SyncPlan::default();
+        for summary in self.remote_files(&ignore)? {
+            let output = parity::safe_join(dest.as_ref(), &summary.name)?;
+            plan.push(&summary.name, download_change(&output), summary.length);
+        }
+        Ok(plan)
+    }
+
+    fn download_changed(&mut self, dest: &Path, remote: &[EntrySummary]) -> Result<usize> {
+        let mut changed = vec![];
+        for summary in remote {
+            let output = parity::safe_join(dest, &summary.name)?;
+            if !is_up_to_date(&output, summary) {
+                changed.push((summary, output));
+            }
+        }
+        self.ensure_space(dest, changed.iter().map(|(summary, _)| summary.length).sum())?;
+        self.summary.skipped += remote.len() - changed.len();
+        changed.sort_by(|(a, _), (b, _)| self.queue_order.compare(a, b));
+        let mut queue = PendingQueue::open(&self.profile_name, dest)?;
+        queue.replace(changed.iter().map(|(summary, _)| *summary))?;
+        let downloaded = self.download_queued(&queue, &changed, false)?;
+        self.prune_trash()?;
+        Ok(downloaded)
+    }
+
+    /// Downloads what is left in the queue of `dest` from a sync that was cut short, returning how
+    /// many files were downloaded.
+    fn resume_queue(&mut self, dest: &Path) -> Result<usize> {
+        if !dest.is_dir() {
+            return Ok(0);
+        }
+        let queue = PendingQueue::open(&self.profile_name, dest)?;
+        let pending = queue.pending()?;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        debug!("Resuming {} queued download(s) into {}", pending.len(), dest.display());
+        let mut queued = vec![];
+        for summary in &pending {
+            queued.push((summary, parity::safe_join(dest, &summary.name)?));
+        }
+        let downloaded = self.download_queued(&queue, &queued, true)?;
+        self.prune_trash()?;
+        Ok(downloaded)
+    }
+
+    /// Downloads `queued` in order, taking each file off `queue` once it is done with. A file that
+    /// fails stays queued, along with the ones after it. With `resume`, partial downloads left over
+    /// from before are continued.
+    fn download_queued(&mut self, queue: &PendingQueue, queued: &[(&EntrySummary, PathBuf)], resume: bool) -> Result<usize> {
+        let mut downloaded = 0;
+        for (summary, output) in queued {
+            self.conn.hold()?;
+            if unless_skipped(self.fetch(summary, output, resume))? {
+                downloaded += 1;
+            }
+            queue.remove(&summary.name)?;
+        }
+        Ok(downloaded)
+    }
+
+    /// Synchronizes the directory `root` with the server both ways (see: [`crate::two_way`]).
+    /// Conflicts are settled by `strategy`, or by `ask` when it leaves them to the user.
+    pub fn sync_two_way<P: AsRef<Path>, F: FnMut(&Conflict) -> Resolution>(
+        &mut self,
+        root: P,
+        strategy: ConflictStrategy,
+        ask: F,
+    ) -> Result<SyncReport> {
+        let root = root.as_ref();
+        let state = SyncState::open(&self.profile_name, root)?;
+        let pass = self.decide_two_way(root, &state, strategy, ask)?;
+        let needed = pass
+            .resolutions
+            .iter()
+            .filter(|(_, resolution)| matches!(resolution, Resolution::KeepRemote | Resolution::KeepBoth))
+            .map(|(name, _)| pass.remote[name].length)
+            .sum();
+        self.ensure_space(root, needed)?;
+        let mut report = SyncReport {
+            conflicts: pass.conflicts,
+            unresolved: pass.unresolved.clone(),
+            ..Default::default()
+        };
+
+        let mut settled = vec![];
+        for (name, resolution) in &pass.resolutions {
+            let path = parity::safe_join(root, name)?;
+            self.conn.hold()?;
+            // Skipped files are left unsettled, for the next pass to pick up again
+            match resolution {
+                Resolution::KeepRemote => {
+                    if !unless_skipped(self.fetch(&pass.remote[name], &path, false))? {
+                        continue;
+                    }
+                    report.downloaded += 1;
+                }
+                Resolution::KeepLocal => {
+                    if !unless_skipped(self.upload(&path, name))? {
+                        continue;
+                    }
+                    report.uploaded += 1;
+                }
+                Resolution::KeepBoth => {
+                    let copy = pass.conflict_copy_name(name);
+                    let copy_path = parity::safe_join(root, &copy)?;
+                    fs::rename(&path, &copy_path)?;
+                    if !unless_skipped(self.upload(&copy_path, &copy))? {
+                        continue;
+                    }
+                    report.uploaded += 1;
+                    settled.push(copy);
+                    if !unless_skipped(self.fetch(&pass.remote[name], &path, false))? {
+                        continue;
+                    }
+                    report.downloaded += 1;
+                }
+                Resolution::Skip => (),
+            }
+            settled.push(name.clone());
+        }
+        let names: HashSet<&String> = pass.remote.keys().chain(pass.local.keys()).collect();
+        let acted = pass.resolutions.iter().filter(|(_, resolution)| *resolution != Resolution::Skip).count();
+        self.summary.skipped += names.len().saturating_sub(acted);
+
+        // Uploads are stamped by the server, so how they ended up there has to be asked for
+        if !settled.is_empty() {
+            let remote: HashMap<String, EntrySummary> =
+                self.list()?.into_iter().map(|summary| (summary.name.clone(), summary)).collect();
+            let local = local_summaries(root, &IgnoreRules::default(), &EntryQuery::default())?;
+            for name in settled {
+                if let (Some(local), Some(remote)) = (local.get(&name), remote.get(&name)) {
+                    state.record(&name, &local.into(), &remote.into())?;
+                }
+            }
+        }
+        self.prune_trash()?;
+        Ok(report)
+    }
+
+    /// What [`Client::sync_two_way`] would do to `root`, without doing it. Conflicts `strategy`
+    /// leaves to the user are listed as such.
+    pub fn plan_two_way<P: AsRef<Path>>(&mut self, root: P, strategy: ConflictStrategy) -> Result<SyncPlan> {
+        let root = root.as_ref();
+        let state = SyncState::open(&self.profile_name, root)?;
+        let pass = self.decide_two_way(root, &state, strategy, |_| Resolution::Skip)?;
+
+        let mut plan = SyncPlan::default();
+        for (name, resolution) in &pass.resolutions {
+            let remote = || pass.remote[name].length;
+            let local = || pass.local[name].length;
+            let download = match pass.local.contains_key(name) {
+                true => Change::Overwrite,
+                false => Change::Download,
+            };
+            match resolution {
+                Resolution::KeepRemote => plan.push(name, download, remote()),
+                Resolution::KeepLocal => plan.push(name, Change::Upload, local()),
+                Resolution::KeepBoth => {
+                    plan.push(&pass.conflict_copy_name(name), Change::Upload, local());
+                    plan.push(name, download, remote());
+                }
+                Resolution::Skip => (),
+            }
+        }
+        for name in &pass.unresolved {
+            plan.push(name, Change::Conflict, 0);
+        }
+        Ok(plan)
+    }
+
+    /// Checks the files in `root` against the hashes of the remote files, without transferring
+    /// any of them. Only files the profile syncs are compared.
+    pub fn verify<P: AsRef<Path>>(&mut self, root: P) -> Result<VerifyReport> {
+        let root = root.as_ref();
+        let ignore = self.ignore_rules(root)?;
+        let remote: Vec<FileInfo> = match self.supports(Capability::Manifest) {
+            true => self.manifest()?.files,
+            false if self.supports(Capability::FileInfo) => self
+                .remote_files(&ignore)?
+                .iter()
+                .map(|summary| self.file_info(&summary.name))
+                .collect::<Result<_>>()?,
+            false => return Err(anyhow!("The server can't tell the hashes of its files")),
+        };
+        let mut remote: Vec<FileInfo> = remote
+            .into_iter()
+            .filter(|file| !ignore.is_ignored(&file.name))
+            .filter(|file| {
+                self.filter.matches(&EntrySummary {
+                    name: file.name.clone(),
+                    length: file.length,
+                    modified: file.modified,
+                })
+            })
+            .collect();
+        remote.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut local = local_summaries(root, &ignore, &self.filter)?;
+
+        let mut report = VerifyReport::default();
+        for file in remote {
+            let summary = match local.remove(&file.name) {
+                Some(summary) => summary,
+                None => {
+                    report.missing.push(file.name);
+                    continue;
+                }
+            };
+            let same = summary.length == file.length && parity::hash_file(parity::safe_join(root, &file.name)?)? == file.hash;
+            match same {
+                true => report.verified += 1,
+                false => report.mismatched.push(file.name),
+            }
+        }
+        report.extra = local.into_keys().collect();
+        report.extra.sort();
+        Ok(report)
+    }
+
+    /// Compares `root` with the remote files and with how both were when last in sync, deciding
+    /// what to do about each file.
+    fn decide_two_way<F: FnMut(&Conflict) -> Resolution>(
+        &mut self,
+        root: &Path,
+        state: &SyncState,
+        strategy: ConflictStrategy,
+        mut ask: F,
+    ) -> Result<TwoWayPass> {
+        if !self.supports(Capability::Upload) {
+            return Err(anyhow!("The server does not accept uploads, so it can't be synced both ways"));
+        }
+        let synced = state.synced()?;
+        let ignore = self.ignore_rules(root)?;
+        let remote: HashMap<String, EntrySummary> =
+            self.remote_files(&ignore)?.into_iter().map(|summary| (summary.name.clone(), summary)).collect();
+        let local = local_summaries(root, &ignore, &self.filter)?;
+
+        // Files recorded as synced before they were excluded are left alone like the others
+        let synced_names = synced.keys().filter(|name| !ignore.is_ignored(name));
+        let names: BTreeSet<&String> = local.keys().chain(remote.keys()).chain(synced_names).collect();
+        let mut resolutions = vec![];
+        let mut conflicts = 0;
+        let mut unresolved = vec![];
+        let mut manifest = None;
+        for name in names {
+            let local_state = local.get(name).map(FileState::from);
+            let remote_state = remote.get(name).map(FileState::from);
+            let resolution = match two_way::plan(name, local_state.as_ref(), remote_state.as_ref(), synced.get(name)) {
+                Action::Nothing => continue,
+                Action::Record => Resolution::Skip,
+                Action::Download => Resolution::KeepRemote,
+                Action::Upload => Resolution::KeepLocal,
+                Action::Conflict(conflict)
+                    if self.same_contents(&conflict, &parity::safe_join(root, name)?, &mut manifest)? =>
+                {
+                    Resolution::Skip
+                }
+                Action::Conflict(conflict) => {
+                    let resolution = conflict.resolve(strategy).unwrap_or_else(|| ask(&conflict));
+                    if resolution == Resolution::Skip {
+                        unresolved.push(conflict.name);
+                        continue;
+                    }
+                    conflicts += 1;
+                    resolution
+                }
+            };
+            resolutions.push((name.clone(), resolution));
+        }
+        // Files recorded as synced that are gone from both sides aren't transferred anyway
+        let gone = EntrySummary::default();
+        let summary = |name: &String| remote.get(name).or_else(|| local.get(name)).unwrap_or(&gone);
+        resolutions.sort_by(|(a, _), (b, _)| self.queue_order.compare(summary(a), summary(b)));
+
+        Ok(TwoWayPass {
+            remote,
+            local,
+            resolutions,
+            conflicts,
+            unresolved,
+        })
+    }
+
+    /// Whether both sides of `conflict` hold the same bytes after all, so there is nothing to
+    /// settle. Only asked of the server for files of the same length, from its `manifest` when it
+    /// has one, which is fetched on first use.
+    fn same_contents(&mut self, conflict: &Conflict, local: &Path, manifest: &mut Option<Manifest>) -> Result<bool> {
+        if conflict.local.length != conflict.remote.length {
+            return Ok(false);
+        }
+        let remote = match self.supports(Capability::Manifest) {
+            true => {
+                if manifest.is_none() {
+                    *manifest = Some(self.manifest()?);
+                }
+                manifest.as_ref().unwrap().get(&conflict.name).map(|file| file.hash.clone())
+            }
+            false if self.supports(Capability::FileInfo) => Some(self.file_info(&conflict.name)?.hash),
+            false => None,
+        };
+        match remote {
+            Some(hash) => Ok(hash == parity::hash_file(local)?),
+            None => Ok(false),
+        }
+    }
+
+    /// Downloads the remote file `summary` into `output`, as little of it as the server allows, and
+    /// gives it the remote modification time. With `resume`, a partial download left in its `.part`
+    /// file is continued rather than started over.
+    fn fetch(&mut self, summary: &EntrySummary, output: &Path, resume: bool) -> Result<()> {
+        let trashed = match &self.trash {
+            Some(trash) if output.is_file() => Some(trash.copy_in(output, &summary.name)?),
+            _ => None,
+        };
+        let result = match summary.length {
+            length if length >= DELTA_THRESHOLD && output.is_file() && self.supports(Capability::Delta) => {
+                Some(self.fetch_delta(&summary.name, output, summary.modified).map(|_| ()))
+            }
+            length if length >= CHUNKED_THRESHOLD && self.supports(Capability::Chunks) => {
+                Some(self.fetch_chunked(&summary.name, output, summary.modified).map(|_| ()))
+            }
+            _ => None,
+        };
+        if let Some(result) = result {
+            self.record(Direction::Download, &summary.name, output, &result);
+            return result;
+        }
+        // A local copy of the same length may only have lost its modification time, such as when
+        // it was copied, which the server can tell without sending it again
+        let same_length = fs::metadata(output).is_ok_and(|metadata| metadata.is_file() && metadata.len() == summary.length);
+        match same_length && self.supports(Capability::Conditional) {
+            true => {
+                if !self.download_if_changed(&summary.name, output)? {
+                    self.summary.skipped += 1;
+                    if let Some(trashed) = trashed {
+                        fs::remove_file(trashed)?;
+                    }
+                }
+            }
+            false => {
+                let result = self.download_resumable(summary, output, resume);
+                self.record(Direction::Download, &summary.name, output, &result);
+                result?;
+            }
+        }
+        if let Some(modified) = summary.modified {
+            File::options()
+                .write(true)
+                .open(output)?
+                .set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+        }
+        Ok(())
+    }
+
+    /// Downloads the remote file `summary` into its `.part` file next to `output`, then moves it
+    /// over `output`. With `resume` and a server that supports it, what the `.part` file already
+    /// holds is kept and only the rest is sent; otherwise the download starts over.
+    fn download_resumable(&mut self, summary: &EntrySummary, output: &Path, resume: bool) -> Result<()> {
+        let partial = partial_path(output);
+        if let Some(parent) = partial.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let name = output
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let offset = match resume && self.supports(Capability::ResumeDownload) {
+            true => fs::metadata(&partial).map(|metadata| metadata.len()).unwrap_or(0),
+            false => 0,
+        };
+        let mut resumed = false;
+        if offset > 0 && offset <= summary.length {
+            self.conn.send_request(&Request::ResumeDownload {
+                name: summary.name.clone(),
+                offset,
+                length: summary.length,
+                modified: summary.modified,
+            })?;
+            match self.conn.read_request_result()?.naturalize() {
+                Ok(_) => {
+                    debug!("Resuming the download of {} from byte {}", summary.name, offset);
+                    self.conn.read_named_file_at(&name, &partial, offset)?;
+                    resumed = true;
+                }
+                Err(e) => debug!("Could not resume the download of {}: {}", summary.name, e),
+            }
+        }
+        if !resumed {
+            self.conn.send_request(&Request::DownloadFileByName(summary.name.clone()))?;
+            self.conn.read_request_result()?.naturalize()?;
+            self.conn.read_named_file_at(&name, &partial, 0)?;
+        }
+        self.authenticated(&summary.name, &partial, Ok(()))?;
+        finish_partial(&partial, output, None)
+    }
+
+    /// Measures the round trip to the server, waiting at most `timeout`.
+    pub fn ping(&mut self, timeout: Duration) -> Result<Duration> {
+        self.conn.ping(timeout)
+    }
+
+    pub fn disconnect(mut self) -> Result<()> {
+        self.conn.send_request(&Request::Disconnect)?;
+        Ok(())
+    }
+
+    /// Receives the files streamed in answer to a multi-file download request into `root`, some
+    /// of them in batches when the server sends small files together.
+    fn receive_files(&mut self, root: &Path) -> Result<usize> {
+        self.conn.read_request_result()?.naturalize()?;
+        let count = self.conn.read_count()?;
+        let batching = self.supports(Capability::Batch);
+        let mut received = 0;
+        // Authenticated once the server is done, as that may take asking it for the manifest again
+        let mut unchecked = vec![];
+        while received < count {
+            let group = match batching {
+                true => self.conn.read_count()?,
+                false => 1,
+            };
+            match group {
+                1 => {
+                    let name = self.conn.read_string()?;
+                    let output = self.batch_output(root, &name)?;
+                    let result = self.conn.read_file(&output);
+                    let elapsed = self.conn.take_transfer_stats().map(|stats| stats.elapsed);
+                    if result.is_err() {
+                        self.record_elapsed(Direction::Download, &name, &output, elapsed, &result);
+                        result?;
+                    }
+                    unchecked.push((name, output, elapsed));
+                }
+                _ => {
+                    for file in self.conn.read_batch()? {
+                        let output = self.batch_output(root, &file.name)?;
+                        let result = self.conn.write_batched(&file, &output);
+                        let elapsed = self.conn.take_transfer_stats().map(|stats| stats.elapsed);
+                        if result.is_err() {
+                            self.record_elapsed(Direction::Download, &file.name, &output, elapsed, &result);
+                            result?;
+                        }
+                        unchecked.push((file.name, output, elapsed));
+                    }
+                }
+            }
+            self.conn.send_request_result(RequestResult::Ok)?;
+            received += group.max(1);
+        }
+
+        let mut failure = None;
+        for (name, output, elapsed) in unchecked {
+            let result = self.authenticated(&name, &output, Ok(()));
+            self.record_elapsed(Direction::Download, &name, &output, elapsed, &result);
+            if let Err(e) = result {
+                failure.get_or_insert(e);
+            }
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(count as usize),
+        }
+    }
+
+    /// Where the streamed file `name` goes in `root`, with its directory created.
+    fn batch_output(&self, root: &Path, name: &str) -> Result<PathBuf> {
+        let output = self.destination(parity::safe_join(root, name)?);
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(output)
+    }
+
+    /// Records a transfer of `name`, whose local copy is at `local`, in the history.
+    fn record(&mut self, direction: Direction, name: &str, local: &Path, result: &Result<()>) {
+        let elapsed = self.conn.take_transfer_stats().map(|stats| stats.elapsed);
+        self.record_elapsed(direction, name, local, elapsed, result);
+    }
+
+    /// Like [`Client::record`], for a transfer that took `elapsed`, measured when it was done.
+    fn record_elapsed(&mut self, direction: Direction, name: &str, local: &Path, elapsed: Option<Duration>, result: &Result<()>) {
+        let bytes = fs::metadata(local).map(|metadata| metadata.len()).unwrap_or(0);
+        match result {
+            Ok(_) => {
+                self.summary.transferred += 1;
+                self.summary.bytes += bytes;
+            }
+            Err(_) => self.summary.failed += 1,
+        }
+        let transferred = Transferred { bytes, elapsed };
+        history::record(Side::Client, &self.profile_name, &self.peer, direction, name, transferred, result);
+    }
+}
+
+impl AsMut<Connection> for Client {
+    fn as_mut(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+}
+
+/// Whether the file `result` is about was transferred, or skipped through the client's
+/// [`TransferControl`], which doesn't stop the files after it.
+fn unless_skipped(result: Result<()>) -> Result<bool> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(e) if e.is::<Cancelled>() => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Where a download into `dest` is assembled until it is complete.
+fn partial_path(dest: &Path) -> PathBuf {
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".part");
+    PathBuf::from(partial)
+}
+
+/// Moves a complete download from `partial` to `dest`, giving it the `modified` time first.
+fn finish_partial(partial: &Path, dest: &Path, modified: Option<u64>) -> Result<()> {
+    if let Some(modified) = modified {
+        File::options()
+            .write(true)
+            .open(partial)?
+            .set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+    }
+    fs::rename(partial, dest)?;
+    Ok(())
+}
+
+/// The server address of `profile`, as `host:port`.
+pub fn server_addr(profile: &ClientProfile) -> String {
+    format!("{}:{}", profile.ipv4.get(), profile.port.get())
+}
+
+/// Opens a TCP connection to `host:port`, through the profile's SOCKS5 proxy if it has one.
+fn dial(profile: &ClientProfile, host: &str, port: u16) -> Result<TcpStream> {
+    match profile.socks_proxy.is_set() {
+        true => Proxy::parse(profile.socks_proxy.get())?.connect(host, port),
+        false => Ok(TcpStream::connect((host, port))?),
+    }
+}
+
+/// The files directly in `root` by name that `filter` selects, leaving out downloads still being
+/// assembled and the files `ignore` excludes.
+fn local_summaries(root: &Path, ignore: &IgnoreRules, filter: &EntryQuery) -> Result<HashMap<String, EntrySummary>> {
+    let mut summaries = HashMap::new();
+    for entry in parity::get_file_entries(root.to_path_buf(), ignore)? {
+        if entry.name.ends_with(".part") {
+            continue;
+        }
+        let summary = parity::get_entry_summary(&entry)?;
+        if filter.matches(&summary) {
+            summaries.insert(entry.name.clone(), summary);
+        }
+    }
+    Ok(summaries)
+}
+
+/// Whether downloading into `output` creates a file or replaces one.
+fn download_change(output: &Path) -> Change {
+    match output.exists() {
+        true => Change::Overwrite,
+        false => Change::Download,
+    }
+}
+
+fn is_up_to_date(local: &Path, remote: &EntrySummary) -> bool {
+    let metadata = match fs::metadata(local) {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs());
+    metadata.len() == remote.length && modified == remote.modified
+}
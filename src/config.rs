@@ -1,25 +1,630 @@
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
+use std::time::Duration;
 
+use crate::accounts;
+use crate::hooks::{Event, Hook};
+use crate::limits;
+use crate::logging;
+use crate::secrets::{self, SecretKey};
+use crate::trash::{Trash, TRASH_DIR};
 use crate::validated_values::*;
 use anyhow::{anyhow, Result};
 use directories::{BaseDirs, UserDirs};
 
+/// What clients of a server may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerMode {
+    /// Files can be listed and downloaded, but not uploaded.
+    ReadOnly,
+    /// Files can be listed, downloaded and uploaded.
+    ReadWrite,
+    /// Files can be uploaded, but nothing can be listed or downloaded.
+    DropBox,
+}
+
+impl ServerMode {
+    pub fn key(&self) -> &'static str {
+        match self {
+            ServerMode::ReadOnly => "read-only",
+            ServerMode::ReadWrite => "read-write",
+            ServerMode::DropBox => "drop-box",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Result<Self> {
+        match key {
+            "read-only" => Ok(ServerMode::ReadOnly),
+            "read-write" => Ok(ServerMode::ReadWrite),
+            "drop-box" => Ok(ServerMode::DropBox),
+            _ => Err(anyhow!(format!("Unknown server mode: {}", key))),
+        }
+    }
+
+    /// The mode after this one, for cycling through them.
+    pub fn next(&self) -> Self {
+        match self {
+            ServerMode::ReadOnly => ServerMode::ReadWrite,
+            ServerMode::ReadWrite => ServerMode::DropBox,
+            ServerMode::DropBox => ServerMode::ReadOnly,
+        }
+    }
+}
+
+/// What an auto-sync pass of a client does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Remote files are downloaded, local changes are left alone.
+    Download,
+    /// Changes are copied both ways (see: [`crate::two_way`]).
+    TwoWay,
+}
+
+impl SyncMode {
+    pub fn key(&self) -> &'static str {
+        match self {
+            SyncMode::Download => "download",
+            SyncMode::TwoWay => "two-way",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Result<Self> {
+        match key {
+            "download" => Ok(SyncMode::Download),
+            "two-way" => Ok(SyncMode::TwoWay),
+            _ => Err(anyhow!(format!("Unknown sync mode: {}", key))),
+        }
+    }
+
+    /// The mode after this one, for cycling through them.
+    pub fn next(&self) -> Self {
+        match self {
+            SyncMode::Download => SyncMode::TwoWay,
+            SyncMode::TwoWay => SyncMode::Download,
+        }
+    }
+}
+
+/// How a two-way sync settles a file that changed on both sides since they were last in sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// The side modified last replaces the other.
+    NewestWins,
+    /// The local version is kept under another name on both sides, next to the remote one.
+    KeepBoth,
+    /// The user decides, and unattended syncs leave the file alone.
+    Ask,
+}
+
+impl ConflictStrategy {
+    pub fn key(&self) -> &'static str {
+        match self {
+            ConflictStrategy::NewestWins => "newest-wins",
+            ConflictStrategy::KeepBoth => "keep-both",
+            ConflictStrategy::Ask => "ask",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Result<Self> {
+        match key {
+            "newest-wins" => Ok(ConflictStrategy::NewestWins),
+            "keep-both" => Ok(ConflictStrategy::KeepBoth),
+            "ask" => Ok(ConflictStrategy::Ask),
+            _ => Err(anyhow!(format!("Unknown conflict strategy: {}", key))),
+        }
+    }
+
+    /// The strategy after this one, for cycling through them.
+    pub fn next(&self) -> Self {
+        match self {
+            ConflictStrategy::NewestWins => ConflictStrategy::KeepBoth,
+            ConflictStrategy::KeepBoth => ConflictStrategy::Ask,
+            ConflictStrategy::Ask => ConflictStrategy::NewestWins,
+        }
+    }
+}
+
+/// What listings of a parity root do about symlinks. Symlinks leading outside of the parity root
+/// are refused whatever the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Symlinks are left out as if they weren't there.
+    Skip,
+    /// Symlinks are listed as the files they point to.
+    #[default]
+    Follow,
+    /// Listing a directory with a symlink in it fails.
+    Error,
+}
+
+impl SymlinkPolicy {
+    pub fn key(&self) -> &'static str {
+        match self {
+            SymlinkPolicy::Skip => "skip",
+            SymlinkPolicy::Follow => "follow",
+            SymlinkPolicy::Error => "error",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Result<Self> {
+        match key {
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "error" => Ok(SymlinkPolicy::Error),
+            _ => Err(anyhow!(format!("Unknown symlink policy: {}", key))),
+        }
+    }
+
+    /// The policy after this one, for cycling through them.
+    pub fn next(&self) -> Self {
+        match self {
+            SymlinkPolicy::Skip => SymlinkPolicy::Follow,
+            SymlinkPolicy::Follow => SymlinkPolicy::Error,
+            SymlinkPolicy::Error => SymlinkPolicy::Skip,
+        }
+    }
+}
+
+/// What downloads do when the file they write to is there already. Syncs always update files in
+/// place, as keeping both would copy every file that changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CollisionPolicy {
+    /// The existing file is replaced.
+    #[default]
+    Overwrite,
+    /// The download is written next to it as `name (1).ext` (see: [`crate::parity::keep_both_path`]).
+    KeepBoth,
+}
+
+impl CollisionPolicy {
+    pub fn key(&self) -> &'static str {
+        match self {
+            CollisionPolicy::Overwrite => "overwrite",
+            CollisionPolicy::KeepBoth => "keep-both",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Result<Self> {
+        match key {
+            "overwrite" => Ok(CollisionPolicy::Overwrite),
+            "keep-both" => Ok(CollisionPolicy::KeepBoth),
+            _ => Err(anyhow!(format!("Unknown collision policy: {}", key))),
+        }
+    }
+
+    /// The policy after this one, for cycling through them.
+    pub fn next(&self) -> Self {
+        match self {
+            CollisionPolicy::Overwrite => CollisionPolicy::KeepBoth,
+            CollisionPolicy::KeepBoth => CollisionPolicy::Overwrite,
+        }
+    }
+}
+
+/// What order syncs download files in, after those pinned by [`ClientProfile::priority`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DownloadOrder {
+    /// As the server lists them.
+    #[default]
+    Listed,
+    /// Smallest first, so many files arrive quickly.
+    SmallestFirst,
+    /// Most recently modified first.
+    NewestFirst,
+}
+
+impl DownloadOrder {
+    pub fn key(&self) -> &'static str {
+        match self {
+            DownloadOrder::Listed => "listed",
+            DownloadOrder::SmallestFirst => "smallest-first",
+            DownloadOrder::NewestFirst => "newest-first",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Result<Self> {
+        match key {
+            "listed" => Ok(DownloadOrder::Listed),
+            "smallest-first" => Ok(DownloadOrder::SmallestFirst),
+            "newest-first" => Ok(DownloadOrder::NewestFirst),
+            _ => Err(anyhow!(format!("Unknown download order: {}", key))),
+        }
+    }
+
+    /// The order after this one, for cycling through them.
+    pub fn next(&self) -> Self {
+        match self {
+            DownloadOrder::Listed => DownloadOrder::SmallestFirst,
+            DownloadOrder::SmallestFirst => DownloadOrder::NewestFirst,
+            DownloadOrder::NewestFirst => DownloadOrder::Listed,
+        }
+    }
+}
+
+/// What the files of a parity root are listed by, which also decides what
+/// [`crate::request::Request::DownloadFileByIndex`] indices refer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    pub fn key(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Result<Self> {
+        match key {
+            "name" => Ok(SortKey::Name),
+            "size" => Ok(SortKey::Size),
+            "modified" => Ok(SortKey::Modified),
+            _ => Err(anyhow!(format!("Unknown sort key: {}", key))),
+        }
+    }
+
+    /// The key after this one, for cycling through them.
+    pub fn next(&self) -> Self {
+        match self {
+            SortKey::Name => SortKey::Size,
+            SortKey::Size => SortKey::Modified,
+            SortKey::Modified => SortKey::Name,
+        }
+    }
+}
+
+/// Someone allowed to log into a server, with their own parity root and permissions.
+#[derive(Debug, Clone)]
+pub struct UserAccount {
+    pub name: String,
+    pub salt: String,
+    /// See: [`crate::accounts::hash_password`].
+    pub password_hash: String,
+    /// The PBKDF2 rounds `password_hash` took, [`accounts::LEGACY_ROUNDS`] for accounts whose
+    /// password wasn't reset since they were hashed with SHA-256 alone.
+    pub rounds: u32,
+    pub parity_root: ValidatedDirectory,
+    pub mode: ServerMode,
+    /// Overrides [`ServerProfile::max_upload_size`] for this user, zero to keep the profile's.
+    pub max_upload_size: u64,
+    /// Overrides [`ServerProfile::upload_quota`] for this user's uploads, zero to keep the
+    /// profile's.
+    pub upload_quota: u64,
+}
+
+impl UserAccount {
+    pub fn new<S: ToString, T: ToString>(name: S, password: &str, parity_root: T, mode: ServerMode) -> Result<Self> {
+        let salt = accounts::new_salt()?;
+        Ok(Self {
+            name: name.to_string(),
+            password_hash: accounts::hash_password(&salt, password, accounts::PASSWORD_ROUNDS),
+            rounds: accounts::PASSWORD_ROUNDS,
+            salt,
+            parity_root: ValidatedDirectory::new(parity_root.to_string()),
+            mode,
+            max_upload_size: 0,
+            upload_quota: 0,
+        })
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        accounts::verify_password(&self.salt, password, self.rounds, &self.password_hash)
+    }
+
+    /// Whether the password is hashed more weakly than new ones are, until it is reset.
+    pub fn needs_rehash(&self) -> bool {
+        self.rounds < accounts::PASSWORD_ROUNDS
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ServerProfile {
     pub name: String,
+    /// Free-form text for whoever manages the profile, never used by the server.
+    pub notes: String,
+    /// Labels the profile list can be narrowed to (see: [`has_tag`]).
+    pub tags: Vec<String>,
     pub parity_root: ValidatedDirectory,
     pub port: ValidatedPort,
     pub mask: ValidatedIPv4,
+    /// Whether the parity root is watched so subscribed clients are pushed changes.
+    pub watch: bool,
+    /// Whether the server announces itself on the local network over mDNS.
+    pub advertise: bool,
+    /// Whether the server asks the local router to forward its port from the internet.
+    pub port_mapping: bool,
+    /// Ranges clients must connect from. Empty lets everyone in.
+    pub allow: ValidatedCidrList,
+    /// Ranges clients are refused from, whether or not they are allowed.
+    pub deny: ValidatedCidrList,
+    /// How many connections one IP may hold open at once, zero for unlimited.
+    pub max_connections_per_ip: ValidatedLimit,
+    /// How many requests one IP may send per minute, zero for unlimited.
+    pub max_requests_per_minute: ValidatedLimit,
+    /// How many seconds a client gets to complete the handshake and send its first request, zero
+    /// for no limit.
+    pub first_request_timeout: ValidatedLimit,
+    /// Bytes per second transfers are dropped below (see: [`crate::limits::MinimumRate`]), zero
+    /// for no limit.
+    pub min_transfer_rate: ValidatedLimit,
+    pub mode: ServerMode,
+    /// Directory uploads are stored in instead of the parity root, empty to use the parity root.
+    pub upload_root: ValidatedOptionalDirectory,
+    /// Largest file clients may upload in bytes, zero for unlimited.
+    pub max_upload_size: ValidatedLimit,
+    /// How many bytes the upload root (or the parity root without one) may hold once an upload
+    /// is stored, zero for unlimited.
+    pub upload_quota: ValidatedLimit,
+    /// Whether uploads replacing a file keep the previous version (see: [`crate::versions`]).
+    pub versioning: bool,
+    /// When not empty, clients must log in as one of these users.
+    pub users: Vec<UserAccount>,
+    /// Secret connections are encrypted with (see: [`crate::psk`]), empty to not encrypt them.
+    /// Clients must have the same one.
+    pub shared_secret: String,
+    /// Hex-encoded Ed25519 key the manifest is signed with (see: [`crate::signing`]), empty to not
+    /// sign it.
+    pub signing_key: String,
+    /// User the server switches to once its port is bound (see: [`crate::privileges`]), empty to
+    /// keep running as whoever started it.
+    pub run_as: ValidatedRunAs,
+    /// Whether the server also confines itself to the parity root when it switches users. The
+    /// config directory is out of reach from there, so hooks, share links and log rotation can't
+    /// be used along with it, and the transfer history isn't recorded.
+    pub chroot: bool,
+    pub hooks: Vec<Hook>,
+    /// Port Prometheus metrics are served on, zero to not serve them.
+    pub metrics_port: ValidatedOptionalPort,
+    /// Port the parity root is also served on over plain HTTP, zero to not serve it.
+    pub http_port: ValidatedOptionalPort,
+    /// Port the protocol is also spoken on over WebSocket, zero to not listen for WebSockets.
+    pub ws_port: ValidatedOptionalPort,
+    /// Bucket files are served from instead of the parity root, empty to serve the parity root.
+    pub storage: ValidatedStorage,
+    /// Files that are never listed or served (see: [`crate::ignore`]).
+    pub exclude: ValidatedPatternList,
+    /// Whether files and directories whose name starts with a `.` are listed and served.
+    pub hidden_files: bool,
+    pub symlinks: SymlinkPolicy,
+    pub sort_by: SortKey,
+    pub sort_descending: bool,
+    /// File the server logs to while it runs, empty to only log to the terminal.
+    pub log_file: ValidatedLogFile,
+    /// Size in bytes after which the log file is rotated, zero for no limit.
+    pub log_max_size: ValidatedLimit,
+    /// Seconds after which the log file is rotated, zero for no limit.
+    pub log_max_age: ValidatedLimit,
+    /// How many rotated log files are kept, as `<log file>.1` (the newest) and so on.
+    pub log_retention: ValidatedLimit,
 }
 
 #[derive(Debug, Clone)]
 pub struct ClientProfile {
     pub name: String,
+    /// Free-form text for whoever manages the profile, never used by the client.
+    pub notes: String,
+    /// Labels the profile list can be narrowed to (see: [`has_tag`]).
+    pub tags: Vec<String>,
     pub parity_root: ValidatedDirectory,
     pub port: ValidatedPort,
     pub ipv4: ValidatedIPv4,
+    /// Seconds between two passes of the auto-sync daemon.
+    pub sync_interval: ValidatedSeconds,
+    /// When the auto-sync daemon syncs instead, such as `daily 03:00` (see: [`crate::schedule`]).
+    /// Empty to sync every `sync_interval`.
+    pub schedule: ValidatedSchedule,
+    /// How fast transfers may go, by time of day (see: [`crate::bandwidth`]). Empty for unlimited.
+    pub bandwidth: ValidatedBandwidth,
+    pub sync_mode: SyncMode,
+    /// How two-way syncs settle conflicts.
+    pub conflicts: ConflictStrategy,
+    /// Whether download syncs remove local files that are no longer on the server, so the parity
+    /// root mirrors it rather than collecting everything it ever had.
+    pub mirror_deletions: bool,
+    /// Whether syncs move the files they replace or remove into the trash instead of destroying
+    /// them (see: [`crate::trash`]).
+    pub keep_previous: bool,
+    /// Where the trash is, empty for [`TRASH_DIR`] in the parity root.
+    pub trash_dir: ValidatedOptionalDirectory,
+    /// Seconds files stay in the trash, zero to keep them until they're removed by hand.
+    pub trash_max_age: ValidatedLimit,
+    /// Files that are never synced either way (see: [`crate::ignore`]).
+    pub exclude: ValidatedPatternList,
+    /// Whether files and directories whose name starts with a `.` are synced.
+    pub hidden_files: bool,
+    pub symlinks: SymlinkPolicy,
+    /// What downloads do about files that are there already.
+    pub collisions: CollisionPolicy,
+    /// What syncs are limited to, as an [`crate::parity::EntryQuery`]. Empty to sync everything.
+    pub filter: ValidatedQuery,
+    /// What order syncs download files in.
+    pub download_order: DownloadOrder,
+    /// Patterns of files syncs download before any other, those matching earlier patterns first
+    /// (see: [`crate::queue`]).
+    pub priority: ValidatedPatternList,
+    /// `user@host[:port]` to tunnel the connection through over SSH, empty to connect directly.
+    pub ssh_jump: ValidatedSshJump,
+    /// SOCKS5 proxy to connect through, empty to connect directly.
+    pub socks_proxy: ValidatedProxy,
+    /// User to log into the server as, empty to connect anonymously.
+    pub user: String,
+    pub password: String,
+    /// Secret the connection is encrypted with (see: [`crate::psk`]), the same as the server's.
+    /// Empty to not encrypt it.
+    pub shared_secret: String,
+    /// The server's public key, which downloads are checked against when set (see:
+    /// [`crate::signing`]).
+    pub server_public_key: ValidatedPublicKey,
+    pub hooks: Vec<Hook>,
+    /// When a connection test last got through to the server, in seconds since the Unix epoch.
+    pub last_successful_connection: Option<u64>,
+    /// Whether files go into a directory named after the server inside the parity root, so
+    /// profiles of different servers can share one parity root without mixing their files.
+    pub per_server_dir: bool,
+}
+
+impl ClientProfile {
+    /// Everything wrong with the profile, one sentence each prefixed with the field it is about.
+    /// The client may not be started while there is anything.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let fields: [(&str, Result<()>); 12] = [
+            ("Parity root", self.parity_root.is_valid().and_then(|_| writable(self.parity_root.get()))),
+            ("Port", self.port.is_valid()),
+            ("IPv4", self.ipv4.is_valid()),
+            ("SSH jump", self.ssh_jump.is_valid()),
+            ("SOCKS5 proxy", self.socks_proxy.is_valid()),
+            ("Trash directory", self.trash_dir.is_valid()),
+            ("Exclusions", self.exclude.is_valid()),
+            ("Sync filter", self.filter.is_valid()),
+            ("Priority", self.priority.is_valid()),
+            ("Sync schedule", self.schedule.is_valid()),
+            ("Bandwidth limit", self.bandwidth.is_valid()),
+            ("Server public key", self.server_public_key.is_valid()),
+        ];
+        for (field, result) in fields {
+            if let Err(e) = result {
+                problems.push(format!("{}: {}.", field, e));
+            }
+        }
+        problems
+    }
+
+    /// Where the profile's files are kept locally: the parity root, or the server's directory in
+    /// it with [`ClientProfile::per_server_dir`] on.
+    pub fn local_root(&self) -> PathBuf {
+        let root = PathBuf::from(self.parity_root.get());
+        match self.per_server_dir {
+            true => root.join(self.server_dir_name()),
+            false => root,
+        }
+    }
+
+    /// Where syncs keep what they replace or remove, `None` when they destroy it.
+    pub fn trash(&self) -> Option<Trash> {
+        if !self.keep_previous {
+            return None;
+        }
+        let dir = match self.trash_dir.is_set() {
+            true => PathBuf::from(self.trash_dir.get()),
+            false => self.local_root().join(TRASH_DIR),
+        };
+        let max_age = match *self.trash_max_age.get() {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        Some(Trash::new(dir, max_age))
+    }
+
+    /// The server's host, with its port unless that's the default one, made safe as a directory
+    /// name: `files.example.com` or `192.168.1.20_50000`.
+    pub fn server_dir_name(&self) -> String {
+        let host: String = self
+            .ipv4
+            .get()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+            .collect();
+        match *self.port.get() {
+            crate::server::DEFAULT_PORT => host,
+            port => format!("{}_{}", host, port),
+        }
+    }
+}
+
+impl ServerProfile {
+    /// Everything wrong with the profile, one sentence each prefixed with the field it is about.
+    /// The server may not be started while there is anything.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        // A bucket is served instead of the parity root, which then only has to exist
+        let parity_root = match self.storage.is_set() {
+            true => self.parity_root.is_valid(),
+            false => self.parity_root.is_valid().and_then(|_| readable(self.parity_root.get())),
+        };
+        let upload_root = match self.upload_root.is_set() {
+            true => self.upload_root.is_valid().and_then(|_| writable(self.upload_root.get())),
+            false => Ok(()),
+        };
+        let signing_key = match self.signing_key.is_empty() {
+            true => Ok(()),
+            false => crate::signing::public_key(&self.signing_key).map(|_| ()),
+        };
+        let fields: [(&str, Result<()>); 10] = [
+            ("Parity root", parity_root),
+            ("Port", self.port.is_valid()),
+            ("Mask", self.mask.is_valid()),
+            ("Upload root", upload_root),
+            ("Allowlist", self.allow.is_valid()),
+            ("Denylist", self.deny.is_valid()),
+            ("Exclusions", self.exclude.is_valid()),
+            ("Storage", self.storage.is_valid().and_then(|_| crate::server::validate_storage(self))),
+            ("Signing key", signing_key),
+            ("Run as", self.run_as.is_valid().and_then(|_| crate::server::validate_privileges(self))),
+        ];
+        for (field, result) in fields {
+            if let Err(e) = result {
+                problems.push(format!("{}: {}.", field, e));
+            }
+        }
+
+        let optional_ports = [
+            ("Metrics port", &self.metrics_port),
+            ("HTTP gateway port", &self.http_port),
+            ("WebSocket port", &self.ws_port),
+        ];
+        for (i, (label, port)) in optional_ports.iter().enumerate() {
+            if let Err(e) = port.is_valid() {
+                problems.push(format!("{}: {}.", label, e));
+            } else if port.is_set()
+                && (port.get() == self.port.get() || optional_ports[..i].iter().any(|(_, other)| other.get() == port.get()))
+            {
+                problems.push(format!("{}: already used by another listener.", label));
+            }
+        }
+
+        if let Err(e) = self.log_file.is_valid() {
+            problems.push(format!("Log file: {}.", e));
+        }
+        problems
+    }
+}
+
+/// What a check of every profile in a config file found wrong with one of them.
+#[derive(Debug, Clone)]
+pub struct ProfileCheck {
+    pub name: String,
+    /// See: [`ClientProfile::problems`] and [`ServerProfile::problems`]. A profile that could not
+    /// be read has that as its only problem.
+    pub problems: Vec<String>,
+}
+
+impl ProfileCheck {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Fails unless the directory `path` can be listed.
+fn readable(path: &str) -> Result<()> {
+    fs::read_dir(path).map_err(|e| anyhow!(format!("Not readable ({})", e)))?;
+    Ok(())
+}
+
+/// Fails unless files can be written into the directory `path`.
+fn writable(path: &str) -> Result<()> {
+    readable(path)?;
+    match fs::metadata(path)?.permissions().readonly() {
+        true => Err(anyhow!("Not writable")),
+        false => Ok(()),
+    }
 }
 
 #[inline]
@@ -62,6 +667,15 @@ pub fn config_dir_ext<S: AsRef<str>>(ext: S) -> Result<PathBuf> {
     Ok(path)
 }
 
+/// The placeholders [`fill_path_placeholders`] expands at the start of a path, and what they stand for.
+pub const PATH_PLACEHOLDERS: [(&str, &str); 5] = [
+    ("~", "Your home directory"),
+    ("{home}", "Your home directory"),
+    ("{config}", "The local config directory"),
+    ("{appdata}", "The application data directory"),
+    ("{download}", "Your downloads directory"),
+];
+
 struct PathPlaceholderReplacer(String);
 
 impl PathPlaceholderReplacer {
@@ -83,6 +697,22 @@ pub fn fill_path_placeholders(string_path: String) -> Result<String> {
     Ok(ppr.0)
 }
 
+/// The tags in the comma-separated `input`, trimmed and without duplicates, e.g. `work, backups`.
+pub fn parse_tags(input: &str) -> Vec<String> {
+    let mut tags: Vec<String> = vec![];
+    for tag in input.split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+        if !tags.iter().any(|other| other.eq_ignore_ascii_case(tag)) {
+            tags.push(tag.to_string());
+        }
+    }
+    tags
+}
+
+/// Whether `tags` holds `tag`, ignoring case.
+pub fn has_tag(tags: &[String], tag: &str) -> bool {
+    tags.iter().any(|other| other.eq_ignore_ascii_case(tag.trim()))
+}
+
 
 
 pub(self) mod json_help {
@@ -158,6 +788,65 @@ pub(self) mod json_help {
             .ok_or(anyhow!("Could not interpret value as u16"))?)
     }
 
+    #[inline]
+    pub fn object_get_u64_or<S: AsRef<str>>(object: &Object, key: S, default: u64) -> Result<u64> {
+        match object.get(key.as_ref()) {
+            None => Ok(default),
+            Some(value) => Ok(value
+                .as_u64()
+                .ok_or(anyhow!("Could not interpret value as u64"))?),
+        }
+    }
+
+    /// Like the other getters, but falls back to `default` when the key is missing, so that
+    /// settings added after a config file was created don't invalidate it.
+    #[inline]
+    pub fn object_get_bool_or<S: AsRef<str>>(object: &Object, key: S, default: bool) -> Result<bool> {
+        match object.get(key.as_ref()) {
+            None => Ok(default),
+            Some(value) => Ok(value
+                .as_bool()
+                .ok_or(anyhow!("Could not interpret value as bool"))?),
+        }
+    }
+
+    #[inline]
+    pub fn object_get_str_or<'a, S: AsRef<str>>(object: &'a Object, key: S, default: &'a str) -> Result<&'a str> {
+        match object.get(key.as_ref()) {
+            None => Ok(default),
+            Some(value) => Ok(value
+                .as_str()
+                .ok_or(anyhow!("Could not interpret value as str"))?),
+        }
+    }
+
+    /// Like [`object_get_object`], but `None` when the key is missing.
+    #[inline]
+    pub fn object_get_optional_object<S: AsRef<str>>(object: &Object, key: S) -> Result<Option<&Object>> {
+        match object.get(key.as_ref()) {
+            None => Ok(None),
+            Some(_) => Ok(Some(object_get_object(object, key)?)),
+        }
+    }
+
+    /// Reads an array of strings, falling back to an empty list when the key is missing.
+    #[inline]
+    pub fn object_get_str_list_or_empty<S: AsRef<str>>(object: &Object, key: S) -> Result<Vec<String>> {
+        match object.get(key.as_ref()) {
+            None => Ok(vec![]),
+            Some(JsonValue::Array(values)) => values
+                .iter()
+                .map(|value| {
+                    value
+                        .as_str()
+                        .map(str::to_string)
+                        .ok_or(anyhow!("Could not interpret value as str"))
+                })
+                .collect(),
+            Some(_) => Err(anyhow!(format!("Expected key '{}' to be of type Array.", key.as_ref()))),
+        }
+    }
+
     #[inline]
     pub fn object_get_str<S: AsRef<str>>(object: &Object, key: S) -> Result<&str> {
         let value = get_object_key(object, key)?;
@@ -195,7 +884,7 @@ pub(self) mod common {
             .write(true)
             .truncate(true)
             .open(config_file_path)?;
-        file.write(data)?;
+        file.write_all(data)?;
         Ok(())
     }
 
@@ -215,10 +904,35 @@ pub(self) mod common {
         Ok(profile_names)
     }
 
+    /// Removes a profile, keeping it in the `deleted` section of the config file until it is put
+    /// back (see: [`restore_profile`]) or another profile of the same name is erased.
     pub fn erase_profile<S: AsRef<str>, T: AsRef<str>>(ext: S, profile_name: T) -> Result<()> {
         let mut root = json_help::config_root_object(ext.as_ref())?;
         let profiles = json_help::object_get_mut_object(&mut root, "profiles")?;
-        profiles.remove(profile_name.as_ref());
+        let profile = profiles
+            .remove(profile_name.as_ref())
+            .ok_or(anyhow!(format!("Profile '{}' does not exist", profile_name.as_ref())))?;
+        if root.get("deleted").is_none() {
+            root.insert("deleted", json::JsonValue::new_object());
+        }
+        json_help::object_get_mut_object(&mut root, "deleted")?.insert(profile_name.as_ref(), profile);
+        overwrite_config_file(ext, root.dump().as_bytes())?;
+        Ok(())
+    }
+
+    /// Puts back a profile removed with [`erase_profile`].
+    pub fn restore_profile<S: AsRef<str>, T: AsRef<str>>(ext: S, profile_name: T) -> Result<()> {
+        let name = profile_name.as_ref();
+        let mut root = json_help::config_root_object(ext.as_ref())?;
+        if json_help::object_get_object(&root, "profiles")?.get(name).is_some() {
+            return Err(anyhow!(format!("Profile '{}' already exists", name)));
+        }
+        let profile = match root.get_mut("deleted") {
+            Some(json::JsonValue::Object(deleted)) => deleted.remove(name),
+            _ => None,
+        };
+        let profile = profile.ok_or(anyhow!(format!("Profile '{}' was not erased", name)))?;
+        json_help::object_get_mut_object(&mut root, "profiles")?.insert(name, profile);
         overwrite_config_file(ext, root.dump().as_bytes())?;
         Ok(())
     }
@@ -229,21 +943,136 @@ pub(self) mod common {
         if let Some(_) = profiles.get(new_name.as_ref()) {
             return Err(anyhow!(format!("Profile '{}' already exists", new_name.as_ref())));
         }
-        let profile = json_help::object_get_object(&profiles, profile_name.to_string().clone())?.clone();
+        let profile = json_help::object_get_object(profiles, profile_name.to_string().clone())?.clone();
         profiles.insert(new_name.as_ref(), json::JsonValue::Object(profile));
         profiles.remove(&profile_name.to_string());
         overwrite_config_file(ext, root.dump().as_bytes())?;
         Ok(())
     }
 
+    /// The salt of the `encryption` section of a config file, `None` when secrets are stored in
+    /// plain text.
+    pub fn encryption_salt(root: &json::object::Object) -> Result<Option<String>> {
+        match json_help::object_get_optional_object(root, "encryption")? {
+            Some(encryption) => Ok(Some(json_help::object_get_str(encryption, "salt")?.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks `passphrase` against the `encryption` section of a config file, holding the key it
+    /// makes for the session when it's right.
+    pub fn unlock_secrets<S: AsRef<str>>(ext: S, passphrase: &str) -> Result<()> {
+        let root = json_help::config_root_object(ext)?;
+        let encryption = json_help::object_get_object(&root, "encryption")?;
+        let key = SecretKey::derive(passphrase, json_help::object_get_str(encryption, "salt")?)?;
+        if key.decrypt(json_help::object_get_str(encryption, "check")?)? != secrets::CHECK_TEXT {
+            return Err(anyhow!("Wrong passphrase"));
+        }
+        secrets::set_session_key(Some(key));
+        Ok(())
+    }
+
+    /// Writes a new `encryption` section for `passphrase` and holds its key for the session, or
+    /// removes the section with `None`. Profiles are left as they are, to be saved again.
+    pub fn set_encryption<S: AsRef<str>>(ext: S, passphrase: Option<&str>) -> Result<()> {
+        let mut root = json_help::config_root_object(ext.as_ref())?;
+        let key = match passphrase {
+            Some(passphrase) => {
+                let salt = secrets::new_salt()?;
+                let key = SecretKey::derive(passphrase, &salt)?;
+                root.insert("encryption", json::object! {
+                    "salt": salt,
+                    "check": key.encrypt(secrets::CHECK_TEXT)?,
+                });
+                Some(key)
+            }
+            None => {
+                root.remove("encryption");
+                None
+            }
+        };
+        overwrite_config_file(ext, root.dump().as_bytes())?;
+        secrets::set_session_key(key);
+        Ok(())
+    }
+
+    /// Reads the secret `key` of a profile, opening it with the session key when it's encrypted.
+    pub fn get_secret(profile_object: &json::object::Object, key: &str) -> Result<String> {
+        let value = json_help::object_get_str_or(profile_object, key, "")?;
+        if !secrets::is_encrypted(value) {
+            return Ok(value.to_string());
+        }
+        secrets::session_key()
+            .ok_or(anyhow!("Its secrets are encrypted, and no passphrase was given"))?
+            .decrypt(value)
+    }
+
+    /// A secret as stored in a config file, sealed with the session key when `encrypted`.
+    pub fn secret_json(value: &str, encrypted: bool) -> Result<json::JsonValue> {
+        if !encrypted || value.is_empty() {
+            return Ok(json::JsonValue::String(value.to_string()));
+        }
+        let key = secrets::session_key().ok_or(anyhow!("Secrets are encrypted, and no passphrase was given"))?;
+        Ok(json::JsonValue::String(key.encrypt(value)?))
+    }
+
+    /// Reads the `hooks` array of a profile, which is empty when missing.
+    pub fn get_hooks(profile_object: &json::object::Object) -> Result<Vec<Hook>> {
+        let mut hooks = vec![];
+        for hook in profile_object.get("hooks").map(|hooks| hooks.members()).into_iter().flatten() {
+            let event = hook["event"].as_str().ok_or(anyhow!("Expected a hook to have an event"))?;
+            let action = hook["action"].as_str().ok_or(anyhow!("Expected a hook to have an action"))?;
+            hooks.push(Hook {
+                event: Event::from_key(event)?,
+                action: action.to_string(),
+            });
+        }
+        Ok(hooks)
+    }
+
+    pub fn hooks_json(hooks: &[Hook]) -> json::JsonValue {
+        let mut array = json::JsonValue::new_array();
+        for hook in hooks {
+            let _ = array.push(json::object! {
+                "event": hook.event.key(),
+                "action": hook.action.clone(),
+            });
+        }
+        array
+    }
+
+    /// Reads a profile, with the values it leaves out taken from the `defaults` section of the
+    /// config file (see: [`get_defaults`]).
     pub fn get_profile_object<S: AsRef<str>, T: AsRef<str>>(
         ext: S,
         profile_name: T,
     ) -> Result<json::object::Object> {
         let root = json_help::config_root_object(ext)?;
         let profiles = json_help::object_get_object(&root, "profiles")?;
-        let profile = json_help::object_get_object(&profiles, profile_name.as_ref())?;
-        Ok(profile.clone())
+        let mut profile = json_help::object_get_object(profiles, profile_name.as_ref())?.clone();
+        for (key, value) in get_defaults(&root)?.iter() {
+            if profile.get(key).is_none() {
+                profile.insert(key, value.clone());
+            }
+        }
+        Ok(profile)
+    }
+
+    /// The `defaults` section of a config file, holding values every profile inherits unless it
+    /// sets its own, such as `{ "port": 50000, "bandwidth": "1M" }`. Empty when there is none.
+    pub fn get_defaults(root: &json::object::Object) -> Result<json::object::Object> {
+        Ok(json_help::object_get_optional_object(root, "defaults")?.cloned().unwrap_or_else(json::object::Object::new))
+    }
+
+    /// Leaves out the values of the profile `data` that are the same as in `defaults`, so the
+    /// profile keeps inheriting them when the defaults change.
+    pub fn strip_defaults(mut data: json::JsonValue, defaults: &json::object::Object) -> json::JsonValue {
+        for (key, value) in defaults.iter() {
+            if data[key] == *value {
+                data.remove(key);
+            }
+        }
+        data
     }
 }
 
@@ -282,25 +1111,146 @@ pub mod server {
         let parity_root = ValidatedDirectory::new(path);
         let port = ValidatedPort::new(json_help::object_get_u16(&profile_object, "port")?);
         let mask = ValidatedIPv4::new(json_help::object_get_str(&profile_object, "mask")?.into());
+        let watch = json_help::object_get_bool_or(&profile_object, "watch", false)?;
+        let advertise = json_help::object_get_bool_or(&profile_object, "advertise", true)?;
+        let port_mapping = json_help::object_get_bool_or(&profile_object, "port_mapping", false)?;
+        let allow = ValidatedCidrList::new(json_help::object_get_str_list_or_empty(&profile_object, "allow")?.join(", "));
+        let deny = ValidatedCidrList::new(json_help::object_get_str_list_or_empty(&profile_object, "deny")?.join(", "));
+        let max_connections_per_ip =
+            ValidatedLimit::new(json_help::object_get_u64_or(&profile_object, "max_connections_per_ip", 0)?);
+        let max_requests_per_minute =
+            ValidatedLimit::new(json_help::object_get_u64_or(&profile_object, "max_requests_per_minute", 0)?);
+        let first_request_timeout = ValidatedLimit::new(json_help::object_get_u64_or(
+            &profile_object,
+            "first_request_timeout",
+            limits::DEFAULT_FIRST_REQUEST_TIMEOUT,
+        )?);
+        let min_transfer_rate = ValidatedLimit::new(json_help::object_get_u64_or(&profile_object, "min_transfer_rate", 0)?);
+        let mode = ServerMode::from_key(json_help::object_get_str_or(&profile_object, "mode", "read-only")?)?;
+        let upload_root = ValidatedOptionalDirectory::new(fill_path_placeholders(
+            json_help::object_get_str_or(&profile_object, "upload_root", "")?.to_string(),
+        )?);
+
+        let mut users = vec![];
+        if let Some(users_object) = json_help::object_get_optional_object(&profile_object, "users")? {
+            for (name, _) in users_object.iter() {
+                let user_object = json_help::object_get_object(users_object, name)?;
+                users.push(UserAccount {
+                    name: name.to_string(),
+                    salt: json_help::object_get_str(user_object, "salt")?.to_string(),
+                    password_hash: json_help::object_get_str(user_object, "password_hash")?.to_string(),
+                    rounds: json_help::object_get_u64_or(user_object, "rounds", accounts::LEGACY_ROUNDS as u64)? as u32,
+                    parity_root: ValidatedDirectory::new(fill_path_placeholders(
+                        json_help::object_get_str(user_object, "parity_root")?.to_string(),
+                    )?),
+                    mode: ServerMode::from_key(json_help::object_get_str_or(user_object, "mode", "read-only")?)?,
+                    max_upload_size: json_help::object_get_u64_or(user_object, "max_upload_size", 0)?,
+                    upload_quota: json_help::object_get_u64_or(user_object, "upload_quota", 0)?,
+                });
+            }
+        }
 
         let profile = ServerProfile {
             name: profile_name.as_ref().to_string(),
+            notes: json_help::object_get_str_or(&profile_object, "notes", "")?.to_string(),
+            tags: json_help::object_get_str_list_or_empty(&profile_object, "tags")?,
             parity_root,
             port,
             mask,
+            watch,
+            advertise,
+            port_mapping,
+            allow,
+            deny,
+            max_connections_per_ip,
+            max_requests_per_minute,
+            first_request_timeout,
+            min_transfer_rate,
+            mode,
+            upload_root,
+            max_upload_size: ValidatedLimit::new(json_help::object_get_u64_or(&profile_object, "max_upload_size", 0)?),
+            upload_quota: ValidatedLimit::new(json_help::object_get_u64_or(&profile_object, "upload_quota", 0)?),
+            versioning: json_help::object_get_bool_or(&profile_object, "versioning", false)?,
+            users,
+            shared_secret: json_help::object_get_str_or(&profile_object, "shared_secret", "")?.to_string(),
+            signing_key: json_help::object_get_str_or(&profile_object, "signing_key", "")?.to_string(),
+            run_as: ValidatedRunAs::new(json_help::object_get_str_or(&profile_object, "run_as", "")?.to_string()),
+            chroot: json_help::object_get_bool_or(&profile_object, "chroot", false)?,
+            hooks: common::get_hooks(&profile_object)?,
+            metrics_port: ValidatedOptionalPort::new(u16::try_from(json_help::object_get_u64_or(&profile_object, "metrics_port", 0)?)?),
+            http_port: ValidatedOptionalPort::new(u16::try_from(json_help::object_get_u64_or(&profile_object, "http_port", 0)?)?),
+            ws_port: ValidatedOptionalPort::new(u16::try_from(json_help::object_get_u64_or(&profile_object, "ws_port", 0)?)?),
+            storage: ValidatedStorage::new(json_help::object_get_str_or(&profile_object, "storage", "")?.to_string()),
+            exclude: ValidatedPatternList::new(json_help::object_get_str_list_or_empty(&profile_object, "exclude")?.join(", ")),
+            hidden_files: json_help::object_get_bool_or(&profile_object, "hidden_files", true)?,
+            symlinks: SymlinkPolicy::from_key(json_help::object_get_str_or(&profile_object, "symlinks", "follow")?)?,
+            sort_by: SortKey::from_key(json_help::object_get_str_or(&profile_object, "sort_by", "name")?)?,
+            sort_descending: json_help::object_get_bool_or(&profile_object, "sort_descending", false)?,
+            log_file: ValidatedLogFile::new(json_help::object_get_str_or(&profile_object, "log_file", "")?.to_string()),
+            log_max_size: ValidatedLimit::new(json_help::object_get_u64_or(&profile_object, "log_max_size", logging::DEFAULT_MAX_SIZE)?),
+            log_max_age: ValidatedLimit::new(json_help::object_get_u64_or(&profile_object, "log_max_age", 0)?),
+            log_retention: ValidatedLimit::new(json_help::object_get_u64_or(&profile_object, "log_retention", logging::DEFAULT_RETENTION)?),
         };
         Ok(profile)
     }
 
     pub fn save_profile(profile: &ServerProfile) -> Result<()> {
         let mut root = json_help::config_root_object(config_ext())?;
+        let defaults = common::get_defaults(&root)?;
         let profiles = json_help::object_get_mut_object(&mut root, "profiles")?;
-        let data = json::object! {
+        let mut data = json::object! {
+            "notes": json::JsonValue::String(profile.notes.clone()),
+            "tags": profile.tags.clone(),
             "parity_root": json::JsonValue::String(profile.parity_root.get().clone()),
             "port": json::JsonValue::Number(json::number::Number::from(*profile.port.get())),
             "mask": json::JsonValue::String(profile.mask.get().clone()),
+            "watch": json::JsonValue::Boolean(profile.watch),
+            "advertise": json::JsonValue::Boolean(profile.advertise),
+            "port_mapping": json::JsonValue::Boolean(profile.port_mapping),
+            "allow": profile.allow.entries(),
+            "deny": profile.deny.entries(),
+            "max_connections_per_ip": json::JsonValue::Number(json::number::Number::from(*profile.max_connections_per_ip.get())),
+            "max_requests_per_minute": json::JsonValue::Number(json::number::Number::from(*profile.max_requests_per_minute.get())),
+            "first_request_timeout": json::JsonValue::Number(json::number::Number::from(*profile.first_request_timeout.get())),
+            "min_transfer_rate": json::JsonValue::Number(json::number::Number::from(*profile.min_transfer_rate.get())),
+            "mode": json::JsonValue::String(profile.mode.key().to_string()),
+            "upload_root": json::JsonValue::String(profile.upload_root.get().clone()),
+            "max_upload_size": json::JsonValue::Number(json::number::Number::from(*profile.max_upload_size.get())),
+            "upload_quota": json::JsonValue::Number(json::number::Number::from(*profile.upload_quota.get())),
+            "versioning": json::JsonValue::Boolean(profile.versioning),
+            "shared_secret": json::JsonValue::String(profile.shared_secret.clone()),
+            "signing_key": json::JsonValue::String(profile.signing_key.clone()),
+            "run_as": json::JsonValue::String(profile.run_as.get().clone()),
+            "chroot": json::JsonValue::Boolean(profile.chroot),
+            "metrics_port": json::JsonValue::Number(json::number::Number::from(*profile.metrics_port.get())),
+            "http_port": json::JsonValue::Number(json::number::Number::from(*profile.http_port.get())),
+            "ws_port": json::JsonValue::Number(json::number::Number::from(*profile.ws_port.get())),
+            "storage": json::JsonValue::String(profile.storage.get().clone()),
+            "exclude": profile.exclude.entries(),
+            "hidden_files": json::JsonValue::Boolean(profile.hidden_files),
+            "symlinks": json::JsonValue::String(profile.symlinks.key().to_string()),
+            "sort_by": json::JsonValue::String(profile.sort_by.key().to_string()),
+            "sort_descending": json::JsonValue::Boolean(profile.sort_descending),
+            "log_file": json::JsonValue::String(profile.log_file.get().clone()),
+            "log_max_size": json::JsonValue::Number(json::number::Number::from(*profile.log_max_size.get())),
+            "log_max_age": json::JsonValue::Number(json::number::Number::from(*profile.log_max_age.get())),
+            "log_retention": json::JsonValue::Number(json::number::Number::from(*profile.log_retention.get())),
         };
-        profiles.insert(&profile.name, data);
+        let mut users = json::object::Object::new();
+        for user in &profile.users {
+            users.insert(&user.name, json::object! {
+                "salt": json::JsonValue::String(user.salt.clone()),
+                "password_hash": json::JsonValue::String(user.password_hash.clone()),
+                "rounds": json::JsonValue::Number(json::number::Number::from(user.rounds)),
+                "parity_root": json::JsonValue::String(user.parity_root.get().clone()),
+                "mode": json::JsonValue::String(user.mode.key().to_string()),
+                "max_upload_size": json::JsonValue::Number(json::number::Number::from(user.max_upload_size)),
+                "upload_quota": json::JsonValue::Number(json::number::Number::from(user.upload_quota)),
+            });
+        }
+        data["users"] = json::JsonValue::Object(users);
+        data["hooks"] = common::hooks_json(&profile.hooks);
+        profiles.insert(&profile.name, common::strip_defaults(data, &defaults));
         common::overwrite_config_file(config_ext(), root.dump().as_bytes())?;
         Ok(())
     }
@@ -310,12 +1260,66 @@ pub mod server {
         common::erase_profile(config_ext(), profile_name)
     }
 
+    #[inline]
+    pub fn restore_profile<S: AsRef<str>>(profile_name: S) -> Result<()> {
+        common::restore_profile(config_ext(), profile_name)
+    }
+
+    /// Reads every profile and checks it for problems.
+    pub fn check_profiles() -> Result<Vec<ProfileCheck>> {
+        Ok(get_profile_names()?
+            .into_iter()
+            .map(|name| {
+                let problems = match get_profile(&name) {
+                    Ok(profile) => profile.problems(),
+                    Err(e) => vec![format!("Could not be read: {}.", e.to_string().trim_end_matches('.'))],
+                };
+                ProfileCheck { name, problems }
+            })
+            .collect())
+    }
+
     pub fn create_profile<S: ToString, T: ToString, V: ToString>(profile_name: S, parity_root: T, port: u16, mask: V) -> Result<()> {
         let profile = ServerProfile {
             name: profile_name.to_string(),
+            notes: String::new(),
+            tags: vec![],
             parity_root: ValidatedDirectory::new(parity_root.to_string()),
             port: ValidatedPort::new(port),
             mask: ValidatedIPv4::new(mask.to_string()),
+            watch: false,
+            advertise: true,
+            port_mapping: false,
+            allow: ValidatedCidrList::new(String::new()),
+            deny: ValidatedCidrList::new(String::new()),
+            max_connections_per_ip: ValidatedLimit::new(0),
+            max_requests_per_minute: ValidatedLimit::new(0),
+            first_request_timeout: ValidatedLimit::new(limits::DEFAULT_FIRST_REQUEST_TIMEOUT),
+            min_transfer_rate: ValidatedLimit::new(0),
+            mode: ServerMode::ReadOnly,
+            upload_root: ValidatedOptionalDirectory::new(String::new()),
+            max_upload_size: ValidatedLimit::new(0),
+            upload_quota: ValidatedLimit::new(0),
+            versioning: false,
+            users: vec![],
+            shared_secret: String::new(),
+            signing_key: String::new(),
+            run_as: ValidatedRunAs::new(String::new()),
+            chroot: false,
+            hooks: vec![],
+            metrics_port: ValidatedOptionalPort::new(0),
+            http_port: ValidatedOptionalPort::new(0),
+            ws_port: ValidatedOptionalPort::new(0),
+            storage: ValidatedStorage::new(String::new()),
+            exclude: ValidatedPatternList::new(String::new()),
+            hidden_files: true,
+            symlinks: SymlinkPolicy::Follow,
+            sort_by: SortKey::Name,
+            sort_descending: false,
+            log_file: ValidatedLogFile::new(String::new()),
+            log_max_size: ValidatedLimit::new(logging::DEFAULT_MAX_SIZE),
+            log_max_age: ValidatedLimit::new(0),
+            log_retention: ValidatedLimit::new(logging::DEFAULT_RETENTION),
         };
         save_profile(&profile)
     }
@@ -329,6 +1333,8 @@ pub mod server {
 pub mod client {
     use super::*;
 
+    pub const DEFAULT_SYNC_INTERVAL: u64 = 300;
+
     #[inline]
     fn config_ext() -> &'static str {
         "oxideux/client_config.json"
@@ -361,25 +1367,102 @@ pub mod client {
         let parity_root = ValidatedDirectory::new(path);
         let port = ValidatedPort::new(json_help::object_get_u16(&profile_object, "port")?);
         let ip = ValidatedIPv4::new(json_help::object_get_str(&profile_object, "ipv4")?.into());
+        let sync_interval = ValidatedSeconds::new(json_help::object_get_u64_or(
+            &profile_object,
+            "sync_interval",
+            DEFAULT_SYNC_INTERVAL,
+        )?);
+        let sync_mode = SyncMode::from_key(json_help::object_get_str_or(&profile_object, "sync_mode", "download")?)?;
+        let conflicts = ConflictStrategy::from_key(json_help::object_get_str_or(&profile_object, "conflicts", "newest-wins")?)?;
+        let mirror_deletions = json_help::object_get_bool_or(&profile_object, "mirror_deletions", false)?;
+        let trash_dir = ValidatedOptionalDirectory::new(fill_path_placeholders(
+            json_help::object_get_str_or(&profile_object, "trash_dir", "")?.to_string(),
+        )?);
+        // Profiles from before the setting existed only had a trash when they named a directory
+        let keep_previous = json_help::object_get_bool_or(&profile_object, "keep_previous", trash_dir.is_set())?;
+        let ssh_jump = ValidatedSshJump::new(json_help::object_get_str_or(&profile_object, "ssh_jump", "")?.into());
+        let socks_proxy = ValidatedProxy::new(common::get_secret(&profile_object, "socks_proxy")?);
+        let user = json_help::object_get_str_or(&profile_object, "user", "")?.to_string();
+        let password = common::get_secret(&profile_object, "password")?;
+        let shared_secret = common::get_secret(&profile_object, "shared_secret")?;
 
         let profile = ClientProfile {
             name: profile_name.as_ref().to_string(),
+            notes: json_help::object_get_str_or(&profile_object, "notes", "")?.to_string(),
+            tags: json_help::object_get_str_list_or_empty(&profile_object, "tags")?,
             parity_root,
             port,
             ipv4: ip,
+            sync_interval,
+            schedule: ValidatedSchedule::new(json_help::object_get_str_or(&profile_object, "schedule", "")?.to_string()),
+            bandwidth: ValidatedBandwidth::new(json_help::object_get_str_or(&profile_object, "bandwidth", "")?.to_string()),
+            sync_mode,
+            conflicts,
+            mirror_deletions,
+            keep_previous,
+            trash_dir,
+            trash_max_age: ValidatedLimit::new(json_help::object_get_u64_or(&profile_object, "trash_max_age", 0)?),
+            exclude: ValidatedPatternList::new(json_help::object_get_str_list_or_empty(&profile_object, "exclude")?.join(", ")),
+            hidden_files: json_help::object_get_bool_or(&profile_object, "hidden_files", true)?,
+            symlinks: SymlinkPolicy::from_key(json_help::object_get_str_or(&profile_object, "symlinks", "follow")?)?,
+            collisions: CollisionPolicy::from_key(json_help::object_get_str_or(&profile_object, "collisions", "overwrite")?)?,
+            filter: ValidatedQuery::new(json_help::object_get_str_or(&profile_object, "filter", "")?.to_string()),
+            download_order: DownloadOrder::from_key(json_help::object_get_str_or(&profile_object, "download_order", "listed")?)?,
+            priority: ValidatedPatternList::new(json_help::object_get_str_list_or_empty(&profile_object, "priority")?.join(", ")),
+            ssh_jump,
+            socks_proxy,
+            user,
+            password,
+            shared_secret,
+            server_public_key: ValidatedPublicKey::new(json_help::object_get_str_or(&profile_object, "server_public_key", "")?.to_string()),
+            hooks: common::get_hooks(&profile_object)?,
+            last_successful_connection: match json_help::object_get_u64_or(&profile_object, "last_successful_connection", 0)? {
+                0 => None,
+                secs => Some(secs),
+            },
+            per_server_dir: json_help::object_get_bool_or(&profile_object, "per_server_dir", false)?,
         };
         Ok(profile)
     }
 
     pub fn save_profile(profile: &ClientProfile) -> Result<()> {
         let mut root = json_help::config_root_object(config_ext())?;
+        let defaults = common::get_defaults(&root)?;
+        let encrypted = common::encryption_salt(&root)?.is_some();
         let profiles = json_help::object_get_mut_object(&mut root, "profiles")?;
         let data = json::object! {
+            "notes": json::JsonValue::String(profile.notes.clone()),
+            "tags": profile.tags.clone(),
             "parity_root": json::JsonValue::String(profile.parity_root.get().clone()),
             "port": json::JsonValue::Number(json::number::Number::from(*profile.port.get())),
             "ipv4": json::JsonValue::String(profile.ipv4.get().clone()),
+            "sync_interval": json::JsonValue::Number(json::number::Number::from(*profile.sync_interval.get())),
+            "schedule": json::JsonValue::String(profile.schedule.get().clone()),
+            "bandwidth": json::JsonValue::String(profile.bandwidth.get().clone()),
+            "sync_mode": json::JsonValue::String(profile.sync_mode.key().to_string()),
+            "conflicts": json::JsonValue::String(profile.conflicts.key().to_string()),
+            "mirror_deletions": json::JsonValue::Boolean(profile.mirror_deletions),
+            "keep_previous": json::JsonValue::Boolean(profile.keep_previous),
+            "trash_dir": json::JsonValue::String(profile.trash_dir.get().clone()),
+            "trash_max_age": json::JsonValue::Number(json::number::Number::from(*profile.trash_max_age.get())),
+            "exclude": profile.exclude.entries(),
+            "hidden_files": json::JsonValue::Boolean(profile.hidden_files),
+            "symlinks": json::JsonValue::String(profile.symlinks.key().to_string()),
+            "collisions": json::JsonValue::String(profile.collisions.key().to_string()),
+            "filter": json::JsonValue::String(profile.filter.get().clone()),
+            "download_order": json::JsonValue::String(profile.download_order.key().to_string()),
+            "priority": profile.priority.entries(),
+            "ssh_jump": json::JsonValue::String(profile.ssh_jump.get().clone()),
+            "socks_proxy": common::secret_json(profile.socks_proxy.get(), encrypted)?,
+            "user": json::JsonValue::String(profile.user.clone()),
+            "password": common::secret_json(&profile.password, encrypted)?,
+            "shared_secret": common::secret_json(&profile.shared_secret, encrypted)?,
+            "server_public_key": json::JsonValue::String(profile.server_public_key.get().clone()),
+            "hooks": common::hooks_json(&profile.hooks),
+            "last_successful_connection": json::JsonValue::Number(json::number::Number::from(profile.last_successful_connection.unwrap_or(0))),
+            "per_server_dir": json::JsonValue::Boolean(profile.per_server_dir),
         };
-        profiles.insert(&profile.name, data);
+        profiles.insert(&profile.name, common::strip_defaults(data, &defaults));
         common::overwrite_config_file(config_ext(), root.dump().as_bytes())?;
         Ok(())
     }
@@ -389,12 +1472,89 @@ pub mod client {
         common::erase_profile(config_ext(), profile_name)
     }
 
+    #[inline]
+    pub fn restore_profile<S: AsRef<str>>(profile_name: S) -> Result<()> {
+        common::restore_profile(config_ext(), profile_name)
+    }
+
+    /// Reads every profile and checks it for problems.
+    pub fn check_profiles() -> Result<Vec<ProfileCheck>> {
+        Ok(get_profile_names()?
+            .into_iter()
+            .map(|name| {
+                let problems = match get_profile(&name) {
+                    Ok(profile) => profile.problems(),
+                    Err(e) => vec![format!("Could not be read: {}.", e.to_string().trim_end_matches('.'))],
+                };
+                ProfileCheck { name, problems }
+            })
+            .collect())
+    }
+
+    /// Whether passwords are stored encrypted (see: [`crate::secrets`]).
+    pub fn secrets_encrypted() -> Result<bool> {
+        Ok(common::encryption_salt(&json_help::config_root_object(config_ext())?)?.is_some())
+    }
+
+    /// Whether passwords are stored encrypted and the passphrase has yet to be given.
+    pub fn secrets_locked() -> Result<bool> {
+        Ok(secrets_encrypted()? && secrets::session_key().is_none())
+    }
+
+    /// Checks `passphrase`, holding the key it makes for the rest of the session when it's right.
+    #[inline]
+    pub fn unlock_secrets(passphrase: &str) -> Result<()> {
+        common::unlock_secrets(config_ext(), passphrase)
+    }
+
+    /// Encrypts the passwords of every profile with `passphrase` from now on, or stores them in
+    /// plain text again with `None`. Needs the current passphrase given first when they are
+    /// encrypted already.
+    pub fn set_secrets_passphrase(passphrase: Option<&str>) -> Result<()> {
+        let profiles = get_profile_names()?
+            .iter()
+            .map(get_profile)
+            .collect::<Result<Vec<_>>>()?;
+        common::set_encryption(config_ext(), passphrase)?;
+        for profile in &profiles {
+            save_profile(profile)?;
+        }
+        Ok(())
+    }
+
     pub fn create_profile<S: ToString, T: ToString, V: ToString>(profile_name: S, parity_root: T, port: u16, ipv4: V) -> Result<()> {
         let profile = ClientProfile {
             name: profile_name.to_string(),
+            notes: String::new(),
+            tags: vec![],
             parity_root: ValidatedDirectory::new(parity_root.to_string()),
             port: ValidatedPort::new(port),
             ipv4: ValidatedIPv4::new(ipv4.to_string()),
+            sync_interval: ValidatedSeconds::new(DEFAULT_SYNC_INTERVAL),
+            schedule: ValidatedSchedule::new(String::new()),
+            bandwidth: ValidatedBandwidth::new(String::new()),
+            sync_mode: SyncMode::Download,
+            mirror_deletions: false,
+            keep_previous: false,
+            trash_dir: ValidatedOptionalDirectory::new(String::new()),
+            trash_max_age: ValidatedLimit::new(0),
+            exclude: ValidatedPatternList::new(String::new()),
+            hidden_files: true,
+            symlinks: SymlinkPolicy::Follow,
+            collisions: CollisionPolicy::Overwrite,
+            filter: ValidatedQuery::new(String::new()),
+            download_order: DownloadOrder::Listed,
+            priority: ValidatedPatternList::new(String::new()),
+            conflicts: ConflictStrategy::NewestWins,
+            ssh_jump: ValidatedSshJump::new(String::new()),
+            socks_proxy: ValidatedProxy::new(String::new()),
+            user: String::new(),
+            password: String::new(),
+            shared_secret: String::new(),
+            server_public_key: ValidatedPublicKey::new(String::new()),
+            hooks: vec![],
+            last_successful_connection: None,
+            per_server_dir: false,
         };
         save_profile(&profile)
     }
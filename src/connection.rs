@@ -1,114 +1,1310 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Display;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use std::{net::TcpStream, path::PathBuf};
 
-use crate::parity::Entry;
-use crate::request::{Request, RequestResult};
-use anyhow::Result;
+use crate::bandwidth::Throttle;
+use crate::limits::{MinimumRate, STALL_WINDOW};
+use crate::meter::{TransferMeter, TransferStats};
+use crate::parity::{Entry, StableFile};
+use crate::request::{Request, RequestResult, TaggedRequest, MAX_FRAME_LENGTH};
+use crate::transport::Transport;
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 
-pub struct Connection(pub TcpStream);
+const MAGIC: &[u8; 4] = b"OXDX";
+const PROTOCOL_VERSION: u32 = 3;
+
+/// Chunk header marking the end of a file stream.
+const CHUNK_END: u32 = 0;
+/// Chunk header marking a file stream that was cut short because the file changed while it was
+/// being read (see: [`FileChanged`]).
+const CHUNK_CHANGED: u32 = u32::MAX - 1;
+/// Chunk header marking a file stream that was cut short by a [`Request::Cancel`].
+const CHUNK_CANCELLED: u32 = u32::MAX;
+const CHUNK_SIZE: usize = 4096;
+
+/// Files up to this many bytes are sent together in batches when both peers support
+/// [`Capability::Batch`].
+pub const BATCH_FILE_THRESHOLD: u64 = 64 * 1024;
+/// The most file bytes one batch holds.
+pub const MAX_BATCH_BYTES: u64 = 1024 * 1024;
+
+/// How often paused transfers check whether they were resumed.
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often an idle persistent session is probed with a [`Request::Ping`].
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(20);
+/// How long a peer may stay silent before a persistent session is considered lost.
+pub const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Returned (wrapped in an [`anyhow::Error`]) when a transfer stops because the request it belongs
+/// to was cancelled. Callers can recover it with `error.downcast_ref::<Cancelled>()`.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Returned (wrapped in an [`anyhow::Error`]) when transfers were stopped altogether through a
+/// [`TransferControl`]. Callers can recover it with `error.downcast_ref::<Aborted>()`.
+#[derive(Debug)]
+pub struct Aborted;
+
+impl Display for Aborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Transfers were aborted")
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+/// Returned (wrapped in an [`anyhow::Error`]) on both ends when a file was modified while it was
+/// being sent, so what was received would be a mix of two versions. Callers can recover it with
+/// `error.downcast_ref::<FileChanged>()` and try again.
+#[derive(Debug, Clone)]
+pub struct FileChanged {
+    pub name: String,
+}
+
+impl Display for FileChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "'{}' changed during transfer", self.name)
+    }
+}
+
+impl std::error::Error for FileChanged {}
+
+/// The integrity trailer appended to every frame, as negotiated during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Integrity {
+    None,
+    Crc32,
+}
+
+/// An optional protocol feature. Both peers advertise the features they support during the
+/// handshake and only features supported by both may be used (see: [`Connection::supports`]).
+///
+/// The discriminant is the feature's bit in the advertised bitfield and must never be reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// CRC32 trailers on every frame.
+    Crc32 = 1 << 0,
+    /// [`Request::Cancel`] is honoured while a transfer is streaming.
+    Cancel = 1 << 1,
+    /// [`Request::Ping`] is answered and idle sessions are timed out.
+    KeepAlive = 1 << 2,
+    /// [`Request::GetFileInfo`] is understood.
+    FileInfo = 1 << 3,
+    /// [`Request::DownloadMatching`] is understood.
+    DownloadMatching = 1 << 4,
+    /// [`Request::Subscribe`] is understood. Only advertised by servers with watching enabled.
+    Watch = 1 << 5,
+    /// [`Request::ListFiles`] is understood.
+    List = 1 << 6,
+    /// [`Request::UploadFile`] is understood. Not advertised by read-only servers.
+    Upload = 1 << 7,
+    /// [`Request::Authenticate`] is understood.
+    Accounts = 1 << 8,
+    /// [`Request::RedeemToken`] is understood.
+    Share = 1 << 9,
+    /// [`Request::ReadRange`] is understood.
+    Range = 1 << 10,
+    /// [`Request::GetChunks`] and [`Request::ReadChunks`] are understood.
+    Chunks = 1 << 11,
+    /// [`Request::DownloadDelta`] is understood.
+    Delta = 1 << 12,
+    /// [`Request::GetManifest`] is understood.
+    Manifest = 1 << 13,
+    /// [`Request::QueryFiles`] is understood.
+    Query = 1 << 14,
+    /// [`Request::GetRootStats`] is understood.
+    Stats = 1 << 15,
+    /// [`Request::UploadSizedFile`] is understood. Not advertised by read-only servers.
+    SizedUpload = 1 << 16,
+    /// [`Request::GetUploadOffset`] and [`Request::ResumeUpload`] are understood. Not advertised by
+    /// read-only servers.
+    ResumeUpload = 1 << 17,
+    /// [`Request::ListVersions`] and [`Request::DownloadVersion`] are understood. Only advertised
+    /// by servers with versioning enabled.
+    Versions = 1 << 18,
+    /// [`Request::Search`] is understood.
+    Search = 1 << 19,
+    /// [`Request::DownloadByHash`] is understood.
+    ByHash = 1 << 20,
+    /// [`Request::DownloadIfChanged`] is understood.
+    Conditional = 1 << 21,
+    /// Streams of files, such as answers to [`Request::DownloadAllFiles`], send small files
+    /// together (see: [`Connection::send_batch`]).
+    Batch = 1 << 22,
+    /// [`Request::ResumeDownload`] is understood.
+    ResumeDownload = 1 << 23,
+    /// [`Request::GetSignedManifest`] is understood. Only advertised by servers with a signing key.
+    Signatures = 1 << 24,
+    /// File streams announce their length as a [`u64`] rather than a [`u32`], so files of 4 GiB
+    /// and more can be sent.
+    LargeFiles = 1 << 25,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Capability::Crc32,
+        Capability::Cancel,
+        Capability::KeepAlive,
+        Capability::FileInfo,
+        Capability::DownloadMatching,
+        Capability::Watch,
+        Capability::List,
+        Capability::Upload,
+        Capability::Accounts,
+        Capability::Share,
+        Capability::Range,
+        Capability::Chunks,
+        Capability::Delta,
+        Capability::Manifest,
+        Capability::Query,
+        Capability::Stats,
+        Capability::SizedUpload,
+        Capability::ResumeUpload,
+        Capability::Versions,
+        Capability::Search,
+        Capability::ByHash,
+        Capability::Conditional,
+        Capability::Batch,
+        Capability::ResumeDownload,
+        Capability::Signatures,
+        Capability::LargeFiles,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Crc32 => "crc32",
+            Capability::Cancel => "cancel",
+            Capability::KeepAlive => "keep-alive",
+            Capability::FileInfo => "file-info",
+            Capability::DownloadMatching => "download-matching",
+            Capability::Watch => "watch",
+            Capability::List => "list",
+            Capability::Upload => "upload",
+            Capability::Accounts => "accounts",
+            Capability::Share => "share",
+            Capability::Range => "range",
+            Capability::Chunks => "chunks",
+            Capability::Delta => "delta",
+            Capability::Manifest => "manifest",
+            Capability::Query => "query",
+            Capability::Stats => "stats",
+            Capability::SizedUpload => "sized-upload",
+            Capability::ResumeUpload => "resume-upload",
+            Capability::Versions => "versions",
+            Capability::Search => "search",
+            Capability::ByHash => "by-hash",
+            Capability::Conditional => "conditional",
+            Capability::Batch => "batch",
+            Capability::ResumeDownload => "resume-download",
+            Capability::Signatures => "signatures",
+            Capability::LargeFiles => "large-files",
+        }
+    }
+}
+
+/// A set of [`Capability`] flags as exchanged in the handshake.
+///
+/// Unknown bits sent by newer peers are kept as-is and simply never match a known [`Capability`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u64);
+
+impl Capabilities {
+    /// Every capability this build implements.
+    pub fn local() -> Self {
+        Self::from_slice(Capability::ALL)
+    }
+
+    pub fn from_slice(capabilities: &[Capability]) -> Self {
+        let mut set = Self::default();
+        for capability in capabilities {
+            set.insert(*capability);
+        }
+        set
+    }
+
+    #[inline]
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.0 & capability as u64 != 0
+    }
+
+    #[inline]
+    pub fn insert(&mut self, capability: Capability) {
+        self.0 |= capability as u64;
+    }
+
+    #[inline]
+    pub fn remove(&mut self, capability: Capability) {
+        self.0 &= !(capability as u64);
+    }
+
+    #[inline]
+    pub fn intersection(&self, other: Capabilities) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// The known capabilities in this set.
+    pub fn iter(&self) -> impl Iterator<Item = Capability> + '_ {
+        Capability::ALL.iter().copied().filter(|c| self.contains(*c))
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = self.iter().map(|c| c.name()).collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// A notification about the progress of a file transfer.
+///
+/// Emitted by [`Connection::send_file`] and [`Connection::read_file`] to the handler registered with
+/// [`Connection::set_transfer_handler`]. `Progressed` is emitted once per chunk, so handlers that
+/// draw progress bars should throttle themselves.
+#[derive(Debug)]
+pub enum TransferEvent<'a> {
+    Started { name: &'a str, length: u64 },
+    Progressed { name: &'a str, transferred: u64, length: u64, stats: TransferStats },
+    Completed { name: &'a str, length: u64, stats: TransferStats },
+    Failed { name: &'a str, error: &'a anyhow::Error },
+}
+
+pub type TransferHandler = Box<dyn FnMut(&TransferEvent) + Send>;
+
+/// A handle that pauses, resumes and stops the transfers of a [`Connection`] from another thread,
+/// such as one reading keys pressed meanwhile. Created with [`Connection::transfer_control`]; every
+/// clone acts on the same transfers.
+///
+/// Transfers heed it between chunks. A download can only be stopped midway by a server that
+/// supports [`Capability::Cancel`], and one that isn't arrives whole. Files streamed in answer to a
+/// single request, such as [`Request::DownloadAllFiles`], stop together.
+#[derive(Debug, Clone, Default)]
+pub struct TransferControl {
+    paused: Arc<AtomicBool>,
+    skip: Arc<AtomicBool>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl TransferControl {
+    /// Holds transfers after the chunk in progress until [`TransferControl::resume`].
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Stops the file being transferred, which fails with [`Cancelled`], while the files after it
+    /// go ahead.
+    pub fn skip_current(&self) {
+        self.skip.store(true, Ordering::Relaxed);
+    }
+
+    /// Stops the file being transferred and every one after it with [`Aborted`], until
+    /// [`TransferControl::reset`].
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Relaxed)
+    }
+
+    /// Lets transfers run freely again, whatever was asked before.
+    pub fn reset(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+        self.skip.store(false, Ordering::Relaxed);
+        self.aborted.store(false, Ordering::Relaxed);
+    }
+
+    /// Waits while transfers are paused, failing once they are aborted.
+    fn hold(&self) -> Result<()> {
+        while self.is_paused() && !self.is_aborted() && !self.skip.load(Ordering::Relaxed) {
+            thread::sleep(PAUSE_POLL_INTERVAL);
+        }
+        match self.is_aborted() {
+            true => Err(Aborted.into()),
+            false => Ok(()),
+        }
+    }
+
+    /// Like [`TransferControl::hold`], also failing when the file in progress is to be skipped.
+    fn checkpoint(&self) -> Result<()> {
+        self.hold()?;
+        match self.skip.load(Ordering::Relaxed) {
+            true => Err(Cancelled.into()),
+            false => Ok(()),
+        }
+    }
+}
+
+/// A small file sent together with others by [`Connection::send_batch`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchedFile {
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+/// A buffered connection between a client and a server.
+///
+/// Reads and writes go through a [`BufReader`] and [`BufWriter`] over the same [`Transport`], so
+/// the many small writes that make up a message are coalesced. Every `send_*` method that completes
+/// a message flushes the writer before returning; lower level helpers such as [`Connection::send_u32`]
+/// leave flushing to the caller (see: [`Connection::flush`]).
+pub struct Connection {
+    reader: BufReader<Box<dyn Transport>>,
+    writer: BufWriter<Box<dyn Transport>>,
+    transfer_handler: Option<TransferHandler>,
+    integrity: Integrity,
+    capabilities: Capabilities,
+    peer_capabilities: Capabilities,
+    next_request_id: u32,
+    /// The id of the request most recently returned by [`Connection::read_request`].
+    current_request: Option<u32>,
+    /// Requests that arrived while a transfer was streaming, in the order they were received.
+    pending_requests: VecDeque<TaggedRequest>,
+    cancelled_requests: HashSet<u32>,
+    /// The id of the request most recently sent with [`Connection::send_request`], which a
+    /// transfer being received answers.
+    last_sent_request: Option<u32>,
+    control: TransferControl,
+    throttle: Option<Throttle>,
+    minimum_rate: Option<MinimumRate>,
+    /// Measures the transfer in progress, or the latest one until it's taken.
+    meter: Option<TransferMeter>,
+}
 
 impl Connection {
+    pub fn new(stream: TcpStream) -> Result<Self> {
+        Self::with_transport(Box::new(stream))
+    }
+
+    /// Like [`Connection::new`], but over any [`Transport`], such as a
+    /// [`crate::websocket::WsTransport`].
+    pub fn with_transport(transport: Box<dyn Transport>) -> Result<Self> {
+        let reader = BufReader::new(transport.try_clone()?);
+        let writer = BufWriter::new(transport);
+        Ok(Self {
+            reader,
+            writer,
+            transfer_handler: None,
+            integrity: Integrity::None,
+            capabilities: Capabilities::default(),
+            peer_capabilities: Capabilities::default(),
+            next_request_id: 1,
+            current_request: None,
+            pending_requests: VecDeque::new(),
+            cancelled_requests: HashSet::new(),
+            last_sent_request: None,
+            control: TransferControl::default(),
+            throttle: None,
+            minimum_rate: None,
+            meter: None,
+        })
+    }
+
+    /// Registers the handler that receives every [`TransferEvent`] of this connection, replacing
+    /// any previously registered handler.
+    pub fn set_transfer_handler<F: FnMut(&TransferEvent) + Send + 'static>(&mut self, handler: F) {
+        self.transfer_handler = Some(Box::new(handler));
+    }
+
+    /// Holds file data and frames to `throttle` from now on, or lifts the limit with `None`.
+    pub fn set_throttle(&mut self, throttle: Option<Throttle>) {
+        self.throttle = throttle;
+    }
+
+    /// Fails once the peer takes what is sent, or sends a file, slower than `minimum_rate` from
+    /// now on, or takes or sends nothing at all for a [`STALL_WINDOW`]. `None` lifts the limit.
+    pub fn set_minimum_rate(&mut self, minimum_rate: Option<MinimumRate>) -> Result<()> {
+        self.stream().set_write_timeout(minimum_rate.as_ref().map(|_| STALL_WINDOW))?;
+        self.minimum_rate = minimum_rate;
+        Ok(())
+    }
+
+    /// A handle that pauses and stops the transfers of this connection from another thread.
+    pub fn transfer_control(&self) -> TransferControl {
+        self.control.clone()
+    }
+
+    /// Waits while transfers are paused, failing with [`Aborted`] once they are aborted. For
+    /// between files, where skipping doesn't apply.
+    pub(crate) fn hold(&self) -> Result<()> {
+        self.control.hold()
+    }
+
+    /// Like [`Connection::hold`], also failing with [`Cancelled`] when the file in progress is to be
+    /// skipped. Only where the transfer can stop without leaving the peer behind.
+    pub(crate) fn checkpoint(&self) -> Result<()> {
+        self.control.checkpoint()
+    }
+
+    #[inline]
+    fn pace(&mut self, bytes: usize) {
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.pace(bytes);
+        }
+    }
+
+    #[inline]
+    fn check_rate(&mut self, bytes: usize, since: Instant) -> Result<()> {
+        match self.minimum_rate.as_mut() {
+            Some(minimum_rate) => minimum_rate.check(bytes, since.elapsed()),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the bulk of a message, such as a frame or a chunk of a file, held to the minimum rate.
+    #[inline]
+    fn write_payload(&mut self, bytes: &[u8]) -> Result<()> {
+        let start = Instant::now();
+        self.writer.write_all(bytes).map_err(|e| stalled(e.into()))?;
+        self.check_rate(bytes.len(), start)
+    }
+
+    #[inline]
+    /// Reports `event`. A starting transfer names its file in the `file` field of the span it runs
+    /// in, such as the server's span for the request, if it has one.
+    pub(crate) fn emit(&mut self, event: TransferEvent) {
+        if let TransferEvent::Started { name, .. } = &event {
+            tracing::Span::current().record("file", *name);
+        }
+        if let Some(handler) = self.transfer_handler.as_mut() {
+            handler(&event);
+        }
+    }
+
+    /// Starts measuring a transfer of `length` bytes, and reports it.
+    pub(crate) fn emit_started(&mut self, name: &str, length: u64) {
+        // A skip asked for between files is for the one that was, not this one
+        self.control.skip.store(false, Ordering::Relaxed);
+        self.meter = Some(TransferMeter::start(length));
+        self.emit(TransferEvent::Started { name, length });
+    }
+
+    pub(crate) fn emit_progressed(&mut self, name: &str, transferred: u64, length: u64) {
+        let stats = self.meter.get_or_insert_with(|| TransferMeter::start(length)).update(transferred);
+        self.emit(TransferEvent::Progressed { name, transferred, length, stats });
+    }
+
+    pub(crate) fn emit_completed(&mut self, name: &str, length: u64) {
+        let stats = self.meter.get_or_insert_with(|| TransferMeter::start(length)).finish();
+        self.emit(TransferEvent::Completed { name, length, stats });
+    }
+
+    /// How the latest transfer went, failed or not, or `None` if there was none since this was
+    /// last asked.
+    pub fn take_transfer_stats(&mut self) -> Option<TransferStats> {
+        self.meter.take().map(|meter| meter.stats())
+    }
+
+    #[inline]
+    pub fn stream(&self) -> &dyn Transport {
+        self.writer.get_ref().as_ref()
+    }
+
+    #[inline]
+    pub fn flush(&mut self) -> Result<()> {
+        let start = Instant::now();
+        self.writer.flush().map_err(|e| stalled(e.into()))?;
+        self.check_rate(0, start)
+    }
+
     #[inline]
     pub fn shutdown(&mut self, how: Shutdown) -> Result<()> {
-        self.0.shutdown(how)?;
+        self.flush()?;
+        self.writer.get_ref().shutdown(how)?;
         Ok(())
     }
 
+    /// Writes a raw [`u32`] without flushing. Use [`Connection::send_count`] for a standalone value.
     #[inline]
     pub fn send_u32(&mut self, value: u32) -> Result<()> {
-        self.0.write_all(&value.to_le_bytes())?;
+        self.writer.write_all(&value.to_le_bytes())?;
         Ok(())
     }
 
     #[inline]
     pub fn read_u32(&mut self) -> Result<u32> {
         let mut buffer = [0u8; 4];
-        self.0.read_exact(&mut buffer)?;
+        self.reader.read_exact(&mut buffer)?;
         Ok(u32::from_le_bytes(buffer))
     }
 
+    /// Writes a raw [`u64`] without flushing.
     #[inline]
-    pub fn send_string(&mut self, value: &String) -> Result<()> {
-        let buffer = value.as_bytes();
-        self.send_u32(buffer.len() as u32)?;
-        self.0.write_all(buffer)?;
+    pub fn send_u64(&mut self, value: u64) -> Result<()> {
+        self.writer.write_all(&value.to_le_bytes())?;
         Ok(())
     }
 
     #[inline]
-    pub fn read_string(&mut self) -> Result<String> {
-        let length = self.read_u32()? as usize;
-        let mut buffer = vec![0u8; length];
-        self.0.read_exact(&mut buffer)?;
-        Ok(String::from_utf8(buffer)?)
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let mut buffer = [0u8; 8];
+        self.reader.read_exact(&mut buffer)?;
+        Ok(u64::from_le_bytes(buffer))
+    }
+
+    /// Announces the length of a file stream, as a [`u64`] with [`Capability::LargeFiles`] and as a
+    /// [`u32`] otherwise.
+    fn send_length(&mut self, length: u64) -> Result<()> {
+        match self.supports(Capability::LargeFiles) {
+            true => self.send_u64(length),
+            false => self.send_u32(u32::try_from(length)?),
+        }
+    }
+
+    fn read_length(&mut self) -> Result<u64> {
+        match self.supports(Capability::LargeFiles) {
+            true => self.read_u64(),
+            false => Ok(self.read_u32()? as u64),
+        }
+    }
+
+    /// Performs the client half of the handshake, advertising `capabilities`.
+    ///
+    /// Afterwards only the capabilities supported by both peers are enabled (see:
+    /// [`Connection::supports`]).
+    pub fn handshake_client(&mut self, capabilities: Capabilities) -> Result<()> {
+        self.send_hello(capabilities)?;
+        let (version, peer) = self.read_hello()?;
+        if version != PROTOCOL_VERSION {
+            return Err(anyhow!(format!(
+                "Protocol version mismatch: server speaks {}, client speaks {}",
+                version, PROTOCOL_VERSION
+            )));
+        }
+        self.negotiate(capabilities, peer);
+        Ok(())
+    }
+
+    /// Performs the server half of the handshake, advertising `capabilities`.
+    pub fn handshake_server(&mut self, capabilities: Capabilities) -> Result<()> {
+        let (version, peer) = self.read_hello()?;
+        self.send_hello(capabilities)?;
+        if version != PROTOCOL_VERSION {
+            return Err(anyhow!(format!(
+                "Protocol version mismatch: client speaks {}, server speaks {}",
+                version, PROTOCOL_VERSION
+            )));
+        }
+        self.negotiate(capabilities, peer);
+        Ok(())
     }
 
+    fn send_hello(&mut self, capabilities: Capabilities) -> Result<()> {
+        self.writer.write_all(MAGIC)?;
+        self.send_u32(PROTOCOL_VERSION)?;
+        self.writer.write_all(&capabilities.0.to_le_bytes())?;
+        self.flush()
+    }
+
+    fn read_hello(&mut self) -> Result<(u32, Capabilities)> {
+        self.read_magic()?;
+        let version = self.read_u32()?;
+        let mut capabilities = [0u8; 8];
+        self.reader.read_exact(&mut capabilities)?;
+        Ok((version, Capabilities(u64::from_le_bytes(capabilities))))
+    }
+
+    fn negotiate(&mut self, local: Capabilities, peer: Capabilities) {
+        self.peer_capabilities = peer;
+        self.capabilities = local.intersection(peer);
+        self.integrity = match self.supports(Capability::Crc32) {
+            true => Integrity::Crc32,
+            false => Integrity::None,
+        };
+    }
+
+    /// Whether `capability` was negotiated, i.e. both peers support it.
     #[inline]
-    pub fn send_request(&mut self, request: &Request) -> Result<()> {
-        let data = bincode::serialize(&request)?;
-        let length = data.len() as u32;
-        self.send_u32(length)?;
-        self.0.write_all(&data)?;
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// The capabilities supported by both peers.
+    #[inline]
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Everything the peer advertised, including capabilities this side does not support.
+    #[inline]
+    pub fn peer_capabilities(&self) -> Capabilities {
+        self.peer_capabilities
+    }
+
+    fn read_magic(&mut self) -> Result<()> {
+        let mut magic = [0u8; 4];
+        self.reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(anyhow!("Peer is not an oxideux endpoint"));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn integrity(&self) -> Integrity {
+        self.integrity
+    }
+
+    /// Writes `checksum` as the integrity trailer, if one was negotiated.
+    #[inline]
+    fn write_trailer(&mut self, checksum: u32) -> Result<()> {
+        if self.integrity == Integrity::Crc32 {
+            self.send_u32(checksum)?;
+        }
         Ok(())
     }
 
+    /// Reads and checks the integrity trailer for a frame whose checksum was computed as `checksum`.
     #[inline]
-    pub fn read_request(&mut self) -> Result<Request> {
-        let length = self.read_u32()? as usize;
+    fn read_trailer(&mut self, checksum: u32) -> Result<()> {
+        if self.integrity == Integrity::Crc32 {
+            let expected = self.read_u32()?;
+            if expected != checksum {
+                return Err(anyhow!(format!(
+                    "Frame failed integrity check (expected CRC32 {:08x}, got {:08x})",
+                    expected, checksum
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a length prefixed frame followed by its integrity trailer and flushes it.
+    fn write_frame(&mut self, data: &[u8]) -> Result<()> {
+        // The peer would refuse it, and only after reading the length
+        if data.len() > MAX_FRAME_LENGTH as usize {
+            return Err(anyhow!(format!("Frame of {} bytes is longer than the most allowed, {}", data.len(), MAX_FRAME_LENGTH)));
+        }
+        self.send_u32(data.len() as u32)?;
+        self.write_payload(data)?;
+        self.write_trailer(crc32fast::hash(data))?;
+        self.pace(data.len());
+        self.flush()
+    }
+
+    /// Reads a length prefixed frame, verifying its integrity trailer.
+    fn read_frame(&mut self) -> Result<Vec<u8>> {
+        let length = self.read_u32()?;
+        if length > MAX_FRAME_LENGTH {
+            return Err(anyhow!(format!("Frame of {} bytes is longer than the most allowed, {}", length, MAX_FRAME_LENGTH)));
+        }
+        let length = length as usize;
         let mut buffer = vec![0u8; length];
-        self.0.read_exact(&mut buffer)?;
-        let request = bincode::deserialize::<Request>(&buffer)?;
-        Ok(request)
+        self.reader.read_exact(&mut buffer)?;
+        self.read_trailer(crc32fast::hash(&buffer))?;
+        self.pace(length);
+        Ok(buffer)
+    }
+
+    /// Sends a standalone [`u32`] message and flushes it.
+    #[inline]
+    pub fn send_count(&mut self, value: u32) -> Result<()> {
+        let bytes = value.to_le_bytes();
+        self.writer.write_all(&bytes)?;
+        self.write_trailer(crc32fast::hash(&bytes))?;
+        self.flush()
+    }
+
+    /// Reads a standalone [`u32`] message sent with [`Connection::send_count`].
+    #[inline]
+    pub fn read_count(&mut self) -> Result<u32> {
+        let mut bytes = [0u8; 4];
+        self.reader.read_exact(&mut bytes)?;
+        self.read_trailer(crc32fast::hash(&bytes))?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    #[inline]
+    pub fn send_string(&mut self, value: &String) -> Result<()> {
+        self.write_frame(value.as_bytes())
+    }
+
+    #[inline]
+    pub fn read_string(&mut self) -> Result<String> {
+        let buffer = self.read_frame()?;
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    /// Sends any serializable value as a single frame.
+    #[inline]
+    pub fn send_object<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        let data = bincode::serialize(value)?;
+        self.write_frame(&data)
+    }
+
+    /// Reads a value sent with [`Connection::send_object`].
+    #[inline]
+    pub fn read_object<T: DeserializeOwned>(&mut self) -> Result<T> {
+        let buffer = self.read_frame()?;
+        Ok(bincode::deserialize::<T>(&buffer)?)
+    }
+
+    /// Sends `request` and returns the id it was tagged with. Requests may be pipelined: the
+    /// server answers them in the order they were sent.
+    pub fn send_request(&mut self, request: &Request) -> Result<u32> {
+        let id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1).max(1);
+        let data = bincode::serialize(&TaggedRequest { id, request: request.clone() })?;
+        self.write_frame(&data)?;
+        self.last_sent_request = Some(id);
+        Ok(id)
+    }
+
+    /// Asks the server to cancel the request tagged with `id`.
+    pub fn send_cancel(&mut self, id: u32) -> Result<()> {
+        let data = bincode::serialize(&TaggedRequest { id: 0, request: Request::Cancel(id) })?;
+        self.write_frame(&data)
+    }
+
+    /// Creates a [`Canceller`] that can cancel requests of this connection from another thread,
+    /// e.g. while this one is blocked in [`Connection::read_file`].
+    pub fn canceller(&self) -> Result<Canceller> {
+        Ok(Canceller {
+            stream: self.stream().try_clone()?,
+            integrity: self.integrity,
+        })
+    }
+
+    /// Reads the next request, returning it along with its id.
+    ///
+    /// Requests that were pipelined behind a transfer are returned first, and requests that were
+    /// cancelled before being handled are skipped.
+    pub fn read_request(&mut self) -> Result<(u32, Request)> {
+        loop {
+            let tagged = match self.pending_requests.pop_front() {
+                Some(tagged) => tagged,
+                None => self.read_tagged_request()?,
+            };
+            if self.cancelled_requests.remove(&tagged.id) {
+                continue;
+            }
+            self.current_request = Some(tagged.id);
+            return Ok((tagged.id, tagged.request));
+        }
+    }
+
+    /// Sends a [`Request::Ping`] and waits at most `timeout` for the [`RequestResult::Pong`],
+    /// returning the round trip time.
+    pub fn ping(&mut self, timeout: Duration) -> Result<Duration> {
+        let start = Instant::now();
+        self.send_request(&Request::Ping)?;
+        self.stream().set_read_timeout(Some(timeout))?;
+        let result = self.read_request_result();
+        self.stream().set_read_timeout(None)?;
+        match result? {
+            RequestResult::Pong => Ok(start.elapsed()),
+            other => Err(anyhow!(format!("Expected a pong, got {:?}", other))),
+        }
+    }
+
+    fn read_tagged_request(&mut self) -> Result<TaggedRequest> {
+        let buffer = self.read_frame()?;
+        Ok(bincode::deserialize::<TaggedRequest>(&buffer)?)
+    }
+
+    /// Checks, without blocking, whether the peer has sent anything since the last read. Any
+    /// [`Request::Cancel`] is recorded and other requests are queued for [`Connection::read_request`].
+    ///
+    /// Returns `true` if the current request has been cancelled.
+    fn poll_cancelled(&mut self) -> Result<bool> {
+        if !self.supports(Capability::Cancel) {
+            return Ok(false);
+        }
+        while self.has_incoming()? {
+            let tagged = self.read_tagged_request()?;
+            match tagged.request {
+                Request::Cancel(id) => {
+                    self.cancelled_requests.insert(id);
+                }
+                _ => self.pending_requests.push_back(tagged),
+            }
+        }
+        Ok(match self.current_request {
+            Some(id) => self.cancelled_requests.contains(&id),
+            None => false,
+        })
+    }
+
+    fn has_incoming(&mut self) -> Result<bool> {
+        if !self.reader.buffer().is_empty() {
+            return Ok(true);
+        }
+        self.reader.get_ref().set_nonblocking(true)?;
+        let result = self.reader.fill_buf().map(|buffer| !buffer.is_empty());
+        self.reader.get_ref().set_nonblocking(false)?;
+        match result {
+            Ok(available) => Ok(available),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        }
     }
 
     #[inline]
     pub fn send_request_result(&mut self, result: RequestResult) -> Result<RequestResult> {
         let data = bincode::serialize(&result)?;
-        let length = data.len();
-        self.send_u32(length as u32)?;
-        self.0.write_all(&data)?;
+        self.write_frame(&data)?;
         Ok(result)
     }
 
     #[inline]
     pub fn read_request_result(&mut self) -> Result<RequestResult> {
-        let length = self.read_u32()? as usize;
-        let mut buffer = vec![0u8; length];
-        self.0.read_exact(&mut buffer)?;
+        let buffer = self.read_frame()?;
         let result = bincode::deserialize::<RequestResult>(&buffer)?;
         Ok(result)
     }
 
-    #[inline]
     pub fn send_file(&mut self, entry: &Entry) -> Result<()> {
-        dbg!(&entry);
-        self.send_u32(entry.length as u32)?;
-        let mut file = File::open(&entry.path)?;
-        let mut file_buffer = [0u8; 4096];
+        self.send_from(entry, || StableFile::open(entry))
+    }
+
+    /// Like [`Connection::send_file`], reading the contents from what `open` returns instead of
+    /// the local file, e.g. from a [`crate::parity::Storage`].
+    pub fn send_from<R: Read, F: FnOnce() -> Result<R>>(&mut self, entry: &Entry, open: F) -> Result<()> {
+        let length = entry.length;
+        self.emit_started(&entry.name, length);
+        let result = self.send_file_data(entry, open);
+        match &result {
+            Ok(_) => self.emit_completed(&entry.name, length),
+            Err(error) => self.emit(TransferEvent::Failed { name: &entry.name, error }),
+        }
+        result
+    }
+
+    fn send_file_data<R: Read, F: FnOnce() -> Result<R>>(&mut self, entry: &Entry, open: F) -> Result<()> {
+        let length = entry.length;
+        if length > u32::MAX as u64 && !self.supports(Capability::LargeFiles) {
+            // Announced as empty and cut short right away, which the peer takes as a cancellation
+            self.send_u32(0)?;
+            self.send_u32(CHUNK_CANCELLED)?;
+            self.flush()?;
+            return Err(anyhow::Error::new(Cancelled).context(format!(
+                "'{}' is {} bytes, more than the peer can receive without large file support",
+                entry.name, length
+            )));
+        }
+        // Opened before the length is announced, so a file that no longer matches its entry is
+        // refused as changed rather than sent with a length it doesn't have
+        let opened = open();
+        self.send_length(length)?;
+        let mut file = match opened {
+            Ok(file) => file,
+            Err(error) if error.is::<FileChanged>() => {
+                self.send_u32(CHUNK_CHANGED)?;
+                self.flush()?;
+                return Err(error);
+            }
+            Err(error) => return Err(error),
+        };
+        let mut file_buffer = [0u8; CHUNK_SIZE];
+        let mut hasher = crc32fast::Hasher::new();
+        let mut bytes_sent = 0;
         loop {
-            let n = file.read(&mut file_buffer)?;
+            let stopped = match self.poll_cancelled()? {
+                true => Err(Cancelled.into()),
+                false => self.checkpoint(),
+            };
+            if let Err(error) = stopped {
+                self.send_u32(CHUNK_CANCELLED)?;
+                self.flush()?;
+                return Err(error);
+            }
+            let n = match file.read(&mut file_buffer) {
+                Ok(n) => n,
+                Err(e) => match e.get_ref().and_then(|inner| inner.downcast_ref::<FileChanged>()) {
+                    Some(changed) => {
+                        let changed = changed.clone();
+                        self.send_u32(CHUNK_CHANGED)?;
+                        self.flush()?;
+                        return Err(changed.into());
+                    }
+                    None => return Err(e.into()),
+                },
+            };
             if n == 0 {
                 break;
             }
-            self.0.write_all(&file_buffer[..n])?;
+            self.send_u32(n as u32)?;
+            self.write_payload(&file_buffer[..n])?;
+            self.pace(n);
+            hasher.update(&file_buffer[..n]);
+            bytes_sent += n as u64;
+            self.emit_progressed(&entry.name, bytes_sent, length);
         }
-        Ok(())
+        self.send_u32(CHUNK_END)?;
+        self.write_trailer(hasher.finalize())?;
+        self.flush()
     }
 
-    #[inline]
     pub fn read_file(&mut self, output: &PathBuf) -> Result<()> {
-        let length = self.read_u32()? as usize;
-        println!("Downloading file ({} MiB)", length / 1048576);
-        let mut file = File::create(output)?;
-        let mut buffer = [0u8; 4096];
+        self.read_file_at(output, 0)
+    }
+
+    /// Like [`Connection::read_file`], keeping the first `offset` bytes of `output` and appending
+    /// what is received after them.
+    pub fn read_file_at(&mut self, output: &PathBuf, offset: u64) -> Result<()> {
+        let name = output
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.read_named_file_at(&name, output, offset)
+    }
+
+    /// Like [`Connection::read_file_at`], reporting the file as `name` rather than by the name of
+    /// `output`, such as when it is received into a `.part` file.
+    pub fn read_named_file_at(&mut self, name: &str, output: &PathBuf, offset: u64) -> Result<()> {
+        let result = self.read_file_data(name, output, offset);
+        if let Err(error) = &result {
+            // Half of one version and half of another is worse than nothing, and so is half a file
+            if error.is::<FileChanged>() || error.is::<Cancelled>() || error.is::<Aborted>() {
+                let _ = std::fs::remove_file(output);
+            }
+            self.emit(TransferEvent::Failed { name, error });
+        }
+        result
+    }
+
+    /// Like [`Connection::read_file`], but writes what is received to `output` instead of a file,
+    /// reporting it as `name`. `output` may hold part of the file already when this fails.
+    pub fn read_file_to<W: Write>(&mut self, name: &str, output: &mut W) -> Result<()> {
+        let result = self.read_stream(name, output);
+        if let Err(error) = &result {
+            self.emit(TransferEvent::Failed { name, error });
+        }
+        result
+    }
+
+    fn read_file_data(&mut self, name: &str, output: &PathBuf, offset: u64) -> Result<()> {
+        let file = match offset {
+            0 => File::create(output)?,
+            offset => {
+                let mut file = File::options().write(true).open(output)?;
+                file.set_len(offset)?;
+                file.seek(SeekFrom::End(0))?;
+                file
+            }
+        };
+        let mut file = BufWriter::new(file);
+        self.read_stream(name, &mut file)
+    }
+
+    /// Sends `files` as a single length-prefixed frame, saving the round trips of sending small
+    /// files one by one. Each is reported as a transfer of its own once the frame is out.
+    pub fn send_batch(&mut self, files: &[BatchedFile]) -> Result<()> {
+        let result = self.send_object(&files);
+        for file in files {
+            let length = file.contents.len() as u64;
+            self.emit_started(&file.name, length);
+            match &result {
+                Ok(_) => self.emit_completed(&file.name, length),
+                Err(error) => self.emit(TransferEvent::Failed { name: &file.name, error }),
+            }
+        }
+        result
+    }
+
+    /// Reads files sent with [`Connection::send_batch`], to be written with
+    /// [`Connection::write_batched`].
+    #[inline]
+    pub fn read_batch(&mut self) -> Result<Vec<BatchedFile>> {
+        self.read_object()
+    }
+
+    /// Writes a file received with [`Connection::read_batch`] to `output`, reporting it like
+    /// [`Connection::read_file`].
+    pub fn write_batched(&mut self, file: &BatchedFile, output: &PathBuf) -> Result<()> {
+        let length = file.contents.len() as u64;
+        self.emit_started(&file.name, length);
+        let result = std::fs::write(output, &file.contents).map_err(anyhow::Error::from);
+        match &result {
+            Ok(_) => self.emit_completed(&file.name, length),
+            Err(error) => self.emit(TransferEvent::Failed { name: &file.name, error }),
+        }
+        result
+    }
+
+    fn read_stream<W: Write>(&mut self, name: &str, output: &mut W) -> Result<()> {
+        // A peer that stops sending altogether would never get to the rate being checked
+        if self.minimum_rate.is_none() {
+            return self.read_chunks(name, output);
+        }
+        self.stream().set_read_timeout(Some(STALL_WINDOW))?;
+        let result = self.read_chunks(name, output).map_err(stalled);
+        self.stream().set_read_timeout(None)?;
+        result
+    }
+
+    fn read_chunks<W: Write>(&mut self, name: &str, output: &mut W) -> Result<()> {
+        let length = self.read_length()?;
+        self.emit_started(name, length);
+        let mut buffer = [0u8; CHUNK_SIZE];
+        let mut hasher = crc32fast::Hasher::new();
         let mut bytes_read = 0;
-        while bytes_read < length {
-            let n = self.0.read(&mut buffer)?;
-            bytes_read += n;
-            file.write_all(&buffer[..n])?;
+        // Why the transfer was stopped here, while the server is asked to stop sending
+        let mut stopped: Option<anyhow::Error> = None;
+        loop {
+            if stopped.is_none() {
+                if let Err(error) = self.checkpoint() {
+                    stopped = self.stop_receiving(error)?;
+                }
+            }
+            let n = match self.read_u32()? {
+                // Past stopping, as the server got to the end first
+                CHUNK_END => break,
+                CHUNK_CANCELLED => return Err(stopped.unwrap_or_else(|| Cancelled.into())),
+                CHUNK_CHANGED => return Err(FileChanged { name: name.to_string() }.into()),
+                n if n as usize > CHUNK_SIZE => {
+                    return Err(anyhow!(format!("Invalid chunk length: {}", n)));
+                }
+                n => n as usize,
+            };
+            let start = Instant::now();
+            self.reader.read_exact(&mut buffer[..n])?;
+            self.check_rate(n, start)?;
+            self.pace(n);
+            bytes_read += n as u64;
+            output.write_all(&buffer[..n])?;
+            hasher.update(&buffer[..n]);
+            self.emit_progressed(name, bytes_read, length);
+        }
+        if bytes_read != length {
+            return Err(anyhow!(format!(
+                "Received {} bytes but expected {}",
+                bytes_read, length
+            )));
         }
+        self.read_trailer(hasher.finalize())?;
+        output.flush()?;
+        self.emit_completed(name, length);
         Ok(())
     }
+
+    /// Asks the server to stop sending the file being received, for `reason`, which is returned
+    /// when it will. Servers that can't be asked send the file whole.
+    fn stop_receiving(&mut self, reason: anyhow::Error) -> Result<Option<anyhow::Error>> {
+        match self.last_sent_request {
+            Some(id) if self.supports(Capability::Cancel) => {
+                self.send_cancel(id)?;
+                Ok(Some(reason))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// `error`, or that the peer stopped sending or taking anything if it is the timeout set along
+/// with a minimum rate running out (see: [`Connection::set_minimum_rate`]).
+fn stalled(error: anyhow::Error) -> anyhow::Error {
+    match error.downcast_ref::<io::Error>().map(io::Error::kind) {
+        Some(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => anyhow!(format!(
+            "Dropped: the peer sent or took nothing for {}s",
+            STALL_WINDOW.as_secs()
+        )),
+        _ => error,
+    }
+}
+
+/// Lets [`KeepAlive`] ping a bare connection as well as types wrapping one.
+impl AsMut<Connection> for Connection {
+    fn as_mut(&mut self) -> &mut Connection {
+        self
+    }
+}
+
+/// A handle that cancels requests of a [`Connection`] from another thread.
+///
+/// Created with [`Connection::canceller`]. Each cancellation is written to the socket as a single
+/// frame, bypassing the buffered writer of the connection it was created from.
+pub struct Canceller {
+    stream: Box<dyn Transport>,
+    integrity: Integrity,
+}
+
+impl Canceller {
+    pub fn cancel(&mut self, id: u32) -> Result<()> {
+        let data = bincode::serialize(&TaggedRequest { id: 0, request: Request::Cancel(id) })?;
+        let mut frame = Vec::with_capacity(data.len() + 8);
+        frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&data);
+        if self.integrity == Integrity::Crc32 {
+            frame.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+        }
+        self.stream.write_all(&frame)?;
+        Ok(())
+    }
+}
+
+/// Background keep-alive for a persistent session.
+///
+/// Every [`KEEP_ALIVE_INTERVAL`] the connection is pinged, unless it is busy (locked by another
+/// thread), in which case traffic is already flowing. The first failed ping marks the session as
+/// lost and calls `on_lost` with the error; the keep-alive then stops. Dropping the [`KeepAlive`]
+/// stops it as well.
+pub struct KeepAlive {
+    stop: Arc<AtomicBool>,
+    lost: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl KeepAlive {
+    pub fn spawn<T: AsMut<Connection> + Send + 'static, F: FnOnce(anyhow::Error) + Send + 'static>(
+        conn: Arc<Mutex<T>>,
+        on_lost: F,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let lost = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let stop = Arc::clone(&stop);
+            let lost = Arc::clone(&lost);
+            thread::spawn(move || {
+                let mut last_ping = Instant::now();
+                while !stop.load(Ordering::Relaxed) {
+                    thread::sleep(Duration::from_millis(250));
+                    if last_ping.elapsed() < KEEP_ALIVE_INTERVAL {
+                        continue;
+                    }
+                    last_ping = Instant::now();
+
+                    let mut conn = match conn.try_lock() {
+                        Ok(conn) => conn,
+                        Err(_) => continue,
+                    };
+                    if let Err(e) = conn.as_mut().ping(KEEP_ALIVE_TIMEOUT) {
+                        lost.store(true, Ordering::Relaxed);
+                        on_lost(e);
+                        return;
+                    }
+                }
+            })
+        };
+
+        Self {
+            stop,
+            lost,
+            handle: Some(handle),
+        }
+    }
+
+    /// Whether a ping has failed since the keep-alive was started.
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Two connected ends that negotiated `capabilities`.
+    fn pair(capabilities: Capabilities) -> (Connection, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut server = Connection::new(listener.accept().unwrap().0).unwrap();
+            server.handshake_server(capabilities).unwrap();
+            server
+        });
+        let mut client = Connection::new(TcpStream::connect(address).unwrap()).unwrap();
+        client.handshake_client(capabilities).unwrap();
+        (server.join().unwrap(), client)
+    }
+
+    fn entry(length: u64) -> Entry {
+        Entry {
+            name: "file.bin".to_string(),
+            path: PathBuf::from("file.bin"),
+            length,
+            modified: None,
+        }
+    }
+
+    #[test]
+    fn lengths_past_u32_are_announced_whole() {
+        let length = u32::MAX as u64 + 2;
+        let (mut sender, mut receiver) = pair(Capabilities::local());
+        // Only the length matters here, so the contents are cut short
+        let sent = thread::spawn(move || sender.send_from(&entry(length), || Ok(&b"head"[..])).is_ok());
+        let received = receiver.read_file_to("file.bin", &mut vec![]).unwrap_err();
+
+        assert!(sent.join().unwrap());
+        assert_eq!(received.to_string(), format!("Received 4 bytes but expected {}", length));
+    }
+
+    #[test]
+    fn progress_of_large_files_counts_every_byte() {
+        let length = 40 << 30;
+        let (mut sender, mut receiver) = pair(Capabilities::local());
+        let progress = Arc::new(Mutex::new(None));
+        let seen = Arc::clone(&progress);
+        receiver.set_transfer_handler(move |event| {
+            if let TransferEvent::Progressed { stats, .. } = event {
+                *seen.lock().unwrap() = Some(*stats);
+            }
+        });
+        let sent = thread::spawn(move || sender.send_from(&entry(length), || Ok(&b"head"[..])).is_ok());
+        let _ = receiver.read_file_to("file.bin", &mut vec![]);
+        let stats = progress.lock().unwrap().unwrap();
+
+        assert!(sent.join().unwrap());
+        assert_eq!((stats.transferred, stats.length), (4, length));
+        assert_eq!(stats.remaining(), length - 4);
+    }
+
+    #[test]
+    fn large_files_are_refused_to_peers_without_large_file_support() {
+        let mut capabilities = Capabilities::local();
+        capabilities.remove(Capability::LargeFiles);
+        let (mut sender, mut receiver) = pair(capabilities);
+        let sent = thread::spawn(move || {
+            let refused = sender.send_from(&entry(u32::MAX as u64 + 1), || Ok(&b""[..])).unwrap_err();
+            let small = sender.send_from(&entry(4), || Ok(&b"tail"[..]));
+            (refused.is::<Cancelled>(), small.is_ok())
+        });
+        let refused = receiver.read_file_to("file.bin", &mut vec![]).unwrap_err();
+        let mut contents = vec![];
+        receiver.read_file_to("file.bin", &mut contents).unwrap();
+
+        assert_eq!(sent.join().unwrap(), (true, true));
+        assert!(refused.is::<Cancelled>());
+        assert_eq!(contents, b"tail");
+    }
 }
@@ -0,0 +1,260 @@
+//! rsync-style delta transfers, so a large file that changed slightly costs about as much as the
+//! change rather than the whole file.
+//!
+//! The client sends the [`Signature`] of its copy: a weak rolling checksum and a strong hash per
+//! block. The server slides a window over its version, looking each position's weak checksum up in
+//! the signature, and answers a stream of [`DeltaOp`]s: blocks the client already has, and the
+//! literal bytes between them. The client rebuilds the file from its copy and the literals, and
+//! checks the result against the hash that ends the stream.
+
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const MIN_BLOCK_SIZE: u32 = 2 * 1024;
+const MAX_BLOCK_SIZE: u32 = 64 * 1024;
+/// The most literal bytes sent in one [`DeltaOp::Literal`].
+pub const MAX_LITERAL_LENGTH: usize = 256 * 1024;
+/// Files smaller than this are cheaper to send whole.
+pub const DELTA_THRESHOLD: u64 = 1024 * 1024;
+
+/// The blocks of a file as the client has it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Signature {
+    pub block_size: u32,
+    /// One per whole block; a shorter last block is left out and always sent as literal bytes.
+    pub blocks: Vec<BlockSignature>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BlockSignature {
+    pub weak: u32,
+    /// The first 16 bytes of the block's SHA-256.
+    pub strong: [u8; 16],
+}
+
+/// One step of rebuilding a file, as streamed in answer to [`crate::request::Request::DownloadDelta`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DeltaOp {
+    /// Block `index` of the client's copy.
+    Copy(u32),
+    Literal(Vec<u8>),
+    /// The rebuilt file is complete, and has this length and lowercase hex SHA-256.
+    End { length: u64, hash: String },
+}
+
+/// About the square root of `length`, so the signature and the matching granularity grow together.
+pub fn block_size(length: u64) -> u32 {
+    let root = (length as f64).sqrt() as u32;
+    root.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE) / 1024 * 1024
+}
+
+/// The rsync weak checksum of a window, which can be rolled along one byte at a time.
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    length: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, byte) in window.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((window.len() - i) as u32 * *byte as u32);
+        }
+        Self {
+            a,
+            b,
+            length: window.len() as u32,
+        }
+    }
+
+    /// Moves the window one byte on, dropping `out` and taking in `input`.
+    fn roll(&mut self, out: u8, input: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(input as u32);
+        self.b = self.b.wrapping_sub(self.length.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(block: &[u8]) -> [u8; 16] {
+    let mut strong = [0u8; 16];
+    strong.copy_from_slice(&Sha256::digest(block)[..16]);
+    strong
+}
+
+/// The signature of everything `reader` yields, in blocks of `block_size`.
+pub fn signature<R: Read>(mut reader: R, block_size: u32) -> Result<Signature> {
+    let mut blocks = vec![];
+    let mut block = vec![0u8; block_size as usize];
+    loop {
+        let mut filled = 0;
+        while filled < block.len() {
+            match reader.read(&mut block[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled < block.len() {
+            return Ok(Signature { block_size, blocks });
+        }
+        blocks.push(BlockSignature {
+            weak: Rolling::new(&block).digest(),
+            strong: strong(&block),
+        });
+    }
+}
+
+/// Compares everything `reader` yields against `signature`, passing the [`DeltaOp`]s that rebuild
+/// it to `emit`, [`DeltaOp::End`] last. Returns how many literal bytes were emitted.
+pub fn delta<R: Read, F: FnMut(DeltaOp) -> Result<()>>(signature: &Signature, mut reader: R, mut emit: F) -> Result<u64> {
+    let block_size = signature.block_size as usize;
+    if block_size == 0 {
+        return Err(anyhow!("Invalid block size"));
+    }
+    let mut by_weak: HashMap<u32, Vec<u32>> = HashMap::new();
+    for (index, block) in signature.blocks.iter().enumerate() {
+        by_weak.entry(block.weak).or_default().push(index as u32);
+    }
+
+    let mut hasher = Sha256::new();
+    let mut length = 0u64;
+    let mut literal_bytes = 0u64;
+    // Holds the pending literal from `start`, then the window from `position`
+    let mut buffer: Vec<u8> = vec![];
+    let (mut start, mut position) = (0, 0);
+    let mut rolling: Option<Rolling> = None;
+    let mut eof = false;
+
+    loop {
+        // The window plus the byte rolled in next
+        while !eof && buffer.len() < position + block_size + 1 {
+            let filled = buffer.len();
+            buffer.resize(filled + 2 * MAX_LITERAL_LENGTH, 0);
+            let n = reader.read(&mut buffer[filled..])?;
+            buffer.truncate(filled + n);
+            hasher.update(&buffer[filled..]);
+            length += n as u64;
+            eof = n == 0;
+        }
+        if buffer.len() < position + block_size {
+            break;
+        }
+
+        let window = &buffer[position..position + block_size];
+        let weak = *rolling.get_or_insert_with(|| Rolling::new(window));
+        let matched = by_weak.get(&weak.digest()).and_then(|candidates| {
+            let strong = strong(window);
+            candidates
+                .iter()
+                .copied()
+                .find(|index| signature.blocks[*index as usize].strong == strong)
+        });
+
+        match matched {
+            Some(index) => {
+                literal_bytes += emit_literal(&buffer[start..position], &mut emit)?;
+                emit(DeltaOp::Copy(index))?;
+                position += block_size;
+                start = position;
+                rolling = None;
+            }
+            None => {
+                if let (Some(rolling), Some(input)) = (rolling.as_mut(), buffer.get(position + block_size)) {
+                    rolling.roll(buffer[position], *input);
+                } else {
+                    rolling = None;
+                }
+                position += 1;
+                if position - start >= MAX_LITERAL_LENGTH {
+                    literal_bytes += emit_literal(&buffer[start..position], &mut emit)?;
+                    start = position;
+                }
+            }
+        }
+
+        // Keep the buffer from growing with the file
+        if start >= 2 * MAX_LITERAL_LENGTH {
+            buffer.drain(..start);
+            position -= start;
+            start = 0;
+        }
+    }
+
+    literal_bytes += emit_literal(&buffer[start..], &mut emit)?;
+    let hash = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+    emit(DeltaOp::End { length, hash })?;
+    Ok(literal_bytes)
+}
+
+/// Emits `bytes` as literals of at most [`MAX_LITERAL_LENGTH`], returning how many there were.
+fn emit_literal<F: FnMut(DeltaOp) -> Result<()>>(bytes: &[u8], emit: &mut F) -> Result<u64> {
+    for piece in bytes.chunks(MAX_LITERAL_LENGTH) {
+        emit(DeltaOp::Literal(piece.to_vec()))?;
+    }
+    Ok(bytes.len() as u64)
+}
+
+/// Rebuilds a file into `output` from [`DeltaOp`]s and the `basis` copy their [`Signature`] was
+/// made from, checking the result against [`DeltaOp::End`].
+pub struct Patcher<B: Read + Seek, W: Write> {
+    basis: B,
+    output: W,
+    block_size: u32,
+    hasher: Sha256,
+    written: u64,
+    block: Vec<u8>,
+}
+
+impl<B: Read + Seek, W: Write> Patcher<B, W> {
+    pub fn new(basis: B, output: W, block_size: u32) -> Self {
+        Self {
+            basis,
+            output,
+            block_size,
+            hasher: Sha256::new(),
+            written: 0,
+            block: vec![0u8; block_size as usize],
+        }
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Applies `op`, returning whether it ended the file.
+    pub fn apply(&mut self, op: DeltaOp) -> Result<bool> {
+        match op {
+            DeltaOp::Copy(index) => {
+                self.basis.seek(SeekFrom::Start(index as u64 * self.block_size as u64))?;
+                self.basis.read_exact(&mut self.block)?;
+                self.output.write_all(&self.block)?;
+                self.hasher.update(&self.block);
+                self.written += self.block.len() as u64;
+                Ok(false)
+            }
+            DeltaOp::Literal(bytes) => {
+                self.output.write_all(&bytes)?;
+                self.hasher.update(&bytes);
+                self.written += bytes.len() as u64;
+                Ok(false)
+            }
+            DeltaOp::End { length, hash } => {
+                self.output.flush()?;
+                let actual: String = self.hasher.clone().finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+                if length != self.written || hash != actual {
+                    return Err(anyhow!("The rebuilt file does not match the server's, the local copy may have changed"));
+                }
+                Ok(true)
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! LAN server discovery over mDNS/DNS-SD.
+//!
+//! Servers advertise themselves as `<profile name>._oxideux._tcp.local.` so clients on the same
+//! network can find them without typing addresses (see: [`discover`]).
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+pub const SERVICE_TYPE: &str = "_oxideux._tcp.local.";
+
+/// A server found by [`discover`].
+#[derive(Debug, Clone)]
+pub struct DiscoveredServer {
+    /// The instance name, i.e. the name of the server profile that is advertised.
+    pub instance: String,
+    pub addresses: Vec<Ipv4Addr>,
+    pub port: u16,
+}
+
+/// A running advertisement. The server stops being discoverable once this is dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertisement {
+    /// Advertises `instance` on `port` on every network interface.
+    pub fn new<S: AsRef<str>>(instance: S, port: u16) -> Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+        let host_name = format!("oxideux-{}.local.", label(instance.as_ref()));
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            instance.as_ref(),
+            &host_name,
+            "",
+            port,
+            None::<HashMap<String, String>>,
+        )?
+        .enable_addr_auto();
+        let fullname = info.get_fullname().to_string();
+        daemon.register(info)?;
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Browses the local network for `timeout` and returns every server that answered.
+pub fn discover(timeout: Duration) -> Result<Vec<DiscoveredServer>> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+
+    let mut servers: Vec<DiscoveredServer> = vec![];
+    let deadline = Instant::now() + timeout;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let event = match events.recv_timeout(remaining) {
+            Ok(event) => event,
+            Err(_) => break,
+        };
+        if let ServiceEvent::ServiceResolved(info) = event {
+            let instance = info
+                .get_fullname()
+                .strip_suffix(SERVICE_TYPE)
+                .unwrap_or(info.get_fullname())
+                .trim_end_matches('.')
+                .to_string();
+            let mut addresses: Vec<Ipv4Addr> = info.get_addresses_v4().into_iter().copied().collect();
+            addresses.sort();
+
+            servers.retain(|server| server.instance != instance);
+            servers.push(DiscoveredServer {
+                instance,
+                addresses,
+                port: info.get_port(),
+            });
+        }
+    }
+
+    let _ = daemon.stop_browse(SERVICE_TYPE);
+    let _ = daemon.shutdown();
+    servers.sort_by(|a, b| a.instance.cmp(&b.instance));
+    Ok(servers)
+}
+
+/// Turns an instance name into something usable as a DNS label.
+fn label(name: &str) -> String {
+    let label: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    match label.trim_matches('-') {
+        "" => "server".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
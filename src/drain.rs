@@ -0,0 +1,188 @@
+//! Draining a running server, so it can be stopped or reconfigured without cutting transfers short.
+//!
+//! Once [`Drain::start`] is called, the server stops accepting connections and closes the ones
+//! waiting for their next request. The others finish the request they are serving and are closed
+//! then. [`crate::server::Server::serve`] returns when none are left, freeing every port it used.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::transport::Transport;
+
+/// How long waking a listener up may take.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// An open connection, and whether it is between requests.
+struct Open {
+    transport: Box<dyn Transport>,
+    idle: bool,
+    /// The span the request being served runs in, and since when.
+    busy: Option<(tracing::Span, Instant)>,
+}
+
+#[derive(Default)]
+struct Inner {
+    draining: AtomicBool,
+    stopped: AtomicBool,
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Open>>,
+    closed: Condvar,
+    /// Where listeners accept connections, and whether each keeps accepting until the server stops
+    /// rather than until it starts draining.
+    listeners: Mutex<Vec<(SocketAddr, bool)>>,
+}
+
+/// A handle to drain a server with, from any thread. Clones drain the same server.
+#[derive(Clone, Default)]
+pub struct Drain(Arc<Inner>);
+
+impl Drain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops accepting connections and closes the idle ones, leaving the rest to finish what they
+    /// are doing.
+    pub fn start(&self) {
+        {
+            let mut connections = self.0.connections.lock().unwrap();
+            if self.0.draining.swap(true, Ordering::Relaxed) {
+                return;
+            }
+            for open in connections.values_mut().filter(|open| open.idle) {
+                let _ = open.transport.shutdown(Shutdown::Both);
+            }
+        }
+        self.wake_listeners(false);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.0.draining.load(Ordering::Relaxed)
+    }
+
+    /// How many connections are still open.
+    pub fn open_connections(&self) -> usize {
+        self.0.connections.lock().unwrap().len()
+    }
+
+    /// Waits at most `timeout` for every connection to be closed, returning whether they are.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut connections = self.0.connections.lock().unwrap();
+        while !connections.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            connections = self.0.closed.wait_timeout(connections, left).unwrap().0;
+        }
+        true
+    }
+
+    /// Stops the listeners left once draining is over, such as the metrics one.
+    pub(crate) fn stop(&self) {
+        self.0.stopped.store(true, Ordering::Relaxed);
+        self.wake_listeners(true);
+    }
+
+    /// The connections `listener` accepts until the server starts draining, or until it stops with
+    /// `until_stopped`. Whatever is accepted once it does is dropped.
+    pub(crate) fn incoming<'a>(
+        &'a self,
+        listener: &'a TcpListener,
+        until_stopped: bool,
+    ) -> impl Iterator<Item = io::Result<TcpStream>> + 'a {
+        if let Ok(addr) = listener.local_addr() {
+            self.0.listeners.lock().unwrap().push((addr, until_stopped));
+        }
+        let flag = match until_stopped {
+            true => &self.0.stopped,
+            false => &self.0.draining,
+        };
+        listener.incoming().take_while(move |_| !flag.load(Ordering::Relaxed))
+    }
+
+    /// Counts a connection as open until the returned [`Session`] is dropped, or returns `None`
+    /// when the server is draining and takes no more. `transport` is closed if the connection is
+    /// idle when draining starts.
+    pub(crate) fn register(&self, transport: Box<dyn Transport>) -> Option<Session> {
+        let mut connections = self.0.connections.lock().unwrap();
+        if self.is_draining() {
+            return None;
+        }
+        let id = self.0.next_id.fetch_add(1, Ordering::Relaxed);
+        connections.insert(id, Open { transport, idle: false, busy: None });
+        Some(Session { drain: self.clone(), id })
+    }
+
+    /// The requests that have been served for at least `age`, by the span they run in, and for
+    /// how long, such as a transfer to a peer that stopped reading.
+    pub(crate) fn busy(&self, age: Duration) -> Vec<(tracing::Span, Duration)> {
+        let connections = self.0.connections.lock().unwrap();
+        connections
+            .values()
+            .filter_map(|open| open.busy.as_ref())
+            .map(|(span, since)| (span.clone(), since.elapsed()))
+            .filter(|(_, busy)| *busy >= age)
+            .collect()
+    }
+
+    /// Connects to every listener accepting until the server starts draining, or until it stops
+    /// with `until_stopped`, so they notice.
+    fn wake_listeners(&self, until_stopped: bool) {
+        let listeners = self.0.listeners.lock().unwrap().clone();
+        for (mut addr, _) in listeners.into_iter().filter(|(_, until)| *until == until_stopped) {
+            // Listeners on every interface are reached over the loopback one
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr.ip() {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                });
+            }
+            let _ = TcpStream::connect_timeout(&addr, WAKE_TIMEOUT);
+        }
+    }
+}
+
+/// A connection counted by a [`Drain`].
+pub(crate) struct Session {
+    drain: Drain,
+    id: u64,
+}
+
+impl Session {
+    /// Marks the connection as waiting for its next request, returning `false` when it should be
+    /// closed instead as the server is draining.
+    pub fn idle(&self) -> bool {
+        self.set_idle(true)
+    }
+
+    /// Marks the connection as serving a request, in the current span, which it is left to finish
+    /// when draining starts.
+    pub fn busy(&self) {
+        self.set_idle(false);
+    }
+
+    fn set_idle(&self, idle: bool) -> bool {
+        let mut connections = self.drain.0.connections.lock().unwrap();
+        if let Some(open) = connections.get_mut(&self.id) {
+            open.idle = idle;
+            open.busy = match idle {
+                true => None,
+                false => Some((tracing::Span::current(), Instant::now())),
+            };
+        }
+        !self.drain.is_draining()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.drain.0.connections.lock().unwrap().remove(&self.id);
+        self.drain.0.closed.notify_all();
+    }
+}
@@ -0,0 +1,86 @@
+//! What a sync or download would do, worked out from the listings alone: nothing is written to
+//! the parity root and no file contents are transferred.
+
+use std::fmt::Write;
+
+/// What would happen to a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// A remote file would be downloaded where there is no local one.
+    Download,
+    /// A remote file would be downloaded over the local one.
+    Overwrite,
+    Upload,
+    /// A local file would be removed, for no longer being on the server.
+    Delete,
+    /// The file changed on both sides, and settling it is left to the user.
+    Conflict,
+}
+
+impl Change {
+    pub const ALL: [Change; 5] = [Change::Download, Change::Overwrite, Change::Upload, Change::Delete, Change::Conflict];
+
+    pub fn key(&self) -> &'static str {
+        match self {
+            Change::Download => "download",
+            Change::Overwrite => "overwrite",
+            Change::Upload => "upload",
+            Change::Delete => "delete",
+            Change::Conflict => "conflict",
+        }
+    }
+}
+
+/// A file a sync or download would touch.
+#[derive(Debug, Clone)]
+pub struct PlannedChange {
+    pub name: String,
+    pub change: Change,
+    /// How many bytes would be transferred or removed, zero for conflicts.
+    pub bytes: u64,
+}
+
+/// Every file a sync or download would touch, in the order it would touch them.
+#[derive(Debug, Clone, Default)]
+pub struct SyncPlan {
+    pub changes: Vec<PlannedChange>,
+}
+
+impl SyncPlan {
+    pub fn push(&mut self, name: &str, change: Change, bytes: u64) {
+        self.changes.push(PlannedChange {
+            name: name.to_string(),
+            change,
+            bytes,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// How many files would see `change`.
+    pub fn count(&self, change: Change) -> usize {
+        self.changes.iter().filter(|planned| planned.change == change).count()
+    }
+
+    /// How many bytes `change` would transfer or remove in total.
+    pub fn bytes(&self, change: Change) -> u64 {
+        self.changes.iter().filter(|planned| planned.change == change).map(|planned| planned.bytes).sum()
+    }
+
+    /// One line per kind of change with its totals, e.g. `download: 3 file(s), 1024 bytes`.
+    pub fn totals(&self) -> String {
+        let mut totals = String::new();
+        for change in Change::ALL {
+            match (change, self.count(change)) {
+                (_, 0) => (),
+                (Change::Conflict, count) => writeln!(totals, "conflict: {} file(s)", count).unwrap(),
+                (change, count) => {
+                    writeln!(totals, "{}: {} file(s), {} bytes", change.key(), count, self.bytes(change)).unwrap()
+                }
+            }
+        }
+        totals
+    }
+}
@@ -0,0 +1,313 @@
+//! Transfer history, shared by the client and the server.
+//!
+//! Every completed or failed transfer is recorded in an SQLite database at
+//! `oxideux/history.sqlite3` under the config directory. Recording never fails a transfer: if the
+//! database can't be written, the problem is printed and the transfer carries on.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use rusqlite::{params, Connection};
+
+use crate::cli::{Align, Table};
+use crate::config;
+use crate::meter;
+
+const DATABASE_FILE: &str = "oxideux/history.sqlite3";
+/// How much of a file name or an error the history table shows.
+const TABLE_TEXT_WIDTH: usize = 40;
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Which program recorded a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// Which way a file went, from the client's point of view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+impl Side {
+    fn key(&self) -> &'static str {
+        match self {
+            Side::Client => "client",
+            Side::Server => "server",
+        }
+    }
+}
+
+impl Direction {
+    pub fn key(&self) -> &'static str {
+        match self {
+            Direction::Download => "download",
+            Direction::Upload => "upload",
+        }
+    }
+
+    fn from_key(key: &str) -> Self {
+        match key {
+            "upload" => Direction::Upload,
+            _ => Direction::Download,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TransferRecord {
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    pub profile: String,
+    pub peer: String,
+    pub direction: Direction,
+    /// The file's name relative to the parity root.
+    pub file: String,
+    pub bytes: u64,
+    /// How long the transfer took, unknown for those recorded before this was.
+    pub elapsed: Option<Duration>,
+    /// Why the transfer failed, if it did.
+    pub error: Option<String>,
+}
+
+impl TransferRecord {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Bytes per second over the whole transfer, when its duration is known.
+    pub fn average_speed(&self) -> Option<f64> {
+        self.elapsed
+            .filter(|elapsed| !elapsed.is_zero())
+            .map(|elapsed| self.bytes as f64 / elapsed.as_secs_f64())
+    }
+
+    /// One line describing the transfer, for display.
+    pub fn summary(&self) -> String {
+        let time = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(self.time));
+        let outcome = match &self.error {
+            None => "ok".to_string(),
+            Some(error) => format!("failed: {}", error),
+        };
+        let timing = match (self.elapsed, self.average_speed()) {
+            (Some(elapsed), Some(speed)) => format!(
+                " in {} at {}/s",
+                humantime::format_duration(Duration::from_secs(elapsed.as_secs())),
+                meter::format_size(speed as u64)
+            ),
+            _ => String::new(),
+        };
+        format!(
+            "{} [{}] {} '{}' ({} bytes{}) with {}: {}",
+            time,
+            self.profile,
+            self.direction.key(),
+            self.file,
+            self.bytes,
+            timing,
+            self.peer,
+            outcome
+        )
+    }
+}
+
+/// `records` as a table for display, one row per transfer.
+pub fn table(records: &[TransferRecord]) -> Table {
+    let mut table = Table::new();
+    table
+        .add_column("Time", Align::Left)
+        .add_column("Profile", Align::Left)
+        .add_column("Direction", Align::Left)
+        .add_column("File", Align::Left)
+        .set_max_width(TABLE_TEXT_WIDTH)
+        .add_column("Size", Align::Right)
+        .add_column("Took", Align::Right)
+        .add_column("Speed", Align::Right)
+        .add_column("Peer", Align::Left)
+        .add_column("Outcome", Align::Left)
+        .set_max_width(TABLE_TEXT_WIDTH);
+    for record in records {
+        let took = record
+            .elapsed
+            .map(|elapsed| humantime::format_duration(Duration::from_secs(elapsed.as_secs())).to_string())
+            .unwrap_or_default();
+        let speed = record
+            .average_speed()
+            .map(|speed| format!("{}/s", meter::format_size(speed as u64)))
+            .unwrap_or_default();
+        let outcome = match &record.error {
+            None => "ok".to_string(),
+            Some(error) => format!("failed: {}", error),
+        };
+        table.add_row([
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(record.time)).to_string(),
+            record.profile.clone(),
+            record.direction.key().to_string(),
+            record.file.clone(),
+            meter::format_size(record.bytes),
+            took,
+            speed,
+            record.peer.clone(),
+            outcome,
+        ]);
+    }
+    table
+}
+
+/// How much of a file went across, and how long that took when it was measured.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Transferred {
+    pub bytes: u64,
+    pub elapsed: Option<Duration>,
+}
+
+impl From<u64> for Transferred {
+    fn from(bytes: u64) -> Self {
+        Self { bytes, elapsed: None }
+    }
+}
+
+/// Stops [`record`] from recording anything for the rest of the process, such as once the
+/// database is out of reach.
+pub fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
+/// Records a transfer. `outcome` is the result of the transfer itself.
+pub fn record<T: Into<Transferred>>(
+    side: Side,
+    profile: &str,
+    peer: &str,
+    direction: Direction,
+    file: &str,
+    transferred: T,
+    outcome: &Result<()>,
+) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let Transferred { bytes, elapsed } = transferred.into();
+    let elapsed_ms = elapsed.map(|elapsed| elapsed.as_millis() as i64);
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let error = outcome.as_ref().err().map(|e| e.to_string());
+
+    let result = with_database(|db| {
+        db.execute(
+            "INSERT INTO transfers (time, side, profile, peer, direction, file, bytes, elapsed_ms, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![time as i64, side.key(), profile, peer, direction.key(), file, bytes as i64, elapsed_ms, error],
+        )?;
+        Ok(())
+    });
+    if let Err(e) = result {
+        warn!("Could not record the transfer in the history: {}", e);
+    }
+}
+
+/// The most recent transfers recorded by `side`, newest first, optionally only those of `profile`.
+pub fn recent(side: Side, profile: Option<&str>, limit: usize) -> Result<Vec<TransferRecord>> {
+    with_database(|db| {
+        let mut statement = db.prepare(
+            "SELECT time, profile, peer, direction, file, bytes, elapsed_ms, error FROM transfers
+             WHERE side = ?1 AND (?2 IS NULL OR profile = ?2)
+             ORDER BY id DESC LIMIT ?3",
+        )?;
+        let records = statement
+            .query_map(params![side.key(), profile, limit as i64], |row| {
+                Ok(TransferRecord {
+                    time: row.get::<_, i64>(0)? as u64,
+                    profile: row.get(1)?,
+                    peer: row.get(2)?,
+                    direction: Direction::from_key(&row.get::<_, String>(3)?),
+                    file: row.get(4)?,
+                    bytes: row.get::<_, i64>(5)? as u64,
+                    elapsed: row.get::<_, Option<i64>>(6)?.map(|ms| Duration::from_millis(ms as u64)),
+                    error: row.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(records)
+    })
+}
+
+/// The names of every profile `side` has recorded transfers for.
+pub fn profiles(side: Side) -> Result<Vec<String>> {
+    with_database(|db| {
+        let mut statement = db.prepare("SELECT DISTINCT profile FROM transfers WHERE side = ?1 ORDER BY profile")?;
+        let names = statement
+            .query_map(params![side.key()], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(names)
+    })
+}
+
+/// Downloads of `profile` whose latest attempt failed, by file name.
+pub fn failed_downloads(profile: &str) -> Result<Vec<String>> {
+    with_database(|db| {
+        let mut statement = db.prepare(
+            "SELECT file FROM transfers AS latest
+             WHERE side = 'client' AND profile = ?1 AND direction = 'download' AND error IS NOT NULL
+             AND id = (SELECT MAX(id) FROM transfers
+                       WHERE side = 'client' AND profile = ?1 AND direction = 'download' AND file = latest.file)
+             ORDER BY file",
+        )?;
+        let files = statement
+            .query_map(params![profile], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(files)
+    })
+}
+
+/// Runs `f` on the database, opening and migrating it on first use.
+fn with_database<T, F: FnOnce(&Connection) -> Result<T>>(f: F) -> Result<T> {
+    static DATABASE: OnceLock<Mutex<Connection>> = OnceLock::new();
+
+    let database = match DATABASE.get() {
+        Some(database) => database,
+        None => {
+            let opened = open()?;
+            // Another thread may have won the race; either connection is fine
+            DATABASE.get_or_init(|| Mutex::new(opened))
+        }
+    };
+    let db = database.lock().map_err(|_| anyhow!("The history database is poisoned"))?;
+    f(&db)
+}
+
+fn open() -> Result<Connection> {
+    let path = config::config_dir_ext(DATABASE_FILE)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let db = Connection::open(path)?;
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS transfers (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             time INTEGER NOT NULL,
+             side TEXT NOT NULL,
+             profile TEXT NOT NULL,
+             peer TEXT NOT NULL,
+             direction TEXT NOT NULL,
+             file TEXT NOT NULL,
+             bytes INTEGER NOT NULL,
+             error TEXT
+         );
+         CREATE INDEX IF NOT EXISTS transfers_by_profile ON transfers (side, profile);",
+    )?;
+    // Databases from before transfers were timed lack the column
+    let version: i64 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version < 1 {
+        db.execute_batch("ALTER TABLE transfers ADD COLUMN elapsed_ms INTEGER; PRAGMA user_version = 1;")?;
+    }
+    Ok(db)
+}
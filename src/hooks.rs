@@ -0,0 +1,176 @@
+//! Hooks: shell commands or HTTP endpoints run when something happens, so transfers can feed
+//! post-processing pipelines without wrapping the binaries.
+//!
+//! A hook whose action starts with `http://` is sent the event as a JSON POST. Any other action is
+//! run as a shell command, with the JSON payload on its standard input and in the
+//! `OXIDEUX_PAYLOAD` environment variable, and the event name in `OXIDEUX_EVENT`. Hooks run on
+//! threads of their own; a failing hook is reported but never fails what triggered it.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+
+const HTTP_SCHEME: &str = "http://";
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A server stored an uploaded file.
+    UploadReceived,
+    /// A server sent a file to a client.
+    DownloadServed,
+    /// A pass of the client's auto-sync downloaded something.
+    SyncCompleted,
+}
+
+impl Event {
+    pub const SERVER: [Event; 2] = [Event::UploadReceived, Event::DownloadServed];
+    pub const CLIENT: [Event; 1] = [Event::SyncCompleted];
+
+    pub fn key(&self) -> &'static str {
+        match self {
+            Event::UploadReceived => "upload-received",
+            Event::DownloadServed => "download-served",
+            Event::SyncCompleted => "sync-completed",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Result<Self> {
+        match key {
+            "upload-received" => Ok(Event::UploadReceived),
+            "download-served" => Ok(Event::DownloadServed),
+            "sync-completed" => Ok(Event::SyncCompleted),
+            _ => Err(anyhow!(format!("Unknown hook event: {}", key))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hook {
+    pub event: Event,
+    /// A shell command, or an `http://` URL to POST to.
+    pub action: String,
+}
+
+impl Hook {
+    /// Checks that `action` can be run, without running it.
+    pub fn validate_action(action: &str) -> Result<()> {
+        if action.trim().is_empty() {
+            return Err(anyhow!("A hook needs a command or URL"));
+        }
+        if action.starts_with("https://") {
+            return Err(anyhow!("HTTPS hooks are not supported, use http:// or a command such as curl"));
+        }
+        if let Some(rest) = action.strip_prefix(HTTP_SCHEME) {
+            split_url(rest)?;
+        }
+        Ok(())
+    }
+
+    fn run(&self, payload: &str) -> Result<()> {
+        match self.action.strip_prefix(HTTP_SCHEME) {
+            Some(rest) => post(rest, payload),
+            None => run_command(&self.action, self.event, payload),
+        }
+    }
+}
+
+/// Runs every hook of `hooks` registered for `event` in the background. `payload` is merged into
+/// the JSON sent to the hook, alongside the event name and `profile_name`.
+pub fn fire(hooks: &[Hook], event: Event, profile_name: &str, mut payload: json::JsonValue) {
+    let hooks: Vec<Hook> = hooks.iter().filter(|hook| hook.event == event).cloned().collect();
+    if hooks.is_empty() {
+        return;
+    }
+
+    payload["event"] = event.key().into();
+    payload["profile"] = profile_name.into();
+    let payload = payload.dump();
+
+    for hook in hooks {
+        let payload = payload.clone();
+        thread::spawn(move || {
+            if let Err(e) = hook.run(&payload) {
+                warn!("Hook '{}' for {} failed: {}", hook.action, hook.event.key(), e);
+            }
+        });
+    }
+}
+
+fn run_command(action: &str, event: Event, payload: &str) -> Result<()> {
+    #[cfg(target_os = "windows")]
+    let mut command = {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(action);
+        command
+    };
+    #[cfg(not(target_os = "windows"))]
+    let mut command = {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(action);
+        command
+    };
+
+    let mut child = command
+        .env("OXIDEUX_EVENT", event.key())
+        .env("OXIDEUX_PAYLOAD", payload)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The command may well not read its input, which is fine
+        let _ = stdin.write_all(payload.as_bytes());
+    }
+    let status = child.wait()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(anyhow!(format!("Command exited with {}", status))),
+    }
+}
+
+/// POSTs `payload` to `rest`, the part of an `http://` URL after the scheme.
+fn post(rest: &str, payload: &str) -> Result<()> {
+    let (host, path) = split_url(rest)?;
+    let mut stream = TcpStream::connect(&host)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\n\r\n{}",
+        path,
+        host,
+        payload.len(),
+        payload
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.lines().next().unwrap_or_default();
+    let code = status.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok());
+    match code {
+        Some(200..=299) => Ok(()),
+        _ => Err(anyhow!(format!("Endpoint answered '{}'", status))),
+    }
+}
+
+/// Splits `host[:port]/path` into `("host:port", "/path")`.
+fn split_url(rest: &str) -> Result<(String, String)> {
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(anyhow!(format!("Invalid hook URL: {}{}", HTTP_SCHEME, rest)));
+    }
+    let host = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:80", host),
+    };
+    Ok((host, path.to_string()))
+}
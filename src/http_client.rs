@@ -0,0 +1,262 @@
+//! A small HTTP/1.1 client for the plain-HTTP services the server talks to: routers (UPnP) and
+//! S3-compatible stores.
+//!
+//! Response heads are parsed with `httparse`, and bodies are read by their length, as chunks
+//! (`Transfer-Encoding: chunked`), or until the connection closes. Every request asks for the
+//! connection to be closed afterwards.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+const HTTP_SCHEME: &str = "http://";
+const HTTP_PORT: u16 = 80;
+/// The most headers a response may have.
+const MAX_HEADERS: usize = 64;
+/// The longest response head read, headers included.
+const MAX_HEAD_LENGTH: usize = 64 * 1024;
+
+/// An absolute `http://host[:port]/path` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    /// The path, with the query if there is one; always starts with `/`.
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = match url.get(..HTTP_SCHEME.len()) {
+            Some(scheme) if scheme.eq_ignore_ascii_case(HTTP_SCHEME) => &url[HTTP_SCHEME.len()..],
+            _ => return Err(anyhow!(format!("Unsupported URL: {}", url))),
+        };
+        let (address, path) = match rest.find(['/', '?']) {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>().map_err(|_| anyhow!(format!("Invalid port in URL: {}", url)))?),
+            None => (address, HTTP_PORT),
+        };
+        if host.is_empty() {
+            return Err(anyhow!(format!("No host in URL: {}", url)));
+        }
+        let path = match path.starts_with('/') {
+            true => path.to_string(),
+            false => format!("/{}", path),
+        };
+        Ok(Self { host: host.to_string(), port, path })
+    }
+
+    /// `reference` resolved against this URL: absolute URLs are taken as they are, absolute paths
+    /// replace the path, and relative ones are taken from this URL's directory.
+    pub fn join(&self, reference: &str) -> Result<Self> {
+        let reference = reference.trim();
+        if reference.contains("://") {
+            return Self::parse(reference);
+        }
+        let path = match reference.starts_with('/') {
+            true => reference.to_string(),
+            false => {
+                let directory = self.path.split('?').next().unwrap_or_default();
+                let directory = &directory[..directory.rfind('/').map_or(0, |index| index + 1)];
+                format!("{}{}", directory, reference)
+            }
+        };
+        Ok(Self { path, ..self.clone() })
+    }
+
+    /// The value of the `Host` header for this URL.
+    pub fn authority(&self) -> String {
+        match self.port == HTTP_PORT {
+            true => self.host.clone(),
+            false => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl std::fmt::Display for Url {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}{}", HTTP_SCHEME, self.authority(), self.path)
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    /// The headers, with lowercase names.
+    headers: Vec<(String, String)>,
+    body: Box<dyn Read + Send>,
+}
+
+impl Response {
+    /// The value of the header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Reads the whole body, which is expected to be small.
+    pub fn body(&mut self) -> Result<Vec<u8>> {
+        let mut body = vec![];
+        self.body.read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    /// The body as a stream, for bodies too large to be held in memory.
+    pub fn into_reader(self) -> Box<dyn Read + Send> {
+        self.body
+    }
+
+    fn read(stream: TcpStream) -> Result<Self> {
+        let mut reader = BufReader::new(stream);
+        let mut head = vec![];
+        loop {
+            let n = reader.by_ref().take(MAX_HEAD_LENGTH as u64).read_until(b'\n', &mut head)?;
+            if n == 0 {
+                return Err(anyhow!("Connection closed before the response was complete"));
+            }
+            if head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n") {
+                break;
+            }
+            if head.len() >= MAX_HEAD_LENGTH {
+                return Err(anyhow!("Response head is too long"));
+            }
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        if !response.parse(&head)?.is_complete() {
+            return Err(anyhow!("Incomplete HTTP response head"));
+        }
+        let status = response.code.ok_or(anyhow!("HTTP response without a status"))?;
+        let headers = response
+            .headers
+            .iter()
+            .map(|header| {
+                let value = String::from_utf8_lossy(header.value).trim().to_string();
+                (header.name.to_ascii_lowercase(), value)
+            })
+            .collect::<Vec<_>>();
+
+        let find = |name: &str| headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str());
+        let chunked = find("transfer-encoding").is_some_and(|encoding| encoding.to_ascii_lowercase().ends_with("chunked"));
+        let body: Box<dyn Read + Send> = match (chunked, find("content-length")) {
+            (true, _) => Box::new(Chunked::new(reader)),
+            (false, Some(length)) => {
+                let length = length.parse::<u64>().map_err(|_| anyhow!(format!("Invalid Content-Length: {}", length)))?;
+                Box::new(reader.take(length))
+            }
+            (false, None) => Box::new(reader),
+        };
+        Ok(Self { status, headers, body })
+    }
+}
+
+/// Sends a request for `url` with `headers` and `body`, and reads the response head.
+pub fn request(url: &Url, method: &str, headers: &[(&str, String)], body: &[u8], timeout: Duration) -> Result<Response> {
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, url.path, url.authority());
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !body.is_empty() || method == "POST" || method == "PUT" {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    Response::read(stream)
+}
+
+/// A body sent with `Transfer-Encoding: chunked`, decoded.
+struct Chunked<R> {
+    reader: R,
+    /// What is left of the current chunk.
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> Chunked<R> {
+    fn new(reader: R) -> Self {
+        Self { reader, remaining: 0, done: false }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Chunked body cut short"));
+        }
+        Ok(line)
+    }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let line = self.read_line()?;
+            // Chunk extensions follow the size after a `;`
+            let size = line.split(';').next().unwrap_or_default().trim();
+            self.remaining = u64::from_str_radix(size, 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid chunk size: {:?}", size)))?;
+            if self.remaining == 0 {
+                // The trailer section ends with an empty line
+                while !self.read_line()?.trim().is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let wanted = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = self.reader.read(&mut buf[..wanted])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Chunked body cut short"));
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 && !self.read_line()?.trim().is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Chunk not followed by a line break"));
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn chunked_bodies_are_decoded() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                      5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nExpires: never\r\n\r\n",
+                )
+                .unwrap();
+        });
+
+        let url = Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+        let mut response = request(&url, "GET", &[], &[], Duration::from_secs(5)).unwrap();
+        server.join().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body().unwrap(), b"hello, world");
+    }
+}
@@ -0,0 +1,295 @@
+//! A read-only HTTP view of a server's parity root, so files can be fetched with a browser or curl
+//! by people without the client.
+//!
+//! `GET /files/<name>` downloads a file and `GET /files/<dir>/` lists a directory. The binary
+//! protocol stays the primary way in; the gateway honours the same access lists, limits and
+//! exclusions.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+
+use crate::access::AccessList;
+use crate::drain::Drain;
+use crate::ignore::IgnoreRules;
+use crate::limits::Limiter;
+use crate::parity;
+
+const FILES_PREFIX: &str = "/files/";
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Told about every file download: the client, the file's name relative to the root, its size and
+/// whether it was sent completely.
+pub type DownloadObserver = Box<dyn Fn(IpAddr, &str, u64, &Result<()>) + Send + Sync>;
+
+pub struct Gateway {
+    root: PathBuf,
+    access: AccessList,
+    limiter: Arc<Limiter>,
+    on_download: DownloadObserver,
+    exclude: IgnoreRules,
+    drain: Drain,
+}
+
+impl Gateway {
+    pub fn new<P: Into<PathBuf>>(root: P, access: AccessList, limiter: Arc<Limiter>, on_download: DownloadObserver) -> Self {
+        Self {
+            root: root.into(),
+            access,
+            limiter,
+            on_download,
+            exclude: IgnoreRules::default(),
+            drain: Drain::new(),
+        }
+    }
+
+    /// Hides the files the profile's rules `exclude` and the root's ignore file exclude, and deals
+    /// with symlinks as the rules say (see: [`crate::ignore`]).
+    pub fn excluding(self, exclude: IgnoreRules) -> Self {
+        Self { exclude, ..self }
+    }
+
+    /// Stops serving when `drain` starts, counting downloads in progress among the connections it
+    /// waits for.
+    pub fn drained_by(self, drain: Drain) -> Self {
+        Self { drain, ..self }
+    }
+
+    /// Starts serving on `addr` in the background.
+    pub fn serve(self, addr: &str) -> Result<()> {
+        self.serve_on(TcpListener::bind(addr)?);
+        Ok(())
+    }
+
+    /// Starts serving on `listener`, bound beforehand, in the background.
+    pub fn serve_on(self, listener: TcpListener) {
+        let gateway = Arc::new(self);
+        thread::spawn(move || {
+            let drain = gateway.drain.clone();
+            for stream in drain.incoming(&listener, false).flatten() {
+                let ip = match stream.peer_addr() {
+                    Ok(peer) => peer.ip(),
+                    Err(_) => continue,
+                };
+                if !gateway.access.permits(ip) {
+                    continue;
+                }
+                let guard = match gateway.limiter.try_connect(ip) {
+                    Some(guard) => guard,
+                    None => continue,
+                };
+                let session = match stream.try_clone().ok().and_then(|clone| drain.register(Box::new(clone))) {
+                    Some(session) => session,
+                    None => continue,
+                };
+                let gateway = Arc::clone(&gateway);
+                thread::spawn(move || {
+                    let (_guard, _session) = (guard, session);
+                    if let Err(e) = gateway.answer(ip, stream) {
+                        warn!("HTTP request from {} failed: {}", ip, e);
+                    }
+                });
+            }
+        });
+    }
+
+    fn answer(&self, ip: IpAddr, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        if !self.limiter.allow_request(ip) {
+            return respond(&mut stream, "429 Too Many Requests", "Slow down and try again in a minute\n");
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+        if method != "GET" {
+            return respond(&mut stream, "405 Method Not Allowed", "Only GET is supported\n");
+        }
+        if target == "/" || target == "/files" {
+            return redirect(&mut stream, FILES_PREFIX);
+        }
+        let name = match target.strip_prefix(FILES_PREFIX).map(percent_decode) {
+            Some(Ok(name)) => name,
+            Some(Err(_)) | None => return respond(&mut stream, "404 Not Found", "Not found\n"),
+        };
+
+        // The ignore file may change at any time, so it is read again for every request
+        let ignore = self.exclude.clone().with_ignore_file(&self.root)?;
+        let path = match self.resolve(&name, &ignore) {
+            Ok(path) => path,
+            Err(_) => return respond(&mut stream, "404 Not Found", "Not found\n"),
+        };
+        let relative = name.trim_end_matches('/');
+        let excluded = match path.is_dir() {
+            true => !relative.is_empty() && ignore.is_ignored_dir(relative),
+            false => ignore.is_ignored(relative),
+        };
+        if excluded {
+            return respond(&mut stream, "404 Not Found", "Not found\n");
+        }
+        if path.is_dir() {
+            if !target.ends_with('/') {
+                return redirect(&mut stream, &format!("{}/", target));
+            }
+            return respond_html(&mut stream, &index(&self.root.canonicalize()?, &path, &name, &ignore)?);
+        }
+
+        let length = fs::metadata(&path)?.len();
+        let result = send_file(&mut stream, &path, length);
+        (self.on_download)(ip, &name, length, &result);
+        result
+    }
+
+    /// The path `name` refers to, refusing anything that ends up outside the root and symlinks
+    /// the policy of `ignore` doesn't follow.
+    fn resolve(&self, name: &str, ignore: &IgnoreRules) -> Result<PathBuf> {
+        let root = self.root.canonicalize()?;
+        let name = name.trim_end_matches('/');
+        if name.is_empty() {
+            return Ok(root);
+        }
+        parity::resolve_links(&root, &parity::safe_join(&root, name)?, ignore)?
+            .ok_or_else(|| anyhow!(format!("Refusing path: {:?}", name)))
+    }
+}
+
+fn send_file(stream: &mut TcpStream, path: &Path, length: u64) -> Result<()> {
+    let mut file = File::open(path)?;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        length
+    )?;
+    let sent = io::copy(&mut file, stream)?;
+    stream.flush()?;
+    if sent != length {
+        return Err(anyhow!(format!("Sent {} bytes but expected {}", sent, length)));
+    }
+    Ok(())
+}
+
+/// An HTML listing of `dir`, which is `name` relative to the canonical `root`, without what
+/// `ignore` excludes or the symlinks its policy doesn't follow.
+fn index(root: &Path, dir: &Path, name: &str, ignore: &IgnoreRules) -> Result<String> {
+    let prefix = name.trim_end_matches('/');
+    let mut entries: Vec<(String, bool)> = vec![];
+    for entry in fs::read_dir(dir)?.flatten() {
+        let target = match parity::resolve_links(root, &dir.join(entry.file_name()), ignore)? {
+            Some(target) => target,
+            None => continue,
+        };
+        let entry = entry.file_name().to_string_lossy().to_string();
+        let relative = match prefix.is_empty() {
+            true => entry.clone(),
+            false => format!("{}/{}", prefix, entry),
+        };
+        let excluded = match target.is_dir() {
+            true => ignore.is_ignored_dir(&relative),
+            false => ignore.is_ignored(&relative),
+        };
+        if !excluded {
+            entries.push((entry, target.is_dir()));
+        }
+    }
+    entries.sort();
+
+    let title = html_escape(&format!("/{}", name));
+    let mut html = format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head><body>\n<h1>{0}</h1>\n<ul>\n", title);
+    if !name.trim_end_matches('/').is_empty() {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (entry, is_dir) in entries {
+        let suffix = if is_dir { "/" } else { "" };
+        html.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            percent_encode(&entry),
+            suffix,
+            html_escape(&entry),
+            suffix
+        ));
+    }
+    html.push_str("</ul>\n</body></html>\n");
+    Ok(html)
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(stream.flush()?)
+}
+
+fn respond_html(stream: &mut TcpStream, body: &str) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    Ok(stream.flush()?)
+}
+
+fn redirect(stream: &mut TcpStream, location: &str) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 301 Moved Permanently\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        location
+    )?;
+    Ok(stream.flush()?)
+}
+
+fn percent_decode(value: &str) -> Result<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = value.get(i + 1..i + 3).ok_or(anyhow!("Truncated escape"))?;
+                decoded.push(u8::from_str_radix(hex, 16)?);
+                i += 3;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,160 @@
+//! Exclusion patterns, so scratch files, dependencies and the like are never listed or transferred.
+//!
+//! Patterns come from the profile and from a [`IGNORE_FILE`] at the top of the parity root, one
+//! per line, with blank lines and lines starting with `#` skipped. Each is a glob (`*.tmp`, `.*`)
+//! matched against every component of a file's path, so `node_modules` also excludes everything
+//! below any directory of that name. A trailing `/` only matches directories (`build/`), and a
+//! pattern with a `/` elsewhere is matched against the path from the top of the parity root
+//! instead (`logs/*.gz`). The ignore file, the [`crate::parity::MANIFEST_FILE`], `.part` files
+//! (transfers still in progress, or interrupted uploads kept to be resumed), the
+//! [`crate::trash::TRASH_DIR`] and the [`crate::versions::VERSIONS_DIR`] are never listed.
+//!
+//! The rules also carry the profile's policies for hidden files (names starting with a `.`) and
+//! for symlinks (see: [`SymlinkPolicy`] and [`crate::parity::resolve_links`]).
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use glob::{MatchOptions, Pattern};
+
+use crate::config::SymlinkPolicy;
+use crate::parity::MANIFEST_FILE;
+use crate::trash::TRASH_DIR;
+use crate::versions::VERSIONS_DIR;
+
+pub const IGNORE_FILE: &str = ".oxideuxignore";
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    pattern: Pattern,
+    directories_only: bool,
+    /// Matched against the whole path rather than each component.
+    anchored: bool,
+}
+
+/// A set of exclusion patterns, and what to do about hidden files and symlinks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+    exclude_hidden: bool,
+    symlinks: SymlinkPolicy,
+}
+
+impl IgnoreRules {
+    pub fn parse<S: AsRef<str>>(patterns: &[S]) -> Result<Self> {
+        let mut rules = vec![];
+        for pattern in patterns {
+            let pattern = pattern.as_ref().trim();
+            if pattern.is_empty() || pattern.starts_with('#') {
+                continue;
+            }
+            let directories_only = pattern.ends_with('/');
+            let trimmed = pattern.trim_end_matches('/');
+            let anchored = trimmed.contains('/');
+            rules.push(Rule {
+                pattern: Pattern::new(trimmed.trim_start_matches('/'))
+                    .map_err(|e| anyhow!(format!("Invalid exclusion pattern '{}': {}", pattern, e)))?,
+                directories_only,
+                anchored,
+            });
+        }
+        Ok(Self {
+            rules,
+            ..Default::default()
+        })
+    }
+
+    /// The `patterns` of a profile together with those of the ignore file in `root`, if it has one.
+    pub fn load<P: AsRef<Path>, S: AsRef<str>>(root: P, patterns: &[S]) -> Result<Self> {
+        Self::parse(patterns)?.with_ignore_file(root)
+    }
+
+    /// These rules together with those of the ignore file in `root`, if it has one.
+    pub fn with_ignore_file<P: AsRef<Path>>(mut self, root: P) -> Result<Self> {
+        let path = root.as_ref().join(IGNORE_FILE);
+        if path.is_file() {
+            let contents = fs::read_to_string(&path)?;
+            let lines: Vec<&str> = contents.lines().collect();
+            let file = Self::parse(&lines).map_err(|e| anyhow!(format!("{} in {:?}", e, path)))?;
+            self.rules.extend(file.rules);
+        }
+        Ok(self)
+    }
+
+    /// Whether files and directories whose name starts with a `.` are kept, as they are by default.
+    pub fn hidden_files(self, include: bool) -> Self {
+        Self {
+            exclude_hidden: !include,
+            ..self
+        }
+    }
+
+    pub fn symlinks(self, symlinks: SymlinkPolicy) -> Self {
+        Self { symlinks, ..self }
+    }
+
+    /// Whether no file is excluded by name, apart from the ignore file itself.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && !self.exclude_hidden
+    }
+
+    pub fn symlink_policy(&self) -> SymlinkPolicy {
+        self.symlinks
+    }
+
+    /// Whether the file `name`, a `/`-separated path relative to the parity root, is excluded.
+    pub fn is_ignored(&self, name: &str) -> bool {
+        // The manifest is written next to itself first
+        name == IGNORE_FILE
+            || name.starts_with(MANIFEST_FILE)
+            || name.ends_with(".part")
+            || is_reserved(name)
+            || self.excludes(name, false)
+    }
+
+    /// Whether the directory `name` is excluded, and with it everything below it.
+    pub fn is_ignored_dir(&self, name: &str) -> bool {
+        is_reserved(name) || self.excludes(name, true)
+    }
+
+    /// Whether the file `name` matches one of the patterns, whatever the policies say.
+    pub fn matches(&self, name: &str) -> bool {
+        self.rules_match(name, false)
+    }
+
+    fn excludes(&self, name: &str, is_dir: bool) -> bool {
+        if self.exclude_hidden && name.split('/').any(|component| component.starts_with('.')) {
+            return true;
+        }
+        self.rules_match(name, is_dir)
+    }
+
+    fn rules_match(&self, name: &str, is_dir: bool) -> bool {
+        let components: Vec<&str> = name.split('/').filter(|component| !component.is_empty()).collect();
+        self.rules.iter().any(|rule| {
+            (1..=components.len()).any(|n| {
+                // Every component but the last is a directory
+                if rule.directories_only && n == components.len() && !is_dir {
+                    return false;
+                }
+                match rule.anchored {
+                    true => rule.pattern.matches_with(&components[..n].join("/"), MATCH_OPTIONS),
+                    false => rule.pattern.matches_with(components[n - 1], MATCH_OPTIONS),
+                }
+            })
+        })
+    }
+}
+
+/// Whether `name` is the trash or versions directory at the top of the parity root, or something
+/// in them.
+fn is_reserved(name: &str) -> bool {
+    matches!(name.split('/').next(), Some(TRASH_DIR | VERSIONS_DIR))
+}
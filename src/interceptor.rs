@@ -0,0 +1,80 @@
+//! Extension points of [`crate::server::Server`], so integrators can add policy without forking
+//! the request handling.
+//!
+//! An interceptor can veto or rewrite requests before they are handled, check uploads before they
+//! are moved in place, and answer requests of its own sent as [`Request::Custom`].
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use std::process::Command;
+//!
+//! use oxideux_rs::interceptor::{RequestContext, RequestInterceptor};
+//! use oxideux_rs::request::{ErrorCode, RequestError};
+//! use oxideux_rs::server::Server;
+//!
+//! struct VirusScan;
+//!
+//! impl RequestInterceptor for VirusScan {
+//!     fn check_upload(&self, _: &RequestContext, name: &str, received: &Path) -> Result<(), RequestError> {
+//!         match Command::new("clamscan").arg(received).status() {
+//!             Ok(status) if status.success() => Ok(()),
+//!             _ => Err(RequestError::new(ErrorCode::UnauthorizedAccess, "Upload rejected by the virus scan").with_path(name)),
+//!         }
+//!     }
+//! }
+//!
+//! Server::builder().root("/srv/inbox").interceptor(VirusScan).serve()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::config::ServerProfile;
+use crate::connection::Connection;
+use crate::request::{Request, RequestError};
+
+/// Who made a request and what they may see.
+pub struct RequestContext<'a> {
+    pub ip: IpAddr,
+    /// How the client appears in the audit log: its IP, prefixed by its user once logged in.
+    pub who: &'a str,
+    /// The profile as seen by the client, i.e. scoped to its user's parity root and mode.
+    pub profile: &'a ServerProfile,
+}
+
+/// What to do with an intercepted request.
+pub enum Interception {
+    /// Handle the request, possibly rewritten (e.g. to another path).
+    Continue(Request),
+    /// Answer with the error instead of handling the request.
+    Refuse(RequestError),
+}
+
+/// Every method has a default that lets everything through, so implementors only override what
+/// they need. Interceptors run in the order they were added; the first refusal wins.
+pub trait RequestInterceptor: Send + Sync {
+    /// Called with every request the client is allowed to make, except housekeeping
+    /// ([`Request::Disconnect`], [`Request::Ping`], [`Request::Cancel`]) and logging in.
+    fn intercept(&self, context: &RequestContext, request: Request) -> Interception {
+        let _ = context;
+        Interception::Continue(request)
+    }
+
+    /// Called once an upload named `name` arrived complete at `received`, before it is moved in
+    /// place. Refusing deletes it and the client gets the error.
+    fn check_upload(&self, context: &RequestContext, name: &str, received: &Path) -> Result<(), RequestError> {
+        let _ = (context, name, received);
+        Ok(())
+    }
+
+    /// Answers a [`Request::Custom`] named `name`, returning whether it was one of this
+    /// interceptor's. Whatever is sent back is up to the interceptor, but it should start with a
+    /// [`crate::request::RequestResult`] like every other answer.
+    fn handle_custom(&self, context: &RequestContext, name: &str, payload: &[u8], conn: &mut Connection) -> Result<bool> {
+        let _ = (context, name, payload, conn);
+        Ok(false)
+    }
+}
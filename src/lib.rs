@@ -1,7 +1,49 @@
+pub mod access;
+pub mod accounts;
 pub mod app;
+pub mod audit;
+pub mod bandwidth;
+pub mod batch;
+pub mod chunks;
 pub mod cli;
+pub mod client;
 pub mod config;
 pub mod connection;
+pub mod delta;
+pub mod discovery;
+pub mod drain;
+pub mod dry_run;
+pub mod history;
+pub mod http_client;
+pub mod http_gateway;
+pub mod hooks;
+pub mod ignore;
+pub mod interceptor;
+pub mod limits;
+pub mod logging;
+pub mod meter;
+pub mod metrics;
 pub mod parity;
+pub mod port_mapping;
+pub mod privileges;
+pub mod proxy;
+pub mod psk;
+pub mod queue;
 pub mod request;
+pub mod s3;
+pub mod schedule;
+pub mod secrets;
+pub mod server;
+pub mod share;
+pub mod signing;
+pub mod transport;
+pub mod trash;
+#[cfg(unix)]
+pub mod tui;
+pub mod tunnel;
+pub mod two_way;
 pub mod validated_values;
+pub mod versions;
+pub mod watch;
+pub mod websocket;
+pub mod xml;
@@ -0,0 +1,149 @@
+//! Per-client limits for the server, so one misbehaving client can't starve the others.
+//!
+//! Clients are told apart by IP address, so the limits hold across reconnections. Limits on how
+//! slow one connection may be are kept per connection instead (see: [`MinimumRate`]).
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+/// How long waiting on a peer is measured for before it has to have kept up with its
+/// [`MinimumRate`], and how long a peer may take to send or take anything at all meanwhile.
+pub const STALL_WINDOW: Duration = Duration::from_secs(30);
+/// How many seconds clients of new profiles get to send their first request.
+pub const DEFAULT_FIRST_REQUEST_TIMEOUT: u64 = 30;
+
+#[derive(Debug)]
+struct ClientState {
+    connections: u64,
+    window_start: Instant,
+    requests: u64,
+}
+
+impl ClientState {
+    fn new() -> Self {
+        Self {
+            connections: 0,
+            window_start: Instant::now(),
+            requests: 0,
+        }
+    }
+}
+
+/// Tracks open connections and recent requests per IP. A limit of zero means unlimited.
+#[derive(Debug)]
+pub struct Limiter {
+    max_connections_per_ip: u64,
+    max_requests_per_minute: u64,
+    clients: Mutex<HashMap<IpAddr, ClientState>>,
+}
+
+impl Limiter {
+    pub fn new(max_connections_per_ip: u64, max_requests_per_minute: u64) -> Arc<Self> {
+        Arc::new(Self {
+            max_connections_per_ip,
+            max_requests_per_minute,
+            clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Registers a new connection from `ip`, or returns `None` if it already has as many as it may.
+    /// The connection counts until the returned guard is dropped.
+    pub fn try_connect(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut clients = self.clients.lock().unwrap();
+        let state = clients.entry(ip).or_insert_with(ClientState::new);
+        if self.max_connections_per_ip != 0 && state.connections >= self.max_connections_per_ip {
+            return None;
+        }
+        state.connections += 1;
+        Some(ConnectionGuard {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+
+    /// Counts a request from `ip`, returning whether it fits in the current one-minute window.
+    pub fn allow_request(&self, ip: IpAddr) -> bool {
+        if self.max_requests_per_minute == 0 {
+            return true;
+        }
+        let mut clients = self.clients.lock().unwrap();
+        let state = clients.entry(ip).or_insert_with(ClientState::new);
+        if state.window_start.elapsed() >= RATE_WINDOW {
+            state.window_start = Instant::now();
+            state.requests = 0;
+        }
+        if state.requests >= self.max_requests_per_minute {
+            return false;
+        }
+        state.requests += 1;
+        true
+    }
+
+    fn disconnect(&self, ip: IpAddr) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(state) = clients.get_mut(&ip) {
+            state.connections = state.connections.saturating_sub(1);
+            // Forget idle clients once their rate window is over, so the map doesn't grow forever
+            if state.connections == 0 && state.window_start.elapsed() >= RATE_WINDOW {
+                clients.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Keeps a connection counted against its IP's limit for as long as it lives.
+pub struct ConnectionGuard {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.limiter.disconnect(self.ip);
+    }
+}
+
+/// Fails a connection once its peer took or sent fewer bytes per second than it should for a
+/// [`STALL_WINDOW`], so a peer that trickles data can't hold a connection forever. Only the time
+/// spent waiting on the peer in the middle of a transfer counts, not the time between requests.
+#[derive(Debug)]
+pub struct MinimumRate {
+    rate: u64,
+    waited: Duration,
+    bytes: u64,
+}
+
+impl MinimumRate {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            waited: Duration::ZERO,
+            bytes: 0,
+        }
+    }
+
+    /// Counts `bytes` that took `waited` to get through to or from the peer, failing once a whole
+    /// window of waiting went by below the rate.
+    pub fn check(&mut self, bytes: usize, waited: Duration) -> Result<()> {
+        self.bytes += bytes as u64;
+        self.waited += waited;
+        if self.waited < STALL_WINDOW {
+            return Ok(());
+        }
+        let rate = self.bytes as f64 / self.waited.as_secs_f64();
+        if rate < self.rate as f64 {
+            return Err(anyhow!(format!(
+                "Dropped: the peer moved {:.0} bytes/s, below the minimum of {} bytes/s",
+                rate, self.rate
+            )));
+        }
+        self.waited = Duration::ZERO;
+        self.bytes = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+//! Leveled logging for the binaries and the library, through the `log` crate's macros.
+//!
+//! Messages go to the terminal as plain lines, to stdout (or stderr in JSON output mode, see:
+//! [`crate::cli::set_json_output`]), and can be copied to a file as well, stamped with the time and
+//! level. How much is shown is picked on the command line (see: [`init_from_args`]):
+//!
+//! - `-q` only warnings and errors
+//! - (default) what the binaries have always printed
+//! - `-v` also details such as every request a server handles
+//! - `-vv` everything, including what other crates log
+//! - `--log-file <path>` appends the same lines to `path`
+//!
+//! Log files can be rotated once they grow too large or old (see: [`Rotation`]), so a server left
+//! running for months doesn't fill its disk.
+//!
+//! Work that takes a while, such as serving a connection or a request, runs in a `tracing` span.
+//! Lines logged inside one name it and its parents, such as
+//! `[peer=10.0.0.2:51234 request=7 kind=DownloadFileByName file=disk.iso]`, when showing details
+//! or writing to a file (see: [`describe`]).
+
+use std::fmt::{self, Debug};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record as SpanRecord};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::{LookupSpan, Registry};
+
+use crate::cli::{self, Style};
+
+/// Size after which a server's log file is rotated, unless its profile says otherwise.
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// How many rotated log files a server keeps, unless its profile says otherwise.
+pub const DEFAULT_RETENTION: u64 = 5;
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+/// Where terminal lines go instead while a full-screen view owns the terminal.
+static DIVERTED: Mutex<Option<Divert>> = Mutex::new(None);
+
+/// Takes the lines meant for the terminal, see: [`divert_terminal`].
+pub type Divert = Box<dyn Fn(Level, String) + Send>;
+
+/// The fields of a span as `name=value` pairs, in the order they were given or recorded.
+#[derive(Default)]
+struct SpanFields(Vec<(&'static str, String)>);
+
+impl Visit for SpanFields {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match self.0.iter_mut().find(|(name, _)| *name == field.name()) {
+            Some((_, recorded)) => *recorded = value.to_string(),
+            None => self.0.push((field.name(), value.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for SpanFields {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pairs: Vec<String> = self.0.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        write!(f, "{}", pairs.join(" "))
+    }
+}
+
+/// Keeps the fields of every span with it, for [`describe`] to show.
+struct FieldsLayer;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for FieldsLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = SpanFields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &SpanRecord<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                values.record(fields);
+            }
+        }
+    }
+}
+
+/// The fields of `span` and of the spans it is in, outermost first, separated by spaces: such as
+/// `peer=10.0.0.2:51234 request=7 kind=DownloadFileByName file=disk.iso`. Empty when logging
+/// wasn't started (see: [`init`]).
+pub fn describe(span: &tracing::Span) -> String {
+    span.with_subscriber(|(id, dispatch)| {
+        let registry = dispatch.downcast_ref::<Registry>()?;
+        let names: Vec<String> = registry
+            .span(id)?
+            .scope()
+            .from_root()
+            .filter_map(|span| span.extensions().get::<SpanFields>().map(ToString::to_string))
+            .filter(|fields| !fields.is_empty())
+            .collect();
+        Some(names.join(" "))
+    })
+    .flatten()
+    .unwrap_or_default()
+}
+
+/// When a log file is set aside for a fresh one: `path` is renamed to `path.1`, the previous
+/// `path.1` to `path.2` and so on, and whatever would go past `keep` is deleted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rotation {
+    /// Size in bytes after which the file is rotated, zero for no limit.
+    pub max_size: u64,
+    /// Age after which the file is rotated, `None` for no limit.
+    pub max_age: Option<Duration>,
+    /// How many rotated files are kept.
+    pub keep: u64,
+}
+
+impl Rotation {
+    /// No rotation: the file grows for as long as it is logged to.
+    pub fn never() -> Self {
+        Self::default()
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// When the file was started, to tell its age by.
+    since: SystemTime,
+    rotation: Rotation,
+}
+
+impl LogFile {
+    fn open(path: &Path, rotation: Rotation) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!(format!("Could not open the log file {}: {}", path.display(), e)))?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size: metadata.len(),
+            since: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            rotation,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.is_due() {
+            if let Err(e) = self.rotate() {
+                // Nowhere else to report it; the line still goes to the current file
+                eprintln!("Could not rotate the log file {}: {}", self.path.display(), e);
+            }
+        }
+        if writeln!(self.file, "{}", line).is_ok() {
+            self.size += line.len() as u64 + 1;
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        let too_large = self.rotation.max_size > 0 && self.size >= self.rotation.max_size;
+        let too_old = match self.rotation.max_age {
+            Some(age) => self.since.elapsed().is_ok_and(|elapsed| elapsed >= age),
+            None => false,
+        };
+        too_large || too_old
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        let rotated = |index: u64| PathBuf::from(format!("{}.{}", self.path.display(), index));
+        match self.rotation.keep {
+            0 => fs::remove_file(&self.path)?,
+            keep => {
+                for index in (1..keep).rev() {
+                    if rotated(index).exists() {
+                        fs::rename(rotated(index), rotated(index + 1))?;
+                    }
+                }
+                fs::rename(&self.path, rotated(1))?;
+            }
+        }
+        *self = LogFile::open(&self.path, self.rotation)?;
+        // Windows gives a file created under a recently deleted name its predecessor's creation time
+        self.since = SystemTime::now();
+        Ok(())
+    }
+}
+
+struct Logger {
+    level: LevelFilter,
+    file: Mutex<Option<LogFile>>,
+}
+
+impl Logger {
+    /// Whether `target` is one of ours: the library's modules or one of the binaries.
+    fn is_own(target: &str) -> bool {
+        target.starts_with("oxideux_rs") || target == "client" || target == "server"
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level && (self.level == LevelFilter::Trace || Logger::is_own(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let spans = match describe(&tracing::Span::current()) {
+            spans if spans.is_empty() => spans,
+            spans => format!("[{}] ", spans),
+        };
+        // Spans are details too, left out of the terminal unless those are asked for
+        let context = match self.level >= LevelFilter::Debug {
+            true => spans.as_str(),
+            false => "",
+        };
+        let text = format!("{}{}", context, record.args());
+        if let Some(divert) = DIVERTED.lock().unwrap().as_ref() {
+            divert(record.level(), text.clone());
+        } else {
+            let line = match record.level() {
+                Level::Debug | Level::Trace => format!("[{}] {}", record.level().as_str().to_lowercase(), text),
+                Level::Info => text.clone(),
+                Level::Warn => cli::styled(&text, Style::Notice),
+                Level::Error => cli::styled(&text, Style::Error),
+            };
+            match cli::json_output() {
+                true => eprintln!("{}", line),
+                false => println!("{}", line),
+            }
+        }
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            file.write_line(&format!(
+                "{} {:<5} {}{}",
+                humantime::format_rfc3339_seconds(SystemTime::now()),
+                record.level(),
+                spans,
+                record.args()
+            ));
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+/// Starts logging at `level`, also appending to `file` when given. Only the first call counts.
+pub fn init(level: LevelFilter, file: Option<&Path>) -> Result<()> {
+    let file = match file {
+        Some(path) => Some(LogFile::open(path, Rotation::never())?),
+        None => None,
+    };
+    let logger = LOGGER.get_or_init(|| Logger { level, file: Mutex::new(file) });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(logger.level);
+        // Only keeps track of spans, lines are still logged through the log crate
+        let _ = tracing::subscriber::set_global_default(Registry::default().with(FieldsLayer));
+    }
+    Ok(())
+}
+
+/// Whether logs are being copied to a file.
+pub fn logs_to_file() -> bool {
+    LOGGER.get().is_some_and(|logger| logger.file.lock().unwrap().is_some())
+}
+
+/// Copies logs to `path` from now on, rotating it as `rotation` says, instead of to the file
+/// they went to so far. Needs [`init`] to have been called.
+pub fn log_to_file(path: &Path, rotation: Rotation) -> Result<()> {
+    let logger = LOGGER.get().ok_or(anyhow!("Logging has not been started"))?;
+    let file = LogFile::open(path, rotation)?;
+    *logger.file.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+/// Hands the lines meant for the terminal to `divert` instead, with their level and without
+/// colors, such as while a full-screen view would be scribbled over by them. `None` prints them
+/// again. Log files are unaffected.
+pub fn divert_terminal(divert: Option<Divert>) {
+    *DIVERTED.lock().unwrap() = divert;
+}
+
+/// Takes `-q`, `-v`, `-vv` and `--log-file <path>` out of the command line `args`, wherever they
+/// are, and starts logging as they say.
+pub fn init_from_args(args: &mut Vec<String>) -> Result<()> {
+    let mut level = LevelFilter::Info;
+    let mut file = None;
+    let mut index = 0;
+    while index < args.len() {
+        match args[index].as_str() {
+            "-q" | "--quiet" => level = LevelFilter::Warn,
+            "-v" | "--verbose" => level = LevelFilter::Debug,
+            "-vv" => level = LevelFilter::Trace,
+            "--log-file" => {
+                let path = args
+                    .get(index + 1)
+                    .ok_or(anyhow!("Usage: --log-file <path>"))?;
+                file = Some(path.clone());
+                args.remove(index);
+            }
+            _ => {
+                index += 1;
+                continue;
+            }
+        }
+        args.remove(index);
+    }
+    init(level, file.as_deref().map(Path::new))
+}
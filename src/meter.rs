@@ -0,0 +1,194 @@
+//! Speed and time estimates for transfers, worked out from the progress a
+//! [`crate::connection::Connection`] reports (see: [`crate::connection::TransferEvent`]), and
+//! summaries of whole downloads and syncs.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+/// How long the recent speed is measured over before it's folded into the estimate, so that one
+/// slow or fast chunk doesn't throw it off.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+/// How much each new sample weighs against the speed estimated so far.
+const SMOOTHING: f64 = 0.3;
+
+/// Where a transfer stands.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TransferStats {
+    pub transferred: u64,
+    pub length: u64,
+    pub elapsed: Duration,
+    /// Bytes per second recently, smoothed over the last few samples.
+    pub speed: f64,
+    /// Bytes per second since the transfer started.
+    pub average_speed: f64,
+}
+
+impl TransferStats {
+    pub fn remaining(&self) -> u64 {
+        self.length.saturating_sub(self.transferred)
+    }
+
+    /// The recent speed, or the average one until a recent one has been measured.
+    pub fn current_speed(&self) -> f64 {
+        match self.speed > 0.0 {
+            true => self.speed,
+            false => self.average_speed,
+        }
+    }
+
+    /// How long the rest should take at the current speed, `None` until there is one.
+    pub fn eta(&self) -> Option<Duration> {
+        let speed = self.current_speed();
+        match speed > 0.0 {
+            true => Some(Duration::from_secs_f64(self.remaining() as f64 / speed)),
+            false => None,
+        }
+    }
+}
+
+/// Measures one transfer at a time.
+#[derive(Debug)]
+pub struct TransferMeter {
+    started: Instant,
+    sampled: Instant,
+    sampled_bytes: u64,
+    stats: TransferStats,
+}
+
+impl TransferMeter {
+    pub fn start(length: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            sampled: now,
+            sampled_bytes: 0,
+            stats: TransferStats {
+                length,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Takes note that `transferred` bytes are across so far.
+    pub fn update(&mut self, transferred: u64) -> TransferStats {
+        let now = Instant::now();
+        let since_sample = now - self.sampled;
+        if since_sample >= SAMPLE_INTERVAL {
+            let speed = transferred.saturating_sub(self.sampled_bytes) as f64 / since_sample.as_secs_f64();
+            self.stats.speed = match self.stats.speed > 0.0 {
+                true => SMOOTHING * speed + (1.0 - SMOOTHING) * self.stats.speed,
+                false => speed,
+            };
+            self.sampled = now;
+            self.sampled_bytes = transferred;
+        }
+        self.stats.transferred = transferred;
+        self.stats.elapsed = now - self.started;
+        let seconds = self.stats.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.stats.average_speed = transferred as f64 / seconds;
+        }
+        self.stats
+    }
+
+    /// The final stats, once everything is across.
+    pub fn finish(&mut self) -> TransferStats {
+        self.update(self.stats.length)
+    }
+
+    pub fn stats(&self) -> TransferStats {
+        self.stats
+    }
+}
+
+/// What a run of transfers, such as a sync, came to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransferSummary {
+    pub transferred: usize,
+    /// Files left alone, for being up to date already or for a conflict nobody settled.
+    pub skipped: usize,
+    pub failed: usize,
+    /// Bytes of the files transferred.
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl TransferSummary {
+    /// Bytes per second over the whole run.
+    pub fn average_speed(&self) -> f64 {
+        match self.elapsed.is_zero() {
+            true => 0.0,
+            false => self.bytes as f64 / self.elapsed.as_secs_f64(),
+        }
+    }
+
+    /// Such as `12 transferred, 340 skipped, 0 failed: 1.2 GiB in 3m 2s (6.8 MiB/s)`.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} transferred, {} skipped, {} failed: {} in {} ({}/s)",
+            self.transferred,
+            self.skipped,
+            self.failed,
+            format_size(self.bytes),
+            humantime::format_duration(Duration::from_secs(self.elapsed.as_secs())),
+            format_size(self.average_speed() as u64)
+        )
+    }
+
+    pub fn to_json_value(&self) -> json::JsonValue {
+        json::object! {
+            "transferred": self.transferred,
+            "skipped": self.skipped,
+            "failed": self.failed,
+            "bytes": self.bytes,
+            "elapsed_seconds": self.elapsed.as_secs_f64(),
+            "average_speed": self.average_speed(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        self.to_json_value().pretty(2)
+    }
+
+    pub fn to_csv(&self) -> String {
+        format!(
+            "transferred,skipped,failed,bytes,elapsed_seconds,average_speed\n{},{},{},{},{:.3},{:.0}\n",
+            self.transferred,
+            self.skipped,
+            self.failed,
+            self.bytes,
+            self.elapsed.as_secs_f64(),
+            self.average_speed()
+        )
+    }
+
+    /// Writes the summary to `path`, as JSON or CSV depending on its extension.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let contents = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => self.to_json(),
+            Some("csv") => self.to_csv(),
+            _ => return Err(anyhow!(format!("Reports are written as .json or .csv, not {}", path.display()))),
+        };
+        fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+/// `41016729190` as `38.2 GiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
@@ -0,0 +1,166 @@
+//! Server metrics, exposed over HTTP in the Prometheus text format so long-running servers can be
+//! monitored like any other service.
+//!
+//! Counters are plain atomics updated by the connection threads; [`serve`] answers
+//! `GET /metrics` on a listener of its own.
+
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::warn;
+
+use crate::drain::Drain;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct Metrics {
+    connections_total: AtomicU64,
+    connections_active: AtomicU64,
+    connections_rejected_total: AtomicU64,
+    requests_total: AtomicU64,
+    bytes_sent_total: AtomicU64,
+    bytes_received_total: AtomicU64,
+    transfers_active: AtomicU64,
+    errors_total: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Counts an accepted connection for as long as the returned gauge lives.
+    pub fn connection(self: &Arc<Self>) -> Gauge {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        Gauge::new(Arc::clone(self), |metrics| &metrics.connections_active)
+    }
+
+    /// Counts a running transfer for as long as the returned gauge lives.
+    pub fn transfer(self: &Arc<Self>) -> Gauge {
+        Gauge::new(Arc::clone(self), |metrics| &metrics.transfers_active)
+    }
+
+    pub fn rejected(&self) {
+        self.connections_rejected_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn request(&self) {
+        self.requests_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: u64) {
+        self.bytes_sent_total.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn received(&self, bytes: u64) {
+        self.bytes_received_total.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a failed transfer or a connection that ended in an error.
+    pub fn error(&self) {
+        self.errors_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            ("oxideux_connections_total", "counter", "Connections accepted.", &self.connections_total),
+            ("oxideux_connections_active", "gauge", "Connections currently open.", &self.connections_active),
+            (
+                "oxideux_connections_rejected_total",
+                "counter",
+                "Connections refused by the access lists or connection limit.",
+                &self.connections_rejected_total,
+            ),
+            ("oxideux_requests_total", "counter", "Requests received from clients.", &self.requests_total),
+            ("oxideux_bytes_sent_total", "counter", "Bytes of files sent to clients.", &self.bytes_sent_total),
+            (
+                "oxideux_bytes_received_total",
+                "counter",
+                "Bytes of files uploaded by clients.",
+                &self.bytes_received_total,
+            ),
+            ("oxideux_transfers_active", "gauge", "Files currently being transferred.", &self.transfers_active),
+            (
+                "oxideux_errors_total",
+                "counter",
+                "Failed transfers and connections that ended in an error.",
+                &self.errors_total,
+            ),
+        ];
+
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            let _ = writeln!(text, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        text
+    }
+}
+
+/// Decrements a gauge of [`Metrics`] when dropped.
+pub struct Gauge {
+    metrics: Arc<Metrics>,
+    gauge: fn(&Metrics) -> &AtomicU64,
+}
+
+impl Gauge {
+    fn new(metrics: Arc<Metrics>, gauge: fn(&Metrics) -> &AtomicU64) -> Self {
+        gauge(&metrics).fetch_add(1, Ordering::Relaxed);
+        Self { metrics, gauge }
+    }
+}
+
+impl Drop for Gauge {
+    fn drop(&mut self) {
+        (self.gauge)(&self.metrics).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Starts answering scrapes of `metrics` on `listener` in the background, until `drain` is done,
+/// so draining can be followed.
+pub fn serve(metrics: Arc<Metrics>, listener: TcpListener, drain: Drain) {
+    thread::spawn(move || {
+        for stream in drain.incoming(&listener, true).flatten() {
+            // Scrapes are rare and quick, so one at a time is plenty
+            if let Err(e) = answer(&metrics, stream) {
+                warn!("Metrics request failed: {}", e);
+            }
+        }
+    });
+}
+
+fn answer(metrics: &Metrics, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, nothing in them matters here
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "Not found, try /metrics\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
@@ -4,14 +4,306 @@
 /// root.
 
 use anyhow::Result;
-use std::fs;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug)]
+use crate::config::{ServerProfile, SortKey, SymlinkPolicy};
+use crate::connection::FileChanged;
+use crate::ignore::IgnoreRules;
+use crate::request::{ErrorCode, RequestError};
+use crate::s3::Bucket;
+use crate::validated_values::ValidatedValue;
+use crate::watch::{Notification, RootWatcher};
+
+/// Where a parity root's [`Manifest`] is kept, at its top. Never listed.
+pub const MANIFEST_FILE: &str = ".oxideux-manifest.json";
+
+/// How long a listing of a root nothing notifies [`EntryCache`] about is trusted, as editing a file
+/// in place doesn't change the modification time of its directory.
+const LISTING_MAX_AGE: Duration = Duration::from_secs(2);
+/// How long opening a file for sending waits for a writer's exclusive lock to be released.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
 pub struct Entry {
     pub name: String,
+    /// Where the file is kept by its [`Storage`]: a local path, or an object key.
     pub path: PathBuf,
-    pub length: u32,
+    pub length: u64,
+    /// Last modification time in seconds since the unix epoch, if known.
+    pub modified: Option<u64>,
+}
+
+/// Where the files of a parity root are kept. The server lists and reads files only through this,
+/// so a parity root can live in a local directory ([`LocalStorage`]) or an S3-compatible bucket
+/// ([`crate::s3::Bucket`]).
+pub trait Storage: Send + Sync {
+    /// The files directly in the parity root.
+    fn entries(&self) -> Result<Vec<Entry>>;
+
+    /// The file named `name`, a `/`-separated path relative to the parity root.
+    fn find(&self, name: &str) -> std::result::Result<Entry, RequestError>;
+
+    /// The files matching the glob `pattern`, named relative to the parity root.
+    fn matching(&self, pattern: &str) -> Result<Vec<Entry>>;
+
+    /// Reads the contents of `entry`, starting `offset` bytes in.
+    fn open(&self, entry: &Entry, offset: u64) -> Result<Box<dyn Read + Send>>;
+
+    fn summary(&self, entry: &Entry) -> Result<EntrySummary> {
+        Ok(EntrySummary {
+            name: entry.name.clone(),
+            length: entry.length,
+            modified: entry.modified,
+        })
+    }
+
+    fn file_info(&self, entry: &Entry) -> Result<FileInfo> {
+        let summary = self.summary(entry)?;
+        Ok(FileInfo {
+            name: summary.name,
+            length: summary.length,
+            modified: summary.modified,
+            hash: hash_reader(self.open(entry, 0)?)?,
+        })
+    }
+
+    /// The [`FileInfo`] of every file, hashing each of them.
+    fn manifest(&self) -> Result<Manifest> {
+        let files = self.entries()?.iter().map(|entry| self.file_info(entry)).collect::<Result<_>>()?;
+        Ok(Manifest {
+            generated: unix_now(),
+            files,
+        })
+    }
+}
+
+/// A parity root that is a local directory, without the files `ignore` excludes.
+pub struct LocalStorage {
+    root: PathBuf,
+    ignore: IgnoreRules,
+    cache: Option<Arc<EntryCache>>,
+}
+
+impl LocalStorage {
+    pub fn new<P: Into<PathBuf>>(root: P, ignore: IgnoreRules) -> Self {
+        Self {
+            root: root.into(),
+            ignore,
+            cache: None,
+        }
+    }
+
+    /// Lists the root through `cache` rather than reading it every time.
+    pub fn cached(self, cache: Arc<EntryCache>) -> Self {
+        Self {
+            cache: Some(cache),
+            ..self
+        }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn entries(&self) -> Result<Vec<Entry>> {
+        match &self.cache {
+            Some(cache) => Ok(cache.entries(&self.root, &self.ignore)?.to_vec()),
+            None => get_file_entries(self.root.clone(), &self.ignore),
+        }
+    }
+
+    /// Refuses anything that escapes the parity root, symlinks included, and excluded files.
+    fn find(&self, name: &str) -> std::result::Result<Entry, RequestError> {
+        let not_found = || RequestError::new(ErrorCode::NotFound, "No such file in the parity root").with_path(name);
+
+        let parity_root = self
+            .root
+            .canonicalize()
+            .map_err(|e| RequestError::new(ErrorCode::Internal, format!("Parity root is unavailable: {}", e)))?;
+
+        let mut file_path = parity_root.clone();
+        file_path.push(name);
+        let file_path = file_path.canonicalize().map_err(|_| not_found())?;
+
+        // Unauthorized file access
+        if !file_path.starts_with(&parity_root) {
+            return Err(RequestError::new(ErrorCode::UnauthorizedAccess, "Path leads outside of the parity root").with_path(name));
+        }
+        if self.ignore.is_ignored(&relative_name(&parity_root, &file_path)) {
+            return Err(not_found());
+        }
+        match resolve_links(&parity_root, &parity_root.join(name), &self.ignore) {
+            Ok(Some(_)) => (),
+            Ok(None) => return Err(not_found()),
+            Err(e) => return Err(RequestError::new(ErrorCode::UnauthorizedAccess, e).with_path(name)),
+        }
+
+        get_file_entry(file_path).map_err(|_| not_found())
+    }
+
+    fn matching(&self, pattern: &str) -> Result<Vec<Entry>> {
+        get_matching_entries(&self.root, pattern, &self.ignore)
+    }
+
+    fn open(&self, entry: &Entry, offset: u64) -> Result<Box<dyn Read + Send>> {
+        let mut file = StableFile::open(entry)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(file))
+    }
+
+    fn summary(&self, entry: &Entry) -> Result<EntrySummary> {
+        get_entry_summary(entry)
+    }
+
+    fn file_info(&self, entry: &Entry) -> Result<FileInfo> {
+        get_file_info(entry)
+    }
+
+    /// Only hashes the files that changed since the manifest saved in the root was made.
+    fn manifest(&self) -> Result<Manifest> {
+        Manifest::refresh(&self.root, &self.entries()?)
+    }
+}
+
+/// The storage `profile` serves from: its bucket if it has one, its parity root otherwise.
+pub fn open_storage(profile: &ServerProfile) -> Result<Box<dyn Storage>> {
+    match profile.storage.is_set() {
+        true => Ok(Box::new(Bucket::parse(profile.storage.get())?.ignoring(profile_rules(profile)?))),
+        false => Ok(Box::new(LocalStorage::new(profile.parity_root.get(), ignore_rules(profile)?))),
+    }
+}
+
+/// Like [`open_storage`], but a parity root is listed through `cache`.
+pub fn open_cached_storage(profile: &ServerProfile, cache: &Arc<EntryCache>) -> Result<Box<dyn Storage>> {
+    match profile.storage.is_set() {
+        true => open_storage(profile),
+        false => Ok(Box::new(
+            LocalStorage::new(profile.parity_root.get(), ignore_rules(profile)?).cached(Arc::clone(cache)),
+        )),
+    }
+}
+
+/// The listings of parity roots, shared by every connection so that directories with many files
+/// aren't read again for every request.
+///
+/// A listing is read again when the modification time of its directory changed (a file was
+/// added, removed or renamed), when the ignore rules changed, and either when it is older than
+/// [`LISTING_MAX_AGE`] or, for roots a [`RootWatcher`] reports on, as soon as anything in it changes.
+#[derive(Default)]
+pub struct EntryCache {
+    listings: Mutex<HashMap<PathBuf, Listing>>,
+    watched: Mutex<HashSet<PathBuf>>,
+    /// Bumped by every invalidation, so a listing read while a change came in isn't kept.
+    generation: AtomicU64,
+}
+
+struct Listing {
+    modified: Option<SystemTime>,
+    listed_at: Instant,
+    generation: u64,
+    ignore: IgnoreRules,
+    entries: Arc<Vec<Entry>>,
+}
+
+impl EntryCache {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// The files directly in `root`, as [`get_file_entries`] lists them.
+    pub fn entries(&self, root: &Path, ignore: &IgnoreRules) -> Result<Arc<Vec<Entry>>> {
+        let root = root.canonicalize()?;
+        let modified = fs::metadata(&root)?.modified().ok();
+        let generation = self.generation.load(Ordering::SeqCst);
+        let watched = self.watched.lock().unwrap().contains(&root);
+
+        if let Some(listing) = self.listings.lock().unwrap().get(&root) {
+            let fresh = watched || listing.listed_at.elapsed() < LISTING_MAX_AGE;
+            if fresh && listing.modified == modified && listing.generation == generation && listing.ignore == *ignore {
+                return Ok(Arc::clone(&listing.entries));
+            }
+        }
+
+        // List without holding the lock, as large directories take a while
+        let entries = Arc::new(get_file_entries(root.clone(), ignore)?);
+        self.listings.lock().unwrap().insert(root, Listing {
+            modified,
+            listed_at: Instant::now(),
+            generation,
+            ignore: ignore.clone(),
+            entries: Arc::clone(&entries),
+        });
+        Ok(entries)
+    }
+
+    /// Drops the listing of `root`, e.g. after a file was written into it.
+    pub fn invalidate<P: AsRef<Path>>(&self, root: P) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        if let Ok(root) = root.as_ref().canonicalize() {
+            self.listings.lock().unwrap().remove(&root);
+        }
+    }
+
+    /// Drops the listing of `root` whenever `watcher` reports a change in it, which lets it be
+    /// trusted for longer than [`LISTING_MAX_AGE`].
+    pub fn follow<P: AsRef<Path>>(self: &Arc<Self>, root: P, watcher: &RootWatcher) -> Result<()> {
+        let root = root.as_ref().canonicalize()?;
+        let notifications = watcher.subscribe();
+        self.watched.lock().unwrap().insert(root.clone());
+        let cache = Arc::downgrade(self);
+        thread::spawn(move || {
+            for notification in notifications {
+                // The server is gone
+                let cache = match cache.upgrade() {
+                    Some(cache) => cache,
+                    None => return,
+                };
+                if notification != Notification::Heartbeat {
+                    cache.invalidate(&root);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// What `profile` excludes from its parity root (see: [`crate::ignore`]).
+pub fn ignore_rules(profile: &ServerProfile) -> Result<IgnoreRules> {
+    profile_rules(profile)?.with_ignore_file(profile.parity_root.get())
+}
+
+/// The exclusions and policies of `profile` itself, without those of the ignore file.
+pub fn profile_rules(profile: &ServerProfile) -> Result<IgnoreRules> {
+    Ok(IgnoreRules::parse(&profile.exclude.entries())?
+        .hidden_files(profile.hidden_files)
+        .symlinks(profile.symlinks))
+}
+
+/// Where `path`, a path below the canonical `root`, leads if the symlink policy of `ignore` lets
+/// it be listed or served, `None` when it doesn't. Paths that lead outside of `root` or nowhere are
+/// refused whatever the policy.
+pub fn resolve_links(root: &Path, path: &Path, ignore: &IgnoreRules) -> Result<Option<PathBuf>> {
+    let target = match path.canonicalize() {
+        Ok(target) if target.starts_with(root) => target,
+        _ => return Ok(None),
+    };
+    // No symlinks on the way
+    if target == path {
+        return Ok(Some(target));
+    }
+    match ignore.symlink_policy() {
+        SymlinkPolicy::Skip => Ok(None),
+        SymlinkPolicy::Follow => Ok(Some(target)),
+        SymlinkPolicy::Error => Err(anyhow::anyhow!(format!("Refusing to follow the symlink at {:?}", path))),
+    }
 }
 
 pub fn get_file_entry(path: PathBuf) -> Result<Entry> {
@@ -20,32 +312,718 @@ pub fn get_file_entry(path: PathBuf) -> Result<Entry> {
     }
 
     let name = path.file_name().unwrap().to_string_lossy().to_string();
-    let length = fs::metadata(&path)?.len() as u32;
+    let metadata = fs::metadata(&path)?;
 
     Ok(Entry {
         name,
         path: path.clone(),
-        length,
+        length: metadata.len(),
+        modified: modified_secs(&metadata),
     })
 }
 
-pub fn get_file_entries(path: PathBuf) -> Result<Vec<Entry>> {
+/// The files directly in `path`, without the ones `ignore` excludes. Symlinks are dealt with as
+/// its policy says, and never lead outside of `path`.
+pub fn get_file_entries(path: PathBuf, ignore: &IgnoreRules) -> Result<Vec<Entry>> {
     let mut entries = vec![];
 
+    let root = path.canonicalize()?;
     let read_dir = fs::read_dir(path)?;
     for res in read_dir {
         let entry = res?;
 
-        if entry.metadata()?.is_dir() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if ignore.is_ignored(&name) {
+            continue;
+        }
+        let metadata = match entry.file_type()?.is_symlink() {
+            true => match resolve_links(&root, &root.join(&name), ignore)? {
+                Some(target) => fs::metadata(target)?,
+                None => continue,
+            },
+            false => entry.metadata()?,
+        };
+        if metadata.is_dir() {
             continue;
         }
 
-        let name = entry.file_name().to_string_lossy().to_string();
         let path = entry.path();
-        let length = entry.metadata()?.len() as u32;
+        let length = metadata.len();
 
-        entries.push(Entry { name, path, length });
+        entries.push(Entry { name, path, length, modified: modified_secs(&metadata) });
     }
 
+    // The directory is read in no particular order
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
     Ok(entries)
 }
+
+/// Orders `entries` by `key`, with names breaking ties, so an index refers to the same file from
+/// one request to the next as long as the files don't change.
+pub fn sort_entries(entries: &mut [Entry], key: SortKey, descending: bool) {
+    entries.sort_by(|a, b| {
+        let order = match key {
+            SortKey::Name => std::cmp::Ordering::Equal,
+            SortKey::Size => a.length.cmp(&b.length),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        match descending {
+            true => order.reverse(),
+            false => order,
+        }
+    });
+}
+
+/// Joins a relative, `/`-separated `name` onto `root`, refusing names that are absolute or contain
+/// anything other than plain components (such as `..`), so the result always stays below `root`.
+pub fn safe_join<P: AsRef<Path>>(root: P, name: &str) -> Result<PathBuf> {
+    let relative = Path::new(name);
+    if name.is_empty() || relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return Err(anyhow::anyhow!(format!("Refusing unsafe path: {:?}", name)));
+    }
+    Ok(root.as_ref().join(relative))
+}
+
+/// Like [`safe_join`], for a file about to be written: also refuses `name` when a symlink on the
+/// way, or the file itself, leads out of `root`. Only the parts that exist are resolved, as the
+/// rest is yet to be created.
+pub fn confined_join<P: AsRef<Path>>(root: P, name: &str) -> Result<PathBuf> {
+    let path = safe_join(&root, name)?;
+    match (resolve_existing(root.as_ref()), resolve_existing(&path)) {
+        (Ok(root), Ok(resolved)) if resolved.starts_with(&root) => Ok(path),
+        _ => Err(anyhow::anyhow!(format!("Refusing to write outside the parity root: {:?}", name))),
+    }
+}
+
+/// `path` with its nearest existing part canonicalized. A dangling symlink can't be resolved, and
+/// is an error, and so is a `..` in the part that doesn't exist, which can't be resolved either.
+fn resolve_existing(path: &Path) -> std::io::Result<PathBuf> {
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.symlink_metadata().is_ok())
+        .ok_or(std::io::ErrorKind::NotFound)?;
+    let rest = path.strip_prefix(existing).unwrap_or(Path::new(""));
+    if rest.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return Err(std::io::ErrorKind::InvalidInput.into());
+    }
+    Ok(existing.canonicalize()?.join(rest))
+}
+
+/// `path` when nothing is there yet, otherwise the first free one of `name (1).ext`,
+/// `name (2).ext`, and so on, so a new file can be kept next to the one it would replace. The
+/// extension after the last dot stays at the end, and names are put together as `OsStr`s, leaving
+/// whatever Unicode they hold intact.
+pub fn keep_both_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    let taken = |path: &Path| path.symlink_metadata().is_ok();
+    if !taken(path) {
+        return path.to_path_buf();
+    }
+
+    let stem = path.file_stem().unwrap_or_default();
+    let mut n = 1;
+    loop {
+        let mut name = stem.to_os_string();
+        name.push(format!(" ({})", n));
+        if let Some(extension) = path.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        let candidate = path.with_file_name(name);
+        if !taken(&candidate) {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// `time` as `2026-10-16 03-58-00` (UTC), to put in file names. Colons aren't allowed in names
+/// everywhere, and names stamped this way sort in time order.
+pub fn name_timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time)
+        .to_string()
+        .trim_end_matches('Z')
+        .replace('T', " ")
+        .replace(':', "-")
+}
+
+/// Expands a glob `pattern` (such as `*.iso` or `reports/2024-*`) relative to `root` and returns the
+/// matching files, named by their `/`-separated path relative to `root`.
+///
+/// Patterns that are absolute or contain `..` are refused, and matches that resolve outside of
+/// `root` (e.g. through symlinks) or that `ignore` excludes are skipped. Other symlinks are dealt
+/// with as its policy says.
+pub fn get_matching_entries<P: AsRef<Path>>(root: P, pattern: &str, ignore: &IgnoreRules) -> Result<Vec<Entry>> {
+    let root = root.as_ref().canonicalize()?;
+    let full_pattern = safe_join(&root, pattern)?;
+    let full_pattern = full_pattern
+        .to_str()
+        .ok_or(anyhow::anyhow!("Pattern is not valid unicode"))?;
+
+    let mut entries = vec![];
+    for path in glob::glob(full_pattern)? {
+        let path = path?;
+        let canonical = match resolve_links(&root, &path, ignore)? {
+            Some(canonical) if canonical.is_file() => canonical,
+            _ => continue,
+        };
+
+        let name = relative_name(&root, &path);
+        if ignore.is_ignored(&name) {
+            continue;
+        }
+        let metadata = fs::metadata(&canonical)?;
+        let length = metadata.len();
+        entries.push(Entry { name, path: canonical, length, modified: modified_secs(&metadata) });
+    }
+
+    Ok(entries)
+}
+
+/// The `/`-separated path of `path` relative to `root`, which it must be below.
+pub fn relative_name(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The cheap-to-gather metadata of a file, as listed by [`crate::request::Request::ListFiles`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EntrySummary {
+    pub name: String,
+    pub length: u64,
+    /// Last modification time in seconds since the unix epoch, if the platform reports one.
+    pub modified: Option<u64>,
+}
+
+/// A filter on files by extension, size, modification time and name, as answered by
+/// [`crate::request::Request::QueryFiles`] and [`crate::request::Request::Search`] and applied by clients to what they sync.
+///
+/// Written as space-separated terms (see: [`EntryQuery::parse`]), every one of which a file must
+/// match, e.g. `ext:iso,img size:1G.. since:2024-05-01 name:backup`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryQuery {
+    /// Extensions without the dot, any of which matches. Empty for any extension.
+    pub extensions: Vec<String>,
+    pub min_length: Option<u64>,
+    pub max_length: Option<u64>,
+    /// Seconds since the unix epoch. Files without a modification time never match.
+    pub modified_since: Option<u64>,
+    /// Part of the name, matched regardless of case.
+    pub name_contains: Option<String>,
+}
+
+impl EntryQuery {
+    /// Parses space-separated terms:
+    ///
+    /// - `ext:iso,img` for files with any of these extensions
+    /// - `size:1M..2G`, `size:..500K` or `size:10M..` for files in a size range, with `K`, `M`, `G`
+    ///   and `T` standing for powers of 1024
+    /// - `since:2024-05-01`, `since:2024-05-01T12:00:00Z` or `since:7d` for files modified since
+    ///   then, or within that duration
+    /// - `name:report` for files whose name contains the text
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut query = Self::default();
+        for term in spec.split_whitespace() {
+            let (key, value) = term
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!(format!("Expected key:value, got '{}'", term)))?;
+            match key {
+                "ext" => query.extensions.extend(
+                    value
+                        .split(',')
+                        .map(|extension| extension.trim_start_matches('.').to_lowercase())
+                        .filter(|extension| !extension.is_empty()),
+                ),
+                "size" => {
+                    let (min, max) = value.split_once("..").unwrap_or((value, value));
+                    query.min_length = (!min.is_empty()).then(|| parse_size(min)).transpose()?;
+                    query.max_length = (!max.is_empty()).then(|| parse_size(max)).transpose()?;
+                }
+                "since" => query.modified_since = Some(parse_since(value)?),
+                "name" => query.name_contains = Some(value.to_string()),
+                _ => return Err(anyhow::anyhow!(format!("Unknown query term '{}'", key))),
+            }
+        }
+        Ok(query)
+    }
+
+    /// Whether nothing is filtered out.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether the entry passes every term of the query.
+    pub fn matches(&self, summary: &EntrySummary) -> bool {
+        if !self.matches_name(&summary.name) {
+            return false;
+        }
+        if self.min_length.is_some_and(|min| summary.length < min) || self.max_length.is_some_and(|max| summary.length > max) {
+            return false;
+        }
+        match self.modified_since {
+            Some(since) => summary.modified.is_some_and(|modified| modified >= since),
+            None => true,
+        }
+    }
+
+    /// Whether a file named `name` passes the extension and name terms, the ones that need nothing
+    /// but the name.
+    pub fn matches_name(&self, name: &str) -> bool {
+        let file = name.rsplit('/').next().unwrap_or(name);
+        let extension = file.rsplit_once('.').map(|(_, extension)| extension.to_lowercase());
+        if !self.extensions.is_empty() && !extension.is_some_and(|extension| self.extensions.contains(&extension)) {
+            return false;
+        }
+        match &self.name_contains {
+            Some(part) => name.to_lowercase().contains(&part.to_lowercase()),
+            None => true,
+        }
+    }
+}
+
+/// A size such as `512`, `64K` or `1.5G`, in bytes.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let invalid = || anyhow::anyhow!(format!("Invalid size '{}'", size));
+    let upper = size.to_uppercase();
+    let digits = upper.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match upper[digits.len()..].trim_end_matches('B').trim_end_matches('I') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(invalid()),
+    };
+    let number: f64 = digits.parse().map_err(|_| invalid())?;
+    if number < 0.0 {
+        return Err(invalid());
+    }
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// A date, a date and time, or a duration counting back from now, in seconds since the unix epoch.
+fn parse_since(since: &str) -> Result<u64> {
+    if let Ok(duration) = humantime::parse_duration(since) {
+        return Ok(unix_now().saturating_sub(duration.as_secs()));
+    }
+    let time = match since.len() {
+        10 => humantime::parse_rfc3339_weak(&format!("{} 00:00:00", since)),
+        _ => humantime::parse_rfc3339_weak(since),
+    }
+    .map_err(|_| anyhow::anyhow!(format!("Invalid date or duration '{}'", since)))?;
+    Ok(time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default())
+}
+
+pub fn get_entry_summary(entry: &Entry) -> Result<EntrySummary> {
+    let metadata = fs::metadata(&entry.path)?;
+    Ok(EntrySummary {
+        name: entry.name.clone(),
+        length: metadata.len(),
+        modified: modified_secs(&metadata),
+    })
+}
+
+/// The modification time of `metadata` in seconds since the unix epoch, if the platform reports one.
+#[inline]
+pub fn modified_secs(metadata: &fs::Metadata) -> Option<u64> {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+}
+
+/// A local file opened for sending, which fails with [`FileChanged`] when it no longer matches the
+/// entry it was listed as, or once read to the end if it was modified in the meantime.
+///
+/// Writers that take an exclusive advisory lock (`flock`) on the file are waited for when opening,
+/// for up to [`LOCK_TIMEOUT`], and kept out until it is closed.
+pub struct StableFile {
+    file: File,
+    name: String,
+    length: u64,
+    modified: Option<SystemTime>,
+}
+
+impl StableFile {
+    pub fn open(entry: &Entry) -> Result<Self> {
+        let changed = || FileChanged { name: entry.name.clone() };
+        let file = File::open(&entry.path)?;
+        if !lock_shared(&file)? {
+            // Still being written
+            return Err(changed().into());
+        }
+        let metadata = file.metadata()?;
+        if metadata.len() != entry.length || modified_secs(&metadata) != entry.modified {
+            return Err(changed().into());
+        }
+        Ok(Self {
+            name: entry.name.clone(),
+            length: metadata.len(),
+            modified: metadata.modified().ok(),
+            file,
+        })
+    }
+}
+
+impl Read for StableFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.file.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let metadata = self.file.metadata()?;
+            if metadata.len() != self.length || metadata.modified().ok() != self.modified {
+                return Err(std::io::Error::other(FileChanged { name: self.name.clone() }));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Seek for StableFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+/// Takes a shared lock on `file`, waiting up to [`LOCK_TIMEOUT`] for an exclusive one to be released.
+/// Returns whether the lock was taken.
+#[cfg(unix)]
+fn lock_shared(file: &File) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let deadline = Instant::now() + LOCK_TIMEOUT;
+    loop {
+        // SAFETY: the descriptor belongs to `file`, which stays open for the whole call.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } == 0 {
+            return Ok(true);
+        }
+        let error = std::io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EWOULDBLOCK) if Instant::now() >= deadline => return Ok(false),
+            Some(libc::EWOULDBLOCK) => thread::sleep(LOCK_RETRY_INTERVAL),
+            Some(libc::EINTR) => (),
+            _ => return Err(error.into()),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_shared(_file: &File) -> Result<bool> {
+    Ok(true)
+}
+
+/// The total length of the files in `dir` and its subdirectories, zero when it doesn't exist.
+/// Symlinks aren't followed.
+pub fn disk_usage<P: AsRef<Path>>(dir: P) -> Result<u64> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut total = 0;
+    for entry in read_dir {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            total += disk_usage(entry.path())?;
+        } else if file_type.is_file() {
+            total += entry.metadata()?.len();
+        }
+    }
+    Ok(total)
+}
+
+/// How many bytes can still be written to the filesystem `path` is on, or would be created on
+/// when it doesn't exist yet. `None` where the platform can't tell.
+pub fn available_space<P: AsRef<Path>>(path: P) -> Result<Option<u64>> {
+    let existing = path.as_ref().ancestors().find(|ancestor| ancestor.exists());
+    statvfs_available(existing.unwrap_or(Path::new(".")))
+}
+
+#[cfg(unix)]
+fn statvfs_available(path: &Path) -> Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(Some(stats.f_bavail as u64 * stats.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn statvfs_available(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Totals over a parity root, as answered to [`crate::request::Request::GetRootStats`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RootStats {
+    pub files: u64,
+    pub bytes: u64,
+    /// `None` when the root is empty.
+    pub largest: Option<EntrySummary>,
+    /// The most recently modified file, `None` when the root is empty or no file has a
+    /// modification time.
+    pub newest: Option<EntrySummary>,
+}
+
+impl RootStats {
+    pub fn of(summaries: &[EntrySummary]) -> Self {
+        Self {
+            files: summaries.len() as u64,
+            bytes: summaries.iter().map(|summary| summary.length).sum(),
+            largest: summaries.iter().max_by_key(|summary| summary.length).cloned(),
+            newest: summaries
+                .iter()
+                .filter(|summary| summary.modified.is_some())
+                .max_by_key(|summary| summary.modified)
+                .cloned(),
+        }
+    }
+}
+
+/// Metadata of a single file, as answered to [`crate::request::Request::GetFileInfo`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileInfo {
+    pub name: String,
+    pub length: u64,
+    /// Last modification time in seconds since the unix epoch, if the platform reports one.
+    pub modified: Option<u64>,
+    /// Lowercase hex SHA-256 of the contents.
+    pub hash: String,
+}
+
+impl FileInfo {
+    pub fn modified_time(&self) -> Option<SystemTime> {
+        self.modified
+            .map(|secs| UNIX_EPOCH + std::time::Duration::from_secs(secs))
+    }
+}
+
+/// The [`FileInfo`] of every file of a parity root, as answered to
+/// [`crate::request::Request::GetManifest`].
+///
+/// Local parity roots keep theirs in [`MANIFEST_FILE`], so a file is only hashed again when its
+/// length or modification time changed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    /// When the manifest was made, in seconds since the unix epoch.
+    pub generated: u64,
+    pub files: Vec<FileInfo>,
+}
+
+impl Manifest {
+    /// The manifest saved in `root`, `None` when there is none or it can't be read.
+    pub fn load<P: AsRef<Path>>(root: P) -> Option<Self> {
+        let contents = fs::read_to_string(root.as_ref().join(MANIFEST_FILE)).ok()?;
+        let root = json::parse(&contents).ok()?;
+        let files = root["files"]
+            .members()
+            .map(|file| {
+                Some(FileInfo {
+                    name: file["name"].as_str()?.to_string(),
+                    length: file["length"].as_u64()?,
+                    modified: file["modified"].as_u64(),
+                    hash: file["hash"].as_str()?.to_string(),
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            generated: root["generated"].as_u64()?,
+            files,
+        })
+    }
+
+    /// Writes the manifest to `root`, replacing the previous one at once so that readers never
+    /// see half of it.
+    pub fn save<P: AsRef<Path>>(&self, root: P) -> Result<()> {
+        let mut files = json::JsonValue::new_array();
+        for file in &self.files {
+            files.push(json::object! {
+                "name": file.name.clone(),
+                "length": file.length,
+                "modified": file.modified,
+                "hash": file.hash.clone(),
+            })?;
+        }
+        let data = json::object! {
+            "generated": self.generated,
+            "files": files,
+        };
+
+        let path = root.as_ref().join(MANIFEST_FILE);
+        let partial = root.as_ref().join(format!("{}.part", MANIFEST_FILE));
+        fs::write(&partial, data.dump())?;
+        fs::rename(partial, path)?;
+        Ok(())
+    }
+
+    /// The manifest of `entries`, the files of `root`, reusing the hashes of the one saved in
+    /// `root` for files whose length and modification time didn't change. It is saved back when
+    /// anything did, if the root can be written to.
+    pub fn refresh<P: AsRef<Path>>(root: P, entries: &[Entry]) -> Result<Self> {
+        let saved = Self::load(&root).unwrap_or_default();
+        let known: HashMap<&str, &FileInfo> = saved.files.iter().map(|file| (file.name.as_str(), file)).collect();
+
+        let mut manifest = Self {
+            generated: unix_now(),
+            files: vec![],
+        };
+        let mut changed = entries.len() != saved.files.len();
+        for entry in entries {
+            let metadata = fs::metadata(&entry.path)?;
+            let (length, modified) = (metadata.len(), modified_secs(&metadata));
+            // A file modified in the second the saved manifest was made may have changed after it
+            let reusable = known
+                .get(entry.name.as_str())
+                .filter(|file| file.length == length && file.modified == modified)
+                .filter(|file| file.modified.is_some_and(|modified| modified < saved.generated));
+            let file = match reusable {
+                Some(file) => (*file).clone(),
+                None => {
+                    changed = true;
+                    FileInfo {
+                        name: entry.name.clone(),
+                        length,
+                        modified,
+                        hash: hash_file(&entry.path)?,
+                    }
+                }
+            };
+            manifest.files.push(file);
+        }
+
+        if changed {
+            // Read-only roots still get a manifest, just one made from scratch every time
+            let _ = manifest.save(root);
+        }
+        Ok(manifest)
+    }
+
+    /// The file named `name`, if it is listed.
+    pub fn get(&self, name: &str) -> Option<&FileInfo> {
+        self.files.iter().find(|file| file.name == name)
+    }
+
+    /// The first listed file whose contents hash to `hash`, whatever it is called.
+    pub fn find_hash(&self, hash: &str) -> Option<&FileInfo> {
+        self.files.iter().find(|file| file.hash.eq_ignore_ascii_case(hash))
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn get_file_info(entry: &Entry) -> Result<FileInfo> {
+    let metadata = fs::metadata(&entry.path)?;
+
+    Ok(FileInfo {
+        name: entry.name.clone(),
+        length: metadata.len(),
+        modified: modified_secs(&metadata),
+        hash: hash_file(&entry.path)?,
+    })
+}
+
+/// Returns the lowercase hex SHA-256 of the file at `path`.
+pub fn hash_file<P: AsRef<Path>>(path: P) -> Result<String> {
+    hash_reader(File::open(path)?)
+}
+
+/// Returns the lowercase hex SHA-256 of everything `reader` yields.
+pub fn hash_reader<R: Read>(mut reader: R) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 65536];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn confined_join_refuses_escapes() {
+        let dir = std::env::temp_dir().join(format!("oxideux-parity-test-{}", std::process::id()));
+        let (root, outside) = (dir.join("root"), dir.join("outside"));
+        fs::create_dir_all(root.join("inside")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        symlink(&outside, root.join("linked")).unwrap();
+        symlink(root.join("inside"), root.join("linked_inside")).unwrap();
+        symlink(dir.join("missing"), root.join("dangling")).unwrap();
+        symlink(outside.join("stolen"), root.join("file.txt.part")).unwrap();
+
+        let confined = |name: &str| confined_join(&root, name).is_ok();
+        let results = [
+            confined("inside/new.txt"),
+            confined("new/deeper/file.txt"),
+            confined("linked_inside/new.txt"),
+            confined("linked/new.txt"),
+            confined("dangling"),
+            confined("dangling/new.txt"),
+            confined("inside/../../outside/new.txt"),
+            confined("file.txt"),
+            confined("file.txt.part"),
+        ];
+        let unresolved = resolve_existing(&root.join("missing/../../outside")).is_err();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(results, [true, true, true, false, false, false, false, true, false]);
+        assert!(unresolved);
+    }
+
+    #[test]
+    fn stable_files_refuse_entries_that_changed() {
+        let dir = std::env::temp_dir().join(format!("oxideux-stable-file-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+        fs::write(&path, b"listed").unwrap();
+        let entry = get_file_entry(path.clone()).unwrap();
+        let unchanged = StableFile::open(&entry).is_ok();
+        fs::write(&path, b"written since").unwrap();
+        let changed = StableFile::open(&entry).err().map(|e| e.is::<FileChanged>());
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(unchanged);
+        assert_eq!(changed, Some(true));
+    }
+
+    #[test]
+    fn entries_keep_lengths_past_u32() {
+        let dir = std::env::temp_dir().join(format!("oxideux-large-entry-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("large.iso");
+        // Sparse, so nothing is written
+        let length = 5 << 30;
+        File::create(&path).unwrap().set_len(length).unwrap();
+        let entry = get_file_entry(path.clone()).map(|entry| entry.length);
+        let listed = get_file_entries(dir.clone(), &IgnoreRules::default()).map(|entries| entries.iter().map(|entry| entry.length).collect::<Vec<_>>());
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(entry.unwrap(), length);
+        assert_eq!(listed.unwrap(), [length]);
+    }
+}
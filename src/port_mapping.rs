@@ -0,0 +1,323 @@
+//! Router port mapping, so a server behind a home router can be reached from outside the LAN.
+//!
+//! UPnP IGD is tried first (it is what most consumer routers speak), then NAT-PMP. NAT-PMP is
+//! spoken directly over [`std::net`], UPnP over [`crate::http_client`]; the mapping is removed
+//! again when the [`PortMapping`] is dropped.
+
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+use crate::http_client::{self, Url};
+use crate::xml::Element;
+
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const NAT_PMP_PORT: u16 = 5351;
+const NAT_PMP_LIFETIME: u32 = 3600;
+const TIMEOUT: Duration = Duration::from_secs(3);
+const DESCRIPTION: &str = "oxideux";
+
+enum Method {
+    Upnp { control_url: Url, service_type: String },
+    NatPmp { gateway: Ipv4Addr, stop: Arc<AtomicBool>, renewal: Option<JoinHandle<()>> },
+}
+
+/// An active TCP port mapping on the local router.
+pub struct PortMapping {
+    /// The address peers outside the LAN can connect to.
+    pub external: SocketAddrV4,
+    port: u16,
+    method: Method,
+}
+
+impl PortMapping {
+    /// Maps external TCP `port` on the router to the same port on this machine.
+    pub fn new(port: u16) -> Result<Self> {
+        let upnp_error = match Self::upnp(port) {
+            Ok(mapping) => return Ok(mapping),
+            Err(e) => e,
+        };
+        Self::nat_pmp(port).map_err(|nat_pmp_error| {
+            anyhow!(format!(
+                "No router accepted the mapping (UPnP: {}; NAT-PMP: {})",
+                upnp_error, nat_pmp_error
+            ))
+        })
+    }
+
+    /// Whether the mapping was made over UPnP (`"UPnP"`) or NAT-PMP (`"NAT-PMP"`).
+    pub fn method_name(&self) -> &'static str {
+        match self.method {
+            Method::Upnp { .. } => "UPnP",
+            Method::NatPmp { .. } => "NAT-PMP",
+        }
+    }
+
+    fn upnp(port: u16) -> Result<Self> {
+        let location = Url::parse(&ssdp_search()?)?;
+        let description = http(&location, "GET", &[], "")?;
+        // Control URLs are relative to the base URL the description gives, or to where it was found
+        let base = match description.child_text("URLBase").map(str::trim).filter(|base| !base.is_empty()) {
+            Some(base) => location.join(base)?,
+            None => location.clone(),
+        };
+        let (service_type, control_url) = find_wan_service(&description)?;
+        let control_url = base.join(&control_url)?;
+
+        let gateway: Ipv4Addr = location
+            .host
+            .parse()
+            .map_err(|_| anyhow!(format!("Gateway is not at an IPv4 address: {}", location.host)))?;
+        let local_ip = local_ip_towards(gateway)?;
+
+        let arguments = format!(
+            "<NewRemoteHost></NewRemoteHost><NewExternalPort>{port}</NewExternalPort>\
+             <NewProtocol>TCP</NewProtocol><NewInternalPort>{port}</NewInternalPort>\
+             <NewInternalClient>{local_ip}</NewInternalClient><NewEnabled>1</NewEnabled>\
+             <NewPortMappingDescription>{DESCRIPTION}</NewPortMappingDescription>\
+             <NewLeaseDuration>0</NewLeaseDuration>"
+        );
+        soap(&control_url, &service_type, "AddPortMapping", &arguments)?;
+
+        let response = soap(&control_url, &service_type, "GetExternalIPAddress", "")?;
+        let external_ip: Ipv4Addr = response
+            .descendant_text("NewExternalIPAddress")
+            .ok_or(anyhow!("Router did not report its external address"))?
+            .trim()
+            .parse()?;
+
+        Ok(Self {
+            external: SocketAddrV4::new(external_ip, port),
+            port,
+            method: Method::Upnp { control_url, service_type },
+        })
+    }
+
+    fn nat_pmp(port: u16) -> Result<Self> {
+        let gateway = default_gateway()?;
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        socket.connect(SocketAddrV4::new(gateway, NAT_PMP_PORT))?;
+
+        let external_port = nat_pmp_map(&socket, port, NAT_PMP_LIFETIME)?;
+
+        socket.send(&[0, 0])?;
+        let mut response = [0u8; 12];
+        socket.recv(&mut response)?;
+        nat_pmp_check(&response, 128)?;
+        let external_ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+
+        // NAT-PMP mappings expire, so renew at half the lifetime for as long as we're alive
+        let stop = Arc::new(AtomicBool::new(false));
+        let renewal = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut renewed = Instant::now();
+                while !stop.load(Ordering::Relaxed) {
+                    thread::sleep(Duration::from_millis(500));
+                    if renewed.elapsed() >= Duration::from_secs(NAT_PMP_LIFETIME as u64 / 2) {
+                        let _ = nat_pmp_map(&socket, port, NAT_PMP_LIFETIME);
+                        renewed = Instant::now();
+                    }
+                }
+            })
+        };
+
+        Ok(Self {
+            external: SocketAddrV4::new(external_ip, external_port),
+            port,
+            method: Method::NatPmp { gateway, stop, renewal: Some(renewal) },
+        })
+    }
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        match &mut self.method {
+            Method::Upnp { control_url, service_type } => {
+                let arguments = format!(
+                    "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort>\
+                     <NewProtocol>TCP</NewProtocol>",
+                    self.external.port()
+                );
+                let _ = soap(control_url, service_type, "DeletePortMapping", &arguments);
+            }
+            Method::NatPmp { gateway, stop, renewal } => {
+                stop.store(true, Ordering::Relaxed);
+                if let Some(renewal) = renewal.take() {
+                    let _ = renewal.join();
+                }
+                // A lifetime of zero deletes the mapping
+                if let Ok(socket) = UdpSocket::bind("0.0.0.0:0") {
+                    let _ = socket.set_read_timeout(Some(TIMEOUT));
+                    if socket.connect(SocketAddrV4::new(*gateway, NAT_PMP_PORT)).is_ok() {
+                        let _ = nat_pmp_map(&socket, self.port, 0);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sends a NAT-PMP TCP mapping request and returns the external port the router assigned.
+fn nat_pmp_map(socket: &UdpSocket, port: u16, lifetime: u32) -> Result<u16> {
+    let mut request = vec![0u8, 2, 0, 0];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&(if lifetime == 0 { 0 } else { port }).to_be_bytes());
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    socket.send(&request)?;
+
+    let mut response = [0u8; 16];
+    socket.recv(&mut response)?;
+    nat_pmp_check(&response, 130)?;
+    Ok(u16::from_be_bytes([response[10], response[11]]))
+}
+
+fn nat_pmp_check(response: &[u8], opcode: u8) -> Result<()> {
+    if response[1] != opcode {
+        return Err(anyhow!("Unexpected NAT-PMP response"));
+    }
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 => Ok(()),
+        code => Err(anyhow!(format!("Router refused with NAT-PMP result code {}", code))),
+    }
+}
+
+/// Asks the network for an internet gateway device and returns the URL of its description.
+fn ssdp_search() -> Result<String> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    let search = "M-SEARCH * HTTP/1.1\r\n\
+                  HOST: 239.255.255.250:1900\r\n\
+                  ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                  MAN: \"ssdp:discover\"\r\n\
+                  MX: 2\r\n\r\n";
+    socket.send_to(search.as_bytes(), SSDP_ADDR)?;
+
+    let mut buffer = [0u8; 2048];
+    let (n, _) = socket.recv_from(&mut buffer)?;
+    let response = String::from_utf8_lossy(&buffer[..n]);
+    response
+        .lines()
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case("location").then(|| value.trim().to_string())
+        })
+        .ok_or(anyhow!("Gateway did not send a description location"))
+}
+
+/// Performs a request of the router and returns the XML document it answered with.
+fn http(url: &Url, method: &str, headers: &[(&str, String)], body: &str) -> Result<Element> {
+    let mut response = http_client::request(url, method, headers, body.as_bytes(), TIMEOUT)?;
+    if response.status != 200 {
+        return Err(anyhow!(format!("Router answered {}", response.status)));
+    }
+    Element::parse(&String::from_utf8(response.body()?)?)
+}
+
+fn soap(control_url: &Url, service_type: &str, action: &str, arguments: &str) -> Result<Element> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+         <u:{action} xmlns:u=\"{service_type}\">{arguments}</u:{action}>\
+         </s:Body></s:Envelope>"
+    );
+    let headers = [
+        ("Content-Type", "text/xml; charset=\"utf-8\"".to_string()),
+        ("SOAPAction", format!("\"{}#{}\"", service_type, action)),
+    ];
+    http(control_url, "POST", &headers, &body)
+}
+
+/// Finds the WAN connection service of a device description, returning its type and control URL
+/// as given, which may be relative.
+fn find_wan_service(description: &Element) -> Result<(String, String)> {
+    description
+        .descendants("service")
+        .into_iter()
+        .find_map(|service| {
+            let service_type = service.child_text("serviceType")?.trim();
+            let is_wan = service_type.contains(":WANIPConnection:") || service_type.contains(":WANPPPConnection:");
+            let control_url = service.child_text("controlURL")?.trim();
+            is_wan.then(|| (service_type.to_string(), control_url.to_string()))
+        })
+        .ok_or(anyhow!("Gateway has no WAN connection service"))
+}
+
+/// The address of this machine on the interface that routes to `target`.
+pub fn local_ip_towards(target: Ipv4Addr) -> Result<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(SocketAddrV4::new(target, 9))?;
+    match socket.local_addr()?.ip() {
+        std::net::IpAddr::V4(ip) => Ok(ip),
+        std::net::IpAddr::V6(_) => Err(anyhow!("No IPv4 route to the gateway")),
+    }
+}
+
+/// Reads the default IPv4 gateway from the kernel routing table.
+fn default_gateway() -> Result<Ipv4Addr> {
+    let routes = fs::read_to_string("/proc/net/route")
+        .map_err(|_| anyhow!("The default gateway can't be determined on this platform"))?;
+    routes
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 || fields[1] != "00000000" {
+                return None;
+            }
+            let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+            Some(Ipv4Addr::from(gateway.to_le_bytes()))
+        })
+        .ok_or(anyhow!("No default gateway"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control_url(location: &str, description: &str) -> Url {
+        let location = Url::parse(location).unwrap();
+        let description = Element::parse(description).unwrap();
+        let base = match description.child_text("URLBase") {
+            Some(base) => location.join(base).unwrap(),
+            None => location.clone(),
+        };
+        base.join(&find_wan_service(&description).unwrap().1).unwrap()
+    }
+
+    #[test]
+    fn control_urls_are_resolved_against_the_description() {
+        let service = |control: &str| {
+            format!(
+                "<?xml version=\"1.0\"?><root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device><serviceList>\
+                 <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+                 <controlURL>/l3f</controlURL></service>\
+                 <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                 <controlURL>{}</controlURL></service></serviceList></device></root>",
+                control
+            )
+        };
+        let location = "http://192.168.1.1:5000/rootDesc.xml";
+        assert_eq!(control_url(location, &service("/ctl/IPConn")).to_string(), "http://192.168.1.1:5000/ctl/IPConn");
+        assert_eq!(
+            control_url(location, &service("http://192.168.1.1:5001/ctl/IPConn")).to_string(),
+            "http://192.168.1.1:5001/ctl/IPConn"
+        );
+        assert_eq!(
+            control_url("http://192.168.1.1/igd/desc.xml", &service("ctl/IPConn")).to_string(),
+            "http://192.168.1.1/igd/ctl/IPConn"
+        );
+
+        let prefixed = "<u:root xmlns:u=\"urn:schemas-upnp-org:device-1-0\"><u:URLBase>http://192.168.1.1:49000</u:URLBase>\
+                        <u:service><u:serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</u:serviceType>\
+                        <u:controlURL>/upnp/control/WANPPPConn1</u:controlURL></u:service></u:root>";
+        assert_eq!(control_url(location, prefixed).to_string(), "http://192.168.1.1:49000/upnp/control/WANPPPConn1");
+    }
+}
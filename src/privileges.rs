@@ -0,0 +1,133 @@
+//! Giving up root once a server started as root to bind a privileged port has bound it, so a bug
+//! in handling clients can't be used to do anything root could.
+//!
+//! The server switches to the user (and group) of its profile's `run_as`, and can first confine
+//! itself to its parity root with `chroot(2)`. Only Unix systems can do either. Every listener is
+//! bound, and every file kept open, before the switch.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+/// A user, and optionally a group, to run as, given as `user[:group]` by name or numeric id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunAs {
+    pub user: String,
+    pub group: Option<String>,
+}
+
+impl RunAs {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (user, group) = match spec.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (spec, None),
+        };
+        if user.is_empty() || group.is_some_and(str::is_empty) {
+            return Err(anyhow!("Expected user or user:group"));
+        }
+        Ok(Self {
+            user: user.to_string(),
+            group: group.map(str::to_string),
+        })
+    }
+
+    /// The ids of the user and group, the group being the user's own unless one was given.
+    pub fn resolve(&self) -> Result<(u32, u32)> {
+        imp::resolve(self)
+    }
+}
+
+/// Switches the process to `run_as`, after confining it to `chroot` when given. Fails unless the
+/// process runs as root, and when root could be regained afterwards.
+pub fn drop_privileges(run_as: &RunAs, chroot: Option<&Path>) -> Result<()> {
+    imp::drop_privileges(run_as, chroot)
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use anyhow::{anyhow, Result};
+
+    use super::RunAs;
+
+    pub fn resolve(run_as: &RunAs) -> Result<(u32, u32)> {
+        let (uid, user_gid) = match run_as.user.parse::<u32>() {
+            Ok(uid) => (uid, uid),
+            Err(_) => {
+                let name = CString::new(run_as.user.as_str())?;
+                // Only looked up while the server starts, before other threads could be too
+                let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+                if entry.is_null() {
+                    return Err(anyhow!(format!("No user named '{}'", run_as.user)));
+                }
+                unsafe { ((*entry).pw_uid, (*entry).pw_gid) }
+            }
+        };
+        let gid = match &run_as.group {
+            None => user_gid,
+            Some(group) => match group.parse::<u32>() {
+                Ok(gid) => gid,
+                Err(_) => {
+                    let name = CString::new(group.as_str())?;
+                    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+                    if entry.is_null() {
+                        return Err(anyhow!(format!("No group named '{}'", group)));
+                    }
+                    unsafe { (*entry).gr_gid }
+                }
+            },
+        };
+        Ok((uid, gid))
+    }
+
+    pub fn drop_privileges(run_as: &RunAs, chroot: Option<&Path>) -> Result<()> {
+        if unsafe { libc::geteuid() } != 0 {
+            return Err(anyhow!("Switching users needs the server to be started as root"));
+        }
+        // Resolved before the chroot, which hides the user database
+        let (uid, gid) = resolve(run_as)?;
+
+        if let Some(root) = chroot {
+            let root = CString::new(root.as_os_str().as_bytes())?;
+            check("chdir", unsafe { libc::chdir(root.as_ptr()) })?;
+            check("chroot", unsafe { libc::chroot(root.as_ptr()) })?;
+            check("chdir", unsafe { libc::chdir(c"/".as_ptr()) })?;
+        }
+        // Supplementary groups first, as they can't be changed once root is given up
+        check("setgroups", unsafe { libc::setgroups(1, &gid) })?;
+        check("setgid", unsafe { libc::setgid(gid) })?;
+        check("setuid", unsafe { libc::setuid(uid) })?;
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(anyhow!("Root could be regained after switching users"));
+        }
+        Ok(())
+    }
+
+    fn check(call: &str, result: libc::c_int) -> Result<()> {
+        match result {
+            0 => Ok(()),
+            _ => Err(anyhow!(format!("{} failed: {}", call, io::Error::last_os_error()))),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::path::Path;
+
+    use anyhow::{anyhow, Result};
+
+    use super::RunAs;
+
+    pub fn resolve(_run_as: &RunAs) -> Result<(u32, u32)> {
+        Err(anyhow!("Switching users is only supported on Unix"))
+    }
+
+    pub fn drop_privileges(_run_as: &RunAs, _chroot: Option<&Path>) -> Result<()> {
+        Err(anyhow!("Switching users is only supported on Unix"))
+    }
+}
@@ -0,0 +1,158 @@
+//! SOCKS5 client (RFC 1928, with RFC 1929 username/password authentication).
+//!
+//! Host names are passed to the proxy unresolved, so the proxy does the lookup. This is what
+//! makes `.onion` addresses work through Tor.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream};
+
+use anyhow::{anyhow, Result};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE: u8 = 0xFF;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// A parsed `[user:password@]host:port` proxy specification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub host: String,
+    pub port: u16,
+    pub credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (credentials, address) = match spec.rsplit_once('@') {
+            Some((credentials, address)) => {
+                let (user, password) = credentials
+                    .split_once(':')
+                    .ok_or(anyhow!("Expected user:password before '@'"))?;
+                if user.len() > 255 || password.len() > 255 {
+                    return Err(anyhow!("Proxy credentials are too long"));
+                }
+                (Some((user.to_string(), password.to_string())), address)
+            }
+            None => (None, spec),
+        };
+        let (host, port) = address
+            .rsplit_once(':')
+            .ok_or(anyhow!("Expected [user:password@]host:port"))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| anyhow!(format!("Invalid proxy port: {}", port)))?;
+        if host.is_empty() {
+            return Err(anyhow!("Expected [user:password@]host:port"));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            credentials,
+        })
+    }
+
+    /// Opens a TCP connection to `host:port` through the proxy.
+    pub fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        self.negotiate(&mut stream)?;
+        request_connect(&mut stream, host, port)?;
+        Ok(stream)
+    }
+
+    fn negotiate(&self, stream: &mut TcpStream) -> Result<()> {
+        let offered = match self.credentials {
+            Some(_) => vec![VERSION, 2, NO_AUTH, USER_PASS],
+            None => vec![VERSION, 1, NO_AUTH],
+        };
+        stream.write_all(&offered)?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(anyhow!("Proxy does not speak SOCKS5"));
+        }
+
+        match (reply[1], &self.credentials) {
+            (NO_AUTH, _) => Ok(()),
+            (USER_PASS, Some((user, password))) => {
+                let mut request = vec![1, user.len() as u8];
+                request.extend_from_slice(user.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request)?;
+
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status)?;
+                match status[1] {
+                    0 => Ok(()),
+                    _ => Err(anyhow!("Proxy rejected the credentials")),
+                }
+            }
+            (NO_ACCEPTABLE, _) => Err(anyhow!("Proxy accepted none of the offered authentication methods")),
+            (method, _) => Err(anyhow!(format!("Proxy chose unsupported authentication method {}", method))),
+        }
+    }
+}
+
+fn request_connect(stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+    let mut request = vec![VERSION, CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(anyhow!("Host name is too long for SOCKS5"));
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(anyhow!(format!("Proxy could not connect: {}", reply_message(reply[1]))));
+    }
+
+    // The bound address isn't needed, but has to be consumed before the stream is ours
+    let address_length = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut length = [0u8; 1];
+            stream.read_exact(&mut length)?;
+            length[0] as usize
+        }
+        atyp => return Err(anyhow!(format!("Proxy replied with unknown address type {}", atyp))),
+    };
+    let mut bound = vec![0u8; address_length + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
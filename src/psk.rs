@@ -0,0 +1,377 @@
+//! Encryption of connections with a secret shared by the client and server profiles, for networks
+//! where setting up TLS is more than it is worth.
+//!
+//! Right after connecting, both sides send [`MAGIC`] and a random salt. The keys of the session,
+//! one per direction, are derived from the shared secret and both salts with PBKDF2-HMAC-SHA256,
+//! so every session has its own, and everything afterwards travels in records sealed with
+//! ChaCha20-Poly1305: a big-endian [`u32`] length and the ciphertext, whose nonce is how many
+//! records were sent before in that direction. Frames and file payloads are both carried this
+//! way, so the protocol above is unchanged. Each side seals [`crate::secrets::CHECK_TEXT`] into
+//! the first record, so a secret that doesn't match the peer's is told apart from a broken
+//! connection.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use sha2::Sha256;
+
+use crate::secrets::CHECK_TEXT;
+use crate::transport::Transport;
+
+/// What a connection encrypted with a shared secret starts with, before the salt.
+pub const MAGIC: &[u8; 8] = b"OXIDPSK1";
+
+const SALT_LENGTH: usize = 16;
+const KDF_ROUNDS: u32 = 10_000;
+const TAG_LENGTH: usize = 16;
+/// The most plaintext one record carries.
+const MAX_RECORD: usize = 1 << 16;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which end of the connection this is, which decides what key seals which direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Seals what is written, one record per write.
+struct Sealer {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+/// Opens what is read.
+struct Opener {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    /// Bytes read from the transport that don't make up a whole record yet. Kept so a record cut
+    /// short by a non-blocking read or a timeout is picked up again on the next one.
+    raw: Vec<u8>,
+    /// Opened bytes that didn't fit in the buffer they were read for.
+    plaintext: Vec<u8>,
+    offset: usize,
+}
+
+/// A [`Transport`] whose bytes are encrypted with a shared secret. Clones share the keys and
+/// counters, so the reader and the writer of a [`crate::connection::Connection`] stay in step.
+pub struct PskTransport {
+    inner: Box<dyn Transport>,
+    sealer: Arc<Mutex<Sealer>>,
+    opener: Arc<Mutex<Opener>>,
+}
+
+impl PskTransport {
+    /// Sets up encryption with `secret` over `inner`, a freshly opened connection, failing when the
+    /// peer doesn't use the same secret.
+    pub fn new(mut inner: Box<dyn Transport>, secret: &str, role: Role) -> Result<Self> {
+        let mut salt = [0u8; SALT_LENGTH];
+        getrandom::getrandom(&mut salt).map_err(|e| anyhow!(format!("Could not generate a salt: {}", e)))?;
+        let mut hello = MAGIC.to_vec();
+        hello.extend_from_slice(&salt);
+        inner.write_all(&hello)?;
+        inner.flush()?;
+
+        inner.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut peer_hello = [0u8; MAGIC.len() + SALT_LENGTH];
+        inner
+            .read_exact(&mut peer_hello)
+            .map_err(|e| anyhow!(format!("The peer did not start encryption with a shared secret: {}", e)))?;
+        if !peer_hello.starts_with(MAGIC) {
+            return Err(anyhow!("The peer does not use a shared secret"));
+        }
+        let peer_salt = &peer_hello[MAGIC.len()..];
+
+        let (client_salt, server_salt) = match role {
+            Role::Client => (&salt[..], peer_salt),
+            Role::Server => (peer_salt, &salt[..]),
+        };
+        let mut kdf_salt = client_salt.to_vec();
+        kdf_salt.extend_from_slice(server_salt);
+        let mut keys = [0u8; 64];
+        pbkdf2::pbkdf2_hmac::<Sha256>(secret.as_bytes(), &kdf_salt, KDF_ROUNDS, &mut keys);
+        let (client_key, server_key) = keys.split_at(32);
+        let (seal_key, open_key) = match role {
+            Role::Client => (client_key, server_key),
+            Role::Server => (server_key, client_key),
+        };
+
+        let mut transport = Self {
+            inner,
+            sealer: Arc::new(Mutex::new(Sealer {
+                cipher: ChaCha20Poly1305::new(seal_key.into()),
+                counter: 0,
+            })),
+            opener: Arc::new(Mutex::new(Opener {
+                cipher: ChaCha20Poly1305::new(open_key.into()),
+                counter: 0,
+                raw: vec![],
+                plaintext: vec![],
+                offset: 0,
+            })),
+        };
+        transport.write_all(CHECK_TEXT.as_bytes())?;
+        transport.flush()?;
+        let mut check = [0u8; CHECK_TEXT.len()];
+        match transport.read_exact(&mut check) {
+            Ok(()) if check == CHECK_TEXT.as_bytes() => (),
+            Ok(()) => return Err(anyhow!("The shared secret does not match the peer's")),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return Err(anyhow!("The shared secret does not match the peer's"))
+            }
+            Err(e) => return Err(e.into()),
+        }
+        transport.inner.set_read_timeout(None)?;
+        Ok(transport)
+    }
+
+    /// Reads more bytes from the transport into `raw`, returning how many arrived.
+    fn fill_raw(&mut self, opener: &mut Opener) -> io::Result<usize> {
+        let mut buffer = [0u8; 8192];
+        let n = self.inner.read(&mut buffer)?;
+        opener.raw.extend_from_slice(&buffer[..n]);
+        Ok(n)
+    }
+}
+
+impl Opener {
+    /// Opens the record at the start of `raw`, if a whole one is there.
+    fn open_record(&mut self) -> io::Result<bool> {
+        if self.raw.len() < 4 {
+            return Ok(false);
+        }
+        let length = u32::from_be_bytes(self.raw[..4].try_into().unwrap()) as usize;
+        if !(TAG_LENGTH..=MAX_RECORD + TAG_LENGTH).contains(&length) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid encrypted record length"));
+        }
+        if self.raw.len() < 4 + length {
+            return Ok(false);
+        }
+        let plaintext = self
+            .cipher
+            .decrypt(&nonce(self.counter), &self.raw[4..4 + length])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Could not decrypt a record"))?;
+        self.raw.drain(..4 + length);
+        self.counter += 1;
+        self.plaintext = plaintext;
+        self.offset = 0;
+        Ok(true)
+    }
+}
+
+impl Read for PskTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let opener = self.opener.clone();
+        let mut opener = opener.lock().unwrap();
+        loop {
+            if opener.offset < opener.plaintext.len() {
+                let n = buf.len().min(opener.plaintext.len() - opener.offset);
+                buf[..n].copy_from_slice(&opener.plaintext[opener.offset..opener.offset + n]);
+                opener.offset += n;
+                return Ok(n);
+            }
+            if opener.open_record()? {
+                continue;
+            }
+            if self.fill_raw(&mut opener)? == 0 {
+                return match opener.raw.is_empty() {
+                    true => Ok(0),
+                    false => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Encrypted record cut short")),
+                };
+            }
+        }
+    }
+}
+
+impl Write for PskTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(MAX_RECORD);
+        let mut sealer = self.sealer.lock().unwrap();
+        let ciphertext = sealer
+            .cipher
+            .encrypt(&nonce(sealer.counter), &buf[..n])
+            .map_err(|_| io::Error::other("Could not encrypt a record"))?;
+        sealer.counter += 1;
+        let mut record = Vec::with_capacity(4 + ciphertext.len());
+        record.extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+        record.extend_from_slice(&ciphertext);
+        // Written while holding the sealer, so records of clones can't interleave
+        self.inner.write_all(&record)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for PskTransport {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            inner: self.inner.try_clone()?,
+            sealer: self.sealer.clone(),
+            opener: self.opener.clone(),
+        }))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+/// The nonce of the record sent after `counter` others.
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// A client and a server encrypting with their own secret, set up over a connection relayed by
+    /// `relay`, which gets the stream from the client and the one to the server.
+    fn pair<F>(client_secret: &str, server_secret: &str, relay: F) -> (Result<PskTransport>, Result<PskTransport>)
+    where
+        F: FnOnce(TcpStream, TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (address, relay_address) = (listener.local_addr().unwrap(), relay_listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let from_client = relay_listener.accept().unwrap().0;
+            relay(from_client, TcpStream::connect(address).unwrap());
+        });
+        let server_secret = server_secret.to_string();
+        let server = thread::spawn(move || {
+            let stream = listener.accept().unwrap().0;
+            PskTransport::new(Box::new(stream), &server_secret, Role::Server)
+        });
+        let client = PskTransport::new(Box::new(TcpStream::connect(relay_address).unwrap()), client_secret, Role::Client);
+        (client, server.join().unwrap())
+    }
+
+    /// Copies everything from `from` to `to`, flipping a bit of the byte at `tampered` if set.
+    fn forward(mut from: TcpStream, mut to: TcpStream, tampered: Option<usize>) {
+        let mut position = 0;
+        let mut buffer = [0u8; 4096];
+        loop {
+            let n = match from.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if let Some(index) = tampered.and_then(|tampered| tampered.checked_sub(position)).filter(|index| *index < n) {
+                buffer[index] ^= 1;
+            }
+            position += n;
+            if to.write_all(&buffer[..n]).is_err() {
+                break;
+            }
+        }
+        let _ = to.shutdown(Shutdown::Write);
+    }
+
+    /// Relays both ways, tampering with what the client sends at `tampered`.
+    fn relay(tampered: Option<usize>) -> impl FnOnce(TcpStream, TcpStream) + Send + 'static {
+        move |client, server| {
+            let (client_out, server_out) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+            thread::spawn(move || forward(server_out, client_out, None));
+            forward(client, server, tampered);
+        }
+    }
+
+    #[test]
+    fn bytes_arrive_as_they_were_sent() {
+        let (client, server) = pair("secret", "secret", relay(None));
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        // Several records long
+        let sent: Vec<u8> = (0..3 * MAX_RECORD + 5).map(|i| (i % 251) as u8).collect();
+        client.write_all(&sent).unwrap();
+        client.flush().unwrap();
+        let mut received = vec![0u8; sent.len()];
+        server.read_exact(&mut received).unwrap();
+        server.write_all(b"back").unwrap();
+        let mut back = [0u8; 4];
+        client.read_exact(&mut back).unwrap();
+
+        assert!(received == sent);
+        assert_eq!(&back, b"back");
+    }
+
+    #[test]
+    fn different_secrets_are_refused() {
+        let (client, server) = pair("secret", "other secret", relay(None));
+
+        assert_eq!(client.err().unwrap().to_string(), "The shared secret does not match the peer's");
+        assert_eq!(server.err().unwrap().to_string(), "The shared secret does not match the peer's");
+    }
+
+    #[test]
+    fn tampered_records_are_refused() {
+        // The first byte of ciphertext after the client's hello and check record
+        let check_record = 4 + CHECK_TEXT.len() + TAG_LENGTH;
+        let tampered = MAGIC.len() + SALT_LENGTH + check_record + 4;
+        let (client, server) = pair("secret", "secret", relay(Some(tampered)));
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        client.write_all(b"payload").unwrap();
+        client.flush().unwrap();
+        let error = server.read(&mut [0u8; 16]).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Could not decrypt a record");
+    }
+
+    #[test]
+    fn clones_share_counters() {
+        let (client, server) = pair("secret", "secret", relay(None));
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        let mut client_clone = client.try_clone().unwrap();
+        let mut server_clone = server.try_clone().unwrap();
+        // Each write is a record of its own, sealed with the next nonce whichever clone makes it
+        for i in 0..4u8 {
+            let writer = match i % 2 {
+                0 => &mut client as &mut dyn Write,
+                _ => &mut client_clone,
+            };
+            writer.write_all(&[i; 3]).unwrap();
+            writer.flush().unwrap();
+        }
+        let mut received = [0u8; 12];
+        server.read_exact(&mut received[..5]).unwrap();
+        server_clone.read_exact(&mut received[5..]).unwrap();
+        server_clone.write_all(b"one").unwrap();
+        server.write_all(b"two").unwrap();
+        let mut back = [0u8; 6];
+        client_clone.read_exact(&mut back).unwrap();
+
+        assert_eq!(received, [0, 0, 0, 1, 1, 1, 2, 2, 2, 3, 3, 3]);
+        assert_eq!(&back, b"onetwo");
+    }
+}
@@ -0,0 +1,146 @@
+//! The order syncs download files in: those pinned by the profile's priority patterns first, in
+//! the order of the patterns, then the rest as its [`DownloadOrder`] says. Files that compare equal
+//! stay in the order the server listed them.
+//!
+//! What a sync is about to download is also kept as a [`PendingQueue`], in an SQLite database at
+//! `oxideux/queue.sqlite3` under the config directory, so that a sync cut short by a crash or a
+//! reboot picks up where it left off. Files being downloaded are assembled in `.part` files next
+//! to where they go, which keep what was received for the download to be resumed.
+
+use std::cmp::{Ordering, Reverse};
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::{params, Connection};
+
+use crate::config::{self, DownloadOrder};
+use crate::ignore::IgnoreRules;
+use crate::parity::EntrySummary;
+
+/// How a sync orders what it is about to download.
+#[derive(Debug, Clone, Default)]
+pub struct QueueOrder {
+    order: DownloadOrder,
+    /// The rules of one priority pattern each, in the order of the patterns.
+    pinned: Vec<IgnoreRules>,
+}
+
+impl QueueOrder {
+    /// `priority` holds patterns as exclusions are written (see: [`crate::ignore`]).
+    pub fn new<S: AsRef<str>>(order: DownloadOrder, priority: &[S]) -> Result<Self> {
+        let pinned = priority
+            .iter()
+            .map(|pattern| IgnoreRules::parse(&[pattern]))
+            .collect::<Result<_>>()?;
+        Ok(Self { order, pinned })
+    }
+
+    /// Which of `a` and `b` is downloaded first, for sorting what a sync is about to download with
+    /// a stable sort.
+    pub fn compare(&self, a: &EntrySummary, b: &EntrySummary) -> Ordering {
+        let pinned = self.pin(&a.name).cmp(&self.pin(&b.name));
+        pinned.then_with(|| match self.order {
+            DownloadOrder::Listed => Ordering::Equal,
+            DownloadOrder::SmallestFirst => a.length.cmp(&b.length),
+            // Files without a modification time go last
+            DownloadOrder::NewestFirst => Reverse(a.modified).cmp(&Reverse(b.modified)),
+        })
+    }
+
+    /// The index of the first priority pattern `name` matches, past every pattern when it matches
+    /// none.
+    fn pin(&self, name: &str) -> usize {
+        self.pinned
+            .iter()
+            .position(|rules| rules.matches(name))
+            .unwrap_or(self.pinned.len())
+    }
+}
+
+const DATABASE_FILE: &str = "oxideux/queue.sqlite3";
+
+/// The files a sync of a profile's parity root still has to download, in the order it downloads
+/// them in.
+pub struct PendingQueue {
+    db: Connection,
+    profile: String,
+    root: String,
+}
+
+impl PendingQueue {
+    /// The queue of the parity root `root` of the profile `profile`.
+    pub fn open<P: AsRef<Path>>(profile: &str, root: P) -> Result<Self> {
+        let path = config::config_dir_ext(DATABASE_FILE)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let db = Connection::open(path)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS pending (
+                 profile TEXT NOT NULL,
+                 root TEXT NOT NULL,
+                 position INTEGER NOT NULL,
+                 name TEXT NOT NULL,
+                 length INTEGER NOT NULL,
+                 modified INTEGER,
+                 PRIMARY KEY (profile, root, name)
+             );",
+        )?;
+        Ok(Self {
+            db,
+            profile: profile.to_string(),
+            root: root.as_ref().canonicalize()?.to_string_lossy().to_string(),
+        })
+    }
+
+    /// The files left to download, as the server listed them when they were queued.
+    pub fn pending(&self) -> Result<Vec<EntrySummary>> {
+        let mut statement = self.db.prepare(
+            "SELECT name, length, modified FROM pending
+             WHERE profile = ?1 AND root = ?2 ORDER BY position",
+        )?;
+        let rows = statement.query_map(params![self.profile, self.root], |row| {
+            Ok(EntrySummary {
+                name: row.get(0)?,
+                length: row.get::<_, i64>(1)? as u64,
+                modified: row.get::<_, Option<i64>>(2)?.map(|secs| secs as u64),
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Replaces the queue with `files`, in that order.
+    pub fn replace<'a, I: IntoIterator<Item = &'a EntrySummary>>(&mut self, files: I) -> Result<()> {
+        let transaction = self.db.transaction()?;
+        transaction.execute(
+            "DELETE FROM pending WHERE profile = ?1 AND root = ?2",
+            params![self.profile, self.root],
+        )?;
+        for (position, summary) in files.into_iter().enumerate() {
+            transaction.execute(
+                "INSERT OR REPLACE INTO pending (profile, root, position, name, length, modified)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    self.profile,
+                    self.root,
+                    position as i64,
+                    summary.name,
+                    summary.length as i64,
+                    summary.modified.map(|secs| secs as i64),
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Takes `name` off the queue, once it was downloaded or skipped.
+    pub fn remove(&self, name: &str) -> Result<()> {
+        self.db.execute(
+            "DELETE FROM pending WHERE profile = ?1 AND root = ?2 AND name = ?3",
+            params![self.profile, self.root, name],
+        )?;
+        Ok(())
+    }
+}
@@ -1,29 +1,270 @@
-use anyhow::{anyhow, Result};
+use std::fmt::Display;
+
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The most [`Request::ReadRange`] returns at once.
+pub const MAX_RANGE_LENGTH: u32 = 1 << 20;
+/// The most bytes one frame of the protocol may hold, such as a request, a listing or a manifest.
+/// Longer ones are refused before anything is allocated for them, as their length comes from the
+/// peer.
+pub const MAX_FRAME_LENGTH: u32 = 64 << 20;
+/// The most chunks one [`Request::ReadChunks`] may ask for.
+pub const MAX_CHUNKS_PER_REQUEST: usize = 64;
+/// The most files one [`Request::Search`] returns.
+pub const MAX_SEARCH_RESULTS: u32 = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     Disconnect,
+    /// Keep-alive probe, answered with [`RequestResult::Pong`].
+    Ping,
+    /// Asks the server to stop working on the request with the given id. Cancelling a request that
+    /// already finished is a no-op; no result is sent back either way.
+    Cancel(u32),
     GetFileCount,
+    /// Downloads the file at an index of [`Request::ListFiles`], which lists files in the order
+    /// the server is configured with (by name by default).
     DownloadFileByIndex(u64),
     DownloadFileByName(String),
     DownloadAllFiles,
-    // UploadFile(u64),
+    /// Asks for the [`crate::parity::FileInfo`] of a file, sent after [`RequestResult::Ok`].
+    GetFileInfo(String),
+    /// Downloads every file matching a glob pattern relative to the parity root, e.g. `*.iso` or
+    /// `reports/2024-*`. Streamed like [`Request::DownloadAllFiles`].
+    DownloadMatching(String),
+    /// Lists the [`crate::parity::EntrySummary`] of every file, sent after [`RequestResult::Ok`].
+    ListFiles,
+    /// Turns the connection into a stream of [`crate::watch::Notification`]s about the parity root.
+    /// After [`RequestResult::Ok`] the server only pushes notifications until the client hangs up.
+    Subscribe,
+    /// Stores a file under the given name, relative to the parity root. Once the server answers
+    /// [`RequestResult::Ok`] the client streams the file, and the server answers again once it
+    /// has been written. Refused by servers with upload quotas, which need the length up front
+    /// (see: [`Request::UploadSizedFile`]).
+    UploadFile(String),
+    /// Logs in as one of the server's users, after which every request is scoped to that user's
+    /// parity root and permissions. Servers with users refuse most requests until this succeeds.
+    Authenticate { user: String, password: String },
+    /// Downloads whatever a share token (see: [`crate::share`]) was minted for, without logging in.
+    /// Streamed like [`Request::DownloadAllFiles`].
+    RedeemToken(String),
+    /// Reads up to `length` bytes of a file starting at `offset`, sent as a byte vector after
+    /// [`RequestResult::Ok`]. Fewer bytes come back at the end of the file, and lengths above
+    /// [`MAX_RANGE_LENGTH`] are clamped to it.
+    ReadRange { name: String, offset: u64, length: u32 },
+    /// A request added by an extension of the server (see: [`crate::interceptor`]), identified by
+    /// `name`. Servers without an extension answering it refuse it as [`ErrorCode::NotFound`].
+    Custom { name: String, payload: Vec<u8> },
+    /// Asks for the [`crate::chunks::Chunk`]s a file cuts into, sent as a vector after
+    /// [`RequestResult::Ok`].
+    GetChunks(String),
+    /// Asks for chunks of a file by hash, as listed by [`Request::GetChunks`], each sent as a byte
+    /// vector after [`RequestResult::Ok`] in the order asked. At most [`MAX_CHUNKS_PER_REQUEST`].
+    ReadChunks { name: String, hashes: Vec<String> },
+    /// Downloads a file as the difference to the client's copy, whose [`crate::delta::Signature`]
+    /// is sent along. Answered with [`RequestResult::Ok`], the file's length as a [`u64`], and a
+    /// stream of [`crate::delta::DeltaOp`]s ending with [`crate::delta::DeltaOp::End`].
+    DownloadDelta { name: String, signature: crate::delta::Signature },
+    /// Asks for the [`crate::parity::Manifest`] of the parity root, sent after
+    /// [`RequestResult::Ok`].
+    GetManifest,
+    /// Lists the [`crate::parity::EntrySummary`] of the files matching a
+    /// [`crate::parity::EntryQuery`], sent like [`Request::ListFiles`].
+    QueryFiles(crate::parity::EntryQuery),
+    /// Asks for the [`crate::parity::RootStats`] of the parity root, sent after
+    /// [`RequestResult::Ok`].
+    GetRootStats,
+    /// Like [`Request::UploadFile`], announcing the file's length in bytes so that an upload over
+    /// the server's quotas is refused with [`ErrorCode::QuotaExceeded`] before it is sent.
+    UploadSizedFile { name: String, length: u64 },
+    /// Asks how many bytes of an interrupted upload of `name` the server kept, sent as a [`u64`]
+    /// after [`RequestResult::Ok`]. Zero when there is nothing to resume.
+    GetUploadOffset(String),
+    /// Like [`Request::UploadSizedFile`] for a file of `length` bytes, keeping the first `offset`
+    /// bytes of an interrupted upload (see: [`Request::GetUploadOffset`]) so only the rest is
+    /// streamed.
+    ResumeUpload { name: String, offset: u64, length: u64 },
+    /// Lists the [`crate::versions::Version`]s uploads replaced of a file, newest first, sent
+    /// after [`RequestResult::Ok`]. Empty when there are none.
+    ListVersions(String),
+    /// Downloads a previous version of a file, as listed by [`Request::ListVersions`]. Sent like
+    /// [`Request::DownloadFileByName`].
+    DownloadVersion { name: String, version: String },
+    /// Lists the [`crate::parity::EntrySummary`] of the first `limit` files matching a
+    /// [`crate::parity::EntryQuery`], in listing order, sent like [`Request::ListFiles`]. Unlike
+    /// [`Request::QueryFiles`] the server stops at the limit, and only looks past its cached
+    /// listing for files whose name matches. Zero, or limits above [`MAX_SEARCH_RESULTS`], are
+    /// clamped to it.
+    Search { query: crate::parity::EntryQuery, limit: u32 },
+    /// Downloads a file by the SHA-256 of its contents, as listed in the
+    /// [`crate::parity::Manifest`], whatever the file is called by now. Sent like
+    /// [`Request::DownloadFileByIndex`], with the name of a file holding those contents.
+    DownloadByHash(String),
+    /// Downloads a file like [`Request::DownloadFileByName`] unless the client's copy, of `length`
+    /// bytes, is the same: when it was last modified at the same `modified` time or its contents
+    /// hash to `hash` (see: [`crate::parity::FileInfo`]). The server answers
+    /// [`RequestResult::NotModified`] without sending anything then.
+    DownloadIfChanged {
+        name: String,
+        length: u64,
+        modified: Option<u64>,
+        hash: Option<String>,
+    },
+    /// Like [`Request::DownloadFileByName`], only sending what comes after the first `offset`
+    /// bytes, which the client kept from an interrupted download. Refused with [`ErrorCode::NotFound`]
+    /// unless the file is still `length` bytes long and was last modified at `modified`, so both
+    /// parts are of the same version.
+    ResumeDownload {
+        name: String,
+        offset: u64,
+        length: u64,
+        modified: Option<u64>,
+    },
+    /// Like [`Request::GetManifest`], followed by the manifest's detached signature (see:
+    /// [`crate::signing`]) as a byte vector.
+    GetSignedManifest,
+}
+
+impl Request {
+    /// The variant's name, to log what a request was without its arguments (which may be secrets,
+    /// such as passwords and share tokens, or large, such as delta signatures).
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Disconnect => "Disconnect",
+            Request::Ping => "Ping",
+            Request::Cancel(_) => "Cancel",
+            Request::GetFileCount => "GetFileCount",
+            Request::DownloadFileByIndex(_) => "DownloadFileByIndex",
+            Request::DownloadFileByName(_) => "DownloadFileByName",
+            Request::DownloadAllFiles => "DownloadAllFiles",
+            Request::GetFileInfo(_) => "GetFileInfo",
+            Request::DownloadMatching(_) => "DownloadMatching",
+            Request::ListFiles => "ListFiles",
+            Request::Subscribe => "Subscribe",
+            Request::UploadFile(_) => "UploadFile",
+            Request::Authenticate { .. } => "Authenticate",
+            Request::RedeemToken(_) => "RedeemToken",
+            Request::ReadRange { .. } => "ReadRange",
+            Request::Custom { .. } => "Custom",
+            Request::GetChunks(_) => "GetChunks",
+            Request::ReadChunks { .. } => "ReadChunks",
+            Request::DownloadDelta { .. } => "DownloadDelta",
+            Request::GetManifest => "GetManifest",
+            Request::QueryFiles(_) => "QueryFiles",
+            Request::GetRootStats => "GetRootStats",
+            Request::UploadSizedFile { .. } => "UploadSizedFile",
+            Request::GetUploadOffset(_) => "GetUploadOffset",
+            Request::ResumeUpload { .. } => "ResumeUpload",
+            Request::ListVersions(_) => "ListVersions",
+            Request::DownloadVersion { .. } => "DownloadVersion",
+            Request::Search { .. } => "Search",
+            Request::DownloadByHash(_) => "DownloadByHash",
+            Request::DownloadIfChanged { .. } => "DownloadIfChanged",
+            Request::ResumeDownload { .. } => "ResumeDownload",
+            Request::GetSignedManifest => "GetSignedManifest",
+        }
+    }
+}
+
+/// A [`Request`] as it travels over the wire, tagged with an id unique to its connection so that it
+/// can be referenced later (see: [`Request::Cancel`]).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TaggedRequest {
+    pub id: u32,
+    pub request: Request,
+}
+
+/// The reason a [`Request`] was refused.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    UnauthorizedAccess,
+    IndexOutOfBounds,
+    NotFound,
+    Internal,
+    /// The client sent more requests than the server allows per minute.
+    RateLimited,
+    /// The server has user accounts and the client hasn't logged in, or failed to.
+    Unauthenticated,
+    /// The upload is larger than the server accepts, or would take the upload root over its quota.
+    QuotaExceeded,
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            ErrorCode::UnauthorizedAccess => "Unauthorized access",
+            ErrorCode::IndexOutOfBounds => "Index out of bounds",
+            ErrorCode::NotFound => "Not found",
+            ErrorCode::Internal => "Internal server error",
+            ErrorCode::RateLimited => "Too many requests",
+            ErrorCode::Unauthenticated => "Not logged in",
+            ErrorCode::QuotaExceeded => "Quota exceeded",
+        };
+        write!(f, "{}", text)
+    }
 }
 
+/// The error payload of [`RequestResult::Err`].
+///
+/// `message` is meant for humans and `path`, when present, names the file (relative to the parity
+/// root) the request was about.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub path: Option<String>,
+}
+
+impl RequestError {
+    pub fn new<S: ToString>(code: ErrorCode, message: S) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            path: None,
+        }
+    }
+
+    pub fn with_path<S: ToString>(mut self, path: S) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)?;
+        if let Some(path) = &self.path {
+            write!(f, " ('{}')", path)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RequestError {}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RequestResult {
     Ok,
-    ErrUnauthorizedAccess,
-    ErrIndexOutOfBounds,
+    Pong,
+    /// The answer to [`Request::DownloadIfChanged`] when the client's copy is up to date.
+    NotModified,
+    Err(RequestError),
 }
 
 impl RequestResult {
+    /// Converts the result into an [`anyhow::Result`]. The error, if any, can be recovered with
+    /// `error.downcast_ref::<RequestError>()`.
     pub fn naturalize(&self) -> Result<()> {
         match self {
-            RequestResult::Ok => Ok(()),
-            RequestResult::ErrUnauthorizedAccess => Err(anyhow!("Unauthorized access")),
-            RequestResult::ErrIndexOutOfBounds => Err(anyhow!("Index out of bounds")),
+            RequestResult::Ok | RequestResult::Pong | RequestResult::NotModified => Ok(()),
+            RequestResult::Err(e) => Err(e.clone().into()),
         }
     }
 }
+
+impl From<RequestError> for RequestResult {
+    fn from(error: RequestError) -> Self {
+        RequestResult::Err(error)
+    }
+}
@@ -0,0 +1,336 @@
+//! Minimal S3 client, enough to serve a parity root out of an S3-compatible bucket (AWS S3, MinIO,
+//! Garage, ...): listing objects and reading them, signed with AWS Signature Version 4.
+//!
+//! Requests are plain HTTP with path-style addressing (`http://host:port/bucket/key`), which every
+//! S3-compatible store accepts, sent with [`crate::http_client`]; listings and errors are read
+//! with [`crate::xml`]. No TLS implementation is among our dependencies, so HTTPS is not spoken:
+//! put a TLS-terminating proxy in front of stores that require it, such as AWS S3 itself. The
+//! secret key never leaves this machine, as requests are only signed with it, but object
+//! contents and listings travel in the clear to the proxy.
+
+use std::fmt::Display;
+use std::io::Read;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::http_client::{self, Response, Url};
+use crate::ignore::IgnoreRules;
+use crate::parity::{self, Entry, Storage};
+use crate::request::{ErrorCode, RequestError};
+use crate::xml::Element;
+
+const SCHEME: &str = "s3://";
+const HTTP_PORT: u16 = 80;
+/// The region MinIO and most other S3-compatible stores use unless configured otherwise.
+const DEFAULT_REGION: &str = "us-east-1";
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// SHA-256 of an empty payload, which every request of this client has.
+const EMPTY_PAYLOAD_HASH: &str = "e3b0c44298fc1c149afbfc8c996fb92427ae41e4649b934ca495991b7852b855";
+
+/// A parsed `s3://access_key:secret_key@host[:port]/bucket[/prefix][?region=name]` specification:
+/// the objects of `bucket` whose keys start with `prefix/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bucket {
+    pub host: String,
+    pub port: u16,
+    pub bucket: String,
+    /// Key prefix without a trailing `/`, empty for the whole bucket.
+    pub prefix: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Objects that are never listed or served.
+    pub ignore: IgnoreRules,
+}
+
+impl Bucket {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let usage = || anyhow!("Expected s3://access_key:secret_key@host[:port]/bucket[/prefix][?region=name]");
+        let rest = spec.strip_prefix(SCHEME).ok_or_else(usage)?;
+        let (rest, region) = match rest.split_once('?') {
+            Some((rest, query)) => match query.strip_prefix("region=") {
+                Some(region) if !region.is_empty() => (rest, region),
+                _ => return Err(anyhow!(format!("Unknown S3 option: {}", query))),
+            },
+            None => (rest, DEFAULT_REGION),
+        };
+        let (credentials, rest) = rest.rsplit_once('@').ok_or_else(usage)?;
+        let (access_key, secret_key) = credentials.split_once(':').ok_or_else(usage)?;
+        let (address, path) = rest.split_once('/').ok_or_else(usage)?;
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>().map_err(|_| anyhow!(format!("Invalid S3 port: {}", port)))?),
+            None => (address, HTTP_PORT),
+        };
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        let prefix = prefix.trim_end_matches('/');
+        if access_key.is_empty() || secret_key.is_empty() || host.is_empty() || bucket.is_empty() {
+            return Err(usage());
+        }
+        if !prefix.is_empty() {
+            parity::safe_join("", prefix)?;
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            region: region.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+            ignore: IgnoreRules::default(),
+        })
+    }
+
+    /// Leaves out the objects `ignore` excludes. Buckets have no ignore file of their own, so only
+    /// the patterns given here apply.
+    pub fn ignoring(self, ignore: IgnoreRules) -> Self {
+        Self { ignore, ..self }
+    }
+
+    /// The specification this was parsed from, credentials included.
+    pub fn spec(&self) -> String {
+        let region = match self.region == DEFAULT_REGION {
+            true => String::new(),
+            false => format!("?region={}", self.region),
+        };
+        format!(
+            "{}{}:{}@{}:{}/{}{}",
+            SCHEME,
+            self.access_key,
+            self.secret_key,
+            self.host,
+            self.port,
+            self.location(),
+            region
+        )
+    }
+
+    /// The part of the bucket below `prefix`, relative to this one's prefix.
+    pub fn scoped(&self, prefix: &str) -> Result<Self> {
+        parity::safe_join("", prefix)?;
+        Ok(Self {
+            prefix: format!("{}{}", self.key_prefix(), prefix.trim_end_matches('/')),
+            ..self.clone()
+        })
+    }
+
+    /// The bucket, followed by the prefix if there is one.
+    fn location(&self) -> String {
+        match self.prefix.is_empty() {
+            true => self.bucket.clone(),
+            false => format!("{}/{}", self.bucket, self.prefix),
+        }
+    }
+
+    /// The prefix object keys of this bucket start with, with a trailing `/` unless empty.
+    fn key_prefix(&self) -> String {
+        match self.prefix.is_empty() {
+            true => String::new(),
+            false => format!("{}/", self.prefix),
+        }
+    }
+
+    /// Lists the objects whose keys start with `key_prefix` as entries named relative to this
+    /// bucket's prefix. With `recursive` unset, keys with a `/` after `key_prefix` are left out, like
+    /// the contents of subdirectories. Stops after the first page when `first_page_only` is set.
+    fn list(&self, key_prefix: &str, recursive: bool, first_page_only: bool) -> Result<Vec<Entry>> {
+        let own_prefix = self.key_prefix();
+        let mut entries = vec![];
+        let mut continuation = None;
+        loop {
+            let mut query = vec![("list-type", "2".to_string()), ("prefix", key_prefix.to_string())];
+            if !recursive {
+                query.push(("delimiter", "/".to_string()));
+            }
+            if let Some(token) = continuation.take() {
+                query.push(("continuation-token", token));
+            }
+            let mut response = self.send("GET", "", &query, None)?;
+            let listing = Element::parse(&String::from_utf8(response.body()?)?)?;
+
+            for contents in listing.children_named("Contents") {
+                let key = contents.child_text("Key").unwrap_or_default().to_string();
+                let name = key.strip_prefix(&own_prefix).unwrap_or(&key);
+                // Placeholders some tools create for "directories"
+                if name.is_empty() || name.ends_with('/') {
+                    continue;
+                }
+                let length = contents.child_text("Size").and_then(|size| size.trim().parse::<u64>().ok()).unwrap_or(0);
+                let modified = contents
+                    .child_text("LastModified")
+                    .and_then(|time| humantime::parse_rfc3339_weak(time.trim()).ok())
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|duration| duration.as_secs());
+                entries.push(Entry {
+                    name: name.to_string(),
+                    path: key.clone().into(),
+                    length,
+                    modified,
+                });
+            }
+
+            if first_page_only || listing.child_text("IsTruncated").map(str::trim) != Some("true") {
+                break;
+            }
+            continuation = listing.child_text("NextContinuationToken").map(str::to_string);
+            if continuation.is_none() {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Sends a signed request for `key` (the bucket itself when empty) and reads the response head.
+    fn send(&self, method: &str, key: &str, query: &[(&str, String)], range: Option<u64>) -> Result<Response> {
+        let path = format!("/{}{}", self.bucket, key);
+        let uri = uri_encode(&path, false);
+        let mut query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true)))
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query.join("&");
+        let url = Url {
+            host: self.host.clone(),
+            port: self.port,
+            path: format!("{}{}{}", uri, if query.is_empty() { "" } else { "?" }, query),
+        };
+        let host = url.authority();
+
+        let timestamp = humantime::format_rfc3339_seconds(SystemTime::now())
+            .to_string()
+            .replace(['-', ':'], "");
+        let date = &timestamp[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, uri, query, host, EMPTY_PAYLOAD_HASH, timestamp, EMPTY_PAYLOAD_HASH
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+        let mut headers = vec![
+            ("x-amz-content-sha256", EMPTY_PAYLOAD_HASH.to_string()),
+            ("x-amz-date", timestamp.clone()),
+            (
+                "Authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                    self.access_key, scope, signature
+                ),
+            ),
+        ];
+        if let Some(offset) = range {
+            headers.push(("Range", format!("bytes={}-", offset)));
+        }
+
+        let mut response = http_client::request(&url, method, &headers, &[], TIMEOUT)?;
+        // Missing objects and reads past the end are up to the caller
+        if !(200..300).contains(&response.status) && response.status != 404 && response.status != 416 {
+            let body = String::from_utf8_lossy(&response.body().unwrap_or_default()).to_string();
+            let error = Element::parse(&body).ok();
+            let code = error.as_ref().and_then(|error| error.child_text("Code")).unwrap_or("no details");
+            let message = error.as_ref().and_then(|error| error.child_text("Message")).unwrap_or_default();
+            return Err(anyhow!(format!("S3 answered {} ({}) {}", response.status, code, message)));
+        }
+        Ok(response)
+    }
+}
+
+impl Display for Bucket {
+    /// Without the credentials, so it can be shown and logged.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}:{}/{}", SCHEME, self.host, self.port, self.location())
+    }
+}
+
+impl Storage for Bucket {
+    fn entries(&self) -> Result<Vec<Entry>> {
+        let mut entries = self.list(&self.key_prefix(), false, false)?;
+        entries.retain(|entry| !self.ignore.is_ignored(&entry.name));
+        Ok(entries)
+    }
+
+    fn find(&self, name: &str) -> std::result::Result<Entry, RequestError> {
+        if parity::safe_join("", name).is_err() {
+            return Err(RequestError::new(ErrorCode::UnauthorizedAccess, "Path leads outside of the parity root").with_path(name));
+        }
+        if self.ignore.is_ignored(name) {
+            return Err(RequestError::new(ErrorCode::NotFound, "No such file in the parity root").with_path(name));
+        }
+        // Listing the key as a prefix gets its metadata in the same format as every other listing;
+        // the key itself sorts before every longer key it prefixes
+        let key = format!("{}{}", self.key_prefix(), name);
+        let listed = self
+            .list(&key, true, true)
+            .map_err(|e| RequestError::new(ErrorCode::Internal, e).with_path(name))?;
+        listed
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| RequestError::new(ErrorCode::NotFound, "No such file in the parity root").with_path(name))
+    }
+
+    fn matching(&self, pattern: &str) -> Result<Vec<Entry>> {
+        parity::safe_join("", pattern)?;
+        let pattern = glob::Pattern::new(pattern)?;
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..glob::MatchOptions::new()
+        };
+        Ok(self
+            .list(&self.key_prefix(), true, false)?
+            .into_iter()
+            .filter(|entry| pattern.matches_with(&entry.name, options) && !self.ignore.is_ignored(&entry.name))
+            .collect())
+    }
+
+    fn open(&self, entry: &Entry, offset: u64) -> Result<Box<dyn Read + Send>> {
+        let key = format!("/{}", entry.path.to_string_lossy());
+        let response = self.send("GET", &key, &[], Some(offset).filter(|offset| *offset > 0))?;
+        if response.status == 404 {
+            return Err(anyhow!(format!("Object no longer exists: {}", entry.name)));
+        }
+        // Past the end, there is nothing left to read
+        if response.status == 416 {
+            return Ok(Box::new(std::io::empty()));
+        }
+        Ok(response.into_reader())
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, and `/` unless `encode_slash` is set, as
+/// AWS Signature Version 4 requires.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            b'/' if !encode_slash => "/".to_string(),
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// HMAC-SHA256 of `message` under `key`.
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,126 @@
+//! Sync schedules: when the client daemon syncs a profile, as typed in its config.
+//!
+//! `every <duration>` (such as `every 6h` or `every 90m`) syncs that long after the previous pass
+//! started, and `daily <HH:MM>` (such as `daily 03:00`) syncs once a day at that local time.
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    Every(Duration),
+    Daily { hour: u8, minute: u8 },
+}
+
+impl Schedule {
+    pub fn parse(input: &str) -> Result<Self> {
+        let input = input.trim();
+        let (kind, argument) = input
+            .split_once(char::is_whitespace)
+            .map(|(kind, argument)| (kind, argument.trim()))
+            .ok_or(anyhow!(format!("Invalid schedule '{}', expected e.g. 'every 6h' or 'daily 03:00'", input)))?;
+        match kind {
+            "every" => {
+                let interval = humantime::parse_duration(argument)
+                    .map_err(|e| anyhow!(format!("Invalid interval '{}': {}", argument, e)))?;
+                if interval.as_secs() < 60 {
+                    return Err(anyhow!("Scheduled syncs must be at least a minute apart"));
+                }
+                Ok(Schedule::Every(interval))
+            }
+            "daily" => {
+                let (hour, minute) = parse_time_of_day(argument)?;
+                Ok(Schedule::Daily { hour, minute })
+            }
+            _ => Err(anyhow!(format!("Unknown schedule '{}', expected 'every' or 'daily'", kind))),
+        }
+    }
+
+    /// When the pass after one that started at `last` is due.
+    pub fn next_after(&self, last: SystemTime) -> SystemTime {
+        match *self {
+            Schedule::Every(interval) => last + interval,
+            Schedule::Daily { hour, minute } => {
+                let last = last.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                // Today's occurrence, or the next day's once it has passed
+                let mut day = last;
+                let mut next = local_time_on(day, hour, minute);
+                while next <= last {
+                    day += DAY;
+                    next = local_time_on(day, hour, minute);
+                }
+                UNIX_EPOCH + Duration::from_secs(next)
+            }
+        }
+    }
+}
+
+/// Parses `HH:MM` into an hour and a minute.
+pub(crate) fn parse_time_of_day(input: &str) -> Result<(u8, u8)> {
+    let invalid = || anyhow!(format!("Invalid time of day '{}', expected HH:MM", input));
+    let (hour, minute) = input.split_once(':').ok_or_else(invalid)?;
+    let hour = hour.parse::<u8>().ok().filter(|hour| *hour < 24).ok_or_else(invalid)?;
+    let minute = minute.parse::<u8>().ok().filter(|minute| *minute < 60).ok_or_else(invalid)?;
+    Ok((hour, minute))
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Every(interval) => write!(f, "every {}", humantime::format_duration(*interval)),
+            Schedule::Daily { hour, minute } => write!(f, "daily {:02}:{:02}", hour, minute),
+        }
+    }
+}
+
+/// `hour:minute` local time on the day `secs` falls on, in seconds since the epoch.
+#[cfg(unix)]
+fn local_time_on(secs: u64, hour: u8, minute: u8) -> u64 {
+    let utc = secs - secs % DAY + (hour as u64 * 60 + minute as u64) * 60;
+    let time = secs as libc::time_t;
+    // SAFETY: both pointers are to locals that outlive the calls
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return utc;
+        }
+        tm.tm_hour = hour as libc::c_int;
+        tm.tm_min = minute as libc::c_int;
+        tm.tm_sec = 0;
+        tm.tm_isdst = -1;
+        match libc::mktime(&mut tm) {
+            -1 => utc,
+            time => time as u64,
+        }
+    }
+}
+
+/// Without a portable way to tell the local time zone, times of day are taken as UTC.
+#[cfg(not(unix))]
+fn local_time_on(secs: u64, hour: u8, minute: u8) -> u64 {
+    secs - secs % DAY + (hour as u64 * 60 + minute as u64) * 60
+}
+
+/// How many minutes into the local day `time` is.
+#[cfg(unix)]
+pub(crate) fn local_minute_of_day(time: SystemTime) -> u32 {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let time = secs as libc::time_t;
+    // SAFETY: both pointers are to locals that outlive the call
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return (secs % DAY / 60) as u32;
+        }
+        (tm.tm_hour * 60 + tm.tm_min) as u32
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn local_minute_of_day(time: SystemTime) -> u32 {
+    (time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % DAY / 60) as u32
+}
@@ -0,0 +1,105 @@
+//! Encryption of the secrets profiles keep in config files, such as passwords.
+//!
+//! Once turned on for a config file, secret fields are stored as `enc:<nonce>:<ciphertext>`, both
+//! hex-encoded, sealed with ChaCha20-Poly1305 under a key derived from a passphrase with
+//! PBKDF2-HMAC-SHA256. The salt, and a sealed [`CHECK_TEXT`] to tell a wrong passphrase from a
+//! right one, are kept in the file's `encryption` section. The passphrase is asked for once per
+//! session, and the key derived from it held in memory until the program exits.
+
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use sha2::Sha256;
+
+/// Environment variable a passphrase can be given in, for when nobody is there to type it.
+pub const PASSPHRASE_VAR: &str = "OXIDEUX_PASSPHRASE";
+/// What is sealed into the `encryption` section to check passphrases against.
+pub const CHECK_TEXT: &str = "oxideux";
+
+const PREFIX: &str = "enc:";
+const KDF_ROUNDS: u32 = 200_000;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+
+/// A key secrets are sealed with.
+#[derive(Clone)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    /// The key `passphrase` makes with `salt`, which is hex-encoded.
+    pub fn derive(passphrase: &str, salt: &str) -> Result<Self> {
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), &unhex(salt)?, KDF_ROUNDS, &mut key);
+        Ok(Self(key))
+    }
+
+    /// Seals `plaintext` into a value that can be stored in place of it.
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        getrandom::getrandom(&mut nonce).map_err(|e| anyhow!(format!("Could not generate a nonce: {}", e)))?;
+        let ciphertext = ChaCha20Poly1305::new((&self.0).into())
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .map_err(|_| anyhow!("Could not encrypt a secret"))?;
+        Ok(format!("{}{}:{}", PREFIX, hex(&nonce), hex(&ciphertext)))
+    }
+
+    /// Opens a value sealed with [`SecretKey::encrypt`], failing when it was sealed with another
+    /// key.
+    pub fn decrypt(&self, value: &str) -> Result<String> {
+        let (nonce, ciphertext) = value
+            .strip_prefix(PREFIX)
+            .and_then(|sealed| sealed.split_once(':'))
+            .ok_or(anyhow!("Not an encrypted secret"))?;
+        let nonce = unhex(nonce)?;
+        if nonce.len() != NONCE_LENGTH {
+            return Err(anyhow!("Not an encrypted secret"));
+        }
+        let plaintext = ChaCha20Poly1305::new((&self.0).into())
+            .decrypt(Nonce::from_slice(&nonce), unhex(ciphertext)?.as_slice())
+            .map_err(|_| anyhow!("Wrong passphrase"))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+/// Whether `value` is a secret sealed with [`SecretKey::encrypt`].
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+/// A fresh salt for [`SecretKey::derive`], hex-encoded.
+pub fn new_salt() -> Result<String> {
+    let mut salt = [0u8; SALT_LENGTH];
+    getrandom::getrandom(&mut salt).map_err(|e| anyhow!(format!("Could not generate a salt: {}", e)))?;
+    Ok(hex(&salt))
+}
+
+fn session() -> &'static Mutex<Option<SecretKey>> {
+    static SESSION: OnceLock<Mutex<Option<SecretKey>>> = OnceLock::new();
+    SESSION.get_or_init(|| Mutex::new(None))
+}
+
+/// The key secrets are sealed with this session, once the passphrase was given.
+pub fn session_key() -> Option<SecretKey> {
+    session().lock().unwrap().clone()
+}
+
+/// Holds `key` for the rest of the session, or forgets it with `None`.
+pub fn set_session_key(key: Option<SecretKey>) {
+    *session().lock().unwrap() = key;
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn unhex(text: &str) -> Result<Vec<u8>> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return Err(anyhow!("Invalid hex"));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| anyhow!("Invalid hex")))
+        .collect()
+}
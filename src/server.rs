@@ -0,0 +1,1980 @@
+//! The server side of the protocol as a library, so other programs can embed an oxideux server.
+//!
+//! ```no_run
+//! use oxideux_rs::server::Server;
+//!
+//! Server::builder()
+//!     .root("/srv/files")
+//!     .port(49160)
+//!     .auth("alice", "correct horse battery staple")
+//!     .serve()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! [`Server::serve`] runs the whole server as configured by its profile, listeners included, until
+//! it is drained (see: [`Server::drain`]). Programs with an accept loop of their own hand each
+//! connection to [`Server::handle_client`] instead.
+
+use std::fs;
+use std::io::{self, Read};
+use std::net::{IpAddr, Shutdown, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, log_enabled, trace, warn, Level};
+
+use crate::access::AccessList;
+use crate::audit::AuditLog;
+use crate::chunks::ManifestCache;
+use crate::config::{ServerMode, ServerProfile, SortKey, SymlinkPolicy, UserAccount};
+use crate::connection::{
+    BatchedFile, Cancelled, Capabilities, Capability, Connection, FileChanged, TransferEvent, BATCH_FILE_THRESHOLD,
+    KEEP_ALIVE_INTERVAL, KEEP_ALIVE_TIMEOUT, MAX_BATCH_BYTES,
+};
+use crate::delta;
+use crate::discovery::Advertisement;
+use crate::drain::Drain;
+use crate::history::{self, Direction, Side, Transferred};
+use crate::hooks::{self, Event};
+use crate::http_gateway::Gateway;
+use crate::ignore::IgnoreRules;
+use crate::interceptor::{Interception, RequestContext, RequestInterceptor};
+use crate::limits::{self, Limiter, MinimumRate};
+use crate::logging;
+use crate::metrics::{self, Metrics};
+use crate::parity::{self, EntryCache, RootStats, Storage};
+use crate::privileges::{self, RunAs};
+use crate::psk::{PskTransport, Role};
+use crate::port_mapping::PortMapping;
+use crate::request::{
+    ErrorCode, Request, RequestError, RequestResult, MAX_CHUNKS_PER_REQUEST, MAX_RANGE_LENGTH, MAX_SEARCH_RESULTS,
+};
+use crate::s3::Bucket;
+use crate::share;
+use crate::signing;
+use crate::transport::Transport;
+use crate::validated_values::{
+    ValidatedCidrList, ValidatedDirectory, ValidatedIPv4, ValidatedLimit, ValidatedLogFile, ValidatedOptionalDirectory,
+    ValidatedOptionalPort, ValidatedPatternList, ValidatedPort, ValidatedRunAs, ValidatedStorage, ValidatedValue,
+};
+use crate::versions;
+use crate::watch::{Notification, RootWatcher};
+use crate::websocket::WsTransport;
+
+/// The name servers made with [`Server::builder`] go by in the audit log, history and share links.
+pub const DEFAULT_NAME: &str = "embedded";
+pub const DEFAULT_PORT: u16 = 49160;
+/// How long a connection may be busy with the same thing before it is logged as possibly stuck,
+/// and how often that is checked. Only when details are logged (see: [`crate::logging`]).
+const BUSY_REPORT_INTERVAL: Duration = Duration::from_secs(30);
+/// How often a draining server says how many connections it still waits for.
+const DRAIN_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Configures a [`Server`] without a saved profile. Everything not set keeps the defaults of a new
+/// profile, except that the server doesn't advertise itself on the local network.
+pub struct ServerBuilder {
+    profile: ServerProfile,
+    /// Users to create once the parity root and mode are final.
+    logins: Vec<(String, String)>,
+    interceptors: Vec<Arc<dyn RequestInterceptor>>,
+}
+
+impl ServerBuilder {
+    fn new() -> Self {
+        Self {
+            profile: ServerProfile {
+                name: DEFAULT_NAME.to_string(),
+                notes: String::new(),
+                tags: vec![],
+                parity_root: ValidatedDirectory::new(".".to_string()),
+                port: ValidatedPort::new(DEFAULT_PORT),
+                mask: ValidatedIPv4::new("0.0.0.0".to_string()),
+                watch: false,
+                advertise: false,
+                port_mapping: false,
+                allow: ValidatedCidrList::new(String::new()),
+                deny: ValidatedCidrList::new(String::new()),
+                max_connections_per_ip: ValidatedLimit::new(0),
+                max_requests_per_minute: ValidatedLimit::new(0),
+                first_request_timeout: ValidatedLimit::new(limits::DEFAULT_FIRST_REQUEST_TIMEOUT),
+                min_transfer_rate: ValidatedLimit::new(0),
+                mode: ServerMode::ReadOnly,
+                upload_root: ValidatedOptionalDirectory::new(String::new()),
+                max_upload_size: ValidatedLimit::new(0),
+                upload_quota: ValidatedLimit::new(0),
+                versioning: false,
+                users: vec![],
+                shared_secret: String::new(),
+                signing_key: String::new(),
+                run_as: ValidatedRunAs::new(String::new()),
+                chroot: false,
+                hooks: vec![],
+                metrics_port: ValidatedOptionalPort::new(0),
+                http_port: ValidatedOptionalPort::new(0),
+                ws_port: ValidatedOptionalPort::new(0),
+                storage: ValidatedStorage::new(String::new()),
+                exclude: ValidatedPatternList::new(String::new()),
+                hidden_files: true,
+                symlinks: SymlinkPolicy::Follow,
+                sort_by: SortKey::Name,
+                sort_descending: false,
+                log_file: ValidatedLogFile::new(String::new()),
+                log_max_size: ValidatedLimit::new(logging::DEFAULT_MAX_SIZE),
+                log_max_age: ValidatedLimit::new(0),
+                log_retention: ValidatedLimit::new(logging::DEFAULT_RETENTION),
+            },
+            logins: vec![],
+            interceptors: vec![],
+        }
+    }
+
+    pub fn name<S: ToString>(mut self, name: S) -> Self {
+        self.profile.name = name.to_string();
+        self
+    }
+
+    /// The directory that is served.
+    pub fn root<P: AsRef<Path>>(mut self, root: P) -> Self {
+        self.profile.parity_root.set(root.as_ref().to_string_lossy().to_string());
+        self
+    }
+
+    /// Serves the objects of an S3-compatible bucket instead of the parity root, given as
+    /// `s3://access_key:secret_key@host[:port]/bucket[/prefix]` (see: [`crate::s3::Bucket`]).
+    pub fn bucket<S: ToString>(mut self, spec: S) -> Self {
+        self.profile.storage.set(spec.to_string());
+        self
+    }
+
+    /// Files that are never listed or served, as globs like `*.tmp` (see: [`crate::ignore`]).
+    pub fn exclude<S: ToString>(mut self, pattern: S) -> Self {
+        let mut patterns = self.profile.exclude.entries();
+        patterns.push(pattern.to_string());
+        self.profile.exclude.set(patterns.join(", "));
+        self
+    }
+
+    /// Whether files and directories whose name starts with a `.` are listed and served, as they
+    /// are by default.
+    pub fn hidden_files(mut self, include: bool) -> Self {
+        self.profile.hidden_files = include;
+        self
+    }
+
+    /// What listings do about symlinks, following them by default.
+    pub fn symlinks(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.profile.symlinks = symlinks;
+        self
+    }
+
+    /// What files are listed by, and so what indices refer to. By name by default.
+    pub fn sort_by(mut self, key: SortKey, descending: bool) -> Self {
+        self.profile.sort_by = key;
+        self.profile.sort_descending = descending;
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.profile.port.set(port);
+        self
+    }
+
+    /// The address to listen on, `0.0.0.0` for every interface.
+    pub fn mask<S: ToString>(mut self, mask: S) -> Self {
+        self.profile.mask.set(mask.to_string());
+        self
+    }
+
+    pub fn mode(mut self, mode: ServerMode) -> Self {
+        self.profile.mode = mode;
+        self
+    }
+
+    /// Stores uploads in `upload_root` instead of the parity root.
+    pub fn upload_root<P: AsRef<Path>>(mut self, upload_root: P) -> Self {
+        self.profile.upload_root.set(upload_root.as_ref().to_string_lossy().to_string());
+        self
+    }
+
+    /// Refuses uploads larger than `max_upload_size` bytes, and those that would take the upload
+    /// root over `upload_quota` bytes. Zero for no limit, as by default.
+    pub fn upload_limits(mut self, max_upload_size: u64, upload_quota: u64) -> Self {
+        self.profile.max_upload_size.set(max_upload_size);
+        self.profile.upload_quota.set(upload_quota);
+        self
+    }
+
+    /// Drops clients that take longer than `first_request_timeout` seconds to send their first
+    /// request, and transfers slower than `min_transfer_rate` bytes per second. Zero turns either
+    /// off.
+    pub fn stall_limits(mut self, first_request_timeout: u64, min_transfer_rate: u64) -> Self {
+        self.profile.first_request_timeout.set(first_request_timeout);
+        self.profile.min_transfer_rate.set(min_transfer_rate);
+        self
+    }
+
+    /// Keeps the previous version of every file an upload replaces (see: [`crate::versions`]).
+    pub fn versioning(mut self, versioning: bool) -> Self {
+        self.profile.versioning = versioning;
+        self
+    }
+
+    /// Pushes changes of the parity root to subscribed clients.
+    pub fn watch(mut self, watch: bool) -> Self {
+        self.profile.watch = watch;
+        self
+    }
+
+    pub fn advertise(mut self, advertise: bool) -> Self {
+        self.profile.advertise = advertise;
+        self
+    }
+
+    /// Requires clients to log in, as `user` or as any other user added this way. Users see the
+    /// server's parity root with the server's mode.
+    pub fn auth<S: ToString>(mut self, user: S, password: &str) -> Self {
+        self.logins.push((user.to_string(), password.to_string()));
+        self
+    }
+
+    /// Encrypts connections with `secret`, which clients must have too (see: [`crate::psk`]).
+    pub fn shared_secret<S: ToString>(mut self, secret: S) -> Self {
+        self.profile.shared_secret = secret.to_string();
+        self
+    }
+
+    /// Signs the manifest with `signing_key`, hex-encoded, so clients pinning its public key can
+    /// check what they download (see: [`crate::signing`]).
+    pub fn signing_key<S: ToString>(mut self, signing_key: S) -> Self {
+        self.profile.signing_key = signing_key.to_string();
+        self
+    }
+
+    /// Switches to `run_as` (`user[:group]`) once the port is bound, confined to the parity root
+    /// with `chroot` (see: [`crate::privileges`]). For servers started as root to bind a low port.
+    pub fn run_as<S: ToString>(mut self, run_as: S, chroot: bool) -> Self {
+        self.profile.run_as.set(run_as.to_string());
+        self.profile.chroot = chroot;
+        self
+    }
+
+    /// Adds an extension to the request handling (see: [`crate::interceptor`]).
+    pub fn interceptor<I: RequestInterceptor + 'static>(mut self, interceptor: I) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    pub fn build(mut self) -> Result<Server> {
+        for (user, password) in self.logins {
+            let account = UserAccount::new(user, &password, self.profile.parity_root.get(), self.profile.mode)?;
+            self.profile.users.push(account);
+        }
+        let mut server = Server::new(self.profile)?;
+        server.shared.interceptors = self.interceptors;
+        Ok(server)
+    }
+
+    /// Builds the server and serves until an error stops it (see: [`Server::serve`]).
+    pub fn serve(self) -> Result<()> {
+        self.build()?.serve()
+    }
+}
+
+/// A server ready to serve a profile.
+#[derive(Clone)]
+pub struct Server {
+    shared: Shared,
+    access: AccessList,
+}
+
+/// State shared by every connection of a running server.
+#[derive(Clone)]
+struct Shared {
+    profile: ServerProfile,
+    capabilities: Capabilities,
+    watcher: Option<Arc<RootWatcher>>,
+    limiter: Arc<Limiter>,
+    audit: Arc<AuditLog>,
+    metrics: Arc<Metrics>,
+    /// Chunk lists of the files clients downloaded chunk by chunk.
+    manifests: Arc<ManifestCache>,
+    /// Listings of the parity roots, so they aren't read for every request.
+    entries: Arc<EntryCache>,
+    interceptors: Vec<Arc<dyn RequestInterceptor>>,
+    drain: Drain,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Prepares to serve `profile`, e.g. one saved with [`crate::config::server::save_profile`].
+    pub fn new(profile: ServerProfile) -> Result<Self> {
+        validate(&profile)?;
+
+        let mut capabilities = Capabilities::local();
+        let watcher = match profile.watch {
+            true => Some(Arc::new(RootWatcher::new(profile.parity_root.get(), parity::profile_rules(&profile)?)?)),
+            false => {
+                capabilities.remove(Capability::Watch);
+                None
+            }
+        };
+        if !profile.versioning {
+            capabilities.remove(Capability::Versions);
+        }
+        if profile.signing_key.is_empty() {
+            capabilities.remove(Capability::Signatures);
+        }
+        match profile.mode {
+            ServerMode::ReadOnly => {
+                capabilities.remove(Capability::Upload);
+                capabilities.remove(Capability::SizedUpload);
+                capabilities.remove(Capability::ResumeUpload);
+            }
+            ServerMode::ReadWrite => (),
+            ServerMode::DropBox => {
+                for capability in [
+                    Capability::FileInfo,
+                    Capability::DownloadMatching,
+                    Capability::Watch,
+                    Capability::List,
+                    Capability::Range,
+                    Capability::Chunks,
+                    Capability::Delta,
+                    Capability::Manifest,
+                    Capability::Signatures,
+                    Capability::Query,
+                    Capability::Stats,
+                    Capability::Versions,
+                    Capability::Search,
+                    Capability::ByHash,
+                    Capability::Conditional,
+                ] {
+                    capabilities.remove(capability);
+                }
+            }
+        }
+
+        let entries = EntryCache::new();
+        if let Some(watcher) = &watcher {
+            entries.follow(profile.parity_root.get(), watcher)?;
+        }
+
+        let access = AccessList::new(profile.allow.cidrs()?, profile.deny.cidrs()?);
+        let shared = Shared {
+            capabilities,
+            watcher,
+            limiter: Limiter::new(*profile.max_connections_per_ip.get(), *profile.max_requests_per_minute.get()),
+            audit: Arc::new(AuditLog::open(&profile.name)?),
+            metrics: Metrics::new(),
+            manifests: ManifestCache::new(),
+            entries,
+            interceptors: vec![],
+            drain: Drain::new(),
+            profile,
+        };
+        Ok(Self { shared, access })
+    }
+
+    /// Adds an extension to the request handling (see: [`crate::interceptor`]).
+    pub fn with_interceptor<I: RequestInterceptor + 'static>(mut self, interceptor: I) -> Self {
+        self.shared.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    pub fn profile(&self) -> &ServerProfile {
+        &self.shared.profile
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.shared.metrics)
+    }
+
+    /// Whether the access lists let `ip` in. Custom accept loops should drop connections that
+    /// aren't.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        self.access.permits(ip)
+    }
+
+    /// A handle that drains the server from another thread, e.g. to stop it or start it again
+    /// with a changed profile without cutting transfers short (see: [`crate::drain`]).
+    pub fn drain(&self) -> Drain {
+        self.shared.drain.clone()
+    }
+
+    /// Listens on the profile's port, and on its metrics, HTTP gateway and WebSocket ports if they
+    /// are set, serving each connection on a thread of its own. Returns once the server is drained,
+    /// or if a listener can't be opened.
+    pub fn serve(&self) -> Result<()> {
+        let profile = &self.shared.profile;
+        let addr = format!("{}:{}", profile.mask.get(), profile.port.get());
+        let listener = TcpListener::bind(&addr)?;
+        // Every port is bound before the server switches users, as the ports may be privileged ones
+        let bind_optional = |port: &ValidatedOptionalPort| -> Result<Option<(String, TcpListener)>> {
+            if !port.is_set() {
+                return Ok(None);
+            }
+            let addr = format!("{}:{}", profile.mask.get(), port.get());
+            let listener = TcpListener::bind(&addr)?;
+            Ok(Some((addr, listener)))
+        };
+        let metrics_listener = bind_optional(&profile.metrics_port)?;
+        let http_listener = bind_optional(&profile.http_port)?;
+        let ws_listener = bind_optional(&profile.ws_port)?;
+        let shared = self.drop_privileges()?;
+
+        info!("Listening for connections on {}", addr);
+        match profile.storage.is_set() {
+            true => info!("Bucket: {}", Bucket::parse(profile.storage.get())?),
+            false => info!("Parity root: {}", profile.parity_root.get()),
+        }
+        if shared.watcher.is_some() {
+            info!("Watching the parity root for changes");
+        }
+        info!("Mode: {}", profile.mode.key());
+
+        // Kept until the server stops; discovery is a convenience, so failing to advertise isn't fatal
+        let _advertisement = match profile.advertise {
+            true => match Advertisement::new(&profile.name, *profile.port.get()) {
+                Ok(advertisement) => {
+                    info!("Advertising as '{}' on the local network", profile.name);
+                    Some(advertisement)
+                }
+                Err(e) => {
+                    warn!("Could not advertise on the local network: {}", e);
+                    None
+                }
+            },
+            false => None,
+        };
+
+        // Removed from the router when the server stops; the LAN still works without it
+        let _port_mapping = match profile.port_mapping {
+            true => match PortMapping::new(*profile.port.get()) {
+                Ok(mapping) => {
+                    info!("Reachable from the internet at {} ({})", mapping.external, mapping.method_name());
+                    Some(mapping)
+                }
+                Err(e) => {
+                    warn!("Could not map the port on the router: {}", e);
+                    None
+                }
+            },
+            false => None,
+        };
+
+        if let Some((metrics_addr, metrics_listener)) = metrics_listener {
+            metrics::serve(Arc::clone(&shared.metrics), metrics_listener, shared.drain.clone());
+            info!("Serving metrics on http://{}/metrics", metrics_addr);
+        }
+
+        if let Some((http_addr, http_listener)) = http_listener {
+            start_http_gateway(&shared, self.access.clone(), &http_addr, http_listener)?;
+        }
+
+        if let Some((ws_addr, ws_listener)) = ws_listener {
+            info!("Listening for WebSocket connections on {}", ws_addr);
+            let (shared, access) = (shared.clone(), self.access.clone());
+            thread::spawn(move || accept_connections(&ws_listener, &shared, &access, true));
+        }
+
+        if log_enabled!(Level::Debug) {
+            let drain = shared.drain.clone();
+            thread::spawn(move || report_busy_connections(&drain));
+        }
+
+        accept_connections(&listener, &shared, &self.access, false);
+        drop(listener);
+
+        info!("Draining: no longer accepting connections");
+        while !shared.drain.wait(DRAIN_REPORT_INTERVAL) {
+            info!("Draining: waiting for {} connection(s) to finish", shared.drain.open_connections());
+        }
+        shared.drain.stop();
+        info!("Drained");
+        Ok(())
+    }
+
+    /// Switches to the profile's user once the port is bound, returning what connections are then
+    /// served with. When the server confines itself to the parity root, its paths are rewritten to
+    /// be seen from inside it.
+    fn drop_privileges(&self) -> Result<Shared> {
+        let profile = &self.shared.profile;
+        let mut shared = self.shared.clone();
+        if !profile.run_as.is_set() {
+            return Ok(shared);
+        }
+        let run_as = RunAs::parse(profile.run_as.get())?;
+        let chroot = match profile.chroot {
+            true => Some(PathBuf::from(profile.parity_root.get()).canonicalize()?),
+            false => None,
+        };
+        if let Some(root) = &chroot {
+            shared.profile = confined(profile, root)?;
+            history::disable();
+            info!("Not recording the transfer history, which is out of reach once confined");
+        }
+        privileges::drop_privileges(&run_as, chroot.as_deref())?;
+        match &chroot {
+            Some(root) => info!("Running as {}, confined to {}", profile.run_as.get(), root.display()),
+            None => info!("Running as {}", profile.run_as.get()),
+        }
+        Ok(shared)
+    }
+
+    /// Serves one client over `conn` until it disconnects, starting with the handshake. The
+    /// connection limit isn't applied here, only the rate limit.
+    pub fn handle_client(&self, conn: &mut Connection) -> Result<()> {
+        let _gauge = self.shared.metrics.connection();
+        let result = handle_client(&self.shared, conn);
+        if result.is_err() {
+            self.shared.metrics.error();
+        }
+        result
+    }
+}
+
+/// The builder sets values without validating them, so profiles are checked before serving.
+fn validate(profile: &ServerProfile) -> Result<()> {
+    let signing_key = match profile.signing_key.is_empty() {
+        true => Ok(()),
+        false => signing::public_key(&profile.signing_key).map(|_| ()),
+    };
+    let checks = [
+        ("Parity root", profile.parity_root.is_valid()),
+        ("Port", profile.port.is_valid()),
+        ("Mask", profile.mask.is_valid()),
+        ("Upload root", profile.upload_root.is_valid()),
+        ("Allowlist", profile.allow.is_valid()),
+        ("Denylist", profile.deny.is_valid()),
+        ("Metrics port", profile.metrics_port.is_valid()),
+        ("HTTP gateway port", profile.http_port.is_valid()),
+        ("WebSocket port", profile.ws_port.is_valid()),
+        ("Storage", profile.storage.is_valid()),
+        ("Exclusions", profile.exclude.is_valid()),
+        ("Signing key", signing_key),
+        ("Run as", profile.run_as.is_valid()),
+    ];
+    for (label, check) in checks {
+        if let Err(e) = check {
+            return Err(anyhow!(format!("{}: {}", label, e)));
+        }
+    }
+    validate_storage(profile)?;
+    validate_privileges(profile)
+}
+
+/// Checks that the server can confine itself to the parity root, when the profile asks it to:
+/// everything it serves has to be in there.
+pub fn validate_privileges(profile: &ServerProfile) -> Result<()> {
+    if !profile.chroot {
+        return Ok(());
+    }
+    if !profile.run_as.is_set() {
+        return Err(anyhow!("Confining the server to the parity root needs a user to run as"));
+    }
+    if profile.storage.is_set() {
+        return Err(anyhow!("The server can't be confined to the parity root when serving from a bucket"));
+    }
+    if profile.watch {
+        return Err(anyhow!("Watching is not supported when the server is confined to the parity root"));
+    }
+    // What the server keeps in the config directory is out of reach from inside the parity root
+    if !profile.hooks.is_empty() {
+        return Err(anyhow!("Hooks can't be run when the server is confined to the parity root"));
+    }
+    if profile.log_file.is_set() && (*profile.log_max_size.get() != 0 || *profile.log_max_age.get() != 0) {
+        return Err(anyhow!("The log file can't be rotated when the server is confined to the parity root"));
+    }
+    if !share::list(&profile.name)?.is_empty() {
+        return Err(anyhow!("Share links can't be redeemed when the server is confined to the parity root"));
+    }
+    confined(profile, &PathBuf::from(profile.parity_root.get()).canonicalize()?).map(|_| ())
+}
+
+/// `profile` as seen from inside `root`, its canonical parity root, once the server is confined
+/// to it.
+fn confined(profile: &ServerProfile, root: &Path) -> Result<ServerProfile> {
+    let inside = |path: &str| -> Result<String> {
+        let relative = PathBuf::from(path)
+            .canonicalize()?
+            .strip_prefix(root)
+            .map(Path::to_path_buf)
+            .map_err(|_| anyhow!(format!("'{}' is outside the parity root the server is confined to", path)))?;
+        Ok(Path::new("/").join(relative).to_string_lossy().to_string())
+    };
+    let mut confined = profile.clone();
+    confined.parity_root.set("/".to_string());
+    if profile.upload_root.is_set() {
+        confined.upload_root.set(inside(profile.upload_root.get())?);
+    }
+    for user in &mut confined.users {
+        user.parity_root.set(inside(user.parity_root.get())?);
+    }
+    Ok(confined)
+}
+
+/// Checks that nothing the profile turns on needs its files to be in a local directory.
+pub fn validate_storage(profile: &ServerProfile) -> Result<()> {
+    if !profile.storage.is_set() {
+        return Ok(());
+    }
+    if profile.watch {
+        return Err(anyhow!("Watching is not supported when serving from a bucket"));
+    }
+    if profile.http_port.is_set() {
+        return Err(anyhow!("The HTTP gateway is not supported when serving from a bucket"));
+    }
+    if profile.mode != ServerMode::ReadOnly && !profile.upload_root.is_set() {
+        return Err(anyhow!("Uploads need an upload root when serving from a bucket"));
+    }
+    if profile.versioning {
+        return Err(anyhow!("Versioning is not supported when serving from a bucket"));
+    }
+    Ok(())
+}
+
+/// Serves the parity root over HTTP, unless the profile's mode or users would be bypassed by doing so.
+fn start_http_gateway(shared: &Shared, access: AccessList, addr: &str, listener: TcpListener) -> Result<()> {
+    let profile = &shared.profile;
+    if profile.mode == ServerMode::DropBox {
+        warn!("Not starting the HTTP gateway: files can't be downloaded in drop-box mode");
+        return Ok(());
+    }
+    if !profile.users.is_empty() {
+        warn!("Not starting the HTTP gateway: it can't check logins");
+        return Ok(());
+    }
+
+    let (audit, metrics, profile_name) = (Arc::clone(&shared.audit), Arc::clone(&shared.metrics), profile.name.clone());
+    let on_download = move |ip: IpAddr, name: &str, bytes: u64, result: &Result<()>| {
+        let who = ip.to_string();
+        match result {
+            Ok(_) => metrics.sent(bytes),
+            Err(_) => metrics.error(),
+        }
+        audit.record(&who, "http download", name, bytes, result);
+        history::record(Side::Server, &profile_name, &who, Direction::Download, name, bytes, result);
+    };
+
+    Gateway::new(profile.parity_root.get(), access, Arc::clone(&shared.limiter), Box::new(on_download))
+        .excluding(parity::profile_rules(profile)?)
+        .drained_by(shared.drain.clone())
+        .serve_on(listener);
+    info!("Serving the parity root on http://{}/files/", addr);
+    Ok(())
+}
+
+/// Accepts connections on `listener` until the server drains, serving each on a thread of its own. Over
+/// `websocket` listeners, the WebSocket handshake comes first, then encryption with the shared
+/// secret if the profile has one.
+fn accept_connections(listener: &TcpListener, shared: &Shared, access: &AccessList, websocket: bool) {
+    for connection in shared.drain.incoming(listener, false) {
+        match connection {
+            Ok(stream) => {
+                let peer = match stream.peer_addr() {
+                    Ok(peer) => peer,
+                    Err(error) => {
+                        warn!("Connection error: {}", error);
+                        continue;
+                    }
+                };
+                if !access.permits(peer.ip()) {
+                    info!("Rejected connection from {}", peer);
+                    shared.metrics.rejected();
+                    let _ = stream.shutdown(Shutdown::Both);
+                    continue;
+                }
+                let guard = match shared.limiter.try_connect(peer.ip()) {
+                    Some(guard) => guard,
+                    None => {
+                        warn!("Rejected connection from {}: too many connections", peer);
+                        shared.metrics.rejected();
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+                };
+                info!("Connection established: {}", peer);
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let _guard = guard;
+                    let _gauge = shared.metrics.connection();
+                    let _span = tracing::info_span!("connection", peer = %peer).entered();
+                    let transport: Result<Box<dyn Transport>> = match websocket {
+                        true => WsTransport::accept(stream).map(|transport| Box::new(transport) as Box<dyn Transport>),
+                        false => Ok(Box::new(stream)),
+                    };
+                    let transport = match shared.profile.shared_secret.is_empty() {
+                        true => transport,
+                        false => transport.and_then(|transport| {
+                            let secret = &shared.profile.shared_secret;
+                            Ok(Box::new(PskTransport::new(transport, secret, Role::Server)?) as Box<dyn Transport>)
+                        }),
+                    };
+                    let result = transport.and_then(Connection::with_transport).and_then(|mut conn| {
+                        conn.set_transfer_handler(log_transfer_event);
+                        handle_client(&shared, &mut conn)
+                    });
+                    match result {
+                        Ok(_) => info!("Connection terminated ({})", peer),
+                        Err(e) => {
+                            shared.metrics.error();
+                            warn!("Connection terminated ({}): {}", peer, e);
+                        }
+                    }
+                });
+            }
+            Err(error) => {
+                warn!("Connection error: {}", error);
+            }
+        }
+    }
+}
+
+/// `error`, or why the client was dropped if it is the read timeout running out while waiting for
+/// it to `what`.
+fn late(error: anyhow::Error, what: &str) -> anyhow::Error {
+    match error.downcast_ref::<io::Error>().map(io::Error::kind) {
+        Some(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            anyhow!(format!("Dropped: the client did not {} in time", what))
+        }
+        _ => error,
+    }
+}
+
+/// Logs what connections have been busy with for a while, such as a transfer to a peer that
+/// stopped reading, so a server that seems hung can be told apart from one that is slow.
+fn report_busy_connections(drain: &Drain) {
+    loop {
+        thread::sleep(BUSY_REPORT_INTERVAL);
+        for (span, busy) in drain.busy(BUSY_REPORT_INTERVAL) {
+            let busy = humantime::format_duration(Duration::from_secs(busy.as_secs()));
+            debug!("Still busy: {} (for {})", logging::describe(&span), busy);
+        }
+    }
+}
+
+fn log_transfer_event(event: &TransferEvent) {
+    match event {
+        TransferEvent::Started { name, length } => info!("Transferring '{}' ({} bytes)", name, length),
+        TransferEvent::Progressed { name, transferred, length, .. } => trace!("'{}': {} of {} bytes", name, transferred, length),
+        TransferEvent::Completed { name, stats, .. } => {
+            info!("Transferred '{}'", name);
+            debug!("'{}' took {:.1}s at {:.0} bytes/s", name, stats.elapsed.as_secs_f64(), stats.average_speed);
+        }
+        TransferEvent::Failed { name, error } => warn!("Failed to transfer '{}': {}", name, error),
+    }
+}
+
+fn handle_client(shared: &Shared, conn: &mut Connection) -> Result<()> {
+    let session = match shared.drain.register(conn.stream().try_clone()?) {
+        Some(session) => session,
+        None => return Ok(()),
+    };
+    // Connecting and then saying nothing mustn't hold a connection (and its slot) forever
+    let first_request_timeout = match *shared.profile.first_request_timeout.get() {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    conn.stream().set_read_timeout(first_request_timeout)?;
+    conn.handshake_server(shared.capabilities).map_err(|e| late(e, "complete the handshake"))?;
+    debug!("Negotiated capabilities: {}", conn.capabilities());
+    let keep_alive = conn.supports(Capability::KeepAlive);
+    let min_transfer_rate = *shared.profile.min_transfer_rate.get();
+    if min_transfer_rate > 0 {
+        conn.set_minimum_rate(Some(MinimumRate::new(min_transfer_rate)))?;
+    }
+    let ip = conn.stream().peer_addr()?.ip();
+
+    // What this client may see; on servers with users, nothing until it logs in
+    let mut scope = match shared.profile.users.is_empty() {
+        true => Some(shared.profile.clone()),
+        false => None,
+    };
+    // How the client appears in the audit log
+    let mut who = ip.to_string();
+
+    let mut first = true;
+    loop {
+        // A client in a persistent session pings regularly, so a long silence means it is gone
+        if keep_alive && !(first && first_request_timeout.is_some()) {
+            conn.stream().set_read_timeout(Some(KEEP_ALIVE_TIMEOUT))?;
+        }
+        if !session.idle() {
+            return Ok(());
+        }
+        let (id, request) = match conn.read_request() {
+            // Closed for being idle when the server started draining
+            Err(_) if shared.drain.is_draining() => return Ok(()),
+            Err(e) if first => return Err(late(e, "send a request")),
+            result => result?,
+        };
+        conn.stream().set_read_timeout(None)?;
+        // Transfers fill in the file, see: [`Connection::emit`]
+        let _span = tracing::info_span!("request", request = id, kind = %request.kind(), file = tracing::field::Empty).entered();
+        session.busy();
+        first = false;
+        shared.metrics.request();
+        debug!("Request received");
+
+        // After these the connection is either closed or dedicated to something else
+        let ends_session = matches!(request, Request::Disconnect | Request::Subscribe);
+
+        // Keep-alive traffic and housekeeping don't count against the rate limit
+        let counted = !matches!(request, Request::Disconnect | Request::Ping | Request::Cancel(_));
+        if counted && !shared.limiter.allow_request(ip) {
+            warn!("Request {} from {} refused: rate limit reached", id, ip);
+            conn.send_request_result(RequestError::new(ErrorCode::RateLimited, "Slow down and try again in a minute").into())?;
+            continue;
+        }
+
+        if let Request::Authenticate { user, password } = &request {
+            match user_scope(&shared.profile, user, password) {
+                Ok(profile) => {
+                    info!("{} logged in as '{}'", ip, user);
+                    scope = Some(profile);
+                    who = format!("{}@{}", user, ip);
+                    conn.send_request_result(RequestResult::Ok)?;
+                }
+                Err(e) => {
+                    warn!("{} failed to log in as '{}'", ip, user);
+                    conn.send_request_result(e.into())?;
+                }
+            }
+            continue;
+        }
+
+        let open_to_anyone = matches!(
+            request,
+            Request::Disconnect | Request::Ping | Request::Cancel(_) | Request::RedeemToken(_)
+        );
+        let profile = match (&scope, open_to_anyone) {
+            (Some(profile), _) => profile,
+            (None, true) => &shared.profile,
+            (None, false) => {
+                conn.send_request_result(RequestError::new(ErrorCode::Unauthenticated, "Log in first").into())?;
+                continue;
+            }
+        };
+
+        if let Some(error) = refusal(profile.mode, &request) {
+            conn.send_request_result(error.into())?;
+            continue;
+        }
+
+        let context = RequestContext { ip, who: &who, profile };
+        let request = match intercept(shared, &context, request) {
+            Interception::Continue(request) => request,
+            Interception::Refuse(error) => {
+                conn.send_request_result(error.into())?;
+                continue;
+            }
+        };
+
+        // Waiting for changes to push is as good as idle, so draining closes the connection
+        if matches!(request, Request::Subscribe) && !session.idle() {
+            return Ok(());
+        }
+
+        match handle_request(shared, &context, conn, request) {
+            Ok(_) => (),
+            Err(e) if e.is::<Cancelled>() => info!("Request {} cancelled", id),
+            Err(e) if e.is::<FileChanged>() => info!("Request {} stopped: {}", id, e),
+            Err(e) => return Err(e),
+        }
+
+        if ends_session {
+            return Ok(());
+        }
+    }
+}
+
+/// The profile as seen by `user`: their own parity root and mode, and their own directory inside
+/// the upload root.
+fn user_scope(profile: &ServerProfile, user: &str, password: &str) -> std::result::Result<ServerProfile, RequestError> {
+    let wrong = || RequestError::new(ErrorCode::Unauthenticated, "Wrong user name or password");
+    let account = profile
+        .users
+        .iter()
+        .find(|account| account.name == user)
+        .ok_or_else(wrong)?;
+    if !account.verify(password) {
+        return Err(wrong());
+    }
+    if account.needs_rehash() {
+        warn!("User '{}' has a password hashed the old, weaker way; reset it to rehash it", user);
+    }
+
+    let mut scoped = profile.clone();
+    scoped.parity_root = account.parity_root.clone();
+    scoped.mode = account.mode;
+    if account.max_upload_size != 0 {
+        scoped.max_upload_size.set(account.max_upload_size);
+    }
+    if account.upload_quota != 0 {
+        scoped.upload_quota.set(account.upload_quota);
+    }
+    if profile.storage.is_set() {
+        let bucket = Bucket::parse(profile.storage.get())
+            .and_then(|bucket| bucket.scoped(user))
+            .map_err(|e| RequestError::new(ErrorCode::Internal, e))?;
+        scoped.storage.set(bucket.spec());
+    }
+    if profile.upload_root.is_set() {
+        let upload_root = parity::safe_join(profile.upload_root.get(), user)
+            .map_err(|e| RequestError::new(ErrorCode::Internal, e))?;
+        scoped.upload_root.set(upload_root.to_string_lossy().to_string());
+    }
+    Ok(scoped)
+}
+
+/// Why `request` isn't allowed in `mode`, if it isn't.
+fn refusal(mode: ServerMode, request: &Request) -> Option<RequestError> {
+    let reads = matches!(
+        request,
+        Request::GetFileCount
+            | Request::DownloadFileByIndex(_)
+            | Request::DownloadFileByName(_)
+            | Request::DownloadAllFiles
+            | Request::GetFileInfo(_)
+            | Request::DownloadMatching(_)
+            | Request::ListFiles
+            | Request::Subscribe
+            | Request::ReadRange { .. }
+            | Request::GetChunks(_)
+            | Request::ReadChunks { .. }
+            | Request::DownloadDelta { .. }
+            | Request::GetManifest
+            | Request::GetSignedManifest
+            | Request::QueryFiles(_)
+            | Request::GetRootStats
+            | Request::ListVersions(_)
+            | Request::DownloadVersion { .. }
+            | Request::Search { .. }
+            | Request::DownloadByHash(_)
+            | Request::DownloadIfChanged { .. }
+            | Request::ResumeDownload { .. }
+    );
+    let writes = matches!(
+        request,
+        Request::UploadFile(_)
+            | Request::UploadSizedFile { .. }
+            | Request::GetUploadOffset(_)
+            | Request::ResumeUpload { .. }
+    );
+
+    match mode {
+        ServerMode::ReadOnly if writes => Some(RequestError::new(ErrorCode::UnauthorizedAccess, "This server is read-only")),
+        ServerMode::DropBox if reads => Some(RequestError::new(
+            ErrorCode::UnauthorizedAccess,
+            "This server is a drop-box, files can only be uploaded",
+        )),
+        _ => None,
+    }
+}
+
+/// Passes `request` through every interceptor, stopping at the first refusal.
+fn intercept(shared: &Shared, context: &RequestContext, request: Request) -> Interception {
+    if matches!(request, Request::Disconnect | Request::Ping | Request::Cancel(_)) {
+        return Interception::Continue(request);
+    }
+    let mut request = request;
+    for interceptor in &shared.interceptors {
+        match interceptor.intercept(context, request) {
+            Interception::Continue(rewritten) => request = rewritten,
+            refusal => return refusal,
+        }
+    }
+    Interception::Continue(request)
+}
+
+fn handle_request(shared: &Shared, context: &RequestContext, conn: &mut Connection, request: Request) -> Result<()> {
+    let (profile, who) = (context.profile, context.who);
+    let storage = parity::open_cached_storage(profile, &shared.entries)?;
+    let storage = storage.as_ref();
+    match request {
+        Request::Disconnect => {
+            conn.shutdown(Shutdown::Both)?;
+        }
+        // Cancellations of requests that already finished
+        Request::Cancel(_) => (),
+        // Handled by handle_client, since it changes what the following requests may see
+        Request::Authenticate { .. } => (),
+        Request::Ping => {
+            conn.send_request_result(RequestResult::Pong)?;
+        }
+        Request::GetFileCount => {
+            let entries = match list_entries(storage, profile, conn)? {
+                Some(entries) => entries,
+                None => return Ok(()),
+            };
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_count(entries.len() as u32)?;
+        }
+        Request::DownloadFileByIndex(index) => {
+            let entries = match list_entries(storage, profile, conn)? {
+                Some(entries) => entries,
+                None => return Ok(()),
+            };
+
+            // Index out of bounds
+            if index as usize >= entries.len() {
+                let message = format!("There are {} files, index {} was requested", entries.len(), index);
+                conn.send_request_result(RequestError::new(ErrorCode::IndexOutOfBounds, message).into())?;
+                return Ok(());
+            }
+
+            let entry = &entries[index as usize];
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_string(&entry.name)?;
+            audited_send(shared, storage, who, "download", conn, entry)?;
+        }
+        Request::DownloadFileByName(name) => {
+            let entry = match storage.find(&name) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    conn.send_request_result(e.into())?;
+                    return Ok(());
+                }
+            };
+            conn.send_request_result(RequestResult::Ok)?;
+            audited_send(shared, storage, who, "download", conn, &entry)?;
+        }
+        Request::DownloadIfChanged {
+            name,
+            length,
+            modified,
+            hash,
+        } => {
+            let entry = match storage.find(&name) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    conn.send_request_result(e.into())?;
+                    return Ok(());
+                }
+            };
+            let summary = storage.summary(&entry)?;
+            // The file is only hashed when the modification time alone doesn't tell
+            let unchanged = summary.length == length
+                && match (modified, hash) {
+                    (Some(modified), _) if summary.modified == Some(modified) => true,
+                    (_, Some(hash)) => storage.file_info(&entry)?.hash.eq_ignore_ascii_case(&hash),
+                    _ => false,
+                };
+            if unchanged {
+                conn.send_request_result(RequestResult::NotModified)?;
+                return Ok(());
+            }
+            conn.send_request_result(RequestResult::Ok)?;
+            audited_send(shared, storage, who, "download", conn, &entry)?;
+        }
+        Request::ResumeDownload {
+            name,
+            offset,
+            length,
+            modified,
+        } => {
+            let entry = match storage.find(&name) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    conn.send_request_result(e.into())?;
+                    return Ok(());
+                }
+            };
+            let summary = storage.summary(&entry)?;
+            if summary.length != length || summary.modified != modified || offset > length {
+                let error = RequestError::new(ErrorCode::NotFound, "The file changed since its download was interrupted");
+                conn.send_request_result(error.with_path(&name).into())?;
+                return Ok(());
+            }
+            conn.send_request_result(RequestResult::Ok)?;
+            audited_send_rest(shared, storage, who, "download", conn, &entry, offset)?;
+        }
+        Request::ReadRange { name, offset, length } => {
+            let entry = match storage.find(&name) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    conn.send_request_result(e.into())?;
+                    return Ok(());
+                }
+            };
+            let mut bytes = vec![];
+            storage
+                .open(&entry, offset)?
+                .take(length.min(MAX_RANGE_LENGTH) as u64)
+                .read_to_end(&mut bytes)?;
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_object(&bytes)?;
+            // Too fine-grained for the audit log and history, which record whole transfers
+            shared.metrics.sent(bytes.len() as u64);
+        }
+        Request::GetFileInfo(name) => {
+            let entry = match storage.find(&name) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    conn.send_request_result(e.into())?;
+                    return Ok(());
+                }
+            };
+            let info = match storage.file_info(&entry) {
+                Ok(info) => info,
+                Err(e) => {
+                    let error = RequestError::new(ErrorCode::Internal, e).with_path(&name);
+                    conn.send_request_result(error.into())?;
+                    return Ok(());
+                }
+            };
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_object(&info)?;
+        }
+        Request::DownloadAllFiles => {
+            let entries = match list_entries(storage, profile, conn)? {
+                Some(entries) => entries,
+                None => return Ok(()),
+            };
+            conn.send_request_result(RequestResult::Ok)?;
+            send_entries(shared, storage, who, "download", conn, entries)?;
+        }
+        Request::ListFiles => {
+            let entries = match list_entries(storage, profile, conn)? {
+                Some(entries) => entries,
+                None => return Ok(()),
+            };
+            let summaries = entries
+                .iter()
+                .map(|entry| storage.summary(entry))
+                .collect::<Result<Vec<_>>>()?;
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_object(&summaries)?;
+        }
+        Request::QueryFiles(query) => {
+            let entries = match list_entries(storage, profile, conn)? {
+                Some(entries) => entries,
+                None => return Ok(()),
+            };
+            let mut summaries = entries
+                .iter()
+                .map(|entry| storage.summary(entry))
+                .collect::<Result<Vec<_>>>()?;
+            summaries.retain(|summary| query.matches(summary));
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_object(&summaries)?;
+        }
+        Request::Search { query, limit } => {
+            let entries = match list_entries(storage, profile, conn)? {
+                Some(entries) => entries,
+                None => return Ok(()),
+            };
+            let limit = match limit {
+                0 => MAX_SEARCH_RESULTS,
+                limit => limit.min(MAX_SEARCH_RESULTS),
+            } as usize;
+            let mut found = vec![];
+            // Only files whose name matches are looked up for their size and modification time
+            for entry in entries.iter().filter(|entry| query.matches_name(&entry.name)) {
+                let summary = storage.summary(entry)?;
+                if query.matches(&summary) {
+                    found.push(summary);
+                    if found.len() == limit {
+                        break;
+                    }
+                }
+            }
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_object(&found)?;
+        }
+        Request::GetRootStats => {
+            let entries = match list_entries(storage, profile, conn)? {
+                Some(entries) => entries,
+                None => return Ok(()),
+            };
+            let summaries = entries
+                .iter()
+                .map(|entry| storage.summary(entry))
+                .collect::<Result<Vec<_>>>()?;
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_object(&RootStats::of(&summaries))?;
+        }
+        Request::Subscribe => {
+            // The watcher only covers the profile's own parity root, not those of its users
+            let watches_root = profile.parity_root.get() == shared.profile.parity_root.get();
+            let watcher = match &shared.watcher {
+                Some(watcher) if watches_root => watcher,
+                _ => {
+                    let error = RequestError::new(ErrorCode::UnauthorizedAccess, "Watching is disabled on this server");
+                    conn.send_request_result(error.into())?;
+                    return Ok(());
+                }
+            };
+            let notifications = watcher.subscribe();
+            conn.send_request_result(RequestResult::Ok)?;
+            push_notifications(conn, notifications);
+        }
+        Request::DownloadMatching(pattern) => {
+            let entries = match storage.matching(&pattern) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    let error = RequestError::new(ErrorCode::UnauthorizedAccess, e).with_path(&pattern);
+                    conn.send_request_result(error.into())?;
+                    return Ok(());
+                }
+            };
+            conn.send_request_result(RequestResult::Ok)?;
+            send_entries(shared, storage, who, "download", conn, entries)?;
+        }
+        Request::RedeemToken(token) => {
+            // Tokens always refer to the profile's own parity root, whoever is logged in
+            let storage = parity::open_storage(&shared.profile)?;
+            let storage = storage.as_ref();
+            let entries = match redeem_token(&shared.profile, storage, &token) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    conn.send_request_result(e.into())?;
+                    return Ok(());
+                }
+            };
+            info!("Share token redeemed for {} file(s)", entries.len());
+            conn.send_request_result(RequestResult::Ok)?;
+            send_entries(shared, storage, who, "share", conn, entries)?;
+        }
+        Request::GetChunks(name) => {
+            let entry = match storage.find(&name) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    conn.send_request_result(e.into())?;
+                    return Ok(());
+                }
+            };
+            let manifest = shared.manifests.manifest(storage, &entry)?;
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_object(manifest.as_ref())?;
+        }
+        Request::ReadChunks { name, hashes } => {
+            if hashes.len() > MAX_CHUNKS_PER_REQUEST {
+                let message = format!("At most {} chunks can be read at once, {} were asked for", MAX_CHUNKS_PER_REQUEST, hashes.len());
+                conn.send_request_result(RequestError::new(ErrorCode::IndexOutOfBounds, message).into())?;
+                return Ok(());
+            }
+            let entry = match storage.find(&name) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    conn.send_request_result(e.into())?;
+                    return Ok(());
+                }
+            };
+            let manifest = shared.manifests.manifest(storage, &entry)?;
+            let mut chunks = Vec::with_capacity(hashes.len());
+            for hash in &hashes {
+                let chunk = match manifest.iter().find(|chunk| &chunk.hash == hash) {
+                    Some(chunk) => chunk,
+                    None => {
+                        let error = RequestError::new(ErrorCode::NotFound, format!("No chunk {} in the file, it may have changed", hash));
+                        conn.send_request_result(error.with_path(&name).into())?;
+                        return Ok(());
+                    }
+                };
+                let mut bytes = vec![];
+                storage.open(&entry, chunk.offset)?.take(chunk.length as u64).read_to_end(&mut bytes)?;
+                chunks.push(bytes);
+            }
+            conn.send_request_result(RequestResult::Ok)?;
+            let mut bytes = 0;
+            for chunk in &chunks {
+                conn.send_object(chunk)?;
+                bytes += chunk.len() as u64;
+            }
+            shared.metrics.sent(bytes);
+            shared.audit.record(who, "download-chunks", &name, bytes, &Ok(()));
+        }
+        Request::DownloadDelta { name, signature } => {
+            let entry = match storage.find(&name) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    conn.send_request_result(e.into())?;
+                    return Ok(());
+                }
+            };
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_object(&entry.length)?;
+            let gauge = shared.metrics.transfer();
+            let result = storage
+                .open(&entry, 0)
+                .and_then(|reader| delta::delta(&signature, reader, |op| conn.send_object(&op)));
+            drop(gauge);
+            let bytes = *result.as_ref().unwrap_or(&0);
+            let result = result.map(|_| ());
+            match result {
+                Ok(_) => shared.metrics.sent(bytes),
+                Err(_) => shared.metrics.error(),
+            }
+            shared.audit.record(who, "download-delta", &entry.name, bytes, &result);
+            history::record(Side::Server, &shared.profile.name, who, Direction::Download, &entry.name, bytes, &result);
+            result?;
+        }
+        Request::DownloadByHash(hash) => {
+            let manifest = match storage.manifest() {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    conn.send_request_result(RequestError::new(ErrorCode::Internal, e).into())?;
+                    return Ok(());
+                }
+            };
+            let entry = match manifest.find_hash(&hash) {
+                Some(file) => storage.find(&file.name),
+                None => Err(RequestError::new(ErrorCode::NotFound, format!("No file hashes to {}", hash))),
+            };
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    conn.send_request_result(e.into())?;
+                    return Ok(());
+                }
+            };
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_string(&entry.name)?;
+            audited_send(shared, storage, who, "download", conn, &entry)?;
+        }
+        Request::GetManifest => {
+            let manifest = match storage.manifest() {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    conn.send_request_result(RequestError::new(ErrorCode::Internal, e).into())?;
+                    return Ok(());
+                }
+            };
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_object(&manifest)?;
+        }
+        Request::GetSignedManifest => {
+            if shared.profile.signing_key.is_empty() {
+                let error = RequestError::new(ErrorCode::NotFound, "This server does not sign its manifest");
+                conn.send_request_result(error.into())?;
+                return Ok(());
+            }
+            let signed = storage
+                .manifest()
+                .and_then(|manifest| Ok((signing::sign_manifest(&shared.profile.signing_key, &manifest)?, manifest)));
+            let (signature, manifest) = match signed {
+                Ok(signed) => signed,
+                Err(e) => {
+                    conn.send_request_result(RequestError::new(ErrorCode::Internal, e).into())?;
+                    return Ok(());
+                }
+            };
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_object(&manifest)?;
+            conn.send_object(&signature)?;
+        }
+        Request::Custom { name, payload } => {
+            for interceptor in &shared.interceptors {
+                if interceptor.handle_custom(context, &name, &payload, conn)? {
+                    return Ok(());
+                }
+            }
+            let error = RequestError::new(ErrorCode::NotFound, format!("Unknown request '{}'", name));
+            conn.send_request_result(error.into())?;
+        }
+        Request::UploadFile(name) => handle_upload(shared, context, conn, &name, None, 0)?,
+        Request::UploadSizedFile { name, length } => handle_upload(shared, context, conn, &name, Some(length), 0)?,
+        Request::ResumeUpload { name, offset, length } => {
+            handle_upload(shared, context, conn, &name, Some(length), offset)?
+        }
+        Request::GetUploadOffset(name) => {
+            let offset = match resumable_upload(profile, &name) {
+                Ok(Some(partial)) => fs::metadata(partial).map(|metadata| metadata.len()).unwrap_or(0),
+                _ => 0,
+            };
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_object(&offset)?;
+        }
+        Request::ListVersions(name) => {
+            if !profile.versioning {
+                conn.send_request_result(versioning_disabled().into())?;
+                return Ok(());
+            }
+            let versions = match versions::list(Path::new(profile.parity_root.get()), &name) {
+                Ok(versions) => versions,
+                Err(e) => {
+                    conn.send_request_result(RequestError::new(ErrorCode::UnauthorizedAccess, e).with_path(&name).into())?;
+                    return Ok(());
+                }
+            };
+            conn.send_request_result(RequestResult::Ok)?;
+            conn.send_object(&versions)?;
+        }
+        Request::DownloadVersion { name, version } => {
+            if !profile.versioning {
+                conn.send_request_result(versioning_disabled().into())?;
+                return Ok(());
+            }
+            let path = match versions::path(Path::new(profile.parity_root.get()), &name, &version) {
+                Ok(Some(path)) => path,
+                Ok(None) => {
+                    let error = RequestError::new(ErrorCode::NotFound, format!("No version {} of the file", version));
+                    conn.send_request_result(error.with_path(&name).into())?;
+                    return Ok(());
+                }
+                Err(e) => {
+                    conn.send_request_result(RequestError::new(ErrorCode::UnauthorizedAccess, e).with_path(&name).into())?;
+                    return Ok(());
+                }
+            };
+            // Sent under the file's own name, so it arrives as that file
+            let mut entry = parity::get_file_entry(path)?;
+            entry.name = name;
+            let storage = parity::LocalStorage::new(profile.parity_root.get(), IgnoreRules::default());
+            conn.send_request_result(RequestResult::Ok)?;
+            audited_send(shared, &storage, who, "download-version", conn, &entry)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn versioning_disabled() -> RequestError {
+    RequestError::new(ErrorCode::UnauthorizedAccess, "Versioning is disabled on this server")
+}
+
+/// The files of `storage` in the order of `profile`, or `None` once the client was told why they
+/// couldn't be listed (such as a symlink under the error policy).
+fn list_entries(storage: &dyn Storage, profile: &ServerProfile, conn: &mut Connection) -> Result<Option<Vec<parity::Entry>>> {
+    match storage.entries() {
+        Ok(mut entries) => {
+            parity::sort_entries(&mut entries, profile.sort_by, profile.sort_descending);
+            Ok(Some(entries))
+        }
+        Err(e) => {
+            conn.send_request_result(RequestError::new(ErrorCode::Internal, e).into())?;
+            Ok(None)
+        }
+    }
+}
+
+/// The files a share token grants access to: the shared file, or every file of the shared directory.
+fn redeem_token(
+    profile: &ServerProfile,
+    storage: &dyn Storage,
+    token: &str,
+) -> std::result::Result<Vec<parity::Entry>, RequestError> {
+    let internal = |e: anyhow::Error| RequestError::new(ErrorCode::Internal, e);
+    let shared = share::redeem(&profile.name, token)
+        .map_err(internal)?
+        .ok_or_else(|| RequestError::new(ErrorCode::NotFound, "Unknown or expired share token"))?;
+
+    // Buckets have no directories, so only single files can be shared out of them
+    if profile.storage.is_set() {
+        return storage.find(&shared.path).map(|entry| vec![entry]).map_err(|e| match e.code {
+            ErrorCode::NotFound => RequestError::new(ErrorCode::NotFound, "The shared file no longer exists").with_path(&shared.path),
+            _ => e,
+        });
+    }
+
+    let gone = || RequestError::new(ErrorCode::NotFound, "The shared file no longer exists").with_path(&shared.path);
+    let parity_root = PathBuf::from(profile.parity_root.get()).canonicalize().map_err(|e| internal(e.into()))?;
+    let path = parity::safe_join(&parity_root, &shared.path)
+        .and_then(|path| Ok(path.canonicalize()?))
+        .map_err(|_| gone())?;
+    if !path.starts_with(&parity_root) {
+        return Err(RequestError::new(ErrorCode::UnauthorizedAccess, "Path leads outside of the parity root").with_path(&shared.path));
+    }
+    // Excluded since it was shared
+    let ignore = parity::ignore_rules(profile).map_err(internal)?;
+    let excluded = |path: &Path| ignore.is_ignored(&parity::relative_name(&parity_root, path));
+    if excluded(&path) {
+        return Err(gone());
+    }
+    let shared_path = parity::safe_join(&parity_root, &shared.path).map_err(|_| gone())?;
+    match parity::resolve_links(&parity_root, &shared_path, &ignore) {
+        Ok(Some(_)) => (),
+        Ok(None) => return Err(gone()),
+        Err(e) => return Err(RequestError::new(ErrorCode::UnauthorizedAccess, e).with_path(&shared.path)),
+    }
+
+    match path.is_dir() {
+        true => {
+            let links = IgnoreRules::default().symlinks(ignore.symlink_policy());
+            let mut entries = parity::get_file_entries(path, &links).map_err(internal)?;
+            entries.retain(|entry| !excluded(&entry.path));
+            Ok(entries)
+        }
+        false => parity::get_file_entry(path).map(|entry| vec![entry]).map_err(internal),
+    }
+}
+
+/// Receives an upload of `name`, announced as `length` bytes long by [`Request::UploadSizedFile`],
+/// continuing an interrupted one from `offset` when not zero.
+fn handle_upload(
+    shared: &Shared,
+    context: &RequestContext,
+    conn: &mut Connection,
+    name: &str,
+    length: Option<u64>,
+    offset: u64,
+) -> Result<()> {
+    let (profile, who) = (context.profile, context.who);
+    if offset > 0 {
+        let kept = match resumable_upload(profile, name) {
+            Ok(Some(partial)) => fs::metadata(partial).map(|metadata| metadata.len()).unwrap_or(0),
+            _ => 0,
+        };
+        if kept < offset {
+            let message = format!("Only {} bytes of an interrupted upload were kept, {} were asked for", kept, offset);
+            conn.send_request_result(RequestError::new(ErrorCode::NotFound, message).with_path(name).into())?;
+            return Ok(());
+        }
+    }
+    let allowance = match UploadAllowance::of(profile, name) {
+        Ok(allowance) => allowance,
+        Err(e) => {
+            conn.send_request_result(RequestError::new(ErrorCode::Internal, e).with_path(name).into())?;
+            return Ok(());
+        }
+    };
+    let checked = match length {
+        Some(length) => allowance.check(name, length),
+        // Quotas are enforced before the payload is accepted, which needs its length
+        None if allowance.is_limited() => Err(RequestError::new(
+            ErrorCode::QuotaExceeded,
+            "Uploads are limited on this server, so their length must be announced",
+        )
+        .with_path(name)),
+        None => Ok(()),
+    };
+    if let Err(error) = checked {
+        conn.send_request_result(error.into())?;
+        return Ok(());
+    }
+    let paths = upload_path(profile, name).and_then(|path| match partial_path(profile, &path) {
+        Ok(partial) => Ok((path, partial)),
+        Err(e) => {
+            // Give back the name reserved in the upload root
+            if profile.upload_root.is_set() {
+                let _ = fs::remove_file(&path);
+            }
+            Err(e)
+        }
+    });
+    let (path, partial) = match paths {
+        Ok(paths) => paths,
+        Err(e) => {
+            let error = RequestError::new(ErrorCode::UnauthorizedAccess, e).with_path(name);
+            conn.send_request_result(error.into())?;
+            return Ok(());
+        }
+    };
+    conn.send_request_result(RequestResult::Ok)?;
+    let gauge = shared.metrics.transfer();
+    let result = receive_upload(shared, context, name, conn, (&path, &partial), &allowance, offset);
+    drop(gauge);
+    let bytes = fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+    match result {
+        Ok(_) => shared.metrics.received(bytes),
+        Err(_) => shared.metrics.error(),
+    }
+    shared.audit.record(who, "upload", name, bytes, &result);
+    let transferred = Transferred {
+        bytes,
+        elapsed: conn.take_transfer_stats().map(|stats| stats.elapsed),
+    };
+    history::record(Side::Server, &shared.profile.name, who, Direction::Upload, name, transferred, &result);
+    if let Err(e) = result {
+        // Give back the name reserved in the upload root
+        if profile.upload_root.is_set() {
+            let _ = fs::remove_file(&path);
+        }
+        // Refused by an interceptor or over quota, the connection itself is fine
+        if let Some(error) = e.downcast_ref::<RequestError>() {
+            conn.send_request_result(error.clone().into())?;
+            return Ok(());
+        }
+        return Err(e);
+    }
+    if let Some(parent) = path.parent() {
+        shared.entries.invalidate(parent);
+    }
+    info!("Received '{}' as {:?}", name, path);
+    hooks::fire(&shared.profile.hooks, Event::UploadReceived, &shared.profile.name, json::object! {
+        "file": name.to_string(),
+        "path": path.to_string_lossy().to_string(),
+        "bytes": bytes,
+        "who": who,
+    });
+    conn.send_request_result(RequestResult::Ok)?;
+    Ok(())
+}
+
+/// What a client may still upload under a profile's quotas.
+struct UploadAllowance {
+    max_size: Option<u64>,
+    /// Bytes left in the upload root, counting the file the upload would replace as free unless it
+    /// is kept as a version.
+    remaining: Option<u64>,
+}
+
+impl UploadAllowance {
+    fn of(profile: &ServerProfile, name: &str) -> Result<Self> {
+        let max_size = Some(*profile.max_upload_size.get()).filter(|limit| *limit != 0);
+        let quota = *profile.upload_quota.get();
+        if quota == 0 {
+            return Ok(Self { max_size, remaining: None });
+        }
+        let (root, replaced) = match profile.upload_root.is_set() {
+            // Uploads to an upload root never replace anything
+            true => (profile.upload_root.get(), 0),
+            false => {
+                let existing = parity::safe_join(profile.parity_root.get(), name)?;
+                let length = |path: &Path| fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+                // A replaced file is kept as a version, so it only frees space without versioning
+                let replaced = match profile.versioning {
+                    true => 0,
+                    false => length(&existing),
+                };
+                let partial = partial_path(profile, &existing).map_or(0, |partial| length(&partial));
+                (profile.parity_root.get(), partial + replaced)
+            }
+        };
+        let used = parity::disk_usage(root)?.saturating_sub(replaced);
+        Ok(Self {
+            max_size,
+            remaining: Some(quota.saturating_sub(used)),
+        })
+    }
+
+    /// Whether any quota applies, so uploads of unknown length can't be accepted.
+    fn is_limited(&self) -> bool {
+        self.max_size.is_some() || self.remaining.is_some()
+    }
+
+    fn check(&self, name: &str, length: u64) -> std::result::Result<(), RequestError> {
+        let refuse = |message: String| Err(RequestError::new(ErrorCode::QuotaExceeded, message).with_path(name));
+        if let Some(max_size) = self.max_size.filter(|max_size| length > *max_size) {
+            return refuse(format!("The upload is {} bytes, but uploads are limited to {} bytes", length, max_size));
+        }
+        if let Some(remaining) = self.remaining.filter(|remaining| length > *remaining) {
+            return refuse(format!("The upload is {} bytes, but the upload quota only has {} bytes left", length, remaining));
+        }
+        Ok(())
+    }
+}
+
+/// Where an upload named `name` is stored. Uploads into an upload root never replace an existing
+/// file; the name gets a numbered suffix instead, and the returned path is reserved by an empty
+/// placeholder file. Excluded names are refused, as they would never be listed.
+fn upload_path(profile: &ServerProfile, name: &str) -> Result<PathBuf> {
+    if parity::ignore_rules(profile)?.is_ignored(name) {
+        return Err(anyhow!(format!("'{}' is excluded from the parity root", name)));
+    }
+    if !profile.upload_root.is_set() {
+        return parity::confined_join(profile.parity_root.get(), name);
+    }
+
+    let path = parity::confined_join(profile.upload_root.get(), name)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy()));
+
+    for suffix in 0u32.. {
+        let candidate = match suffix {
+            0 => path.clone(),
+            n => path.with_file_name(format!("{} ({}){}", stem, n, extension.as_deref().unwrap_or(""))),
+        };
+        // Creating the file atomically claims the name, even against concurrent uploads
+        match fs::OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(_) => return Ok(candidate),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    unreachable!()
+}
+
+/// Receives an uploaded file next to `path` and only moves it in place once it arrived complete
+/// and the interceptors accepted it, so a failed upload never leaves a truncated file behind.
+/// Refusals are returned as [`RequestError`]s.
+fn receive_upload(
+    shared: &Shared,
+    context: &RequestContext,
+    name: &str,
+    conn: &mut Connection,
+    (path, partial): (&PathBuf, &PathBuf),
+    allowance: &UploadAllowance,
+    offset: u64,
+) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let checked = conn.read_file_at(partial, offset).and_then(|_| {
+        allowance.check(name, fs::metadata(partial)?.len())?;
+        for interceptor in &shared.interceptors {
+            interceptor.check_upload(context, name, partial)?;
+        }
+        Ok(())
+    });
+    match checked {
+        Ok(_) => {
+            let profile = context.profile;
+            if profile.versioning && !profile.upload_root.is_set() && path.is_file() {
+                let version = versions::archive(Path::new(profile.parity_root.get()), name, path)?;
+                debug!("Kept the previous version of '{}' as {:?}", name, version);
+            }
+            Ok(fs::rename(partial, path)?)
+        }
+        // A lost connection leaves what arrived to be resumed (see: [`resumable_upload`])
+        Err(e) if e.is::<std::io::Error>() && !context.profile.upload_root.is_set() => Err(e),
+        Err(e) => {
+            let _ = fs::remove_file(partial);
+            Err(e)
+        }
+    }
+}
+
+/// Where an upload to `path` is received before it is moved in place. Refused when that leads out
+/// of the root `path` is in, such as through a `.part` symlink left there.
+fn partial_path(profile: &ServerProfile, path: &Path) -> Result<PathBuf> {
+    let root = match profile.upload_root.is_set() {
+        true => profile.upload_root.get(),
+        false => profile.parity_root.get(),
+    };
+    let name = path.strip_prefix(root)?.to_string_lossy();
+    parity::confined_join(root, &format!("{}.part", name))
+}
+
+/// Where an interrupted upload of `name` would have been kept. Uploads into an upload root get a
+/// new name every time, so they are never resumed.
+fn resumable_upload(profile: &ServerProfile, name: &str) -> Result<Option<PathBuf>> {
+    if profile.upload_root.is_set() || parity::ignore_rules(profile)?.is_ignored(name) {
+        return Ok(None);
+    }
+    let path = parity::confined_join(profile.parity_root.get(), name)?;
+    Ok(Some(partial_path(profile, &path)?))
+}
+
+/// Sends a file and records it in the audit log as `action` by `who`, and in the transfer history.
+fn audited_send(
+    shared: &Shared,
+    storage: &dyn Storage,
+    who: &str,
+    action: &str,
+    conn: &mut Connection,
+    entry: &parity::Entry,
+) -> Result<()> {
+    audited_send_rest(shared, storage, who, action, conn, entry, 0)
+}
+
+/// Like [`audited_send`], only sending what comes after the first `offset` bytes of `entry`.
+fn audited_send_rest(
+    shared: &Shared,
+    storage: &dyn Storage,
+    who: &str,
+    action: &str,
+    conn: &mut Connection,
+    entry: &parity::Entry,
+    offset: u64,
+) -> Result<()> {
+    let rest = parity::Entry {
+        length: entry.length - offset,
+        ..entry.clone()
+    };
+    let gauge = shared.metrics.transfer();
+    let result = conn.send_from(&rest, || storage.open(entry, offset));
+    drop(gauge);
+    let elapsed = conn.take_transfer_stats().map(|stats| stats.elapsed);
+    record_sent(shared, who, action, &entry.name, entry.length, elapsed, &result);
+    result
+}
+
+/// Sends small files together in one frame (see: [`Connection::send_batch`]), recording each like
+/// [`audited_send`].
+fn audited_send_batch(shared: &Shared, who: &str, action: &str, conn: &mut Connection, files: &[BatchedFile]) -> Result<()> {
+    let gauge = shared.metrics.transfer();
+    let result = conn.send_batch(files);
+    drop(gauge);
+    conn.take_transfer_stats();
+    for file in files {
+        let outcome = result.as_ref().map(|_| ()).map_err(|e| anyhow!(e.to_string()));
+        record_sent(shared, who, action, &file.name, file.contents.len() as u64, None, &outcome);
+    }
+    result
+}
+
+/// Records a file sent to `who` in the metrics, audit log and transfer history, and fires the hooks
+/// for it.
+fn record_sent(
+    shared: &Shared,
+    who: &str,
+    action: &str,
+    name: &str,
+    bytes: u64,
+    elapsed: Option<Duration>,
+    result: &Result<()>,
+) {
+    match result {
+        Ok(_) => shared.metrics.sent(bytes),
+        Err(_) => shared.metrics.error(),
+    }
+    shared.audit.record(who, action, name, bytes, result);
+    let profile = &shared.profile.name;
+    let transferred = Transferred { bytes, elapsed };
+    history::record(Side::Server, profile, who, Direction::Download, name, transferred, result);
+    if result.is_ok() {
+        hooks::fire(&shared.profile.hooks, Event::DownloadServed, profile, json::object! {
+            "file": name.to_string(),
+            "bytes": bytes,
+            "who": who,
+        });
+    }
+}
+
+/// Streams `entries` as a count followed by a name and file per entry, waiting for the client to
+/// acknowledge each file. Every file is audited as `action` by `who`.
+///
+/// With [`Capability::Batch`], the entries go in groups, each preceded by how many files it holds:
+/// a single file goes as above, while several small ones go as one batch acknowledged once.
+fn send_entries(
+    shared: &Shared,
+    storage: &dyn Storage,
+    who: &str,
+    action: &str,
+    conn: &mut Connection,
+    entries: Vec<parity::Entry>,
+) -> Result<()> {
+    conn.send_count(entries.len() as u32)?;
+    let batching = conn.supports(Capability::Batch);
+    let small = |entry: &parity::Entry| entry.length <= BATCH_FILE_THRESHOLD;
+
+    let mut entries = entries.into_iter().peekable();
+    while let Some(entry) = entries.next() {
+        let mut group = vec![entry];
+        if batching && small(&group[0]) {
+            let mut bytes = group[0].length;
+            while let Some(entry) = entries.next_if(|entry| small(entry) && bytes + entry.length <= MAX_BATCH_BYTES) {
+                bytes += entry.length;
+                group.push(entry);
+            }
+        }
+
+        if group.len() > 1 {
+            match read_batch(storage, &group) {
+                Ok(files) => {
+                    conn.send_count(files.len() as u32)?;
+                    audited_send_batch(shared, who, action, conn, &files)?;
+                    conn.read_request_result()?;
+                    continue;
+                }
+                // Sent one by one instead, so that the file it concerns reports it
+                Err(e) => debug!("Not batching {} file(s): {}", group.len(), e),
+            }
+        }
+        for entry in group {
+            if batching {
+                conn.send_count(1)?;
+            }
+            conn.send_string(&entry.name)?;
+            audited_send(shared, storage, who, action, conn, &entry)?;
+            conn.read_request_result()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The contents of `entries`, read in full for [`audited_send_batch`].
+fn read_batch(storage: &dyn Storage, entries: &[parity::Entry]) -> Result<Vec<BatchedFile>> {
+    let mut files = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let mut contents = Vec::with_capacity(entry.length as usize);
+        storage.open(entry, 0)?.read_to_end(&mut contents)?;
+        files.push(BatchedFile {
+            name: entry.name.clone(),
+            contents,
+        });
+    }
+    Ok(files)
+}
+
+/// Forwards `notifications` to a subscribed client until it hangs up, filling silences with
+/// heartbeats.
+fn push_notifications(conn: &mut Connection, notifications: Receiver<Notification>) {
+    loop {
+        let notification = match notifications.recv_timeout(KEEP_ALIVE_INTERVAL) {
+            Ok(notification) => notification,
+            Err(RecvTimeoutError::Timeout) => Notification::Heartbeat,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if conn.send_object(&notification).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts;
+
+    /// An account whose password is hashed with few rounds, as hashing itself is tested in
+    /// [`crate::accounts`].
+    fn account(name: &str, password: &str, parity_root: &str, mode: ServerMode) -> UserAccount {
+        let salt = accounts::new_salt().unwrap();
+        UserAccount {
+            name: name.to_string(),
+            password_hash: accounts::hash_password(&salt, password, 1),
+            rounds: 1,
+            salt,
+            parity_root: ValidatedDirectory::new(parity_root.to_string()),
+            mode,
+            max_upload_size: 0,
+            upload_quota: 0,
+        }
+    }
+
+    #[test]
+    fn users_are_scoped_to_their_own_root_and_mode() {
+        let mut profile = Server::builder().profile;
+        profile.mode = ServerMode::ReadWrite;
+        profile.upload_root.set("/srv/uploads".to_string());
+        let mut bob = account("bob", "bob's", "/srv/bob", ServerMode::DropBox);
+        bob.upload_quota = 1 << 20;
+        profile.users = vec![account("alice", "alice's", "/srv/alice", ServerMode::ReadOnly), bob];
+
+        let alice = user_scope(&profile, "alice", "alice's").unwrap();
+        assert_eq!(alice.parity_root.get(), "/srv/alice");
+        assert_eq!(alice.mode, ServerMode::ReadOnly);
+        assert_eq!(Path::new(alice.upload_root.get()), Path::new("/srv/uploads").join("alice"));
+        assert_eq!(*alice.upload_quota.get(), 0);
+
+        let bob = user_scope(&profile, "bob", "bob's").unwrap();
+        assert_eq!(bob.parity_root.get(), "/srv/bob");
+        assert_eq!(bob.mode, ServerMode::DropBox);
+        assert_eq!(*bob.upload_quota.get(), 1 << 20);
+
+        for (user, password) in [("alice", "bob's"), ("alice", ""), ("carol", "alice's"), ("", "")] {
+            let error = user_scope(&profile, user, password).unwrap_err();
+            assert_eq!(error.code, ErrorCode::Unauthenticated);
+        }
+    }
+
+    #[test]
+    fn modes_refuse_what_they_do_not_allow() {
+        let upload = Request::UploadFile("a.txt".to_string());
+        let download = Request::DownloadFileByName("a.txt".to_string());
+        let refused = |mode, request: &Request| refusal(mode, request).map(|error| error.code);
+
+        assert_eq!(refused(ServerMode::ReadWrite, &upload), None);
+        assert_eq!(refused(ServerMode::ReadWrite, &download), None);
+        assert_eq!(refused(ServerMode::ReadOnly, &upload), Some(ErrorCode::UnauthorizedAccess));
+        assert_eq!(refused(ServerMode::ReadOnly, &download), None);
+        assert_eq!(refused(ServerMode::DropBox, &upload), None);
+        assert_eq!(refused(ServerMode::DropBox, &download), Some(ErrorCode::UnauthorizedAccess));
+        assert_eq!(refused(ServerMode::DropBox, &Request::ListFiles), Some(ErrorCode::UnauthorizedAccess));
+    }
+}
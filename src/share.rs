@@ -0,0 +1,206 @@
+//! Share links: tokens that let anyone holding them download one file or directory of a server's
+//! parity root, without a profile or an account.
+//!
+//! Tokens are minted from the server menu and kept per server profile in
+//! `oxideux/share_tokens.json` under the config directory, so they survive restarts. A link has
+//! the form `oxideux://host:port/token` (see: [`link`] and [`parse_link`]).
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+
+use crate::config;
+
+const TOKENS_FILE: &str = "oxideux/share_tokens.json";
+const LINK_SCHEME: &str = "oxideux://";
+
+/// Held while the tokens file is read, changed and written back, so a single-use token can't be
+/// redeemed twice by concurrent connections.
+static TOKENS_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareToken {
+    pub token: String,
+    /// The shared file or directory, relative to the parity root.
+    pub path: String,
+    /// Seconds since the unix epoch after which the token stops working, if it ever does.
+    pub expires: Option<u64>,
+    /// Whether the token stops working once redeemed.
+    pub single_use: bool,
+}
+
+impl ShareToken {
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| now() >= expires)
+    }
+
+    /// When the token expires, for display.
+    pub fn expiry(&self) -> String {
+        match self.expires {
+            Some(expires) => humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(expires)).to_string(),
+            None => "never".to_string(),
+        }
+    }
+}
+
+/// Creates a token for `path` and stores it under `profile_name`.
+pub fn mint(profile_name: &str, path: &str, lifetime: Option<Duration>, single_use: bool) -> Result<ShareToken> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!(format!("Could not generate a token: {}", e)))?;
+
+    let token = ShareToken {
+        token: bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        path: path.to_string(),
+        expires: lifetime.map(|lifetime| now() + lifetime.as_secs()),
+        single_use,
+    };
+
+    let _lock = TOKENS_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut root = read_root()?;
+    let mut tokens = tokens_of(&root, profile_name);
+    tokens.push(token.clone());
+    write_tokens(&mut root, profile_name, &tokens)?;
+    Ok(token)
+}
+
+/// The tokens of `profile_name` that still work.
+pub fn list(profile_name: &str) -> Result<Vec<ShareToken>> {
+    Ok(tokens_of(&read_root()?, profile_name)
+        .into_iter()
+        .filter(|token| !token.is_expired())
+        .collect())
+}
+
+pub fn revoke(profile_name: &str, token: &str) -> Result<()> {
+    let _lock = TOKENS_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut root = read_root()?;
+    let tokens: Vec<ShareToken> = tokens_of(&root, profile_name)
+        .into_iter()
+        .filter(|candidate| candidate.token != token)
+        .collect();
+    write_tokens(&mut root, profile_name, &tokens)
+}
+
+/// Looks `token` up, consuming it if it is single-use. Returns `None` for unknown or expired tokens.
+///
+/// Expired tokens are pruned along the way.
+pub fn redeem(profile_name: &str, token: &str) -> Result<Option<ShareToken>> {
+    let _lock = TOKENS_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut root = read_root()?;
+    let mut tokens: Vec<ShareToken> = tokens_of(&root, profile_name)
+        .into_iter()
+        .filter(|candidate| !candidate.is_expired())
+        .collect();
+
+    let found = tokens.iter().position(|candidate| candidate.token == token);
+    let redeemed = match found {
+        Some(index) if tokens[index].single_use => Some(tokens.remove(index)),
+        Some(index) => Some(tokens[index].clone()),
+        None => None,
+    };
+    write_tokens(&mut root, profile_name, &tokens)?;
+    Ok(redeemed)
+}
+
+/// Formats a share link.
+pub fn link(host: &str, port: u16, token: &str) -> String {
+    format!("{}{}:{}/{}", LINK_SCHEME, host, port, token)
+}
+
+/// Splits a share link into its host, port and token.
+pub fn parse_link(link: &str) -> Result<(String, u16, String)> {
+    let invalid = || anyhow!(format!("Invalid share link, expected {}host:port/token", LINK_SCHEME));
+    let rest = link.strip_prefix(LINK_SCHEME).ok_or_else(invalid)?;
+    let (address, token) = rest.split_once('/').ok_or_else(invalid)?;
+    let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+    if host.is_empty() || token.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host.to_string(), port, token.to_string()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+fn tokens_path() -> Result<PathBuf> {
+    config::config_dir_ext(TOKENS_FILE)
+}
+
+fn read_root() -> Result<json::JsonValue> {
+    let path = tokens_path()?;
+    if !path.exists() {
+        return Ok(json::object! {});
+    }
+    Ok(json::parse(&fs::read_to_string(path)?)?)
+}
+
+fn tokens_of(root: &json::JsonValue, profile_name: &str) -> Vec<ShareToken> {
+    root[profile_name]
+        .members()
+        .filter_map(|token| {
+            Some(ShareToken {
+                token: token["token"].as_str()?.to_string(),
+                path: token["path"].as_str()?.to_string(),
+                expires: token["expires"].as_u64(),
+                single_use: token["single_use"].as_bool().unwrap_or(false),
+            })
+        })
+        .collect()
+}
+
+fn write_tokens(root: &mut json::JsonValue, profile_name: &str, tokens: &[ShareToken]) -> Result<()> {
+    let mut array = json::JsonValue::new_array();
+    for token in tokens {
+        array.push(json::object! {
+            "token": token.token.clone(),
+            "path": token.path.clone(),
+            "expires": token.expires,
+            "single_use": token.single_use,
+        })?;
+    }
+    root[profile_name] = array;
+
+    let path = tokens_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, root.dump())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    #[test]
+    fn single_use_token_is_redeemed_once() {
+        let config = std::env::temp_dir().join(format!("oxideux-share-test-{}", std::process::id()));
+        std::env::set_var("XDG_CONFIG_HOME", &config);
+
+        let token = mint("test", "file.txt", None, true).unwrap();
+        let barrier = Arc::new(Barrier::new(8));
+        let redeemers: Vec<_> = (0..8)
+            .map(|_| {
+                let (barrier, token) = (barrier.clone(), token.token.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    redeem("test", &token).unwrap()
+                })
+            })
+            .collect();
+        let redeemed: Vec<_> = redeemers.into_iter().filter_map(|redeemer| redeemer.join().unwrap()).collect();
+        let _ = fs::remove_dir_all(&config);
+
+        assert_eq!(redeemed, vec![token]);
+    }
+}
@@ -0,0 +1,113 @@
+//! Ed25519 signatures over a server's [`Manifest`], so clients can tell the files they download
+//! really come from that server rather than only that they arrived intact.
+//!
+//! A server with a signing key answers [`crate::request::Request::GetSignedManifest`] with its
+//! manifest and a detached signature of it. Clients pinning the matching public key check the
+//! signature, then the SHA-256 of every file they download against the signed manifest. Keys are
+//! kept hex-encoded: the 32-byte seed on the server, the 32-byte public key on clients.
+
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+use crate::parity::Manifest;
+use crate::secrets::{hex, unhex};
+
+/// Put before the manifest when signing, so a signature can't be passed off as one of something
+/// else made with the same key.
+const CONTEXT: &[u8] = b"oxideux manifest v1\0";
+
+/// A fresh signing key, hex-encoded.
+pub fn generate_key() -> Result<String> {
+    let mut seed = [0u8; 32];
+    getrandom::getrandom(&mut seed).map_err(|e| anyhow!(format!("Could not generate a key: {}", e)))?;
+    Ok(hex(&seed))
+}
+
+/// The public key clients pin to trust what `signing_key` signs, hex-encoded.
+pub fn public_key(signing_key: &str) -> Result<String> {
+    Ok(hex(parse_signing_key(signing_key)?.verifying_key().as_bytes()))
+}
+
+/// Fails unless `public_key` is a hex-encoded Ed25519 public key.
+pub fn check_public_key(public_key: &str) -> Result<()> {
+    parse_public_key(public_key).map(|_| ())
+}
+
+/// The detached signature of `manifest` made with `signing_key`.
+pub fn sign_manifest(signing_key: &str, manifest: &Manifest) -> Result<Vec<u8>> {
+    let message = message(manifest)?;
+    Ok(parse_signing_key(signing_key)?.sign(&message).to_bytes().to_vec())
+}
+
+/// Fails unless `signature` is of `manifest`, made with the signing key of `public_key`.
+pub fn verify_manifest(public_key: &str, manifest: &Manifest, signature: &[u8]) -> Result<()> {
+    let signature = Signature::from_slice(signature).map_err(|_| anyhow!("Malformed manifest signature"))?;
+    parse_public_key(public_key)?
+        .verify_strict(&message(manifest)?, &signature)
+        .map_err(|_| anyhow!("The manifest's signature does not match the pinned public key"))
+}
+
+fn message(manifest: &Manifest) -> Result<Vec<u8>> {
+    let mut message = CONTEXT.to_vec();
+    message.extend(bincode::serialize(manifest)?);
+    Ok(message)
+}
+
+fn parse_signing_key(signing_key: &str) -> Result<SigningKey> {
+    let seed: [u8; 32] = unhex(signing_key)?
+        .try_into()
+        .map_err(|_| anyhow!("A signing key is 64 hex digits"))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+fn parse_public_key(public_key: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = unhex(public_key)?
+        .try_into()
+        .map_err(|_| anyhow!("A public key is 64 hex digits"))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| anyhow!("Not an Ed25519 public key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parity::FileInfo;
+
+    fn manifest() -> Manifest {
+        Manifest {
+            generated: 1_700_000_000,
+            files: vec![FileInfo {
+                name: "release.tar.gz".to_string(),
+                length: 1024,
+                modified: Some(1_700_000_000),
+                hash: "ab".repeat(32),
+            }],
+        }
+    }
+
+    #[test]
+    fn signatures_only_hold_for_what_was_signed() {
+        let signing_key = generate_key().unwrap();
+        let pinned = public_key(&signing_key).unwrap();
+        let signature = sign_manifest(&signing_key, &manifest()).unwrap();
+        assert!(verify_manifest(&pinned, &manifest(), &signature).is_ok());
+
+        let mut swapped = manifest();
+        swapped.files[0].hash = "cd".repeat(32);
+        let mut grown = manifest();
+        grown.files[0].length += 1;
+        let mut added = manifest();
+        added.files.push(FileInfo {
+            name: "extra".to_string(),
+            ..manifest().files[0].clone()
+        });
+        for modified in [swapped, grown, added] {
+            assert!(verify_manifest(&pinned, &modified, &signature).is_err());
+        }
+
+        let mut forged = signature.clone();
+        forged[0] ^= 1;
+        assert!(verify_manifest(&pinned, &manifest(), &forged).is_err());
+        let other_key = public_key(&generate_key().unwrap()).unwrap();
+        assert!(verify_manifest(&other_key, &manifest(), &signature).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+//! The byte streams a [`crate::connection::Connection`] can run over.
+//!
+//! The protocol only needs an ordered, reliable stream, so a plain [`TcpStream`] is the usual
+//! transport; [`crate::websocket::WsTransport`] carries the same bytes in WebSocket frames for
+//! clients that can only speak WebSocket, such as browsers.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// A stream the protocol can be spoken over. Mirrors the parts of [`TcpStream`] that
+/// [`crate::connection::Connection`] relies on.
+pub trait Transport: Read + Write + Send {
+    /// Another handle to the same stream, e.g. to read and write from separate buffers.
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
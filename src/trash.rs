@@ -0,0 +1,108 @@
+//! Previous versions of local files, kept instead of being destroyed when a sync replaces or
+//! removes them.
+//!
+//! Files go into the trash directory under their path relative to the parity root, with the moment
+//! they were trashed before the extension: `reports/q3 (2026-10-16 03-58-00).pdf`. Their
+//! modification time is set to that moment too, which is what [`Trash::prune`] goes by. The
+//! default trash directory, [`TRASH_DIR`] at the top of the parity root, is never listed or synced.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+
+use crate::parity;
+
+/// Where previous versions are kept in the parity root, unless the profile names a directory.
+pub const TRASH_DIR: &str = ".oxideux-trash";
+
+#[derive(Debug, Clone)]
+pub struct Trash {
+    dir: PathBuf,
+    /// How long trashed files are kept, `None` for as long as nobody removes them.
+    max_age: Option<Duration>,
+}
+
+impl Trash {
+    pub fn new<P: AsRef<Path>>(dir: P, max_age: Option<Duration>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            max_age,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Moves the file at `path`, the local copy of `name`, into the trash.
+    pub fn move_in(&self, path: &Path, name: &str) -> Result<PathBuf> {
+        let target = self.target(name)?;
+        // Renaming fails across file systems, where the file has to be copied instead
+        if fs::rename(path, &target).is_err() {
+            fs::copy(path, &target)?;
+            fs::remove_file(path)?;
+        }
+        stamp(&target)?;
+        Ok(target)
+    }
+
+    /// Copies the file at `path`, the local copy of `name`, into the trash, leaving it in place so
+    /// that a download replacing it can still reuse what it holds.
+    pub fn copy_in(&self, path: &Path, name: &str) -> Result<PathBuf> {
+        let target = self.target(name)?;
+        fs::copy(path, &target)?;
+        stamp(&target)?;
+        Ok(target)
+    }
+
+    /// Deletes the trashed files older than the maximum age, returning how many there were.
+    pub fn prune(&self) -> Result<usize> {
+        let max_age = match self.max_age {
+            Some(max_age) if self.dir.is_dir() => max_age,
+            _ => return Ok(0),
+        };
+        let now = SystemTime::now();
+        let mut removed = 0;
+        let mut dirs = vec![self.dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                    continue;
+                }
+                let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+                if age > max_age {
+                    fs::remove_file(entry.path())?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// A free path in the trash for the current version of `name`.
+    fn target(&self, name: &str) -> Result<PathBuf> {
+        let path = parity::safe_join(&self.dir, name)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+        file_name.push(format!(" ({})", parity::name_timestamp(SystemTime::now())));
+        if let Some(extension) = path.extension() {
+            file_name.push(".");
+            file_name.push(extension);
+        }
+        Ok(parity::keep_both_path(path.with_file_name(file_name)))
+    }
+}
+
+/// Marks `path` as trashed now, for [`Trash::prune`].
+fn stamp(path: &Path) -> Result<()> {
+    File::options().write(true).open(path)?.set_modified(SystemTime::now())?;
+    Ok(())
+}
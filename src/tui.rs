@@ -0,0 +1,462 @@
+//! A full-screen view of the client, started with `client --tui`: the profiles, the files on the
+//! server of the one picked, and the transfers going on, with their progress live.
+//!
+//! It's drawn with `ratatui` on the terminal's alternate screen, taken over through `crossterm`, so
+//! whatever was on the terminal is back once it's closed. Keys:
+//!
+//! - up and down pick a profile or a file, and tab goes from one panel to the other
+//! - enter lists the files of the profile picked, or downloads the file picked
+//! - `s` syncs the profile as its sync mode says, skipping conflicts that are up to the user
+//! - `r` lists the files again, and `q` quits
+
+use std::io::{self, IsTerminal, Stdout};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use crossterm::cursor::{Hide, Show};
+use crossterm::event::{self, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use log::Level;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{Frame, Terminal};
+
+use crate::cli;
+use crate::client::Client;
+use crate::config::{self, ClientProfile, SyncMode};
+use crate::connection::TransferEvent;
+use crate::logging;
+use crate::meter::format_size;
+use crate::parity::{self, EntrySummary};
+use crate::two_way::Resolution;
+
+/// How often the screen is drawn again while nothing happens, which also picks up a resized
+/// terminal.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+/// How often a transfer's progress is passed on to be drawn.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+/// How many transfers the transfers panel shows, the latest ones.
+const TRANSFER_ROWS: usize = 5;
+/// How wide the profiles panel is at most.
+const PROFILES_WIDTH: u16 = 28;
+const KEYS: &str = "↑↓ pick  tab switch  enter list/download  s sync  r refresh  q quit";
+
+enum Event {
+    Key(KeyEvent),
+    /// The terminal can no longer be read from.
+    Closed,
+    /// The files of the profile named, or why they couldn't be listed.
+    Listed(String, Result<Vec<EntrySummary>>),
+    Transfer(TransferRow),
+    /// A download or sync is over, with what to say about it.
+    Done(Result<String>),
+    Log(Level, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TransferState {
+    Active,
+    Completed,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+struct TransferRow {
+    name: String,
+    transferred: u64,
+    length: u64,
+    /// Bytes per second, recently while active and on average once completed.
+    speed: f64,
+    state: TransferState,
+}
+
+impl TransferRow {
+    fn describe(&self) -> String {
+        let percent = match self.length {
+            0 => 100,
+            length => self.transferred * 100 / length,
+        };
+        match &self.state {
+            TransferState::Active => format!(
+                "{:>3}%  {} of {} at {}/s  {}",
+                percent,
+                format_size(self.transferred),
+                format_size(self.length),
+                format_size(self.speed as u64),
+                self.name
+            ),
+            TransferState::Completed => format!(
+                "done  {} at {}/s  {}",
+                format_size(self.length),
+                format_size(self.speed as u64),
+                self.name
+            ),
+            TransferState::Failed(error) => format!("failed  {}: {}", self.name, error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Panel {
+    Profiles,
+    Files,
+}
+
+/// Takes the terminal over until dropped, even when leaving on an error.
+struct Screen {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+}
+
+impl Screen {
+    fn enter() -> Result<Self> {
+        if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+            return Err(anyhow!("The TUI needs a terminal"));
+        }
+        let terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+        terminal::enable_raw_mode().map_err(|e| anyhow!(format!("Could not take over the terminal: {}", e)))?;
+        let mut screen = Self { terminal };
+        execute!(screen.terminal.backend_mut(), EnterAlternateScreen, Hide)?;
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        logging::divert_terminal(None);
+        let _ = execute!(self.terminal.backend_mut(), Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+struct View {
+    profiles: Vec<String>,
+    profile: usize,
+    /// The profile the files listed are of.
+    listed: Option<String>,
+    files: Vec<EntrySummary>,
+    file: usize,
+    panel: Panel,
+    /// The latest transfers, oldest first.
+    transfers: Vec<TransferRow>,
+    status: String,
+    /// Whether a listing, download or sync is under way, only one being run at a time.
+    busy: bool,
+}
+
+/// Runs the TUI until `q` is pressed.
+pub fn run() -> Result<()> {
+    let profiles = config::client::get_profile_names()?;
+    let mut screen = Screen::enter()?;
+
+    let (sender, events) = mpsc::channel();
+    let log_sender = sender.clone();
+    logging::divert_terminal(Some(Box::new(move |level, line| {
+        let _ = log_sender.send(Event::Log(level, line));
+    })));
+    let key_sender = sender.clone();
+    thread::spawn(move || loop {
+        let event = match event::read() {
+            Ok(event::Event::Key(key)) if key.kind == KeyEventKind::Press => Event::Key(key),
+            Ok(_) => continue,
+            Err(_) => Event::Closed,
+        };
+        let closed = matches!(event, Event::Closed);
+        if key_sender.send(event).is_err() || closed {
+            break;
+        }
+    });
+
+    let mut view = View {
+        profiles,
+        profile: 0,
+        listed: None,
+        files: vec![],
+        file: 0,
+        panel: Panel::Profiles,
+        transfers: vec![],
+        status: String::new(),
+        busy: false,
+    };
+    match view.profiles.is_empty() {
+        true => view.status = "No profiles yet, create one with 'client' first".to_string(),
+        false => view.list(&sender),
+    }
+
+    loop {
+        screen.terminal.draw(|frame| view.draw(frame))?;
+        let mut event = match events.recv_timeout(REDRAW_INTERVAL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        // Everything that piled up is taken in before drawing again
+        loop {
+            if !view.handle(event, &sender) {
+                return Ok(());
+            }
+            event = match events.try_recv() {
+                Ok(event) => event,
+                Err(_) => break,
+            };
+        }
+    }
+}
+
+impl View {
+    /// Takes `event` in, returning whether to carry on.
+    fn handle(&mut self, event: Event, sender: &Sender<Event>) -> bool {
+        match event {
+            Event::Closed => return false,
+            Event::Key(key) if key.code == KeyCode::Char('q') => return false,
+            Event::Key(key) if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            Event::Key(key) => self.press(key.code, sender),
+            Event::Listed(name, result) => {
+                self.busy = false;
+                match result {
+                    Ok(files) => {
+                        self.status = format!("{} file(s) on the server of {}", files.len(), name);
+                        self.files = files;
+                        self.file = 0;
+                        self.listed = Some(name);
+                    }
+                    Err(e) => self.status = format!("Could not list the files of {}: {}", name, e),
+                }
+            }
+            Event::Transfer(row) => {
+                let active = self
+                    .transfers
+                    .iter_mut()
+                    .rev()
+                    .find(|known| known.name == row.name && known.state == TransferState::Active);
+                match active {
+                    Some(known) if matches!(row.state, TransferState::Failed(_)) => known.state = row.state,
+                    Some(known) => *known = row,
+                    None => self.transfers.push(row),
+                }
+                let excess = self.transfers.len().saturating_sub(TRANSFER_ROWS);
+                self.transfers.drain(..excess);
+            }
+            Event::Done(result) => {
+                self.busy = false;
+                self.status = match result {
+                    Ok(message) => message,
+                    Err(e) => e.to_string(),
+                };
+            }
+            Event::Log(Level::Error | Level::Warn, line) => self.status = line,
+            Event::Log(Level::Info, line) if !self.busy => self.status = line,
+            Event::Log(..) => {}
+        }
+        true
+    }
+
+    fn press(&mut self, key: KeyCode, sender: &Sender<Event>) {
+        let (selected, count) = match self.panel {
+            Panel::Profiles => (&mut self.profile, self.profiles.len()),
+            Panel::Files => (&mut self.file, self.files.len()),
+        };
+        match key {
+            KeyCode::Up => *selected = selected.saturating_sub(1),
+            KeyCode::Down => *selected = (*selected + 1).min(count.saturating_sub(1)),
+            KeyCode::Home => *selected = 0,
+            KeyCode::End => *selected = count.saturating_sub(1),
+            KeyCode::Tab | KeyCode::BackTab | KeyCode::Left | KeyCode::Right => {
+                self.panel = match self.panel {
+                    Panel::Profiles => Panel::Files,
+                    Panel::Files => Panel::Profiles,
+                }
+            }
+            KeyCode::Enter if self.panel == Panel::Profiles => self.list(sender),
+            KeyCode::Enter => self.download(sender),
+            KeyCode::Char('r') => self.list(sender),
+            KeyCode::Char('s') => self.sync(sender),
+            _ => {}
+        }
+    }
+
+    /// Runs `job` on a thread of its own, unless another one is still running.
+    fn start<F: FnOnce(Sender<Event>) + Send + 'static>(&mut self, sender: &Sender<Event>, what: String, job: F) {
+        if self.busy {
+            self.status = "Busy, wait for it to finish".to_string();
+            return;
+        }
+        self.busy = true;
+        self.status = what;
+        let sender = sender.clone();
+        thread::spawn(move || job(sender));
+    }
+
+    fn list(&mut self, sender: &Sender<Event>) {
+        let name = match self.profiles.get(self.profile) {
+            Some(name) => name.clone(),
+            None => return,
+        };
+        self.start(sender, format!("Listing the files of {}…", name), move |sender| {
+            let result = config::client::get_profile(&name).and_then(|profile| {
+                let mut client = Client::connect(&profile)?;
+                let files = client.list();
+                let _ = client.disconnect();
+                files
+            });
+            let _ = sender.send(Event::Listed(name, result));
+        });
+    }
+
+    fn download(&mut self, sender: &Sender<Event>) {
+        let (profile_name, name) = match (&self.listed, self.files.get(self.file)) {
+            (Some(profile_name), Some(file)) => (profile_name.clone(), file.name.clone()),
+            _ => return,
+        };
+        self.start(sender, format!("Downloading {}…", name), move |sender| {
+            let result = config::client::get_profile(&profile_name).and_then(|profile| {
+                let mut client = connect(&profile, &sender)?;
+                let dest = parity::safe_join(profile.local_root(), &name)?;
+                let result = client.download(&name, &dest);
+                let _ = client.disconnect();
+                result.map(|_| format!("Downloaded {} to {}", name, dest.display()))
+            });
+            let _ = sender.send(Event::Done(result));
+        });
+    }
+
+    fn sync(&mut self, sender: &Sender<Event>) {
+        let name = match self.profiles.get(self.profile) {
+            Some(name) => name.clone(),
+            None => return,
+        };
+        self.start(sender, format!("Syncing {}…", name), move |sender| {
+            let result = config::client::get_profile(&name).and_then(|profile| {
+                let mut client = connect(&profile, &sender)?;
+                let result = sync(&mut client, &profile);
+                let summary = client.take_summary();
+                let _ = client.disconnect();
+                result.map(|_| format!("Synced {}: {}", name, summary.describe()))
+            });
+            let _ = sender.send(Event::Done(result));
+        });
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [title, body, transfers, status] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(TRANSFER_ROWS as u16 + 1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [profiles, files] =
+            Layout::horizontal([Constraint::Length(PROFILES_WIDTH.min(body.width / 3)), Constraint::Min(0)]).areas(body);
+
+        let reversed = Style::new().add_modifier(Modifier::REVERSED);
+        frame.render_widget(Paragraph::new(format!(" oxideux  {}", KEYS)).style(reversed), title);
+
+        let files_title = match &self.listed {
+            Some(name) => format!(" Files of {}", name),
+            None => " Files".to_string(),
+        };
+        let file_rows: Vec<String> = self
+            .files
+            .iter()
+            .map(|file| format!("{:>10}  {}", format_size(file.length), file.name))
+            .collect();
+        let profiles_block = Block::new().borders(Borders::RIGHT).title(" Profiles");
+        self.draw_panel(frame, profiles, profiles_block, &self.profiles, self.profile, Panel::Profiles);
+        self.draw_panel(frame, files, Block::new().title(files_title), &file_rows, self.file, Panel::Files);
+
+        let error = match cli::colors() {
+            true => Style::new().fg(Color::Red),
+            false => Style::new(),
+        };
+        let rows: Vec<ListItem> = self
+            .transfers
+            .iter()
+            .map(|row| match row.state {
+                TransferState::Failed(_) => ListItem::new(format!(" {}", row.describe())).style(error),
+                _ => ListItem::new(format!(" {}", row.describe())),
+            })
+            .collect();
+        frame.render_widget(List::new(rows).block(Block::new().borders(Borders::TOP).title("─ Transfers ")), transfers);
+
+        frame.render_widget(Paragraph::new(Line::from(format!(" {}", self.status))), status);
+    }
+
+    /// Lists `items` in `area`, scrolled so `selected` shows, and standing out when `panel` has the
+    /// focus.
+    fn draw_panel(&self, frame: &mut Frame, area: Rect, block: Block, items: &[String], selected: usize, panel: Panel) {
+        let highlight = match self.panel == panel {
+            true => Style::new().add_modifier(Modifier::REVERSED),
+            false => Style::new(),
+        };
+        let list = List::new(items.iter().map(String::as_str))
+            .block(block)
+            .highlight_style(highlight)
+            .highlight_symbol("> ");
+        let mut state = ListState::default().with_selected(Some(selected).filter(|_| !items.is_empty()));
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+}
+
+fn connect(profile: &ClientProfile, sender: &Sender<Event>) -> Result<Client> {
+    let mut client = Client::connect(profile)?;
+    let sender = sender.clone();
+    let mut sent: Option<Instant> = None;
+    client.set_transfer_handler(move |event| {
+        let row = match event {
+            TransferEvent::Started { name, length } => TransferRow {
+                name: name.to_string(),
+                transferred: 0,
+                length: *length,
+                speed: 0.0,
+                state: TransferState::Active,
+            },
+            TransferEvent::Progressed { name, transferred, length, stats } => {
+                if sent.is_some_and(|sent| sent.elapsed() < PROGRESS_INTERVAL) {
+                    return;
+                }
+                sent = Some(Instant::now());
+                TransferRow {
+                    name: name.to_string(),
+                    transferred: *transferred,
+                    length: *length,
+                    speed: stats.current_speed(),
+                    state: TransferState::Active,
+                }
+            }
+            TransferEvent::Completed { name, length, stats } => TransferRow {
+                name: name.to_string(),
+                transferred: *length,
+                length: *length,
+                speed: stats.average_speed,
+                state: TransferState::Completed,
+            },
+            TransferEvent::Failed { name, error } => TransferRow {
+                name: name.to_string(),
+                transferred: 0,
+                length: 0,
+                speed: 0.0,
+                state: TransferState::Failed(error.to_string()),
+            },
+        };
+        let _ = sender.send(Event::Transfer(row));
+    });
+    Ok(client)
+}
+
+/// Syncs `profile` as its sync mode says. Nobody can be asked about a conflict from here, so
+/// those left to the user are skipped until the next pass.
+fn sync(client: &mut Client, profile: &ClientProfile) -> Result<usize> {
+    let root = profile.local_root();
+    match profile.sync_mode {
+        SyncMode::Download if profile.mirror_deletions => Ok(client.sync_mirrored(root)?.downloaded),
+        SyncMode::Download => client.sync(root),
+        SyncMode::TwoWay => Ok(client
+            .sync_two_way(root, profile.conflicts, |_| Resolution::Skip)?
+            .transferred()),
+    }
+}
@@ -0,0 +1,176 @@
+//! SSH tunnelling, so a server behind a firewall can be reached through a host that accepts SSH.
+//!
+//! [`open`] logs into the jump host, opens a forwarding channel to the server and hands back a
+//! local [`TcpStream`] whose traffic is pumped through that channel, so a [`crate::connection::Connection`]
+//! can be built on top of it as usual.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use directories::BaseDirs;
+use ssh2::{CheckResult, Channel, KnownHostFileKind, Session};
+
+const SSH_PORT: u16 = 22;
+const IDLE_WAIT: Duration = Duration::from_millis(5);
+const IDENTITIES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// A parsed `user@host[:port]` jump specification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Jump {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Jump {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (user, rest) = spec
+            .split_once('@')
+            .ok_or(anyhow!("Expected user@host[:port]"))?;
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>().map_err(|_| anyhow!("Invalid SSH port: {}", port))?),
+            None => (rest, SSH_PORT),
+        };
+        if user.is_empty() || host.is_empty() {
+            return Err(anyhow!("Expected user@host[:port]"));
+        }
+        Ok(Self {
+            user: user.to_string(),
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// Connects to `target_host:target_port` as seen from `jump`, over `stream` which must already be
+/// connected to the jump host.
+///
+/// The jump host must already be in `~/.ssh/known_hosts`. Authentication tries the SSH agent
+/// first, then the usual unencrypted keys in `~/.ssh`.
+pub fn open(jump: &Jump, stream: TcpStream, target_host: &str, target_port: u16) -> Result<TcpStream> {
+    let mut session = Session::new()?;
+    session.set_tcp_stream(stream);
+    session.handshake()?;
+    verify_host_key(&session, jump)?;
+    authenticate(&session, &jump.user)?;
+
+    let channel = session.channel_direct_tcpip(target_host, target_port, None)?;
+
+    // The connection talks to one end of a loopback pair while the pump owns the other
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let local = TcpStream::connect(listener.local_addr()?)?;
+    let (forwarded, _) = listener.accept()?;
+
+    thread::spawn(move || {
+        let _ = pump(&session, channel, forwarded);
+    });
+
+    Ok(local)
+}
+
+fn ssh_dir() -> Result<PathBuf> {
+    Ok(BaseDirs::new()
+        .ok_or(anyhow!("Home directory could not be retrieved."))?
+        .home_dir()
+        .join(".ssh"))
+}
+
+fn verify_host_key(session: &Session, jump: &Jump) -> Result<()> {
+    let (key, _) = session
+        .host_key()
+        .ok_or(anyhow!("Jump host sent no host key"))?;
+
+    let mut known_hosts = session.known_hosts()?;
+    // A missing file simply means nothing is known yet
+    let _ = known_hosts.read_file(&ssh_dir()?.join("known_hosts"), KnownHostFileKind::OpenSSH);
+
+    match known_hosts.check_port(&jump.host, jump.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(anyhow!(format!(
+            "Host key of {} does not match known_hosts, refusing to connect",
+            jump.host
+        ))),
+        CheckResult::NotFound => Err(anyhow!(format!(
+            "{} is not in known_hosts, connect once with ssh to trust it",
+            jump.host
+        ))),
+        CheckResult::Failure => Err(anyhow!("Could not check the jump host key")),
+    }
+}
+
+fn authenticate(session: &Session, user: &str) -> Result<()> {
+    if session.userauth_agent(user).is_ok() && session.authenticated() {
+        return Ok(());
+    }
+
+    let ssh_dir = ssh_dir()?;
+    for identity in IDENTITIES {
+        let private_key = ssh_dir.join(identity);
+        if !private_key.exists() {
+            continue;
+        }
+        if session.userauth_pubkey_file(user, None, &private_key, None).is_ok() && session.authenticated() {
+            return Ok(());
+        }
+    }
+
+    Err(anyhow!(format!("SSH authentication failed for {}", user)))
+}
+
+/// Shuttles bytes both ways between `stream` and `channel` until either side closes.
+fn pump(session: &Session, mut channel: Channel, mut stream: TcpStream) -> Result<()> {
+    // One thread drives both directions, so neither side may block
+    session.set_blocking(false);
+    stream.set_nonblocking(true)?;
+
+    let mut buffer = [0u8; 16384];
+    loop {
+        let mut idle = true;
+
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                write_all_nonblocking(&mut channel, &buffer[..n])?;
+                idle = false;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        match channel.read(&mut buffer) {
+            Ok(0) if channel.eof() => break,
+            Ok(0) => {}
+            Ok(n) => {
+                write_all_nonblocking(&mut stream, &buffer[..n])?;
+                idle = false;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        if idle {
+            thread::sleep(IDLE_WAIT);
+        }
+    }
+
+    let _ = stream.shutdown(Shutdown::Both);
+    session.set_blocking(true);
+    let _ = channel.close();
+    Ok(())
+}
+
+fn write_all_nonblocking<W: Write>(writer: &mut W, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(IDLE_WAIT),
+            Err(e) => return Err(e),
+        }
+    }
+    writer.flush()
+}
@@ -0,0 +1,237 @@
+//! Two-way synchronization between a client's parity root and a server's.
+//!
+//! Each pass compares both sides with how they were when last in sync, as recorded in an SQLite
+//! database at `oxideux/sync.sqlite3` under the config directory. A file changed on one side only
+//! is copied to the other; a file changed on both is a [`Conflict`], settled by the profile's
+//! [`ConflictStrategy`].
+//!
+//! Deletions are not propagated, as the protocol has no way to delete remote files: a file deleted
+//! on one side stays deleted there until it changes on the other.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use rusqlite::{params, Connection};
+
+use crate::config::{self, ConflictStrategy};
+use crate::parity::EntrySummary;
+
+const DATABASE_FILE: &str = "oxideux/sync.sqlite3";
+
+/// A file as one side has it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileState {
+    pub length: u64,
+    /// Seconds since the unix epoch.
+    pub modified: Option<u64>,
+}
+
+impl From<&EntrySummary> for FileState {
+    fn from(summary: &EntrySummary) -> Self {
+        Self {
+            length: summary.length,
+            modified: summary.modified,
+        }
+    }
+}
+
+/// Both sides of a file as they were when last in sync. The modification times differ after an
+/// upload, as the server stamps the file when it arrives.
+#[derive(Debug, Clone, Copy)]
+pub struct Synced {
+    pub local: FileState,
+    pub remote: FileState,
+}
+
+/// A file changed on both sides since they were last in sync.
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub name: String,
+    pub local: FileState,
+    pub remote: FileState,
+}
+
+/// How a [`Conflict`] is settled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// The local version replaces the remote one.
+    KeepLocal,
+    /// The remote version replaces the local one.
+    KeepRemote,
+    /// The local version is renamed (see: [`conflict_copy_name`]) and both are kept on both sides.
+    KeepBoth,
+    /// Nothing is done, and the conflict comes up again on the next pass.
+    Skip,
+}
+
+impl Conflict {
+    /// How `strategy` settles the conflict, `None` when it is up to the user.
+    pub fn resolve(&self, strategy: ConflictStrategy) -> Option<Resolution> {
+        match strategy {
+            ConflictStrategy::NewestWins if self.local.modified > self.remote.modified => Some(Resolution::KeepLocal),
+            ConflictStrategy::NewestWins => Some(Resolution::KeepRemote),
+            ConflictStrategy::KeepBoth => Some(Resolution::KeepBoth),
+            ConflictStrategy::Ask => None,
+        }
+    }
+}
+
+/// What a two-way sync pass does about one file.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// The file is in sync, or was deleted on one side and left alone on the other.
+    Nothing,
+    /// Both sides already match, but weren't recorded as being in sync.
+    Record,
+    Download,
+    Upload,
+    Conflict(Conflict),
+}
+
+/// Decides what to do about `name`, given how it is on each side and how it was when last in sync.
+pub fn plan(name: &str, local: Option<&FileState>, remote: Option<&FileState>, synced: Option<&Synced>) -> Action {
+    let (local_changed, remote_changed) = match synced {
+        Some(synced) => (
+            local.is_some_and(|local| *local != synced.local),
+            remote.is_some_and(|remote| *remote != synced.remote),
+        ),
+        // Never synced, so whatever exists is new
+        None => (local.is_some(), remote.is_some()),
+    };
+
+    match (local_changed, remote_changed) {
+        (false, false) => Action::Nothing,
+        (true, false) => Action::Upload,
+        (false, true) => Action::Download,
+        (true, true) => {
+            let (local, remote) = (*local.unwrap(), *remote.unwrap());
+            if synced.is_none() && local == remote {
+                return Action::Record;
+            }
+            Action::Conflict(Conflict {
+                name: name.to_string(),
+                local,
+                remote,
+            })
+        }
+    }
+}
+
+/// The name the local version of a conflicting `name` is kept under, e.g.
+/// `report (conflict 2024-05-01).txt`, numbered when `taken` already says it is in use.
+pub fn conflict_copy_name<F: Fn(&str) -> bool>(name: &str, taken: F) -> String {
+    let (dir, file) = match name.rsplit_once('/') {
+        Some((dir, file)) => (format!("{}/", dir), file),
+        None => (String::new(), name),
+    };
+    let (stem, extension) = match file.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (file, String::new()),
+    };
+    let date = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+    let date = &date[..10];
+
+    (1u32..)
+        .map(|n| match n {
+            1 => format!("{}{} (conflict {}){}", dir, stem, date, extension),
+            n => format!("{}{} (conflict {} {}){}", dir, stem, date, n, extension),
+        })
+        .find(|candidate| !taken(candidate))
+        .unwrap()
+}
+
+/// What a two-way sync pass did.
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    pub downloaded: usize,
+    pub uploaded: usize,
+    /// Conflicts that were settled, in either direction.
+    pub conflicts: usize,
+    /// Conflicting files that were skipped and are still waiting to be settled.
+    pub unresolved: Vec<String>,
+}
+
+impl SyncReport {
+    /// How many files were copied either way.
+    pub fn transferred(&self) -> usize {
+        self.downloaded + self.uploaded
+    }
+}
+
+/// How the files of a profile's parity root were when last in sync with the server.
+pub struct SyncState {
+    db: Connection,
+    profile: String,
+    root: String,
+}
+
+impl SyncState {
+    /// The state of the parity root `root` of the profile `profile`.
+    pub fn open<P: AsRef<Path>>(profile: &str, root: P) -> Result<Self> {
+        let path = config::config_dir_ext(DATABASE_FILE)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let db = Connection::open(path)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS synced (
+                 profile TEXT NOT NULL,
+                 root TEXT NOT NULL,
+                 name TEXT NOT NULL,
+                 local_length INTEGER NOT NULL,
+                 local_modified INTEGER,
+                 remote_length INTEGER NOT NULL,
+                 remote_modified INTEGER,
+                 PRIMARY KEY (profile, root, name)
+             );",
+        )?;
+        Ok(Self {
+            db,
+            profile: profile.to_string(),
+            root: root.as_ref().canonicalize()?.to_string_lossy().to_string(),
+        })
+    }
+
+    /// Every file that was in sync, by name.
+    pub fn synced(&self) -> Result<HashMap<String, Synced>> {
+        let mut statement = self.db.prepare(
+            "SELECT name, local_length, local_modified, remote_length, remote_modified
+             FROM synced WHERE profile = ?1 AND root = ?2",
+        )?;
+        let rows = statement.query_map(params![self.profile, self.root], |row| {
+            let state = |length: usize, modified: usize| -> rusqlite::Result<FileState> {
+                Ok(FileState {
+                    length: row.get::<_, i64>(length)? as u64,
+                    modified: row.get::<_, Option<i64>>(modified)?.map(|secs| secs as u64),
+                })
+            };
+            Ok((row.get::<_, String>(0)?, Synced {
+                local: state(1, 2)?,
+                remote: state(3, 4)?,
+            }))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<HashMap<_, _>>>()?)
+    }
+
+    /// Records that `name` is in sync, as `local` here and `remote` on the server.
+    pub fn record(&self, name: &str, local: &FileState, remote: &FileState) -> Result<()> {
+        self.db.execute(
+            "INSERT OR REPLACE INTO synced
+             (profile, root, name, local_length, local_modified, remote_length, remote_modified)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                self.profile,
+                self.root,
+                name,
+                local.length as i64,
+                local.modified.map(|secs| secs as i64),
+                remote.length as i64,
+                remote.modified.map(|secs| secs as i64),
+            ],
+        )?;
+        Ok(())
+    }
+}
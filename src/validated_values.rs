@@ -60,6 +60,8 @@ impl Display for ValidatedDirectory {
     }
 }
 
+/// A TCP port. Servers can only bind those below 1024 when started as root, which they then give
+/// up (see: [`crate::privileges`]).
 #[derive(Debug, Clone)]
 pub struct ValidatedPort(u16);
 
@@ -81,7 +83,7 @@ impl ValidatedValue for ValidatedPort {
     }
 
     fn is_value_valid(value: &u16) -> Result<()> {
-        if *value < 1024 {
+        if *value == 0 {
             return Err(anyhow!(format!("Invalid port: {}", value)));
         }
         Ok(())
@@ -131,3 +133,596 @@ impl Display for ValidatedIPv4 {
         f.debug_tuple("ValidatedIPv4").field(&self.get()).finish()
     }
 }
+
+/// A positive number of seconds, such as an interval.
+#[derive(Debug, Clone)]
+pub struct ValidatedSeconds(u64);
+
+impl ValidatedSeconds {
+    pub fn new(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl ValidatedValue for ValidatedSeconds {
+    type V = u64;
+
+    fn get(&self) -> &u64 {
+        &self.0
+    }
+
+    fn set(&mut self, value: u64) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &u64) -> Result<()> {
+        if *value == 0 {
+            return Err(anyhow!("Must be at least one second"));
+        }
+        Ok(())
+    }
+}
+
+impl Display for ValidatedSeconds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedSeconds").field(&self.get()).finish()
+    }
+}
+
+/// Like [`ValidatedDirectory`], but may also be left empty.
+#[derive(Debug, Clone)]
+pub struct ValidatedOptionalDirectory(String);
+
+impl ValidatedOptionalDirectory {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+impl ValidatedValue for ValidatedOptionalDirectory {
+    type V = String;
+
+    fn get(&self) -> &String {
+        &self.0
+    }
+
+    fn set(&mut self, value: String) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &String) -> Result<()> {
+        if value.is_empty() {
+            return Ok(());
+        }
+        ValidatedDirectory::is_value_valid(value)
+    }
+}
+
+impl Display for ValidatedOptionalDirectory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedOptionalDirectory").field(&self.get()).finish()
+    }
+}
+
+/// Like [`ValidatedPort`], but zero means disabled.
+#[derive(Debug, Clone)]
+pub struct ValidatedOptionalPort(u16);
+
+impl ValidatedOptionalPort {
+    pub fn new(value: u16) -> Self {
+        Self(value)
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0 != 0
+    }
+}
+
+impl ValidatedValue for ValidatedOptionalPort {
+    type V = u16;
+
+    fn get(&self) -> &u16 {
+        &self.0
+    }
+
+    fn set(&mut self, value: u16) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &u16) -> Result<()> {
+        if *value == 0 {
+            return Ok(());
+        }
+        ValidatedPort::is_value_valid(value)
+    }
+}
+
+impl Display for ValidatedOptionalPort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedOptionalPort").field(&self.get()).finish()
+    }
+}
+
+/// A count used as a limit, where zero means unlimited.
+#[derive(Debug, Clone)]
+pub struct ValidatedLimit(u64);
+
+impl ValidatedLimit {
+    pub fn new(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl ValidatedValue for ValidatedLimit {
+    type V = u64;
+
+    fn get(&self) -> &u64 {
+        &self.0
+    }
+
+    fn set(&mut self, value: u64) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(_value: &u64) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Display for ValidatedLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedLimit").field(&self.get()).finish()
+    }
+}
+
+/// An SSH jump host as `user@host[:port]`, or empty when connecting directly.
+#[derive(Debug, Clone)]
+pub struct ValidatedSshJump(String);
+
+impl ValidatedSshJump {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+impl ValidatedValue for ValidatedSshJump {
+    type V = String;
+
+    fn get(&self) -> &String {
+        &self.0
+    }
+
+    fn set(&mut self, value: String) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &String) -> Result<()> {
+        if value.is_empty() {
+            return Ok(());
+        }
+        crate::tunnel::Jump::parse(value)?;
+        Ok(())
+    }
+}
+
+impl Display for ValidatedSshJump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedSshJump").field(&self.get()).finish()
+    }
+}
+
+/// A SOCKS5 proxy as `[user:password@]host:port`, or empty when connecting directly.
+#[derive(Debug, Clone)]
+pub struct ValidatedProxy(String);
+
+impl ValidatedProxy {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+impl ValidatedValue for ValidatedProxy {
+    type V = String;
+
+    fn get(&self) -> &String {
+        &self.0
+    }
+
+    fn set(&mut self, value: String) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &String) -> Result<()> {
+        if value.is_empty() {
+            return Ok(());
+        }
+        crate::proxy::Proxy::parse(value)?;
+        Ok(())
+    }
+}
+
+impl Display for ValidatedProxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedProxy").field(&self.get()).finish()
+    }
+}
+
+/// An S3-compatible bucket as `s3://access_key:secret_key@host[:port]/bucket[/prefix]`, or empty
+/// when serving the parity root directory (see: [`crate::s3::Bucket`]).
+#[derive(Debug, Clone)]
+pub struct ValidatedStorage(String);
+
+impl ValidatedStorage {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+impl ValidatedValue for ValidatedStorage {
+    type V = String;
+
+    fn get(&self) -> &String {
+        &self.0
+    }
+
+    fn set(&mut self, value: String) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &String) -> Result<()> {
+        if value.is_empty() {
+            return Ok(());
+        }
+        crate::s3::Bucket::parse(value)?;
+        Ok(())
+    }
+}
+
+impl Display for ValidatedStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedStorage").field(&self.get()).finish()
+    }
+}
+
+/// A comma-separated list of CIDR ranges, possibly empty.
+#[derive(Debug, Clone)]
+pub struct ValidatedCidrList(String);
+
+impl ValidatedCidrList {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn entries(&self) -> Vec<String> {
+        self.0
+            .split(',')
+            .map(|entry| entry.trim().to_string())
+            .filter(|entry| !entry.is_empty())
+            .collect()
+    }
+
+    pub fn cidrs(&self) -> Result<Vec<crate::access::Cidr>> {
+        crate::access::Cidr::parse_list(&self.0)
+    }
+}
+
+impl ValidatedValue for ValidatedCidrList {
+    type V = String;
+
+    fn get(&self) -> &String {
+        &self.0
+    }
+
+    fn set(&mut self, value: String) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &String) -> Result<()> {
+        crate::access::Cidr::parse_list(value)?;
+        Ok(())
+    }
+}
+
+impl Display for ValidatedCidrList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedCidrList").field(&self.get()).finish()
+    }
+}
+
+/// A comma-separated list of exclusion patterns (see: [`crate::ignore`]), possibly empty.
+#[derive(Debug, Clone)]
+pub struct ValidatedPatternList(String);
+
+impl ValidatedPatternList {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn entries(&self) -> Vec<String> {
+        self.0
+            .split(',')
+            .map(|entry| entry.trim().to_string())
+            .filter(|entry| !entry.is_empty())
+            .collect()
+    }
+}
+
+impl ValidatedValue for ValidatedPatternList {
+    type V = String;
+
+    fn get(&self) -> &String {
+        &self.0
+    }
+
+    fn set(&mut self, value: String) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &String) -> Result<()> {
+        crate::ignore::IgnoreRules::parse(&ValidatedPatternList::new(value.clone()).entries())?;
+        Ok(())
+    }
+}
+
+impl Display for ValidatedPatternList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedPatternList").field(&self.get()).finish()
+    }
+}
+
+/// An [`crate::parity::EntryQuery`] as typed, empty for no filter.
+#[derive(Debug, Clone)]
+pub struct ValidatedQuery(String);
+
+impl ValidatedQuery {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl ValidatedValue for ValidatedQuery {
+    type V = String;
+
+    fn get(&self) -> &String {
+        &self.0
+    }
+
+    fn set(&mut self, value: String) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &String) -> Result<()> {
+        crate::parity::EntryQuery::parse(value)?;
+        Ok(())
+    }
+}
+
+impl Display for ValidatedQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedQuery").field(&self.get()).finish()
+    }
+}
+
+/// A [`crate::schedule::Schedule`] as typed, empty to sync at the profile's interval instead.
+#[derive(Debug, Clone)]
+pub struct ValidatedSchedule(String);
+
+impl ValidatedSchedule {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    /// The schedule, or `None` when there is none or it is invalid.
+    pub fn schedule(&self) -> Option<crate::schedule::Schedule> {
+        crate::schedule::Schedule::parse(&self.0).ok().filter(|_| self.is_set())
+    }
+}
+
+impl ValidatedValue for ValidatedSchedule {
+    type V = String;
+
+    fn get(&self) -> &String {
+        &self.0
+    }
+
+    fn set(&mut self, value: String) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &String) -> Result<()> {
+        if !value.is_empty() {
+            crate::schedule::Schedule::parse(value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for ValidatedSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedSchedule").field(&self.get()).finish()
+    }
+}
+
+/// A [`crate::bandwidth::BandwidthSchedule`] as typed, empty for unlimited transfers.
+#[derive(Debug, Clone)]
+pub struct ValidatedBandwidth(String);
+
+impl ValidatedBandwidth {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+impl ValidatedValue for ValidatedBandwidth {
+    type V = String;
+
+    fn get(&self) -> &String {
+        &self.0
+    }
+
+    fn set(&mut self, value: String) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &String) -> Result<()> {
+        crate::bandwidth::BandwidthSchedule::parse(value)?;
+        Ok(())
+    }
+}
+
+impl Display for ValidatedBandwidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedBandwidth").field(&self.get()).finish()
+    }
+}
+
+/// A hex-encoded Ed25519 public key signatures are checked against (see: [`crate::signing`]), or
+/// empty to not check them.
+#[derive(Debug, Clone)]
+pub struct ValidatedPublicKey(String);
+
+impl ValidatedPublicKey {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+impl ValidatedValue for ValidatedPublicKey {
+    type V = String;
+
+    fn get(&self) -> &String {
+        &self.0
+    }
+
+    fn set(&mut self, value: String) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &String) -> Result<()> {
+        if value.is_empty() {
+            return Ok(());
+        }
+        crate::signing::check_public_key(value)
+    }
+}
+
+impl Display for ValidatedPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedPublicKey").field(&self.get()).finish()
+    }
+}
+
+/// A user to run as, as `user[:group]` of this system (see: [`crate::privileges::RunAs`]), or
+/// empty to keep running as whoever started the program.
+#[derive(Debug, Clone)]
+pub struct ValidatedRunAs(String);
+
+impl ValidatedRunAs {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+impl ValidatedValue for ValidatedRunAs {
+    type V = String;
+
+    fn get(&self) -> &String {
+        &self.0
+    }
+
+    fn set(&mut self, value: String) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &String) -> Result<()> {
+        if value.is_empty() {
+            return Ok(());
+        }
+        crate::privileges::RunAs::parse(value)?.resolve()?;
+        Ok(())
+    }
+}
+
+impl Display for ValidatedRunAs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedRunAs").field(&self.get()).finish()
+    }
+}
+
+/// A file logs are appended to, in a directory that exists. Empty to not log to a file.
+#[derive(Debug, Clone)]
+pub struct ValidatedLogFile(String);
+
+impl ValidatedLogFile {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+impl ValidatedValue for ValidatedLogFile {
+    type V = String;
+
+    fn get(&self) -> &String {
+        &self.0
+    }
+
+    fn set(&mut self, value: String) {
+        self.0 = value;
+    }
+
+    fn is_value_valid(value: &String) -> Result<()> {
+        if value.is_empty() {
+            return Ok(());
+        }
+        let path = PathBuf::from(value);
+        if path.is_dir() {
+            return Err(anyhow!("Is a directory, not a file"));
+        }
+        match path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() || parent.is_dir() => Ok(()),
+            _ => Err(anyhow!("The directory it would be in does not exist")),
+        }
+    }
+}
+
+impl Display for ValidatedLogFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValidatedLogFile").field(&self.get()).finish()
+    }
+}
@@ -0,0 +1,67 @@
+//! Previous versions of files on servers with versioning enabled, kept when an upload replaces
+//! them.
+//!
+//! Each version is stored as `<parity root>/.oxideux-versions/<name>/<timestamp>`, the timestamp
+//! being when it was replaced (see: [`crate::parity::name_timestamp`]), and is identified by that
+//! timestamp in [`crate::request::Request::DownloadVersion`]. [`VERSIONS_DIR`] is never listed or
+//! served as a file of its own.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::parity;
+
+/// Where previous versions are kept, at the top of the parity root.
+pub const VERSIONS_DIR: &str = ".oxideux-versions";
+
+/// A previous version of a file, as listed by [`crate::request::Request::ListVersions`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    /// When the version was replaced, as in its file name.
+    pub id: String,
+    pub length: u64,
+}
+
+/// Moves the file at `path`, the current version of `name` in `root`, among its previous versions.
+pub fn archive(root: &Path, name: &str, path: &Path) -> Result<PathBuf> {
+    let dir = parity::safe_join(root.join(VERSIONS_DIR), name)?;
+    fs::create_dir_all(&dir)?;
+    // Versions replaced within the same second get a numbered suffix
+    let target = parity::keep_both_path(dir.join(parity::name_timestamp(SystemTime::now())));
+    fs::rename(path, &target)?;
+    Ok(target)
+}
+
+/// The previous versions of `name` in `root`, newest first.
+pub fn list(root: &Path, name: &str) -> Result<Vec<Version>> {
+    let dir = parity::safe_join(root.join(VERSIONS_DIR), name)?;
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut versions = vec![];
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            versions.push(Version {
+                id: entry.file_name().to_string_lossy().to_string(),
+                length: metadata.len(),
+            });
+        }
+    }
+    versions.sort_by(|a, b| b.id.cmp(&a.id));
+    Ok(versions)
+}
+
+/// Where the version `id` of `name` in `root` is kept, if there is one.
+pub fn path(root: &Path, name: &str, id: &str) -> Result<Option<PathBuf>> {
+    if id.contains('/') {
+        return Err(anyhow!(format!("Refusing unsafe version: {:?}", id)));
+    }
+    let path = parity::safe_join(parity::safe_join(root.join(VERSIONS_DIR), name)?, id)?;
+    Ok(Some(path).filter(|path| path.is_file()))
+}
@@ -0,0 +1,132 @@
+//! Parity root change notifications.
+//!
+//! A [`RootWatcher`] monitors a parity root and fans every change out to any number of
+//! subscribers, each receiving [`Notification`]s through its own channel. The server hands one
+//! subscription to every client that sends [`crate::request::Request::Subscribe`].
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use notify::event::{ModifyKind, RenameMode};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+
+use crate::ignore::IgnoreRules;
+
+/// A message pushed to subscribed clients. Names are `/`-separated and relative to the parity root.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Notification {
+    /// Sent while nothing changes so both peers notice a dead connection.
+    Heartbeat,
+    Added(String),
+    Changed(String),
+    Removed(String),
+}
+
+type Subscribers = Arc<Mutex<Vec<Sender<Notification>>>>;
+
+pub struct RootWatcher {
+    // Kept alive for as long as the watcher exists; dropping it stops the notifications.
+    _watcher: RecommendedWatcher,
+    subscribers: Subscribers,
+}
+
+impl RootWatcher {
+    /// Starts watching `root` recursively, leaving out the files the profile's rules `exclude`
+    /// and the root's ignore file exclude (see: [`crate::ignore`]).
+    pub fn new<P: AsRef<Path>>(root: P, exclude: IgnoreRules) -> Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+        let subscribers: Subscribers = Arc::new(Mutex::new(vec![]));
+
+        let mut watcher = {
+            let root = root.clone();
+            let subscribers = Arc::clone(&subscribers);
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+                let event = match res {
+                    Ok(event) => event,
+                    Err(_) => return,
+                };
+                let ignore = exclude.clone().with_ignore_file(&root).unwrap_or_else(|_| exclude.clone());
+                for notification in translate(&root, &event) {
+                    let name = match &notification {
+                        Notification::Added(name) | Notification::Changed(name) | Notification::Removed(name) => name,
+                        Notification::Heartbeat => continue,
+                    };
+                    if ignore.is_ignored(name) {
+                        continue;
+                    }
+                    // Subscribers whose receiving end is gone are dropped here
+                    subscribers
+                        .lock()
+                        .unwrap()
+                        .retain(|subscriber| subscriber.send(notification.clone()).is_ok());
+                }
+            })?
+        };
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+
+        Ok(Self {
+            _watcher: watcher,
+            subscribers,
+        })
+    }
+
+    /// Registers a new subscriber. Notifications stop once the [`Receiver`] is dropped.
+    pub fn subscribe(&self) -> Receiver<Notification> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+fn translate(root: &PathBuf, event: &notify::Event) -> Vec<Notification> {
+    let names: Vec<String> = event
+        .paths
+        .iter()
+        .filter_map(|path| relative_name(root, path))
+        .collect();
+
+    match event.kind {
+        EventKind::Create(_) => event
+            .paths
+            .iter()
+            .filter(|path| !path.is_dir())
+            .filter_map(|path| relative_name(root, path))
+            .map(Notification::Added)
+            .collect(),
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            names.into_iter().map(Notification::Removed).collect()
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            names.into_iter().map(Notification::Added).collect()
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if names.len() == 2 => vec![
+            Notification::Removed(names[0].clone()),
+            Notification::Added(names[1].clone()),
+        ],
+        EventKind::Modify(ModifyKind::Data(_)) | EventKind::Modify(ModifyKind::Any) => event
+            .paths
+            .iter()
+            .filter(|path| !path.is_dir())
+            .filter_map(|path| relative_name(root, path))
+            .map(Notification::Changed)
+            .collect(),
+        EventKind::Remove(_) => names.into_iter().map(Notification::Removed).collect(),
+        _ => vec![],
+    }
+}
+
+fn relative_name(root: &PathBuf, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let name = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    match name.is_empty() {
+        true => None,
+        false => Some(name),
+    }
+}
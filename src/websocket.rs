@@ -0,0 +1,130 @@
+//! A WebSocket transport (RFC 6455), so clients that can only open WebSockets can speak the
+//! protocol.
+//!
+//! The protocol's bytes are carried unchanged in binary messages; message boundaries mean nothing,
+//! exactly like segment boundaries on TCP. Framing and both halves of the opening handshake
+//! ([`WsTransport::accept`] for servers, [`WsTransport::connect`] for clients) are left to
+//! `tungstenite`.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tungstenite::protocol::Role;
+use tungstenite::{Error, Message, WebSocket};
+
+use crate::transport::Transport;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most bytes sent in one message, so a large write doesn't have to be copied whole.
+const MAX_MESSAGE: usize = 1024 * 1024;
+
+pub struct WsTransport {
+    socket: WebSocket<TcpStream>,
+    role: Role,
+    /// The payload of the last binary message received, and how much of it was read already.
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+impl WsTransport {
+    fn new(socket: WebSocket<TcpStream>, role: Role) -> Self {
+        Self { socket, role, pending: vec![], offset: 0 }
+    }
+
+    /// Performs the server half of the opening handshake on a freshly accepted `stream`.
+    pub fn accept(stream: TcpStream) -> Result<Self> {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let socket = tungstenite::accept(stream)
+            .map_err(|e| anyhow!(format!("WebSocket handshake failed: {}", e)))?;
+        socket.get_ref().set_read_timeout(None)?;
+        Ok(Self::new(socket, Role::Server))
+    }
+
+    /// Performs the client half of the opening handshake over `stream`, asking for `path` on `host`.
+    pub fn connect(stream: TcpStream, host: &str, path: &str) -> Result<Self> {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let (socket, _) = tungstenite::client::client(format!("ws://{}{}", host, path), stream)
+            .map_err(|e| anyhow!(format!("WebSocket handshake failed: {}", e)))?;
+        socket.get_ref().set_read_timeout(None)?;
+        Ok(Self::new(socket, Role::Client))
+    }
+}
+
+/// `error` as the I/O error a byte stream would have failed with.
+fn io_error(error: Error) -> io::Error {
+    match error {
+        Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+impl Read for WsTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.offset == self.pending.len() {
+            match self.socket.read() {
+                Ok(Message::Binary(payload)) => {
+                    self.pending = payload;
+                    self.offset = 0;
+                }
+                // Pings are answered by tungstenite; nothing else is part of the stream
+                Ok(Message::Close(_)) => return Ok(0),
+                Ok(_) => (),
+                Err(Error::ConnectionClosed | Error::AlreadyClosed) => return Ok(0),
+                Err(e) => return Err(io_error(e)),
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.offset);
+        buf[..n].copy_from_slice(&self.pending[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+impl Write for WsTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(MAX_MESSAGE);
+        match self.socket.write(Message::Binary(buf[..n].to_vec())) {
+            // The message is queued either way, and goes out with the next flush
+            Ok(()) => Ok(n),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(n),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush().map_err(io_error)
+    }
+}
+
+impl Transport for WsTransport {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        let stream = self.socket.get_ref().try_clone()?;
+        Ok(Box::new(Self::new(WebSocket::from_raw_socket(stream, self.role, None), self.role)))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.get_ref().set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.get_ref().set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.socket.get_ref().set_nonblocking(nonblocking)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.get_ref().peer_addr()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket.get_ref().shutdown(how)
+    }
+}
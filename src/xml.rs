@@ -0,0 +1,195 @@
+//! Just enough of an XML reader for the documents routers (UPnP descriptions, SOAP answers) and
+//! S3-compatible stores (listings, errors) send back.
+//!
+//! Documents are read into a tree of [`Element`]s with their text, entities and CDATA sections
+//! decoded. Attributes, comments, processing instructions and doctypes are skipped, and namespace
+//! prefixes are dropped from names, as those documents only use them to qualify the same names.
+
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Element {
+    /// The name without its namespace prefix.
+    pub name: String,
+    pub children: Vec<Element>,
+    /// The text directly inside the element, decoded.
+    pub text: String,
+}
+
+impl Element {
+    /// Reads the root element of `xml`.
+    pub fn parse(xml: &str) -> Result<Self> {
+        let mut open: Vec<Element> = vec![];
+        let mut root = None;
+        let mut rest = xml;
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix("<!--") {
+                rest = skip_past(after, "-->")?;
+            } else if let Some(after) = rest.strip_prefix("<![CDATA[") {
+                let end = after.find("]]>").ok_or(anyhow!("Unterminated CDATA section"))?;
+                match open.last_mut() {
+                    Some(element) => element.text.push_str(&after[..end]),
+                    None => return Err(anyhow!("CDATA section outside of the root element")),
+                }
+                rest = &after[end + 3..];
+            } else if let Some(after) = rest.strip_prefix("<?") {
+                rest = skip_past(after, "?>")?;
+            } else if let Some(after) = rest.strip_prefix("<!") {
+                rest = skip_past(after, ">")?;
+            } else if let Some(after) = rest.strip_prefix("</") {
+                let end = after.find('>').ok_or(anyhow!("Unterminated closing tag"))?;
+                let name = local_name(after[..end].trim());
+                let element = open.pop().ok_or(anyhow!(format!("Unexpected closing tag '{}'", name)))?;
+                if element.name != name {
+                    return Err(anyhow!(format!("Element '{}' closed by '{}'", element.name, name)));
+                }
+                close(&mut open, &mut root, element)?;
+                rest = &after[end + 1..];
+            } else if let Some(after) = rest.strip_prefix('<') {
+                let end = tag_end(after).ok_or(anyhow!("Unterminated tag"))?;
+                let tag = &after[..end];
+                let name = tag.trim_end_matches('/').split_whitespace().next().unwrap_or_default();
+                if name.is_empty() {
+                    return Err(anyhow!("Tag without a name"));
+                }
+                let element = Element {
+                    name: local_name(name).to_string(),
+                    ..Default::default()
+                };
+                match tag.ends_with('/') {
+                    true => close(&mut open, &mut root, element)?,
+                    false => open.push(element),
+                }
+                rest = &after[end + 1..];
+            } else {
+                let end = rest.find('<').unwrap_or(rest.len());
+                let text = &rest[..end];
+                match open.last_mut() {
+                    Some(element) => element.text.push_str(&unescape(text)?),
+                    None if text.trim().is_empty() => (),
+                    None => return Err(anyhow!("Text outside of the root element")),
+                }
+                rest = &rest[end..];
+            }
+        }
+        if let Some(element) = open.last() {
+            return Err(anyhow!(format!("Element '{}' is never closed", element.name)));
+        }
+        root.ok_or(anyhow!("No root element"))
+    }
+
+    /// The direct children called `name`, ignoring case.
+    pub fn children_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> + 'a {
+        self.children.iter().filter(move |child| child.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The first direct child called `name`, ignoring case.
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The text of the first direct child called `name`.
+    pub fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|child| child.text.as_str())
+    }
+
+    /// Every element below this one called `name`, ignoring case, in document order.
+    pub fn descendants<'a>(&'a self, name: &str) -> Vec<&'a Element> {
+        let mut found = vec![];
+        for child in &self.children {
+            if child.name.eq_ignore_ascii_case(name) {
+                found.push(child);
+            }
+            found.extend(child.descendants(name));
+        }
+        found
+    }
+
+    /// The text of the first element below this one called `name`.
+    pub fn descendant_text(&self, name: &str) -> Option<&str> {
+        self.descendants(name).first().map(|element| element.text.as_str())
+    }
+}
+
+/// Adds a closed `element` to the one it is in, or makes it the root.
+fn close(open: &mut [Element], root: &mut Option<Element>, element: Element) -> Result<()> {
+    match (open.last_mut(), root.is_some()) {
+        (Some(parent), _) => parent.children.push(element),
+        (None, false) => *root = Some(element),
+        (None, true) => return Err(anyhow!("More than one root element")),
+    }
+    Ok(())
+}
+
+fn skip_past<'a>(xml: &'a str, end: &str) -> Result<&'a str> {
+    let index = xml.find(end).ok_or(anyhow!(format!("Expected '{}'", end)))?;
+    Ok(&xml[index + end.len()..])
+}
+
+/// Where the tag starting `xml` ends, skipping over `>` inside quoted attribute values.
+fn tag_end(xml: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, c) in xml.char_indices() {
+        match (quote, c) {
+            (None, '>') => return Some(index),
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            _ => (),
+        }
+    }
+    None
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit_once(':').map_or(name, |(_, local)| local)
+}
+
+/// Decodes the predefined entities and character references of `text`.
+fn unescape(text: &str) -> Result<String> {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let end = rest[start..].find(';').ok_or(anyhow!("Unterminated entity"))? + start;
+        let entity = &rest[start + 1..end];
+        let c = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity.strip_prefix('#').and_then(|decimal| decimal.parse::<u32>().ok()),
+                };
+                code.and_then(char::from_u32).ok_or(anyhow!(format!("Unknown entity '&{};'", entity)))?
+            }
+        };
+        decoded.push(c);
+        rest = &rest[end + 1..];
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_decoded() {
+        let listing = Element::parse(
+            "<?xml version=\"1.0\"?><!-- listing --><ListBucketResult>\
+             <Contents><Key>Tom &amp; Jerry &#233;&#x2603;.txt</Key></Contents>\
+             <Contents><Key>a &lt;Key&gt;b&lt;/Key&gt;.txt</Key><Owner/></Contents>\
+             <Contents><Key><![CDATA[<raw> & kept]]></Key></Contents></ListBucketResult>",
+        )
+        .unwrap();
+        let keys: Vec<_> = listing.children_named("contents").filter_map(|contents| contents.child_text("Key")).collect();
+        assert_eq!(keys, ["Tom & Jerry é☃.txt", "a <Key>b</Key>.txt", "<raw> & kept"]);
+
+        assert!(Element::parse("<a><b></a></b>").is_err());
+        assert!(Element::parse("<a>&bogus;</a>").is_err());
+    }
+}